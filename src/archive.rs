@@ -0,0 +1,473 @@
+//! Zip containers whose parts are XML: Office Open XML (.docx, .xlsx,
+//! .pptx), OpenDocument (.odt, .ods, .odp) and EPUB.
+//!
+//! The reader walks the central directory and inflates parts with
+//! miniz_oxide, which keeps a full zip crate out of the wasm build. Stored
+//! and deflated entries are supported; ZIP64 and encrypted archives are not.
+
+use crate::database::{query, DocumentKind};
+use crate::dtd::parse_doctype;
+use crate::parser::{parse_html_to_nodes, parse_xml_to_nodes};
+use crate::NodeData;
+use serde::Serialize;
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+
+/// Most bytes the parts of one archive may inflate to, so a zip bomb fails
+/// before it exhausts wasm memory.
+const MAX_UNCOMPRESSED: usize = 256 * 1024 * 1024;
+
+/// Container formats recognized from the file name; anything else is `zip`.
+const FORMATS: &[&str] = &["docx", "xlsx", "pptx", "odt", "ods", "odp", "epub"];
+
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub path: String,
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed_size: usize,
+    size: usize,
+    header_offset: usize,
+}
+
+pub struct ZipArchive<'a> {
+    bytes: &'a [u8],
+    pub entries: Vec<ZipEntry>,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+impl<'a> ZipArchive<'a> {
+    /// Reads the central directory; entry data is only touched by `read`.
+    pub fn parse(bytes: &'a [u8]) -> Result<ZipArchive<'a>, String> {
+        let corrupt = || "The archive is corrupt".to_string();
+        // The end of central directory record is last, followed by a
+        // comment of at most 64 KiB
+        let search_from = bytes.len().saturating_sub(22 + 0xFFFF);
+        let end = (search_from..bytes.len().saturating_sub(21))
+            .rev()
+            .find(|&at| u32_at(bytes, at) == Some(0x0605_4b50))
+            .ok_or("Not a zip archive")?;
+        let count = u16_at(bytes, end + 10).ok_or_else(corrupt)? as usize;
+        let directory = u32_at(bytes, end + 16).ok_or_else(corrupt)?;
+        if count == 0xFFFF || directory == 0xFFFF_FFFF {
+            return Err("ZIP64 archives are not supported".to_string());
+        }
+
+        let mut entries = Vec::with_capacity(count);
+        let mut at = directory as usize;
+        for _ in 0..count {
+            if u32_at(bytes, at) != Some(0x0201_4b50) {
+                return Err(corrupt());
+            }
+            let field = |offset: usize| u32_at(bytes, at + offset).ok_or_else(corrupt);
+            let short = |offset: usize| u16_at(bytes, at + offset).ok_or_else(corrupt);
+            let (compressed_size, size, header_offset) = (field(20)?, field(24)?, field(42)?);
+            if [compressed_size, size, header_offset].contains(&0xFFFF_FFFF) {
+                return Err("ZIP64 archives are not supported".to_string());
+            }
+            let name_length = short(28)? as usize;
+            let name = bytes
+                .get(at + 46..at + 46 + name_length)
+                .ok_or_else(corrupt)?;
+            entries.push(ZipEntry {
+                path: String::from_utf8_lossy(name).into_owned(),
+                method: short(10)?,
+                flags: short(8)?,
+                crc: field(16)?,
+                compressed_size: compressed_size as usize,
+                size: size as usize,
+                header_offset: header_offset as usize,
+            });
+            at += 46 + name_length + short(30)? as usize + short(32)? as usize;
+        }
+        Ok(ZipArchive { bytes, entries })
+    }
+
+    /// The uncompressed bytes of `entry`, checked against its CRC.
+    pub fn read(&self, entry: &ZipEntry) -> Result<Vec<u8>, String> {
+        let corrupt = || format!("The archive entry '{}' is corrupt", entry.path);
+        if entry.flags & 1 != 0 {
+            return Err(format!("The archive entry '{}' is encrypted", entry.path));
+        }
+        let header = entry.header_offset;
+        if u32_at(self.bytes, header) != Some(0x0403_4b50) {
+            return Err(corrupt());
+        }
+        // The local header repeats the name but may carry a different extra
+        // field, so its own lengths locate the data
+        let start = header
+            + 30
+            + u16_at(self.bytes, header + 26).ok_or_else(corrupt)? as usize
+            + u16_at(self.bytes, header + 28).ok_or_else(corrupt)? as usize;
+        // the sizes come from the archive, so on wasm32 a crafted one can
+        // overflow `usize`
+        let end = start
+            .checked_add(entry.compressed_size)
+            .ok_or_else(corrupt)?;
+        let data = self.bytes.get(start..end).ok_or_else(corrupt)?;
+
+        let content = match entry.method {
+            0 => data.to_vec(),
+            8 => miniz_oxide::inflate::decompress_to_vec_with_limit(data, entry.size)
+                .map_err(|_| corrupt())?,
+            method => {
+                return Err(format!(
+                    "The archive entry '{}' uses unsupported compression method {}",
+                    entry.path, method
+                ))
+            }
+        };
+        if content.len() != entry.size || crc32(&content) != entry.crc {
+            return Err(corrupt());
+        }
+        Ok(content)
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let table: Vec<u32> = (0..256u32)
+        .map(|mut c| {
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xEDB8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            c
+        })
+        .collect();
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The container format named by the file extension, e.g. `docx`.
+pub fn container_format(name: &str) -> &'static str {
+    let extension = name
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    FORMATS
+        .iter()
+        .find(|&&format| format == extension)
+        .copied()
+        .unwrap_or("zip")
+}
+
+/// How a part is parsed, from its extension; `None` for parts that are not
+/// markup, such as images.
+pub fn part_kind(path: &str) -> Option<DocumentKind> {
+    if path.ends_with('/') {
+        return None;
+    }
+    let extension = path
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "xml" | "rels" | "xhtml" | "opf" | "ncx" | "svg" => Some(DocumentKind::Xml),
+        "html" | "htm" => Some(DocumentKind::Html),
+        _ => None,
+    }
+}
+
+/// A parsed part ready for `insert_document`.
+pub struct Part {
+    pub kind: DocumentKind,
+    pub nodes: Vec<NodeData>,
+    pub doctype: Option<String>,
+}
+
+/// Decodes and parses one part. XHTML that is not well-formed XML, which
+/// is common in EPUBs that use HTML entities, falls back to the HTML parser.
+pub fn parse_part(path: &str, kind: DocumentKind, data: &[u8]) -> Result<Part, String> {
+    let content = decode(data).ok_or("The part is not UTF-8 or UTF-16 text")?;
+    let doctype = parse_doctype(&content)
+        .ok()
+        .flatten()
+        .map(|d| d.declaration);
+    let xhtml = path.to_ascii_lowercase().ends_with(".xhtml");
+    let (kind, nodes) = match kind {
+        DocumentKind::Html => (kind, parse_html_to_nodes(&content)?),
+        _ => match parse_xml_to_nodes(&content) {
+            Ok(nodes) => (DocumentKind::Xml, nodes),
+            Err(_) if xhtml => (DocumentKind::Html, parse_html_to_nodes(&content)?),
+            Err(e) => return Err(e),
+        },
+    };
+    Ok(Part {
+        kind,
+        nodes,
+        doctype,
+    })
+}
+
+/// Text of a part: UTF-8 unless a byte order mark says UTF-16.
+fn decode(data: &[u8]) -> Option<String> {
+    let utf16 = |big_endian: bool| {
+        let units: Vec<u16> = data[2..]
+            .chunks_exact(2)
+            .map(|pair| {
+                let pair = [pair[0], pair[1]];
+                if big_endian {
+                    u16::from_be_bytes(pair)
+                } else {
+                    u16::from_le_bytes(pair)
+                }
+            })
+            .collect();
+        String::from_utf16(&units).ok()
+    };
+    match data {
+        [0xFE, 0xFF, ..] => utf16(true),
+        [0xFF, 0xFE, ..] => utf16(false),
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8(rest.to_vec()).ok(),
+        _ => String::from_utf8(data.to_vec()).ok(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchivePart {
+    pub document_id: i64,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedPart {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveSummary {
+    pub container_id: i64,
+    pub format: String,
+    /// Loaded parts in archive order.
+    pub parts: Vec<ArchivePart>,
+    /// Markup parts that failed to parse.
+    pub skipped: Vec<SkippedPart>,
+}
+
+/// Records a container and returns its id.
+pub fn insert_container(db: *mut sqlite3, name: &str, format: &str) -> Result<i64, String> {
+    query(
+        db,
+        "INSERT INTO containers (name, format) VALUES (?1, ?2) RETURNING id",
+        &[json!(name), json!(format)],
+    )?
+    .rows
+    .first()
+    .and_then(|row| row[0].as_i64())
+    .ok_or_else(|| "Failed to insert container".to_string())
+}
+
+/// Links a loaded document to the container part it came from.
+pub fn link_part(
+    db: *mut sqlite3,
+    document_id: i64,
+    container_id: i64,
+    path: &str,
+) -> Result<(), String> {
+    query(
+        db,
+        "UPDATE documents SET container_id = ?2, part_path = ?3 WHERE id = ?1",
+        &[json!(document_id), json!(container_id), json!(path)],
+    )?;
+    Ok(())
+}
+
+/// Parts worth loading, with the total they inflate to checked against
+/// `MAX_UNCOMPRESSED` up front.
+pub fn markup_parts<'a>(
+    archive: &'a ZipArchive<'_>,
+) -> Result<Vec<(&'a ZipEntry, DocumentKind)>, String> {
+    let parts: Vec<(&ZipEntry, DocumentKind)> = archive
+        .entries
+        .iter()
+        .filter_map(|entry| part_kind(&entry.path).map(|kind| (entry, kind)))
+        .collect();
+    // Declared sizes are untrusted, and their sum can wrap on wasm32
+    let total = parts
+        .iter()
+        .try_fold(0usize, |total, (entry, _)| total.checked_add(entry.size));
+    match total {
+        Some(total) if total <= MAX_UNCOMPRESSED => Ok(parts),
+        Some(total) => Err(format!(
+            "The archive's XML parts expand to {} bytes, more than the limit of {}",
+            total, MAX_UNCOMPRESSED
+        )),
+        None => Err(format!(
+            "The archive's XML parts expand to more than the limit of {} bytes",
+            MAX_UNCOMPRESSED
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A zip with one stored and one deflated entry, built by hand.
+    fn build_zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for (name, content, deflate) in entries {
+            let data = if *deflate {
+                miniz_oxide::deflate::compress_to_vec(content, 6)
+            } else {
+                content.to_vec()
+            };
+            let method: u16 = if *deflate { 8 } else { 0 };
+            let offset = out.len() as u32;
+            let mut header = Vec::new();
+            header.extend(0x0403_4b50u32.to_le_bytes());
+            header.extend([20, 0, 0, 0]);
+            header.extend(method.to_le_bytes());
+            header.extend([0, 0, 0, 0]);
+            header.extend(crc32(content).to_le_bytes());
+            header.extend((data.len() as u32).to_le_bytes());
+            header.extend((content.len() as u32).to_le_bytes());
+            header.extend((name.len() as u16).to_le_bytes());
+            header.extend([0, 0]);
+            out.extend(&header);
+            out.extend(name.as_bytes());
+            out.extend(&data);
+
+            directory.extend(0x0201_4b50u32.to_le_bytes());
+            directory.extend([20, 0]);
+            directory.extend(&header[4..]);
+            directory.extend([0; 10]);
+            directory.extend(offset.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let directory_offset = out.len() as u32;
+        out.extend(&directory);
+        out.extend(0x0605_4b50u32.to_le_bytes());
+        out.extend([0, 0, 0, 0]);
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((directory.len() as u32).to_le_bytes());
+        out.extend(directory_offset.to_le_bytes());
+        out.extend([0, 0]);
+        out
+    }
+
+    #[test]
+    fn test_read_zip() {
+        let document = b"<w:document xmlns:w=\"urn:w\"><w:p>Hello</w:p></w:document>";
+        let bytes = build_zip(&[
+            ("mimetype", b"application/epub+zip", false),
+            ("word/document.xml", document, true),
+            ("media/", b"", false),
+        ]);
+        let archive = ZipArchive::parse(&bytes).unwrap();
+        let paths: Vec<&str> = archive.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["mimetype", "word/document.xml", "media/"]);
+        assert_eq!(
+            archive.read(&archive.entries[0]).unwrap(),
+            b"application/epub+zip"
+        );
+        assert_eq!(archive.read(&archive.entries[1]).unwrap(), document);
+
+        let parts = markup_parts(&archive).unwrap();
+        assert_eq!(parts.len(), 1);
+        let part = parse_part("word/document.xml", parts[0].1, document).unwrap();
+        assert_eq!(part.nodes[1].prefix.as_deref(), Some("w"));
+
+        let mut corrupt = bytes.clone();
+        let at = corrupt.windows(5).position(|w| w == b"epub+").unwrap();
+        corrupt[at] = b'E';
+        let archive = ZipArchive::parse(&corrupt).unwrap();
+        assert!(archive.read(&archive.entries[0]).is_err());
+        assert!(ZipArchive::parse(b"<xml/>").is_err());
+
+        let archive = ZipArchive::parse(&bytes).unwrap();
+        let oversized = ZipEntry {
+            compressed_size: usize::MAX,
+            ..archive.entries[0].clone()
+        };
+        let error = archive.read(&oversized).unwrap_err();
+        assert_eq!(error, "The archive entry 'mimetype' is corrupt");
+    }
+
+    #[test]
+    fn test_declared_sizes_cannot_wrap() {
+        let bytes = build_zip(&[("a.xml", b"<a/>", false), ("b.xml", b"<b/>", false)]);
+        let mut archive = ZipArchive::parse(&bytes).unwrap();
+        for entry in &mut archive.entries {
+            entry.size = usize::MAX / 2 + 1;
+        }
+        let error = markup_parts(&archive).err().unwrap();
+        assert!(error.contains("more than the limit"), "{}", error);
+    }
+
+    #[test]
+    fn test_insert_archive() {
+        let db = crate::testing::open();
+        let document = b"<w:document xmlns:w=\"urn:w\"><w:body><w:p>Hello</w:p><w:p>again</w:p></w:body></w:document>";
+        let bytes = build_zip(&[
+            ("[Content_Types].xml", b"<Types/>", true),
+            ("word/document.xml", document, true),
+            ("word/broken.xml", b"<w:p>", false),
+            ("word/media/image1.png", b"\x89PNG", false),
+        ]);
+        let summary = crate::XmlSqlDb { db }
+            .insert_archive(&bytes, "report.docx")
+            .unwrap();
+        assert_eq!(summary.format, "docx");
+        let paths: Vec<&str> = summary.parts.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, ["[Content_Types].xml", "word/document.xml"]);
+        assert_eq!(summary.skipped.len(), 1);
+        assert_eq!(summary.skipped[0].path, "word/broken.xml");
+
+        let rows = query(
+            db,
+            "SELECT d.name, d.part_path, c.name, c.format FROM documents d
+            JOIN containers c ON c.id = d.container_id ORDER BY d.id",
+            &[],
+        )
+        .unwrap()
+        .rows;
+        assert_eq!(
+            rows[1],
+            vec![
+                json!("report.docx/word/document.xml"),
+                json!("word/document.xml"),
+                json!("report.docx"),
+                json!("docx")
+            ]
+        );
+        let sql = crate::selector::css_to_sql("w|p").unwrap();
+        assert_eq!(query(db, &sql, &[]).unwrap().rows.len(), 2);
+    }
+
+    #[test]
+    fn test_parts() {
+        assert_eq!(container_format("Report.DOCX"), "docx");
+        assert_eq!(container_format("bundle.zip"), "zip");
+        assert_eq!(part_kind("OEBPS/ch1.xhtml"), Some(DocumentKind::Xml));
+        assert_eq!(part_kind("_rels/.rels"), Some(DocumentKind::Xml));
+        assert_eq!(part_kind("media/image1.png"), None);
+
+        let xhtml = b"<html><body><p>caf&eacute;</p></body></html>";
+        let part = parse_part("ch1.xhtml", DocumentKind::Xml, xhtml).unwrap();
+        assert_eq!(part.kind, DocumentKind::Html);
+        let utf16: Vec<u8> = [0xFF, 0xFE]
+            .into_iter()
+            .chain("<a>é</a>".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        let part = parse_part("a.xml", DocumentKind::Xml, &utf16).unwrap();
+        assert_eq!(part.nodes[0].text_content.as_deref(), Some("é"));
+    }
+}
@@ -0,0 +1,454 @@
+//! Canonical XML 1.0 and Exclusive XML Canonicalization of stored subtrees.
+//!
+//! Output is written from the stored segments, so text, whitespace,
+//! comments and processing instructions appear where the source had them.
+//! A root element stands for its whole document, and the comments and
+//! processing instructions around it are written too. Documents loaded by
+//! older versions only kept each element's trimmed text, which comes out
+//! before the element's children.
+
+use crate::database::{query, query_value, SegmentKind};
+use crate::parser::XML_NAMESPACE;
+use crate::tree::{
+    ancestor_namespaces, document_segments, local_name, qualified, Scope, StoredNode, StoredTree,
+};
+use crate::Segment;
+use serde::Deserialize;
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct C14nOptions {
+    /// Exclusive C14N: declare only the namespaces an element visibly uses,
+    /// rather than every namespace in scope.
+    pub exclusive: bool,
+    /// Keep comments, as the `#WithComments` variants do.
+    pub with_comments: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Attribute {
+    prefix: Option<String>,
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Element {
+    prefix: Option<String>,
+    name: String,
+    attributes: Vec<Attribute>,
+    /// Declarations made on the element, `""` being the default namespace.
+    declarations: Vec<(String, String)>,
+    children: Vec<usize>,
+    segments: Vec<Segment>,
+}
+
+impl From<&StoredNode> for Element {
+    fn from(node: &StoredNode) -> Element {
+        Element {
+            prefix: node.prefix.clone(),
+            name: node.tag_name.clone(),
+            attributes: node
+                .attributes
+                .iter()
+                .map(|(name, value)| {
+                    let prefix = node.attribute_prefixes.get(name);
+                    Attribute {
+                        prefix: prefix.cloned(),
+                        name: local_name(name, prefix.map(String::as_str)).to_string(),
+                        value: value.clone(),
+                    }
+                })
+                .collect(),
+            declarations: node.namespaces.clone(),
+            children: node.children.clone(),
+            segments: node.segments.clone(),
+        }
+    }
+}
+
+/// Canonical form of the subtree rooted at `node_id`, or `None` if the node
+/// does not exist. For a whole document pass its `root_node_id`.
+pub fn canonicalize(
+    db: *mut sqlite3,
+    node_id: i64,
+    options: &C14nOptions,
+) -> Result<Option<String>, String> {
+    let Some(tree) = StoredTree::load(db, node_id)? else {
+        return Ok(None);
+    };
+
+    let elements: Vec<Element> = tree.nodes.iter().map(Element::from).collect();
+
+    let (scope, xml_attributes) = ancestor_context(db, node_id)?;
+    let inherited = if options.exclusive {
+        Vec::new()
+    } else {
+        xml_attributes
+    };
+    let mut out = render(&elements, options, &scope, &inherited);
+
+    let root_of = query_value(
+        db,
+        "SELECT document_id FROM nodes WHERE id = ?1 AND parent_id IS NULL",
+        &[json!(node_id)],
+    )?;
+    if let Some(document_id) = root_of.and_then(|v| v.as_i64()) {
+        // Each is separated from the root element by a line feed
+        let (mut before, mut after) = (String::new(), String::new());
+        for segment in document_segments(db, document_id)? {
+            if let Some(markup) = segment_markup(&segment, options.with_comments) {
+                if segment.slot == 0 {
+                    before.push_str(&markup);
+                    before.push('\n');
+                } else {
+                    after.push('\n');
+                    after.push_str(&markup);
+                }
+            }
+        }
+        out = before + &out + &after;
+    }
+    Ok(Some(out))
+}
+
+/// Namespaces in scope above `node_id`, and the `xml:*` attributes its
+/// ancestors pass down (the nearest wins), which Canonical XML 1.0 copies
+/// onto the apex of a subtree.
+fn ancestor_context(db: *mut sqlite3, node_id: i64) -> Result<(Scope, Vec<Attribute>), String> {
+    let scope = ancestor_namespaces(db, node_id)?;
+
+    let mut xml_attributes: BTreeMap<String, String> = BTreeMap::new();
+    for row in query(
+        db,
+        "WITH RECURSIVE up(id, level) AS (
+            SELECT parent_id, 1 FROM nodes WHERE id = ?1 AND parent_id IS NOT NULL
+            UNION ALL
+            SELECT n.parent_id, up.level + 1 FROM nodes n JOIN up ON n.id = up.id
+            WHERE n.parent_id IS NOT NULL
+        )
+        SELECT a.name, a.value FROM attributes a JOIN up ON up.id = a.node_id
+        WHERE a.prefix = 'xml' ORDER BY up.level DESC, a.id",
+        &[json!(node_id)],
+    )?
+    .rows
+    {
+        xml_attributes.insert(
+            row[0].as_str().unwrap_or_default().to_string(),
+            row[1].as_str().unwrap_or_default().to_string(),
+        );
+    }
+    let xml_attributes = xml_attributes
+        .into_iter()
+        .map(|(name, value)| Attribute {
+            prefix: Some("xml".to_string()),
+            name,
+            value,
+        })
+        .collect();
+    Ok((scope, xml_attributes))
+}
+
+/// Writes `elements[0]` and its descendants. `scope` holds the namespaces
+/// declared above the apex and `inherited` the attributes to add to it.
+fn render(
+    elements: &[Element],
+    options: &C14nOptions,
+    scope: &Scope,
+    inherited: &[Attribute],
+) -> String {
+    let mut out = String::new();
+    write_element(
+        elements,
+        0,
+        options,
+        scope,
+        &Scope::new(),
+        inherited,
+        &mut out,
+    );
+    out
+}
+
+fn write_element(
+    elements: &[Element],
+    index: usize,
+    options: &C14nOptions,
+    scope: &Scope,
+    rendered: &Scope,
+    inherited: &[Attribute],
+    out: &mut String,
+) {
+    let element = &elements[index];
+    let mut scope = scope.clone();
+    for (prefix, uri) in &element.declarations {
+        scope.insert(prefix.clone(), uri.clone());
+    }
+
+    let candidates: Scope = if options.exclusive {
+        // Visibly used: the element's own prefix (or the default namespace)
+        // and the prefixes of its attributes
+        let used = std::iter::once(element.prefix.as_deref().unwrap_or("")).chain(
+            element
+                .attributes
+                .iter()
+                .filter_map(|a| a.prefix.as_deref())
+                .filter(|&prefix| prefix != "xml"),
+        );
+        used.filter_map(|prefix| match scope.get(prefix) {
+            Some(uri) => Some((prefix.to_string(), uri.clone())),
+            None if prefix.is_empty() => Some((String::new(), String::new())),
+            None => None,
+        })
+        .collect()
+    } else {
+        scope.clone()
+    };
+    let mut rendered = rendered.clone();
+    let mut declarations = Vec::new();
+    for (prefix, uri) in candidates {
+        let current = rendered.get(&prefix).map_or("", String::as_str);
+        let needed = if prefix.is_empty() {
+            // xmlns="" only undoes a default namespace rendered above
+            uri != current
+        } else {
+            !uri.is_empty() && rendered.get(&prefix) != Some(&uri)
+        };
+        if needed {
+            declarations.push((prefix.clone(), uri.clone()));
+            rendered.insert(prefix, uri);
+        }
+    }
+
+    let mut attributes: Vec<(&str, &Attribute)> = element
+        .attributes
+        .iter()
+        .chain(inherited.iter().filter(|a| {
+            !element
+                .attributes
+                .iter()
+                .any(|e| e.prefix == a.prefix && e.name == a.name)
+        }))
+        .map(|attribute| {
+            let uri = match attribute.prefix.as_deref() {
+                None => "",
+                Some("xml") => XML_NAMESPACE,
+                Some(prefix) => scope.get(prefix).map_or("", String::as_str),
+            };
+            (uri, attribute)
+        })
+        .collect();
+    attributes.sort_by(|(a_uri, a), (b_uri, b)| (a_uri, &a.name).cmp(&(b_uri, &b.name)));
+
+    let name = qualified(element.prefix.as_deref(), &element.name);
+    out.push('<');
+    out.push_str(&name);
+    for (prefix, uri) in &declarations {
+        if prefix.is_empty() {
+            out.push_str(" xmlns=\"");
+        } else {
+            out.push_str(&format!(" xmlns:{}=\"", prefix));
+        }
+        out.push_str(&escape_attribute(uri));
+        out.push('"');
+    }
+    for (_, attribute) in attributes {
+        out.push_str(&format!(
+            " {}=\"{}\"",
+            qualified(attribute.prefix.as_deref(), &attribute.name),
+            escape_attribute(&attribute.value)
+        ));
+    }
+    out.push('>');
+    let mut segments = element.segments.iter().peekable();
+    for (position, &child) in element.children.iter().enumerate() {
+        while let Some(segment) = segments.next_if(|s| s.slot as usize <= position) {
+            out.extend(segment_markup(segment, options.with_comments));
+        }
+        write_element(elements, child, options, &scope, &rendered, &[], out);
+    }
+    for segment in segments {
+        out.extend(segment_markup(segment, options.with_comments));
+    }
+    out.push_str("</");
+    out.push_str(&name);
+    out.push('>');
+}
+
+/// A text, comment or processing instruction as the spec writes it, or
+/// `None` for a comment left out.
+fn segment_markup(segment: &Segment, with_comments: bool) -> Option<String> {
+    match segment.kind {
+        SegmentKind::Text => Some(escape_text(&segment.value)),
+        SegmentKind::Comment => with_comments.then(|| format!("<!--{}-->", segment.value)),
+        SegmentKind::Pi => {
+            let target = segment.target.as_deref().unwrap_or_default();
+            Some(if segment.value.is_empty() {
+                format!("<?{}?>", target)
+            } else {
+                format!("<?{} {}?>", target, segment.value)
+            })
+        }
+    }
+}
+
+/// Text escaping from the C14N spec: `&`, `<`, `>` and carriage returns.
+fn escape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Attribute escaping from the C14N spec, which also keeps whitespace
+/// characters from being normalized away when the output is parsed again.
+fn escape_attribute(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{load_xml, node_id, open};
+
+    const EXCLUSIVE: C14nOptions = C14nOptions {
+        exclusive: true,
+        with_comments: false,
+    };
+
+    /// Elements from parser output, as they would be stored.
+    fn parsed(xml: &str) -> Vec<Element> {
+        let nodes = crate::parser::parse_xml_to_nodes(xml).unwrap();
+        StoredTree::from_parsed(&nodes)
+            .nodes
+            .iter()
+            .map(Element::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_inclusive() {
+        let elements = parsed(
+            r#"<doc xmlns="urn:d" xmlns:b="urn:b" z="1" b:y="2" a="&#9;x&#10;"><e/><f xmlns="">1 &gt; 0</f></doc>"#,
+        );
+        assert_eq!(
+            render(&elements, &C14nOptions::default(), &Scope::new(), &[]),
+            "<doc xmlns=\"urn:d\" xmlns:b=\"urn:b\" a=\"&#x9;x&#xA;\" z=\"1\" b:y=\"2\">\
+             <e></e><f xmlns=\"\">1 &gt; 0</f></doc>"
+        );
+    }
+
+    #[test]
+    fn test_keeps_text_comments_and_processing_instructions() {
+        let db = open();
+        load_xml(
+            db,
+            "doc.xml",
+            "<?xml version=\"1.0\"?>\n<?xml-stylesheet href=\"doc.xsl\"?>\n<!-- head -->\n\
+             <doc>\n  <p>Hello <b>big</b> world</p>\n  <!-- note -->\n</doc>\n<!-- tail -->\n",
+        );
+        let doc = node_id(db, "doc");
+        assert_eq!(
+            canonicalize(db, doc, &C14nOptions::default())
+                .unwrap()
+                .unwrap(),
+            "<?xml-stylesheet href=\"doc.xsl\"?>\n\
+             <doc>\n  <p>Hello <b>big</b> world</p>\n  \n</doc>"
+        );
+        let with_comments = C14nOptions {
+            with_comments: true,
+            ..C14nOptions::default()
+        };
+        assert_eq!(
+            canonicalize(db, doc, &with_comments).unwrap().unwrap(),
+            "<?xml-stylesheet href=\"doc.xsl\"?>\n<!-- head -->\n\
+             <doc>\n  <p>Hello <b>big</b> world</p>\n  <!-- note -->\n</doc>\n<!-- tail -->"
+        );
+        // A subtree leaves out what surrounds the document element
+        assert_eq!(
+            canonicalize(db, node_id(db, "p"), &with_comments)
+                .unwrap()
+                .unwrap(),
+            "<p>Hello <b>big</b> world</p>"
+        );
+    }
+
+    #[test]
+    fn test_attributes_sharing_a_local_name() {
+        let xml = r#"<e xmlns:a="urn:1" xmlns:b="urn:2" a:id="1" b:id="2" id="3"/>"#;
+        let expected = "<e xmlns:a=\"urn:1\" xmlns:b=\"urn:2\" id=\"3\" a:id=\"1\" b:id=\"2\"></e>";
+        assert_eq!(
+            render(&parsed(xml), &C14nOptions::default(), &Scope::new(), &[]),
+            expected
+        );
+
+        // and once stored
+        let db = open();
+        load_xml(db, "e.xml", xml);
+        let e = node_id(db, "e");
+        assert_eq!(
+            canonicalize(db, e, &C14nOptions::default())
+                .unwrap()
+                .as_deref(),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn test_exclusive_subtree() {
+        let elements = parsed(
+            r#"<n1:elem2 xmlns:n1="urn:1" xmlns:n2="urn:2" xml:lang="en"><n3:stuff xmlns:n3="urn:3"/></n1:elem2>"#,
+        );
+        assert_eq!(
+            render(&elements, &EXCLUSIVE, &Scope::new(), &[]),
+            "<n1:elem2 xmlns:n1=\"urn:1\" xml:lang=\"en\">\
+             <n3:stuff xmlns:n3=\"urn:3\"></n3:stuff></n1:elem2>"
+        );
+
+        // The child alone, with the parent's declarations and xml:lang in scope
+        let scope: Scope = [("n1", "urn:1"), ("n2", "urn:2")]
+            .iter()
+            .map(|(p, u)| (p.to_string(), u.to_string()))
+            .collect();
+        let lang = [Attribute {
+            prefix: Some("xml".to_string()),
+            name: "lang".to_string(),
+            value: "en".to_string(),
+        }];
+        let child = vec![Element {
+            children: Vec::new(),
+            ..elements[1].clone()
+        }];
+        assert_eq!(
+            render(&child, &EXCLUSIVE, &scope, &[]),
+            "<n3:stuff xmlns:n3=\"urn:3\"></n3:stuff>"
+        );
+        assert_eq!(
+            render(&child, &C14nOptions::default(), &scope, &lang),
+            "<n3:stuff xmlns:n1=\"urn:1\" xmlns:n2=\"urn:2\" xmlns:n3=\"urn:3\" xml:lang=\"en\">\
+             </n3:stuff>"
+        );
+    }
+}
@@ -0,0 +1,511 @@
+use crate::QueryResult;
+use serde::{Deserialize, Serialize};
+use sqlite_wasm_rs::{
+    sqlite3, sqlite3_bind_double, sqlite3_bind_int64, sqlite3_bind_null, sqlite3_bind_text,
+    sqlite3_column_count, sqlite3_column_double, sqlite3_column_int64, sqlite3_column_name,
+    sqlite3_column_text, sqlite3_column_type, sqlite3_errmsg, sqlite3_exec, sqlite3_finalize,
+    sqlite3_free, sqlite3_prepare_v2, sqlite3_step, sqlite3_stmt, SQLITE_DONE, SQLITE_FLOAT,
+    SQLITE_INTEGER, SQLITE_OK, SQLITE_ROW, SQLITE_TEXT, SQLITE_TRANSIENT,
+};
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+
+/// Stored in `documents.kind`; selectors fold name case on HTML documents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentKind {
+    Xml,
+    Html,
+    Json,
+}
+
+impl DocumentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DocumentKind::Xml => "xml",
+            DocumentKind::Html => "html",
+            DocumentKind::Json => "json",
+        }
+    }
+
+    /// Guesses the kind from a file name: `.html` and `.htm` are HTML,
+    /// `.json` is JSON.
+    pub fn from_name(name: &str) -> DocumentKind {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".html") || name.ends_with(".htm") {
+            DocumentKind::Html
+        } else if name.ends_with(".json") {
+            DocumentKind::Json
+        } else {
+            DocumentKind::Xml
+        }
+    }
+}
+
+/// Stored in `segments.kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentKind {
+    Text,
+    Comment,
+    /// A processing instruction
+    Pi,
+}
+
+impl SegmentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SegmentKind::Text => "text",
+            SegmentKind::Comment => "comment",
+            SegmentKind::Pi => "pi",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<SegmentKind> {
+        match kind {
+            "text" => Some(SegmentKind::Text),
+            "comment" => Some(SegmentKind::Comment),
+            "pi" => Some(SegmentKind::Pi),
+            _ => None,
+        }
+    }
+}
+
+pub fn init_schema(db: *mut sqlite3) -> Result<(), String> {
+    let schema_sql = "
+    CREATE TABLE IF NOT EXISTS documents (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        root_node_id INTEGER,
+        created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+        kind TEXT NOT NULL DEFAULT 'xml',
+        doctype TEXT,
+        container_id INTEGER,
+        part_path TEXT,
+        FOREIGN KEY (container_id) REFERENCES containers(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS containers (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        format TEXT NOT NULL,
+        created_at TEXT DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TABLE IF NOT EXISTS nodes (
+        id INTEGER PRIMARY KEY,
+        document_id INTEGER NOT NULL,
+        parent_id INTEGER,
+        tag_name TEXT NOT NULL,
+        text_content TEXT,
+        depth INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        source_line INTEGER,
+        source_column INTEGER,
+        content_hash TEXT,
+        subtree_size INTEGER,
+        prefix TEXT,
+        document_order INTEGER NOT NULL DEFAULT 0,
+        FOREIGN KEY (document_id) REFERENCES documents(id),
+        FOREIGN KEY (parent_id) REFERENCES nodes(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS attributes (
+        id INTEGER PRIMARY KEY,
+        node_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT,
+        prefix TEXT,
+        FOREIGN KEY (node_id) REFERENCES nodes(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS namespaces (
+        id INTEGER PRIMARY KEY,
+        node_id INTEGER NOT NULL,
+        prefix TEXT NOT NULL,
+        uri TEXT NOT NULL,
+        FOREIGN KEY (node_id) REFERENCES nodes(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS segments (
+        id INTEGER PRIMARY KEY,
+        document_id INTEGER NOT NULL,
+        node_id INTEGER,
+        slot INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        target TEXT,
+        value TEXT NOT NULL,
+        FOREIGN KEY (document_id) REFERENCES documents(id),
+        FOREIGN KEY (node_id) REFERENCES nodes(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS materialized_tables (
+        table_name TEXT PRIMARY KEY COLLATE NOCASE,
+        document_id INTEGER NOT NULL,
+        document_name TEXT NOT NULL,
+        record_selector TEXT NOT NULL,
+        refresh INTEGER NOT NULL DEFAULT 0
+    );
+
+    CREATE TABLE IF NOT EXISTS validation_errors (
+        id INTEGER PRIMARY KEY,
+        document_id INTEGER NOT NULL,
+        node_id INTEGER,
+        message TEXT NOT NULL,
+        source_line INTEGER,
+        source_column INTEGER,
+        FOREIGN KEY (document_id) REFERENCES documents(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS diffs (
+        id INTEGER PRIMARY KEY,
+        from_document_id INTEGER NOT NULL,
+        to_document_id INTEGER NOT NULL,
+        op TEXT NOT NULL,
+        from_node_id INTEGER,
+        to_node_id INTEGER,
+        path TEXT,
+        name TEXT,
+        old_value TEXT,
+        new_value TEXT,
+        FOREIGN KEY (from_document_id) REFERENCES documents(id),
+        FOREIGN KEY (to_document_id) REFERENCES documents(id)
+    );
+    ";
+
+    exec(db, schema_sql).map_err(|_| "Failed to init schema".to_string())?;
+    migrate_schema(db)
+}
+
+/// Brings databases saved by older versions up to the current schema.
+fn migrate_schema(db: *mut sqlite3) -> Result<(), String> {
+    let has_kind = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('documents') WHERE name = 'kind'",
+        &[],
+    )?;
+    if has_kind.and_then(|v| v.as_i64()) == Some(0) {
+        // Older databases did not record the kind; guess it from the name
+        exec(
+            db,
+            "ALTER TABLE documents ADD COLUMN kind TEXT NOT NULL DEFAULT 'xml';
+            UPDATE documents SET kind = 'html'
+            WHERE lower(name) LIKE '%.html' OR lower(name) LIKE '%.htm';",
+        )?;
+    }
+    let has_doctype = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('documents') WHERE name = 'doctype'",
+        &[],
+    )?;
+    if has_doctype.and_then(|v| v.as_i64()) == Some(0) {
+        exec(db, "ALTER TABLE documents ADD COLUMN doctype TEXT")?;
+    }
+    let has_position = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('nodes') WHERE name = 'source_line'",
+        &[],
+    )?;
+    if has_position.and_then(|v| v.as_i64()) == Some(0) {
+        exec(
+            db,
+            "ALTER TABLE nodes ADD COLUMN source_line INTEGER;
+            ALTER TABLE nodes ADD COLUMN source_column INTEGER;",
+        )?;
+    }
+    let has_order = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('nodes') WHERE name = 'document_order'",
+        &[],
+    )?;
+    if has_order.and_then(|v| v.as_i64()) == Some(0) {
+        // Before the hash backfill, which loads trees in document order.
+        // Older versions never inserted out of order, so ids give it.
+        exec(
+            db,
+            "ALTER TABLE nodes ADD COLUMN document_order INTEGER NOT NULL DEFAULT 0;
+            UPDATE nodes SET position = numbered.position, document_order = numbered.document_order
+            FROM (
+                SELECT id,
+                    ROW_NUMBER() OVER (PARTITION BY document_id, parent_id ORDER BY id) AS position,
+                    ROW_NUMBER() OVER (PARTITION BY document_id ORDER BY id) AS document_order
+                FROM nodes
+            ) AS numbered
+            WHERE numbered.id = nodes.id;",
+        )?;
+    }
+    let has_hash = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('nodes') WHERE name = 'content_hash'",
+        &[],
+    )?;
+    if has_hash.and_then(|v| v.as_i64()) == Some(0) {
+        exec(
+            db,
+            "ALTER TABLE nodes ADD COLUMN content_hash TEXT;
+            ALTER TABLE nodes ADD COLUMN subtree_size INTEGER;",
+        )?;
+    }
+    let has_prefix = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('nodes') WHERE name = 'prefix'",
+        &[],
+    )?;
+    if has_prefix.and_then(|v| v.as_i64()) == Some(0) {
+        // Namespaces of older documents are unknown; they read as unprefixed
+        exec(
+            db,
+            "ALTER TABLE nodes ADD COLUMN prefix TEXT;
+            ALTER TABLE attributes ADD COLUMN prefix TEXT;",
+        )?;
+    }
+    let has_segments = query_value(db, "SELECT EXISTS (SELECT 1 FROM segments)", &[])?;
+    if has_segments.and_then(|v| v.as_i64()) == Some(0) {
+        // Older versions kept only each element's joined text, which
+        // serialization placed before its children
+        exec(
+            db,
+            "INSERT INTO segments (document_id, node_id, slot, kind, value)
+            SELECT document_id, id, 0, 'text', text_content FROM nodes
+            WHERE text_content <> '' ORDER BY id",
+        )?;
+    }
+    let has_container = query_value(
+        db,
+        "SELECT COUNT(*) FROM pragma_table_info('documents') WHERE name = 'container_id'",
+        &[],
+    )?;
+    if has_container.and_then(|v| v.as_i64()) == Some(0) {
+        exec(
+            db,
+            "ALTER TABLE documents ADD COLUMN container_id INTEGER;
+            ALTER TABLE documents ADD COLUMN part_path TEXT;",
+        )?;
+    }
+    // Last, as hashing loads trees with every column above. Version 0 is
+    // every database from before hashes took namespace URIs in: hash what
+    // is missing, and again documents that declare namespaces.
+    let version = query_value(db, "PRAGMA user_version", &[])?;
+    if version.and_then(|v| v.as_i64()) == Some(0) {
+        exec(
+            db,
+            "UPDATE nodes SET content_hash = NULL WHERE document_id IN (
+                SELECT n.document_id FROM namespaces ns JOIN nodes n ON n.id = ns.node_id
+            )",
+        )?;
+        crate::duplicates::backfill_hashes(db)?;
+        exec(db, "PRAGMA user_version = 1")?;
+    }
+    Ok(())
+}
+
+pub fn exec(db: *mut sqlite3, sql: &str) -> Result<(), String> {
+    let c_sql = CString::new(sql).map_err(|_| "Invalid SQL string".to_string())?;
+    let mut err_msg = ptr::null_mut();
+    let ret = unsafe { sqlite3_exec(db, c_sql.as_ptr(), None, ptr::null_mut(), &mut err_msg) };
+
+    if ret != SQLITE_OK {
+        let msg = unsafe {
+            if !err_msg.is_null() {
+                let s = CStr::from_ptr(err_msg).to_string_lossy().into_owned();
+                sqlite3_free(err_msg as *mut _);
+                s
+            } else {
+                "Unknown error".to_string()
+            }
+        };
+        return Err(msg);
+    }
+    Ok(())
+}
+
+/// Runs a statement with positional parameters and collects every row.
+///
+/// SQL NULLs are returned as `Value::Null`; booleans are bound as 0/1.
+pub fn query(
+    db: *mut sqlite3,
+    sql: &str,
+    params: &[serde_json::Value],
+) -> Result<QueryResult, String> {
+    let c_sql = CString::new(sql).map_err(|_| "Invalid SQL string".to_string())?;
+    let mut stmt = ptr::null_mut();
+
+    let ret = unsafe { sqlite3_prepare_v2(db, c_sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) };
+    if ret != SQLITE_OK {
+        return Err(format!("SQL preparation failed: {}", errmsg(db)));
+    }
+
+    if let Err(e) = bind_params(stmt, params) {
+        unsafe { sqlite3_finalize(stmt) };
+        return Err(e);
+    }
+
+    let col_count = unsafe { sqlite3_column_count(stmt) };
+    let columns = (0..col_count)
+        .map(|i| unsafe {
+            CStr::from_ptr(sqlite3_column_name(stmt, i))
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+
+    let mut rows = Vec::new();
+    loop {
+        match unsafe { sqlite3_step(stmt) } {
+            SQLITE_ROW => rows.push(read_row(stmt, col_count)),
+            SQLITE_DONE => break,
+            _ => {
+                let msg = errmsg(db);
+                unsafe { sqlite3_finalize(stmt) };
+                return Err(format!("Error during query execution: {}", msg));
+            }
+        }
+    }
+
+    unsafe { sqlite3_finalize(stmt) };
+    Ok(QueryResult { columns, rows })
+}
+
+/// Convenience wrapper returning the first column of the first row, if any.
+pub fn query_value(
+    db: *mut sqlite3,
+    sql: &str,
+    params: &[serde_json::Value],
+) -> Result<Option<serde_json::Value>, String> {
+    let result = query(db, sql, params)?;
+    Ok(result
+        .rows
+        .into_iter()
+        .next()
+        .and_then(|row| row.into_iter().next())
+        .filter(|v| !v.is_null()))
+}
+
+fn bind_params(stmt: *mut sqlite3_stmt, params: &[serde_json::Value]) -> Result<(), String> {
+    for (i, param) in params.iter().enumerate() {
+        let idx = (i + 1) as i32;
+        unsafe {
+            match param {
+                serde_json::Value::Null => {
+                    sqlite3_bind_null(stmt, idx);
+                }
+                serde_json::Value::Bool(b) => {
+                    sqlite3_bind_int64(stmt, idx, *b as i64);
+                }
+                serde_json::Value::Number(n) => {
+                    if let Some(i) = n.as_i64() {
+                        sqlite3_bind_int64(stmt, idx, i);
+                    } else {
+                        sqlite3_bind_double(stmt, idx, n.as_f64().unwrap_or(0.0));
+                    }
+                }
+                serde_json::Value::String(s) => {
+                    let c_val = CString::new(s.as_str())
+                        .map_err(|_| "Parameter contains a NUL byte".to_string())?;
+                    sqlite3_bind_text(stmt, idx, c_val.as_ptr(), -1, SQLITE_TRANSIENT());
+                }
+                other => {
+                    let c_val = CString::new(other.to_string())
+                        .map_err(|_| "Parameter contains a NUL byte".to_string())?;
+                    sqlite3_bind_text(stmt, idx, c_val.as_ptr(), -1, SQLITE_TRANSIENT());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_row(stmt: *mut sqlite3_stmt, col_count: i32) -> Vec<serde_json::Value> {
+    (0..col_count)
+        .map(|i| unsafe {
+            match sqlite3_column_type(stmt, i) {
+                SQLITE_INTEGER => serde_json::json!(sqlite3_column_int64(stmt, i)),
+                SQLITE_FLOAT => serde_json::json!(sqlite3_column_double(stmt, i)),
+                SQLITE_TEXT => {
+                    let text = sqlite3_column_text(stmt, i);
+                    if text.is_null() {
+                        serde_json::json!("")
+                    } else {
+                        serde_json::json!(CStr::from_ptr(text as *const c_char).to_string_lossy())
+                    }
+                }
+                _ => serde_json::Value::Null, // BLOBs are not surfaced
+            }
+        })
+        .collect()
+}
+
+pub fn errmsg(db: *mut sqlite3) -> String {
+    unsafe {
+        CStr::from_ptr(sqlite3_errmsg(db))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    #[test]
+    fn test_migration_numbers_siblings_and_document_order() {
+        let db = testing::open();
+        testing::load_xml(db, "a.xml", "<a><b><c/><c/></b><b/></a>");
+        testing::load_xml(db, "b.xml", "<x><y/></x>");
+        // Databases from before `document_order` stored every position as 0
+        exec(
+            db,
+            "UPDATE nodes SET position = 0; ALTER TABLE nodes DROP COLUMN document_order;",
+        )
+        .unwrap();
+
+        init_schema(db).unwrap();
+        let rows = query(
+            db,
+            "SELECT tag_name, position, document_order FROM nodes ORDER BY id",
+            &[],
+        )
+        .unwrap()
+        .rows;
+        let expected = [
+            ("a", 1, 1),
+            ("b", 1, 2),
+            ("c", 1, 3),
+            ("c", 2, 4),
+            ("b", 2, 5),
+            ("x", 1, 1),
+            ("y", 1, 2),
+        ];
+        let expected: Vec<Vec<serde_json::Value>> = expected
+            .iter()
+            .map(|(tag, position, order)| vec![json!(tag), json!(position), json!(order)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_migration_keeps_text_of_older_documents() {
+        let db = testing::open();
+        testing::load_xml(db, "a.xml", "<a>\n  <b>one</b>\n  <c/>two\n</a>");
+        // Older versions stored no segments
+        exec(db, "DELETE FROM segments").unwrap();
+
+        init_schema(db).unwrap();
+        let rows = query(
+            db,
+            "SELECT n.tag_name, s.slot, s.kind, s.value FROM segments s
+            JOIN nodes n ON n.id = s.node_id ORDER BY s.id",
+            &[],
+        )
+        .unwrap()
+        .rows;
+        assert_eq!(
+            rows,
+            vec![
+                vec![json!("a"), json!(0), json!("text"), json!("two")],
+                vec![json!("b"), json!(0), json!("text"), json!("one")],
+            ]
+        );
+    }
+}
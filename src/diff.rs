@@ -0,0 +1,568 @@
+//! Structural diff between two stored documents, e.g. two nightly exports
+//! of the same data.
+//!
+//! Nodes are matched in three passes:
+//! 1. elements with the same tag and a unique `id` or `key` attribute;
+//! 2. subtrees that are identical and occur once in each document;
+//! 3. top-down from matched parents, children with the same tag, identical
+//!    ones first, then in document order.
+//!
+//! Unmatched nodes become inserts or deletes (reported at the top of each
+//! unmatched subtree), matched nodes under a different parent or out of
+//! order become moves, and matched nodes with different text or attributes
+//! become updates.
+
+use crate::database::{exec, query};
+use crate::functions::node_path;
+use crate::tree::StoredTree;
+use serde::Serialize;
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Attributes that identify an element across versions.
+const KEY_ATTRIBUTES: &[&str] = &["id", "key"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Insert,
+    Delete,
+    Move,
+    Update,
+    Attribute,
+}
+
+impl ChangeKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Insert => "insert",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Move => "move",
+            ChangeKind::Update => "update",
+            ChangeKind::Attribute => "attribute",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub op: ChangeKind,
+    /// Node in the old document; `None` for inserts
+    pub from_node: Option<i64>,
+    /// Node in the new document; `None` for deletes
+    pub to_node: Option<i64>,
+    /// Path in the new document, or in the old one for deletes
+    pub path: Option<String>,
+    /// Attribute name for attribute changes
+    pub name: Option<String>,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DiffSummary {
+    pub inserted: usize,
+    pub deleted: usize,
+    pub moved: usize,
+    pub updated: usize,
+    pub attributes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub from_document: i64,
+    pub to_document: i64,
+    pub summary: DiffSummary,
+    pub changes: Vec<Change>,
+}
+
+/// A tree plus what the matcher needs to know about each node.
+struct Side<'a> {
+    tree: &'a StoredTree,
+    parent: Vec<Option<usize>>,
+    hash: Vec<u64>,
+    namespace: Vec<Option<String>>,
+    /// Partner index in the other tree
+    matched: Vec<Option<usize>>,
+}
+
+impl<'a> Side<'a> {
+    fn new(tree: &'a StoredTree) -> Side<'a> {
+        Side {
+            tree,
+            parent: tree.parents(),
+            hash: tree.subtree_hashes(),
+            namespace: tree.element_namespaces(),
+            matched: vec![None; tree.nodes.len()],
+        }
+    }
+
+    fn key(&self, index: usize) -> Option<(&str, &str)> {
+        let node = &self.tree.nodes[index];
+        KEY_ATTRIBUTES.iter().find_map(|key| {
+            node.attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| (node.tag_name.as_str(), value.as_str()))
+        })
+    }
+
+    /// Values that occur exactly once, mapped to their node.
+    fn unique<K: Hash + Eq>(&self, key_of: impl Fn(usize) -> Option<K>) -> HashMap<K, usize> {
+        let mut seen: HashMap<K, Option<usize>> = HashMap::new();
+        for index in 0..self.tree.nodes.len() {
+            if let Some(key) = key_of(index) {
+                seen.entry(key)
+                    .and_modify(|slot| *slot = None)
+                    .or_insert(Some(index));
+            }
+        }
+        seen.into_iter()
+            .filter_map(|(key, index)| index.map(|i| (key, i)))
+            .collect()
+    }
+}
+
+/// Whether old node `a` and new node `b` have the same tag in the same
+/// namespace.
+fn same_name(old: &Side, new: &Side, a: usize, b: usize) -> bool {
+    old.tree.nodes[a].tag_name == new.tree.nodes[b].tag_name && old.namespace[a] == new.namespace[b]
+}
+
+fn pair(old: &mut Side, new: &mut Side, a: usize, b: usize) {
+    old.matched[a] = Some(b);
+    new.matched[b] = Some(a);
+}
+
+/// Matches two identical subtrees node by node.
+fn pair_identical(old: &mut Side, new: &mut Side, a: usize, b: usize) {
+    pair(old, new, a, b);
+    let children: Vec<(usize, usize)> = old.tree.nodes[a]
+        .children
+        .iter()
+        .copied()
+        .zip(new.tree.nodes[b].children.iter().copied())
+        .collect();
+    for (a, b) in children {
+        if old.matched[a].is_none() && new.matched[b].is_none() {
+            pair_identical(old, new, a, b);
+        }
+    }
+}
+
+fn match_trees(old: &mut Side, new: &mut Side) {
+    // 1. Keys
+    let old_keys = old.unique(|i| old.key(i));
+    let new_keys = new.unique(|i| new.key(i));
+    let mut keyed: Vec<(usize, usize)> = old_keys
+        .iter()
+        .filter_map(|(key, &a)| new_keys.get(key).map(|&b| (a, b)))
+        .collect();
+    keyed.sort_unstable();
+    for (a, b) in keyed {
+        pair(old, new, a, b);
+    }
+
+    // 2. Identical subtrees, largest first so their descendants follow
+    let old_hashes = old.unique(|i| Some(old.hash[i]));
+    let new_hashes = new.unique(|i| Some(new.hash[i]));
+    let mut identical: Vec<(usize, usize)> = old_hashes
+        .iter()
+        .filter_map(|(hash, &a)| new_hashes.get(hash).map(|&b| (a, b)))
+        .collect();
+    identical.sort_unstable();
+    for (a, b) in identical {
+        if old.matched[a].is_none() && new.matched[b].is_none() {
+            pair_identical(old, new, a, b);
+        }
+    }
+
+    // 3. Top-down from matched parents
+    if old.matched[0].is_none() && new.matched[0].is_none() && same_name(old, new, 0, 0) {
+        pair(old, new, 0, 0);
+    }
+    let mut queue: VecDeque<usize> = (0..old.tree.nodes.len())
+        .filter(|&a| old.matched[a].is_some())
+        .collect();
+    while let Some(a) = queue.pop_front() {
+        let Some(b) = old.matched[a] else {
+            continue;
+        };
+        let old_children: Vec<usize> = old.tree.nodes[a]
+            .children
+            .iter()
+            .copied()
+            .filter(|&c| old.matched[c].is_none())
+            .collect();
+        let mut new_children: Vec<usize> = new.tree.nodes[b]
+            .children
+            .iter()
+            .copied()
+            .filter(|&c| new.matched[c].is_none())
+            .collect();
+
+        let mut pairs = Vec::new();
+        for same_hash in [true, false] {
+            for &child in &old_children {
+                if pairs.iter().any(|&(a, _)| a == child) {
+                    continue;
+                }
+                // Elements with keys on both sides only match by key
+                let keyed = old.key(child).is_some();
+                let found = new_children.iter().position(|&c| {
+                    same_name(old, new, child, c)
+                        && !(keyed && new.key(c).is_some())
+                        && (!same_hash || new.hash[c] == old.hash[child])
+                });
+                if let Some(position) = found {
+                    pairs.push((child, new_children.remove(position)));
+                }
+            }
+        }
+        for (a, b) in pairs {
+            pair(old, new, a, b);
+            queue.push_back(a);
+        }
+    }
+}
+
+/// Indexes of `sequence` outside one longest increasing subsequence: the
+/// fewest elements that have to move to restore the order.
+pub fn out_of_order(sequence: &[usize]) -> Vec<usize> {
+    // tails[k]: index of the smallest tail of an increasing run of length k+1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; sequence.len()];
+    for (i, &value) in sequence.iter().enumerate() {
+        let k = tails.partition_point(|&t| sequence[t] < value);
+        previous[i] = k.checked_sub(1).map(|k| tails[k]);
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+    let mut kept = vec![false; sequence.len()];
+    let mut cursor = tails.last().copied();
+    while let Some(i) = cursor {
+        kept[i] = true;
+        cursor = previous[i];
+    }
+    (0..sequence.len()).filter(|&i| !kept[i]).collect()
+}
+
+/// Changes between two trees, with `path` left empty.
+fn diff_trees(old_tree: &StoredTree, new_tree: &StoredTree) -> Vec<Change> {
+    let mut old = Side::new(old_tree);
+    let mut new = Side::new(new_tree);
+    match_trees(&mut old, &mut new);
+
+    let change = |op, a: Option<usize>, b: Option<usize>| Change {
+        op,
+        from_node: a.map(|a| old_tree.nodes[a].id),
+        to_node: b.map(|b| new_tree.nodes[b].id),
+        path: None,
+        name: None,
+        old_value: None,
+        new_value: None,
+    };
+    let mut changes = Vec::new();
+
+    for a in 0..old_tree.nodes.len() {
+        let parent_matched = old.parent[a].is_none_or(|p| old.matched[p].is_some());
+        if old.matched[a].is_none() && parent_matched {
+            changes.push(change(ChangeKind::Delete, Some(a), None));
+        }
+    }
+
+    for b in 0..new_tree.nodes.len() {
+        let Some(a) = new.matched[b] else {
+            if new.parent[b].is_none_or(|p| new.matched[p].is_some()) {
+                changes.push(change(ChangeKind::Insert, None, Some(b)));
+            }
+            continue;
+        };
+
+        let moved_parent = match (old.parent[a], new.parent[b]) {
+            (Some(pa), Some(pb)) => old.matched[pa] != Some(pb),
+            (None, None) => false,
+            _ => true,
+        };
+        if moved_parent {
+            changes.push(change(ChangeKind::Move, Some(a), Some(b)));
+        }
+
+        let (old_node, new_node) = (&old_tree.nodes[a], &new_tree.nodes[b]);
+        if old_node.text_content != new_node.text_content {
+            changes.push(Change {
+                old_value: old_node.text_content.clone(),
+                new_value: new_node.text_content.clone(),
+                ..change(ChangeKind::Update, Some(a), Some(b))
+            });
+        }
+        let mut names: Vec<&String> = old_node
+            .attributes
+            .iter()
+            .chain(&new_node.attributes)
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let value_in = |attributes: &[(String, String)]| {
+                attributes
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, v)| v.clone())
+            };
+            let (before, after) = (
+                value_in(&old_node.attributes),
+                value_in(&new_node.attributes),
+            );
+            if before != after {
+                changes.push(Change {
+                    name: Some(name.clone()),
+                    old_value: before,
+                    new_value: after,
+                    ..change(ChangeKind::Attribute, Some(a), Some(b))
+                });
+            }
+        }
+
+        // Children that stayed under this parent but changed places
+        let stayed: Vec<usize> = new_node
+            .children
+            .iter()
+            .filter_map(|&c| new.matched[c].filter(|&ca| old.parent[ca] == Some(a)))
+            .collect();
+        for index in out_of_order(&stayed) {
+            let a = stayed[index];
+            changes.push(change(ChangeKind::Move, Some(a), old.matched[a]));
+        }
+    }
+    changes
+}
+
+/// Diffs two stored documents. With `store`, the changes also replace the
+/// pair's rows in `diffs`.
+pub fn diff_documents(
+    db: *mut sqlite3,
+    from_document: i64,
+    to_document: i64,
+    store: bool,
+) -> Result<DiffReport, String> {
+    let load = |document_id: i64| {
+        StoredTree::load_document(db, document_id)?
+            .ok_or_else(|| format!("Document {} is empty or does not exist", document_id))
+    };
+    let old_tree = load(from_document)?;
+    let new_tree = load(to_document)?;
+
+    let mut changes = diff_trees(&old_tree, &new_tree);
+    let mut summary = DiffSummary::default();
+    for change in &mut changes {
+        let node = match change.op {
+            ChangeKind::Delete => change.from_node,
+            _ => change.to_node,
+        };
+        change.path = match node {
+            Some(node) => node_path(db, node)?,
+            None => None,
+        };
+        match change.op {
+            ChangeKind::Insert => summary.inserted += 1,
+            ChangeKind::Delete => summary.deleted += 1,
+            ChangeKind::Move => summary.moved += 1,
+            ChangeKind::Update => summary.updated += 1,
+            ChangeKind::Attribute => summary.attributes += 1,
+        }
+    }
+
+    if store {
+        exec(db, "SAVEPOINT diff")?;
+        let result = (|| -> Result<(), String> {
+            query(
+                db,
+                "DELETE FROM diffs WHERE from_document_id = ?1 AND to_document_id = ?2",
+                &[json!(from_document), json!(to_document)],
+            )?;
+            for change in &changes {
+                query(
+                    db,
+                    "INSERT INTO diffs (from_document_id, to_document_id, op, from_node_id,
+                    to_node_id, path, name, old_value, new_value)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    &[
+                        json!(from_document),
+                        json!(to_document),
+                        json!(change.op.as_str()),
+                        json!(change.from_node),
+                        json!(change.to_node),
+                        json!(change.path),
+                        json!(change.name),
+                        json!(change.old_value),
+                        json!(change.new_value),
+                    ],
+                )?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => exec(db, "RELEASE diff")?,
+            Err(e) => {
+                let _ = exec(db, "ROLLBACK TO diff; RELEASE diff");
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(DiffReport {
+        from_document,
+        to_document,
+        summary,
+        changes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::StoredNode;
+
+    /// `(depth, tag, text, attributes)`, in pre-order
+    type Row<'a> = (usize, &'a str, Option<&'a str>, &'a [(&'a str, &'a str)]);
+
+    /// Builds a tree from rows with ids starting at `first_id`.
+    fn tree(first_id: i64, rows: &[Row]) -> StoredTree {
+        let mut nodes: Vec<StoredNode> = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+        for (index, (depth, tag, text, attributes)) in rows.iter().enumerate() {
+            stack.truncate(*depth);
+            if let Some(&parent) = stack.last() {
+                nodes[parent].children.push(index);
+            }
+            stack.push(index);
+            nodes.push(StoredNode {
+                id: first_id + index as i64,
+                tag_name: tag.to_string(),
+                text_content: text.map(str::to_string),
+                attributes: attributes
+                    .iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect(),
+                children: Vec::new(),
+                ..StoredNode::default()
+            });
+        }
+        StoredTree { nodes }
+    }
+
+    fn ops(changes: &[Change]) -> Vec<(ChangeKind, Option<i64>, Option<i64>)> {
+        changes
+            .iter()
+            .map(|c| (c.op, c.from_node, c.to_node))
+            .collect()
+    }
+
+    #[test]
+    fn test_identical_trees_have_no_changes() {
+        let rows: &[Row] = &[
+            (0, "list", None, &[]),
+            (1, "item", Some("a"), &[]),
+            (1, "item", Some("a"), &[]),
+        ];
+        assert!(diff_trees(&tree(1, rows), &tree(100, rows)).is_empty());
+    }
+
+    #[test]
+    fn test_keyed_changes() {
+        let old = tree(
+            1,
+            &[
+                (0, "catalog", None, &[]),
+                (1, "product", None, &[("id", "p1")]),
+                (2, "price", Some("10"), &[]),
+                (1, "product", None, &[("id", "p2"), ("color", "red")]),
+                (2, "price", Some("20"), &[]),
+                (1, "product", None, &[("id", "p3")]),
+                (2, "price", Some("30"), &[]),
+            ],
+        );
+        let new = tree(
+            101,
+            &[
+                (0, "catalog", None, &[]),
+                (1, "product", None, &[("id", "p2"), ("color", "blue")]),
+                (2, "price", Some("20"), &[]),
+                (1, "product", None, &[("id", "p1")]),
+                (2, "price", Some("12"), &[]),
+                (1, "product", None, &[("id", "p4")]),
+                (2, "price", Some("40"), &[]),
+            ],
+        );
+        let changes = diff_trees(&old, &new);
+        assert_eq!(
+            ops(&changes),
+            vec![
+                (ChangeKind::Delete, Some(6), None),
+                (ChangeKind::Move, Some(4), Some(102)),
+                (ChangeKind::Attribute, Some(4), Some(102)),
+                (ChangeKind::Update, Some(3), Some(105)),
+                (ChangeKind::Insert, None, Some(106)),
+            ]
+        );
+        assert_eq!(changes[2].name.as_deref(), Some("color"));
+        assert_eq!(changes[2].old_value.as_deref(), Some("red"));
+        assert_eq!(changes[3].new_value.as_deref(), Some("12"));
+    }
+
+    #[test]
+    fn test_subtree_moved_to_another_parent() {
+        let old = tree(
+            1,
+            &[
+                (0, "doc", None, &[]),
+                (1, "draft", None, &[]),
+                (2, "section", None, &[]),
+                (3, "title", Some("Intro"), &[]),
+                (1, "final", None, &[]),
+            ],
+        );
+        let new = tree(
+            11,
+            &[
+                (0, "doc", None, &[]),
+                (1, "draft", None, &[]),
+                (1, "final", None, &[]),
+                (2, "section", None, &[]),
+                (3, "title", Some("Intro"), &[]),
+            ],
+        );
+        assert_eq!(
+            ops(&diff_trees(&old, &new)),
+            vec![(ChangeKind::Move, Some(3), Some(14))]
+        );
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        assert_eq!(out_of_order(&[0, 1, 2]), Vec::<usize>::new());
+        assert_eq!(out_of_order(&[3, 0, 1, 2]), vec![0]);
+        assert_eq!(out_of_order(&[1, 0]), vec![0]);
+    }
+
+    #[test]
+    fn test_namespace_change_is_a_change() {
+        let db = crate::testing::open();
+        let from = crate::testing::load_xml(db, "a.xml", r#"<r><a:p xmlns:a="X">t</a:p></r>"#);
+        let to = crate::testing::load_xml(db, "b.xml", r#"<r><b:p xmlns:b="Y">t</b:p></r>"#);
+        let same = crate::testing::load_xml(db, "c.xml", r#"<r><c:p xmlns:c="X">t</c:p></r>"#);
+
+        let report = diff_documents(db, from, to, false).unwrap();
+        let kinds: Vec<ChangeKind> = report.changes.iter().map(|c| c.op).collect();
+        assert_eq!(kinds, [ChangeKind::Delete, ChangeKind::Insert]);
+        let report = diff_documents(db, from, same, false).unwrap();
+        assert!(report.changes.is_empty());
+    }
+}
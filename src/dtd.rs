@@ -0,0 +1,1058 @@
+//! DOCTYPE declarations and their internal subset.
+//!
+//! roxmltree expands internal entities but keeps nothing else from the DTD,
+//! so the subset is read here as well: element content models, attribute
+//! lists with their defaults, and entity values. That is enough to apply
+//! default attributes, bound entity expansion before parsing, and check a
+//! document against its internal subset. External subsets are never
+//! fetched.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Predefined entities that never need a declaration.
+const PREDEFINED: &[(&str, &str)] = &[
+    ("lt", "<"),
+    ("gt", ">"),
+    ("amp", "&"),
+    ("apos", "'"),
+    ("quot", "\""),
+];
+
+/// Deepest chain of entities referring to entities.
+const MAX_ENTITY_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Default)]
+pub struct Doctype {
+    pub name: String,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    /// The declaration as written, `<!DOCTYPE ...>`
+    pub declaration: String,
+    /// Byte offset just past the declaration
+    end: usize,
+    has_subset: bool,
+    elements: HashMap<String, ContentModel>,
+    attributes: HashMap<String, Vec<AttributeDef>>,
+    /// Internal general entities; external ones are not expanded
+    entities: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ContentModel {
+    Empty,
+    Any,
+    /// `(#PCDATA|a|b)*`
+    Mixed(Vec<String>),
+    Children(Particle),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Repeat {
+    One,
+    Optional,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Particle {
+    Name(String, Repeat),
+    Sequence(Vec<Particle>, Repeat),
+    Choice(Vec<Particle>, Repeat),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttributeType {
+    CData,
+    Id,
+    IdRef,
+    IdRefs,
+    Token,
+    Tokens,
+    Enumeration(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttributeDefault {
+    Required,
+    Implied,
+    Fixed(String),
+    Value(String),
+}
+
+#[derive(Debug, Clone)]
+struct AttributeDef {
+    name: String,
+    kind: AttributeType,
+    default: AttributeDefault,
+}
+
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes up to and including `end`, returning the text before it.
+    fn until(&mut self, end: &str) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let index = rest
+            .find(end)
+            .ok_or_else(|| format!("Unterminated DTD markup, expected '{}'", end))?;
+        self.pos += index + end.len();
+        Ok(&rest[..index])
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        (len > 0).then(|| {
+            self.pos += len;
+            &rest[..len]
+        })
+    }
+
+    fn quoted(&mut self) -> Result<Option<&'a str>, String> {
+        let Some(quote) = self
+            .rest()
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+        else {
+            return Ok(None);
+        };
+        self.pos += 1;
+        let mut end = [0; 4];
+        self.until(quote.encode_utf8(&mut end)).map(Some)
+    }
+
+    /// The body of a markup declaration, up to the `>` outside quotes.
+    fn declaration_body(&mut self) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let mut quote = None;
+        for (index, c) in rest.char_indices() {
+            match (quote, c) {
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), _) if q == c => quote = None,
+                (None, '>') => {
+                    self.pos += index + 1;
+                    return Ok(&rest[..index]);
+                }
+                _ => {}
+            }
+        }
+        Err("Unterminated markup declaration in the DTD".to_string())
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | ':' | '-' | '.') || !c.is_ascii() && !c.is_whitespace()
+}
+
+fn is_name(value: &str) -> bool {
+    value
+        .chars()
+        .next()
+        .is_some_and(|c| !c.is_ascii_digit() && !matches!(c, '-' | '.'))
+        && value.chars().all(is_name_char)
+}
+
+/// Reads the DOCTYPE declaration from the prolog, if there is one. The
+/// keyword is matched case-insensitively, so HTML doctypes are found too.
+pub fn parse_doctype(content: &str) -> Result<Option<Doctype>, String> {
+    let mut cursor = Cursor {
+        text: content,
+        pos: 0,
+    };
+    cursor.eat("\u{feff}");
+    loop {
+        cursor.skip_whitespace();
+        if cursor.eat("<?") {
+            cursor.until("?>")?;
+        } else if cursor.eat("<!--") {
+            cursor.until("-->")?;
+        } else {
+            break;
+        }
+    }
+
+    let start = cursor.pos;
+    if !cursor
+        .rest()
+        .get(..9)
+        .is_some_and(|s| s.eq_ignore_ascii_case("<!DOCTYPE"))
+    {
+        return Ok(None);
+    }
+    cursor.pos += 9;
+    cursor.skip_whitespace();
+    let mut doctype = Doctype {
+        name: cursor
+            .name()
+            .ok_or("DOCTYPE needs a root element name")?
+            .to_string(),
+        ..Doctype::default()
+    };
+
+    cursor.skip_whitespace();
+    let (public_id, system_id) = external_id(&mut cursor)?;
+    doctype.public_id = public_id;
+    doctype.system_id = system_id;
+    cursor.skip_whitespace();
+    if cursor.eat("[") {
+        doctype.has_subset = true;
+        read_subset(&mut cursor, &mut doctype)?;
+        cursor.skip_whitespace();
+    }
+    if !cursor.eat(">") {
+        return Err("Malformed DOCTYPE declaration".to_string());
+    }
+    doctype.declaration = content[start..cursor.pos].to_string();
+    doctype.end = cursor.pos;
+    Ok(Some(doctype))
+}
+
+/// `PUBLIC "pub" "sys"` or `SYSTEM "sys"`, both optional.
+fn external_id(cursor: &mut Cursor) -> Result<(Option<String>, Option<String>), String> {
+    let keyword = cursor.rest().get(..6).map(|s| s.to_ascii_uppercase());
+    match keyword.as_deref() {
+        Some("PUBLIC") => {
+            cursor.pos += 6;
+            cursor.skip_whitespace();
+            let public_id = cursor.quoted()?.ok_or("PUBLIC needs an identifier")?;
+            cursor.skip_whitespace();
+            let system_id = cursor.quoted()?;
+            Ok((Some(public_id.to_string()), system_id.map(str::to_string)))
+        }
+        Some("SYSTEM") => {
+            cursor.pos += 6;
+            cursor.skip_whitespace();
+            let system_id = cursor.quoted()?.ok_or("SYSTEM needs an identifier")?;
+            Ok((None, Some(system_id.to_string())))
+        }
+        _ => Ok((None, None)),
+    }
+}
+
+/// Reads declarations until the closing `]`.
+fn read_subset(cursor: &mut Cursor, doctype: &mut Doctype) -> Result<(), String> {
+    loop {
+        cursor.skip_whitespace();
+        if cursor.rest().is_empty() {
+            return Err("Unterminated DTD internal subset".to_string());
+        }
+        if cursor.eat("]") {
+            return Ok(());
+        } else if cursor.eat("<!--") {
+            cursor.until("-->")?;
+        } else if cursor.eat("<?") {
+            cursor.until("?>")?;
+        } else if cursor.rest().starts_with('%') {
+            // roxmltree rejects them as well
+            return Err("Parameter entity references are not supported".to_string());
+        } else if cursor.eat("<!ELEMENT") {
+            read_element(cursor.declaration_body()?, doctype)?;
+        } else if cursor.eat("<!ATTLIST") {
+            read_attlist(cursor.declaration_body()?, doctype)?;
+        } else if cursor.eat("<!ENTITY") {
+            read_entity(cursor.declaration_body()?, doctype)?;
+        } else if cursor.eat("<!NOTATION") {
+            cursor.declaration_body()?;
+        } else {
+            let excerpt: String = cursor.rest().chars().take(20).collect();
+            return Err(format!("Unexpected content in the DTD: '{}'", excerpt));
+        }
+    }
+}
+
+fn read_element(body: &str, doctype: &mut Doctype) -> Result<(), String> {
+    let mut cursor = Cursor { text: body, pos: 0 };
+    cursor.skip_whitespace();
+    let name = cursor.name().ok_or("<!ELEMENT> needs a name")?;
+    let model = parse_content_model(cursor.rest().trim())
+        .map_err(|e| format!("<!ELEMENT {}>: {}", name, e))?;
+    doctype.elements.entry(name.to_string()).or_insert(model);
+    Ok(())
+}
+
+fn parse_content_model(spec: &str) -> Result<ContentModel, String> {
+    match spec {
+        "EMPTY" => return Ok(ContentModel::Empty),
+        "ANY" => return Ok(ContentModel::Any),
+        _ => {}
+    }
+    let compact: String = spec.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(rest) = compact.strip_prefix("(#PCDATA") {
+        let names = rest
+            .strip_suffix(")*")
+            .or_else(|| rest.strip_suffix(')'))
+            .ok_or_else(|| format!("Malformed mixed content '{}'", spec))?;
+        return Ok(ContentModel::Mixed(
+            names
+                .split('|')
+                .filter(|n| !n.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ));
+    }
+    let mut cursor = Cursor {
+        text: &compact,
+        pos: 0,
+    };
+    let particle = parse_particle(&mut cursor)?;
+    if !cursor.rest().is_empty()
+        || !matches!(particle, Particle::Sequence(..) | Particle::Choice(..))
+    {
+        return Err(format!("Malformed content model '{}'", spec));
+    }
+    Ok(ContentModel::Children(particle))
+}
+
+fn parse_particle(cursor: &mut Cursor) -> Result<Particle, String> {
+    if !cursor.eat("(") {
+        let name = cursor.name().ok_or("Expected a name or '('")?.to_string();
+        return Ok(Particle::Name(name, parse_repeat(cursor)));
+    }
+    let mut items = vec![parse_particle(cursor)?];
+    let mut separator = None;
+    loop {
+        if cursor.eat(")") {
+            break;
+        }
+        let next = cursor.rest().chars().next().ok_or("Unclosed group")?;
+        if !matches!(next, ',' | '|') || separator.is_some_and(|s| s != next) {
+            return Err(format!("Unexpected '{}' in a group", next));
+        }
+        separator = Some(next);
+        cursor.pos += 1;
+        items.push(parse_particle(cursor)?);
+    }
+    let repeat = parse_repeat(cursor);
+    Ok(if separator == Some('|') {
+        Particle::Choice(items, repeat)
+    } else {
+        Particle::Sequence(items, repeat)
+    })
+}
+
+fn parse_repeat(cursor: &mut Cursor) -> Repeat {
+    if cursor.eat("?") {
+        Repeat::Optional
+    } else if cursor.eat("*") {
+        Repeat::ZeroOrMore
+    } else if cursor.eat("+") {
+        Repeat::OneOrMore
+    } else {
+        Repeat::One
+    }
+}
+
+fn read_attlist(body: &str, doctype: &mut Doctype) -> Result<(), String> {
+    let mut cursor = Cursor { text: body, pos: 0 };
+    cursor.skip_whitespace();
+    let element = cursor.name().ok_or("<!ATTLIST> needs an element name")?;
+    let defs = doctype.attributes.entry(element.to_string()).or_default();
+    loop {
+        cursor.skip_whitespace();
+        let Some(name) = cursor.name() else {
+            break;
+        };
+        cursor.skip_whitespace();
+        let kind = if cursor.eat("(") {
+            AttributeType::Enumeration(enumeration(&mut cursor)?)
+        } else {
+            match cursor.name() {
+                Some("CDATA") => AttributeType::CData,
+                Some("ID") => AttributeType::Id,
+                Some("IDREF") => AttributeType::IdRef,
+                Some("IDREFS") => AttributeType::IdRefs,
+                Some("NMTOKEN" | "ENTITY") => AttributeType::Token,
+                Some("NMTOKENS" | "ENTITIES") => AttributeType::Tokens,
+                Some("NOTATION") => {
+                    cursor.skip_whitespace();
+                    if !cursor.eat("(") {
+                        return Err(format!("<!ATTLIST {}>: NOTATION needs a list", element));
+                    }
+                    AttributeType::Enumeration(enumeration(&mut cursor)?)
+                }
+                other => {
+                    return Err(format!(
+                        "<!ATTLIST {}>: unknown attribute type '{}'",
+                        element,
+                        other.unwrap_or_default()
+                    ))
+                }
+            }
+        };
+        cursor.skip_whitespace();
+        let default = if cursor.eat("#REQUIRED") {
+            AttributeDefault::Required
+        } else if cursor.eat("#IMPLIED") {
+            AttributeDefault::Implied
+        } else {
+            let fixed = cursor.eat("#FIXED");
+            cursor.skip_whitespace();
+            let value = cursor
+                .quoted()?
+                .ok_or_else(|| format!("<!ATTLIST {}>: '{}' needs a default", element, name))?
+                .to_string();
+            if fixed {
+                AttributeDefault::Fixed(value)
+            } else {
+                AttributeDefault::Value(value)
+            }
+        };
+        if !defs.iter().any(|d| d.name == name) {
+            defs.push(AttributeDef {
+                name: name.to_string(),
+                kind,
+                default,
+            });
+        }
+    }
+    if !cursor.rest().trim().is_empty() {
+        return Err(format!("<!ATTLIST {}>: malformed declaration", element));
+    }
+    Ok(())
+}
+
+/// The values of `(a|b|c)`, after the opening parenthesis.
+fn enumeration(cursor: &mut Cursor) -> Result<Vec<String>, String> {
+    let list = cursor.until(")")?;
+    Ok(list.split('|').map(|v| v.trim().to_string()).collect())
+}
+
+fn read_entity(body: &str, doctype: &mut Doctype) -> Result<(), String> {
+    let mut cursor = Cursor { text: body, pos: 0 };
+    cursor.skip_whitespace();
+    let parameter = cursor.eat("%");
+    cursor.skip_whitespace();
+    let name = cursor.name().ok_or("<!ENTITY> needs a name")?.to_string();
+    cursor.skip_whitespace();
+    let Some(value) = cursor.quoted()? else {
+        // External entity: declared, but its content is never fetched
+        external_id(&mut cursor)?;
+        return Ok(());
+    };
+    // Parameter entities can only be referenced between declarations,
+    // which is not supported, so only general entities are kept
+    if !parameter {
+        doctype
+            .entities
+            .entry(name)
+            .or_insert_with(|| value.to_string());
+    }
+    Ok(())
+}
+
+impl Doctype {
+    /// Whether the declaration has an internal subset (`[...]`).
+    pub fn has_subset(&self) -> bool {
+        self.has_subset
+    }
+
+    /// Characters that entity reference `name` expands to, references
+    /// inside its value included.
+    fn expanded_length(
+        &self,
+        name: &str,
+        depth: usize,
+        lengths: &mut HashMap<String, usize>,
+    ) -> Result<usize, String> {
+        if let Some(&length) = lengths.get(name) {
+            return Ok(length);
+        }
+        if depth > MAX_ENTITY_DEPTH {
+            return Err(format!("Entity '&{};' is nested too deeply", name));
+        }
+        let value = &self.entities[name];
+        let mut length = 0usize;
+        for part in references(value) {
+            length = length.saturating_add(match part {
+                Reference::Text(text) => text.chars().count(),
+                Reference::Entity(inner) if self.entities.contains_key(inner) => {
+                    self.expanded_length(inner, depth + 1, lengths)?
+                }
+                Reference::Entity(_) | Reference::Character(_) => 1,
+            });
+        }
+        lengths.insert(name.to_string(), length);
+        Ok(length)
+    }
+
+    /// Characters that the entity references in `text` expand to.
+    fn references_length(
+        &self,
+        text: &str,
+        lengths: &mut HashMap<String, usize>,
+    ) -> Result<usize, String> {
+        let mut total = 0usize;
+        for part in references(text) {
+            if let Reference::Entity(name) = part {
+                if self.entities.contains_key(name) {
+                    total = total.saturating_add(self.expanded_length(name, 0, lengths)?);
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// Fails if expanding every entity reference in the document body, or
+    /// those in any one attribute default, would produce more than `limit`
+    /// characters, before anything is expanded. Returns what the body
+    /// expands to; applied defaults are charged against the rest.
+    pub fn check_entity_expansion(&self, content: &str, limit: usize) -> Result<usize, String> {
+        if self.entities.is_empty() {
+            return Ok(0);
+        }
+        let exceeded = || format!("Entity expansion exceeds the limit of {} characters", limit);
+        let mut lengths = HashMap::new();
+        for (element, defs) in &self.attributes {
+            for def in defs {
+                if let AttributeDefault::Fixed(value) | AttributeDefault::Value(value) =
+                    &def.default
+                {
+                    if self.references_length(value, &mut lengths)? > limit {
+                        return Err(format!(
+                            "{} in the default of attribute '{}' on '{}'",
+                            exceeded(),
+                            def.name,
+                            element
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut total = 0usize;
+        for part in references(&content[self.end..]) {
+            if let Reference::Entity(name) = part {
+                if self.entities.contains_key(name) {
+                    total = total.saturating_add(self.expanded_length(name, 0, &mut lengths)?);
+                    if total > limit {
+                        return Err(exceeded());
+                    }
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// Replaces entity and character references, as in an attribute default.
+    /// Characters that come from entity values are taken from `budget`.
+    fn expand(&self, text: &str, depth: usize, budget: &mut usize) -> Result<String, String> {
+        // Literal text of the default itself is free
+        let charge = |budget: &mut usize, count: usize| {
+            if depth > 0 {
+                *budget = budget.checked_sub(count).ok_or(
+                    "Entity expansion in default attributes exceeds the limit".to_string(),
+                )?;
+            }
+            Ok::<(), String>(())
+        };
+        let mut out = String::new();
+        for part in references(text) {
+            match part {
+                Reference::Text(text) => {
+                    charge(budget, text.chars().count())?;
+                    out.push_str(text);
+                }
+                Reference::Character(c) => {
+                    charge(budget, 1)?;
+                    out.push(c);
+                }
+                Reference::Entity(name) => {
+                    if let Some((_, value)) = PREDEFINED.iter().find(|(n, _)| *n == name) {
+                        charge(budget, 1)?;
+                        out.push_str(value);
+                    } else if let (Some(value), true) =
+                        (self.entities.get(name), depth < MAX_ENTITY_DEPTH)
+                    {
+                        out.push_str(&self.expand(value, depth + 1, budget)?);
+                    } else {
+                        charge(budget, 1)?;
+                        out.push('&');
+                        out.push_str(name);
+                        out.push(';');
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Attributes with a default value that `element` is missing. What their
+    /// entity references expand to is taken from `budget`.
+    pub fn default_attributes(
+        &self,
+        element: &str,
+        present: impl Fn(&str) -> bool,
+        budget: &mut usize,
+    ) -> Result<Vec<(&str, String)>, String> {
+        let mut defaults = Vec::new();
+        for def in self.attributes.get(element).into_iter().flatten() {
+            if def.name.starts_with("xmlns") || present(&def.name) {
+                continue;
+            }
+            if let AttributeDefault::Fixed(value) | AttributeDefault::Value(value) = &def.default {
+                defaults.push((def.name.as_str(), self.expand(value, 0, budget)?));
+            }
+        }
+        Ok(defaults)
+    }
+
+    /// Checks a parsed document against the internal subset. Violations are
+    /// `line:column: message`, in document order.
+    pub fn validate(&self, doc: &roxmltree::Document) -> Vec<String> {
+        let mut violations = Vec::new();
+        let mut report = |node: roxmltree::Node, message: String| {
+            let pos = doc.text_pos_at(node.range().start);
+            violations.push(format!("{}:{}: {}", pos.row, pos.col, message));
+        };
+
+        let root = doc.root_element();
+        if qualified_name(root) != self.name {
+            report(
+                root,
+                format!(
+                    "Root element '{}' does not match the DOCTYPE '{}'",
+                    qualified_name(root),
+                    self.name
+                ),
+            );
+        }
+
+        let mut ids = HashSet::new();
+        let mut references = Vec::new();
+        for node in root.descendants().filter(|n| n.is_element()) {
+            let name = qualified_name(node);
+            let Some(model) = self.elements.get(&name) else {
+                report(node, format!("Element '{}' is not declared", name));
+                continue;
+            };
+            if let Some(message) = check_content(&name, model, node) {
+                report(node, message);
+            }
+
+            let defs = self
+                .attributes
+                .get(&name)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            for attr in node.attributes() {
+                let attr_name = qualified_attribute_name(node, &attr);
+                let Some(def) = defs.iter().find(|d| d.name == attr_name) else {
+                    report(
+                        node,
+                        format!("Attribute '{}' is not declared for '{}'", attr_name, name),
+                    );
+                    continue;
+                };
+                let value = if def.kind == AttributeType::CData {
+                    attr.value().to_string()
+                } else {
+                    attr.value()
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                };
+                if let Some(message) = check_attribute(def, &value) {
+                    report(node, message);
+                }
+                match def.kind {
+                    AttributeType::Id if !ids.insert(value.clone()) => {
+                        report(node, format!("Duplicate ID '{}'", value));
+                    }
+                    AttributeType::IdRef | AttributeType::IdRefs => {
+                        for target in value.split(' ') {
+                            references.push((node, target.to_string()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            for def in defs {
+                if def.default == AttributeDefault::Required
+                    && !node
+                        .attributes()
+                        .any(|a| qualified_attribute_name(node, &a) == def.name)
+                {
+                    report(
+                        node,
+                        format!(
+                            "Element '{}' is missing required attribute '{}'",
+                            name, def.name
+                        ),
+                    );
+                }
+            }
+        }
+        for (node, target) in references {
+            if !ids.contains(&target) {
+                report(node, format!("IDREF '{}' does not match any ID", target));
+            }
+        }
+        violations
+    }
+}
+
+enum Reference<'a> {
+    Text(&'a str),
+    Entity(&'a str),
+    Character(char),
+}
+
+/// Splits text into literal runs and `&name;` / `&#n;` references.
+fn references(text: &str) -> impl Iterator<Item = Reference<'_>> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let amp = rest.find('&').unwrap_or(rest.len());
+        if amp > 0 {
+            let (text, tail) = rest.split_at(amp);
+            rest = tail;
+            return Some(Reference::Text(text));
+        }
+        let Some(semi) = rest.find(';').filter(|&i| i > 1 && i < 64) else {
+            let (text, tail) = rest.split_at(1);
+            rest = tail;
+            return Some(Reference::Text(text));
+        };
+        let name = &rest[1..semi];
+        let reference = if let Some(code) = name.strip_prefix('#') {
+            let code = match code.strip_prefix('x') {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => code.parse().ok(),
+            };
+            code.and_then(char::from_u32).map(Reference::Character)
+        } else {
+            is_name(name).then_some(Reference::Entity(name))
+        };
+        match reference {
+            Some(reference) => {
+                rest = &rest[semi + 1..];
+                Some(reference)
+            }
+            None => {
+                let (text, tail) = rest.split_at(1);
+                rest = tail;
+                Some(Reference::Text(text))
+            }
+        }
+    })
+}
+
+/// The element name as written in the document, prefix included.
+pub fn qualified_name(node: roxmltree::Node) -> String {
+    let local = node.tag_name().name();
+    match node
+        .tag_name()
+        .namespace()
+        .and_then(|ns| node.lookup_prefix(ns))
+    {
+        Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, local),
+        _ => local.to_string(),
+    }
+}
+
+fn qualified_attribute_name(node: roxmltree::Node, attr: &roxmltree::Attribute) -> String {
+    match attr.namespace() {
+        Some("http://www.w3.org/XML/1998/namespace") => format!("xml:{}", attr.name()),
+        Some(ns) => match node.lookup_prefix(ns) {
+            Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, attr.name()),
+            _ => attr.name().to_string(),
+        },
+        None => attr.name().to_string(),
+    }
+}
+
+fn check_content(name: &str, model: &ContentModel, node: roxmltree::Node) -> Option<String> {
+    let has_text = node
+        .children()
+        .any(|c| c.is_text() && !c.text().unwrap_or_default().trim().is_empty());
+    let children: Vec<String> = node
+        .children()
+        .filter(|c| c.is_element())
+        .map(qualified_name)
+        .collect();
+    match model {
+        ContentModel::Any => None,
+        ContentModel::Empty => {
+            (has_text || !children.is_empty()).then(|| format!("Element '{}' must be empty", name))
+        }
+        ContentModel::Mixed(allowed) => children
+            .iter()
+            .find(|c| !allowed.contains(c))
+            .map(|c| format!("Element '{}' is not allowed in '{}'", c, name)),
+        ContentModel::Children(particle) => {
+            if has_text {
+                return Some(format!("Element '{}' cannot contain text", name));
+            }
+            let names: Vec<&str> = children.iter().map(String::as_str).collect();
+            (!match_particle(particle, &names, 0).contains(&names.len())).then(|| {
+                format!(
+                    "Content of '{}' ({}) does not match its declaration",
+                    name,
+                    if names.is_empty() {
+                        "empty".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                )
+            })
+        }
+    }
+}
+
+fn check_attribute(def: &AttributeDef, value: &str) -> Option<String> {
+    if let AttributeDefault::Fixed(fixed) = &def.default {
+        if value != fixed {
+            return Some(format!(
+                "Attribute '{}' must have the fixed value '{}'",
+                def.name, fixed
+            ));
+        }
+    }
+    let valid = match &def.kind {
+        AttributeType::CData => true,
+        AttributeType::Id | AttributeType::IdRef => is_name(value),
+        AttributeType::IdRefs => !value.is_empty() && value.split(' ').all(is_name),
+        AttributeType::Token => !value.is_empty() && value.chars().all(is_name_char),
+        AttributeType::Tokens => {
+            !value.is_empty() && value.split(' ').all(|t| t.chars().all(is_name_char))
+        }
+        AttributeType::Enumeration(values) => values.iter().any(|v| v == value),
+    };
+    (!valid).then(|| format!("Invalid value '{}' for attribute '{}'", value, def.name))
+}
+
+/// Every position where `particle` can stop after starting at `start`.
+fn match_particle(particle: &Particle, names: &[&str], start: usize) -> BTreeSet<usize> {
+    let repeat = match particle {
+        Particle::Name(_, r) | Particle::Sequence(_, r) | Particle::Choice(_, r) => *r,
+    };
+    let once = |pos: usize| -> BTreeSet<usize> {
+        match particle {
+            Particle::Name(name, _) => names
+                .get(pos)
+                .filter(|n| **n == name)
+                .map(|_| pos + 1)
+                .into_iter()
+                .collect(),
+            Particle::Sequence(items, _) => {
+                items.iter().fold(BTreeSet::from([pos]), |ends, item| {
+                    ends.iter()
+                        .flat_map(|&p| match_particle(item, names, p))
+                        .collect()
+                })
+            }
+            Particle::Choice(items, _) => items
+                .iter()
+                .flat_map(|item| match_particle(item, names, pos))
+                .collect(),
+        }
+    };
+
+    let mut ends = once(start);
+    if matches!(repeat, Repeat::Optional | Repeat::ZeroOrMore) {
+        ends.insert(start);
+    }
+    if matches!(repeat, Repeat::ZeroOrMore | Repeat::OneOrMore) {
+        let mut frontier: Vec<usize> = ends.iter().copied().collect();
+        while let Some(pos) = frontier.pop() {
+            for end in once(pos) {
+                if ends.insert(end) {
+                    frontier.push(end);
+                }
+            }
+        }
+    }
+    ends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE note [
+  <!ENTITY writer "Ann &amp; Bob">
+  <!ENTITY % unused "ignored">
+  <!ELEMENT note (to+, from, (body | summary)?)>
+  <!ELEMENT to (#PCDATA)>
+  <!ELEMENT from (#PCDATA | em)*>
+  <!ELEMENT em (#PCDATA)>
+  <!ELEMENT body ANY>
+  <!ELEMENT summary EMPTY>
+  <!ATTLIST note
+    id ID #REQUIRED
+    lang (en | de) "en"
+    version CDATA #FIXED "1.0">
+  <!ATTLIST to ref IDREF #IMPLIED>
+]>
+<note id="n1"><to ref="n1">Tove</to><from>&writer;</from></note>"#;
+
+    #[test]
+    fn test_parse_doctype() {
+        let doctype = parse_doctype(NOTE).unwrap().unwrap();
+        assert_eq!(doctype.name, "note");
+        assert!(doctype.has_subset());
+        assert!(doctype.declaration.starts_with("<!DOCTYPE note ["));
+        assert!(doctype.declaration.ends_with("]>"));
+        assert_eq!(doctype.entities["writer"], "Ann &amp; Bob");
+        assert_eq!(
+            doctype.elements["from"],
+            ContentModel::Mixed(vec!["em".to_string()])
+        );
+        let mut budget = usize::MAX;
+        let defaults = doctype
+            .default_attributes("note", |_| false, &mut budget)
+            .unwrap();
+        assert_eq!(
+            defaults,
+            vec![("lang", "en".to_string()), ("version", "1.0".to_string())]
+        );
+
+        let html = parse_doctype("<!doctype html><html></html>")
+            .unwrap()
+            .unwrap();
+        assert_eq!(html.name, "html");
+        assert_eq!(html.declaration, "<!doctype html>");
+        assert!(!html.has_subset());
+        let public = parse_doctype(
+            r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "xhtml1-strict.dtd"><html/>"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(public.system_id.as_deref(), Some("xhtml1-strict.dtd"));
+        assert!(parse_doctype("<root/>").unwrap().is_none());
+        assert!(parse_doctype("<!DOCTYPE a [<!ENTITY % p 'x'> %p;]><a/>").is_err());
+    }
+
+    #[test]
+    fn test_entity_expansion_limit() {
+        let mut laughs = String::from("<!DOCTYPE lolz [<!ENTITY lol0 \"lol\">");
+        for i in 1..10 {
+            laughs.push_str(&format!(
+                "<!ENTITY lol{} \"{}\">",
+                i,
+                format!("&lol{};", i - 1).repeat(10)
+            ));
+        }
+        laughs.push_str("]><lolz>&lol9;</lolz>");
+        let doctype = parse_doctype(&laughs).unwrap().unwrap();
+        assert_eq!(
+            doctype.check_entity_expansion(&laughs, 1_000_000),
+            Err("Entity expansion exceeds the limit of 1000000 characters".to_string())
+        );
+        let note = parse_doctype(NOTE).unwrap().unwrap();
+        assert!(note.check_entity_expansion(NOTE, 9).is_ok());
+        assert!(note.check_entity_expansion(NOTE, 8).is_err());
+    }
+
+    #[test]
+    fn test_default_attribute_expansion_is_bounded() {
+        let mut entities = String::from("<!ENTITY l0 \"lol\">");
+        for i in 1..8 {
+            entities.push_str(&format!(
+                "<!ENTITY l{} \"{}\">",
+                i,
+                format!("&l{};", i - 1).repeat(10)
+            ));
+        }
+        // One default is already too large, though the body references nothing
+        let bomb = format!(
+            "<!DOCTYPE r [{}<!ATTLIST a x CDATA \"&l7;\">]><r><a/></r>",
+            entities
+        );
+        let doctype = parse_doctype(&bomb).unwrap().unwrap();
+        assert!(doctype
+            .check_entity_expansion(&bomb, 1_000_000)
+            .unwrap_err()
+            .contains("the default of attribute 'x' on 'a'"));
+
+        // Each default fits, but applying it to every element does not
+        let small = format!(
+            "<!DOCTYPE r [{}<!ATTLIST a x CDATA \"&l2;\">]><r>{}</r>",
+            entities,
+            "<a/>".repeat(5)
+        );
+        let doctype = parse_doctype(&small).unwrap().unwrap();
+        let mut budget = 1_000 - doctype.check_entity_expansion(&small, 1_000).unwrap();
+        for _ in 0..3 {
+            let defaults = doctype.default_attributes("a", |_| false, &mut budget);
+            assert_eq!(defaults.unwrap()[0].1.len(), 300);
+        }
+        assert!(doctype
+            .default_attributes("a", |_| false, &mut budget)
+            .is_err());
+    }
+
+    #[test]
+    fn test_content_models() {
+        let ContentModel::Children(particle) =
+            parse_content_model("(to+, from, (body|summary)?)").unwrap()
+        else {
+            panic!("expected element content");
+        };
+        let matches = |names: &[&str]| match_particle(&particle, names, 0).contains(&names.len());
+        assert!(matches(&["to", "from"]));
+        assert!(matches(&["to", "to", "from", "summary"]));
+        assert!(!matches(&["from"]));
+        assert!(!matches(&["to", "from", "body", "summary"]));
+        assert!(parse_content_model("(a, b | c)").is_err());
+        assert_eq!(
+            parse_content_model("( #PCDATA )").unwrap(),
+            ContentModel::Mixed(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let doctype = parse_doctype(NOTE).unwrap().unwrap();
+        let options = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..roxmltree::ParsingOptions::default()
+        };
+        let doc = roxmltree::Document::parse_with_options(NOTE, options).unwrap();
+        assert_eq!(doctype.validate(&doc), Vec::<String>::new());
+
+        let invalid = NOTE.replace(
+            r#"<note id="n1"><to ref="n1">Tove</to><from>&writer;</from></note>"#,
+            r#"<note lang="fr" version="2"><to ref="x">Tove</to><summary>!</summary><cc/></note>"#,
+        );
+        let doc = roxmltree::Document::parse_with_options(&invalid, options).unwrap();
+        assert_eq!(
+            doctype.validate(&doc),
+            vec![
+                "17:1: Content of 'note' (to, summary, cc) does not match its declaration",
+                "17:1: Invalid value 'fr' for attribute 'lang'",
+                "17:1: Attribute 'version' must have the fixed value '1.0'",
+                "17:1: Element 'note' is missing required attribute 'id'",
+                "17:50: Element 'summary' must be empty",
+                "17:70: Element 'cc' is not declared",
+                "17:29: IDREF 'x' does not match any ID",
+            ]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Repeated subtrees, found by the `content_hash` each node gets at load time.
+//!
+//! Only the outermost copy of repeated content is reported: two identical
+//! `<footer>`s make one group, not one more group for every element inside
+//! them.
+
+use crate::database::{exec, query};
+use crate::tree::StoredTree;
+use serde::Serialize;
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateNode {
+    pub node_id: i64,
+    pub document_id: i64,
+    pub document_name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    pub content_hash: String,
+    pub tag_name: String,
+    /// Elements in each copy.
+    pub subtree_size: i64,
+    /// Distinct documents the copies appear in.
+    pub documents: usize,
+    /// Every copy by node id, including copies nested in larger
+    /// repeated subtrees.
+    pub nodes: Vec<DuplicateNode>,
+}
+
+/// Subtrees of at least `min_size` elements that occur more than once,
+/// within a document or across documents, largest first.
+///
+/// A group is left out when every copy sits inside a larger repeated
+/// subtree, since that group already covers it.
+pub fn find_duplicates(db: *mut sqlite3, min_size: i64) -> Result<Vec<DuplicateGroup>, String> {
+    let rows = query(
+        db,
+        "WITH repeated AS (
+            SELECT content_hash FROM nodes
+            WHERE content_hash IS NOT NULL AND subtree_size >= ?1
+            GROUP BY content_hash HAVING COUNT(*) > 1
+        )
+        SELECT n.content_hash, n.tag_name, n.subtree_size, n.id, n.document_id, d.name,
+            p.content_hash IN repeated
+        FROM nodes n
+        JOIN repeated USING (content_hash)
+        JOIN documents d ON d.id = n.document_id
+        LEFT JOIN nodes p ON p.id = n.parent_id
+        ORDER BY n.subtree_size DESC, n.content_hash, n.id",
+        &[json!(min_size.max(1))],
+    )?;
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    // Whether some copy of the last group is not inside a repeated parent
+    let mut outermost = false;
+    for row in rows.rows {
+        let hash = row[0].as_str().unwrap_or_default();
+        if groups.last().map(|g| g.content_hash.as_str()) != Some(hash) {
+            if !outermost {
+                groups.pop();
+            }
+            outermost = false;
+            groups.push(DuplicateGroup {
+                content_hash: hash.to_string(),
+                tag_name: row[1].as_str().unwrap_or_default().to_string(),
+                subtree_size: row[2].as_i64().unwrap_or_default(),
+                documents: 0,
+                nodes: Vec::new(),
+            });
+        }
+        outermost |= row[6].as_i64() != Some(1);
+        let group = groups.last_mut().expect("group pushed above");
+        group.nodes.push(DuplicateNode {
+            node_id: row[3].as_i64().unwrap_or_default(),
+            document_id: row[4].as_i64().unwrap_or_default(),
+            document_name: row[5].as_str().unwrap_or_default().to_string(),
+        });
+    }
+    if !outermost {
+        groups.pop();
+    }
+
+    for group in &mut groups {
+        let mut documents: Vec<i64> = group.nodes.iter().map(|n| n.document_id).collect();
+        documents.sort_unstable();
+        documents.dedup();
+        group.documents = documents.len();
+    }
+    Ok(groups)
+}
+
+/// Computes `content_hash` and `subtree_size` for documents loaded before
+/// nodes carried them.
+pub fn backfill_hashes(db: *mut sqlite3) -> Result<(), String> {
+    let documents = query(
+        db,
+        "SELECT DISTINCT document_id FROM nodes WHERE content_hash IS NULL",
+        &[],
+    )?;
+    exec(db, "SAVEPOINT backfill_hashes")?;
+    let result = documents
+        .rows
+        .iter()
+        .filter_map(|row| row[0].as_i64())
+        .try_for_each(|document_id| backfill_document(db, document_id));
+    match result {
+        Ok(()) => exec(db, "RELEASE backfill_hashes"),
+        Err(e) => {
+            let _ = exec(db, "ROLLBACK TO backfill_hashes; RELEASE backfill_hashes");
+            Err(e)
+        }
+    }
+}
+
+fn backfill_document(db: *mut sqlite3, document_id: i64) -> Result<(), String> {
+    let Some(tree) = StoredTree::load_document(db, document_id)? else {
+        return Ok(());
+    };
+    let hashes = tree.subtree_hashes();
+    let mut sizes = vec![1i64; tree.nodes.len()];
+    for index in (0..tree.nodes.len()).rev() {
+        sizes[index] += tree.nodes[index]
+            .children
+            .iter()
+            .map(|&child| sizes[child])
+            .sum::<i64>();
+    }
+    for (index, node) in tree.nodes.iter().enumerate() {
+        query(
+            db,
+            "UPDATE nodes SET content_hash = ?2, subtree_size = ?3 WHERE id = ?1",
+            &[
+                json!(node.id),
+                json!(format!("{:016x}", hashes[index])),
+                json!(sizes[index]),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::init_schema;
+    use crate::testing::{load_xml, open};
+
+    fn hashes(db: *mut sqlite3) -> Vec<serde_json::Value> {
+        query(db, "SELECT content_hash FROM nodes ORDER BY id", &[])
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|mut row| row.remove(0))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_duplicates_reports_outermost_copies() {
+        let db = open();
+        let footer = "<footer><p>contact</p><p>legal</p></footer>";
+        load_xml(db, "a.xml", &format!("<page><main/>{}</page>", footer));
+        load_xml(
+            db,
+            "b.xml",
+            &format!("<page><aside/>{}{}</page>", footer, footer),
+        );
+
+        let groups = find_duplicates(db, 2).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].tag_name, "footer");
+        assert_eq!(groups[0].subtree_size, 3);
+        assert_eq!(groups[0].documents, 2);
+        assert_eq!(groups[0].nodes.len(), 3);
+        // <p>legal</p> only ever occurs inside a footer
+        let groups = find_duplicates(db, 1).unwrap();
+        let tags: Vec<&str> = groups.iter().map(|g| g.tag_name.as_str()).collect();
+        assert_eq!(tags, ["footer"]);
+    }
+
+    #[test]
+    fn test_namespaces_tell_subtrees_apart() {
+        let db = open();
+        load_xml(
+            db,
+            "a.xml",
+            r#"<r><a:p xmlns:a="X">t</a:p><b:p xmlns:b="X">t</b:p></r>"#,
+        );
+        load_xml(db, "b.xml", r#"<c:p xmlns:c="Y">t</c:p>"#);
+        load_xml(db, "c.xml", r#"<p xmlns="X" xmlns:a="X" a:id="1">t</p>"#);
+        load_xml(db, "d.xml", r#"<p xmlns="X" xmlns:b="Y" b:id="1">t</p>"#);
+
+        // Prefixes do not matter, namespace URIs do
+        let groups = find_duplicates(db, 1).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].documents, 1);
+        assert_eq!(groups[0].nodes.len(), 2);
+    }
+
+    #[test]
+    fn test_backfill_hashes() {
+        let db = open();
+        load_xml(
+            db,
+            "a.xml",
+            r#"<r xmlns:a="X"><a:p a:id="1">t</a:p><q/></r>"#,
+        );
+        let stored = hashes(db);
+        exec(
+            db,
+            "UPDATE nodes SET content_hash = NULL, subtree_size = NULL",
+        )
+        .unwrap();
+
+        backfill_hashes(db).unwrap();
+        assert_eq!(hashes(db), stored);
+        let sizes = query(db, "SELECT subtree_size FROM nodes ORDER BY id", &[])
+            .unwrap()
+            .rows;
+        assert_eq!(sizes, vec![vec![json!(3)], vec![json!(1)], vec![json!(1)]]);
+
+        // Hashes stored before namespaces counted are redone on open
+        exec(
+            db,
+            "UPDATE nodes SET content_hash = 'stale'; PRAGMA user_version = 0",
+        )
+        .unwrap();
+        init_schema(db).unwrap();
+        assert_eq!(hashes(db), stored);
+    }
+}
@@ -0,0 +1,250 @@
+//! Declarative extraction: a JSON schema of scoped selectors turned into
+//! records, instead of one `query_selector` call per field.
+//!
+//! ```json
+//! {"root": "article.post",
+//!  "fields": {"title": "h1::text",
+//!             "tags": {"selector": ".tag", "many": true},
+//!             "comments": {"selector": ".comment", "many": true,
+//!                          "fields": {"author": "::attr(data-author)"}}}}
+//! ```
+//!
+//! Field selectors run against each record's element and its descendants.
+//! A field that is only a pseudo-element (`::attr(id)`) reads the record's
+//! own element.
+
+use crate::database::query;
+use crate::selector::{css_to_node_sql, css_to_value_sql, DocumentFilter, SelectorScope};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sqlite_wasm_rs::sqlite3;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schema {
+    /// Each match becomes a record. Without it the document's root element
+    /// is the only record.
+    pub root: Option<String>,
+    pub fields: BTreeMap<String, Field>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Field {
+    /// `"h1::text"`: the first match's value
+    Selector(String),
+    Spec {
+        selector: String,
+        /// Collect every match into an array instead of taking the first.
+        #[serde(default)]
+        many: bool,
+        /// Turn each match into a nested record.
+        fields: Option<BTreeMap<String, Field>>,
+    },
+}
+
+/// Runs `schema` against one document and returns its records in document
+/// order. Fields without a match are `null` (or `[]` with `many`).
+pub fn extract(db: *mut sqlite3, document_id: i64, schema: &Schema) -> Result<Vec<Value>, String> {
+    let roots = match &schema.root {
+        Some(selector) => {
+            let scope = SelectorScope {
+                document: Some(DocumentFilter::Id(document_id)),
+                ..SelectorScope::default()
+            };
+            node_ids(db, &css_to_node_sql(selector, &scope)?)?
+        }
+        None => node_ids(
+            db,
+            &format!(
+                "SELECT id FROM nodes WHERE document_id = {} AND parent_id IS NULL",
+                document_id
+            ),
+        )?,
+    };
+    roots
+        .into_iter()
+        .map(|node_id| record(db, document_id, node_id, &schema.fields))
+        .collect()
+}
+
+fn node_ids(db: *mut sqlite3, sql: &str) -> Result<Vec<i64>, String> {
+    let result = query(
+        db,
+        &format!(
+            "SELECT id FROM nodes WHERE id IN (SELECT id FROM ({})) ORDER BY document_order",
+            sql
+        ),
+        &[],
+    )?;
+    Ok(result
+        .rows
+        .iter()
+        .filter_map(|row| row[0].as_i64())
+        .collect())
+}
+
+fn record(
+    db: *mut sqlite3,
+    document_id: i64,
+    node_id: i64,
+    fields: &BTreeMap<String, Field>,
+) -> Result<Value, String> {
+    let mut record = Map::new();
+    for (name, field) in fields {
+        let value = field_value(db, document_id, node_id, field)
+            .map_err(|e| format!("Field '{}': {}", name, e))?;
+        record.insert(name.clone(), value);
+    }
+    Ok(Value::Object(record))
+}
+
+fn field_value(
+    db: *mut sqlite3,
+    document_id: i64,
+    node_id: i64,
+    field: &Field,
+) -> Result<Value, String> {
+    let (selector, many, nested) = match field {
+        Field::Selector(selector) => (selector, false, None),
+        Field::Spec {
+            selector,
+            many,
+            fields,
+        } => (selector, *many, fields.as_ref()),
+    };
+    let selector = if selector.trim_start().starts_with("::") {
+        format!(":scope{}", selector.trim_start())
+    } else {
+        selector.clone()
+    };
+    let scope = SelectorScope {
+        document: Some(DocumentFilter::Id(document_id)),
+        context_node: Some(node_id),
+        include_context: true,
+    };
+
+    let values: Vec<Value> = match nested {
+        Some(fields) => node_ids(db, &css_to_node_sql(&selector, &scope)?)?
+            .into_iter()
+            .map(|child| record(db, document_id, child, fields))
+            .collect::<Result<_, _>>()?,
+        None => {
+            let sql = format!(
+                "SELECT matched.* FROM ({}) matched JOIN nodes n ON n.id = matched.id
+                ORDER BY n.document_order",
+                css_to_value_sql(&selector, &scope)?
+            );
+            query(db, &sql, &[])?
+                .rows
+                .into_iter()
+                .map(|mut row| row.swap_remove(1))
+                .collect()
+        }
+    };
+
+    Ok(if many {
+        Value::Array(values)
+    } else {
+        values.into_iter().next().unwrap_or(json!(null))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{load_html, open};
+
+    #[test]
+    fn test_schema_fields_accept_shorthand_and_specs() {
+        let schema: Schema = serde_json::from_value(json!({
+            "root": "article.post",
+            "fields": {
+                "title": "h1::text",
+                "tags": {"selector": ".tag", "many": true},
+                "comments": {"selector": ".comment", "fields": {"by": "::attr(data-by)"}}
+            }
+        }))
+        .unwrap();
+        assert_eq!(schema.root.as_deref(), Some("article.post"));
+        assert!(matches!(&schema.fields["title"], Field::Selector(s) if s == "h1::text"));
+        assert!(matches!(
+            &schema.fields["tags"],
+            Field::Spec {
+                many: true,
+                fields: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &schema.fields["comments"],
+            Field::Spec { many: false, fields: Some(nested), .. } if nested.contains_key("by")
+        ));
+        assert!(serde_json::from_value::<Schema>(json!({"fields": {"x": 1}})).is_err());
+    }
+
+    #[test]
+    fn test_extract_records() {
+        let db = open();
+        let page = load_html(
+            db,
+            "blog.html",
+            r#"<article class="post" id="first"><h1>Hello</h1>
+                <span class="tag">news</span><span class="tag">rust</span>
+                <div class="comment" data-by="ann"><p>Nice</p></div>
+                <div class="comment" data-by="bob"></div>
+            </article>
+            <article class="post" id="second"><h1>Again</h1></article>"#,
+        );
+        // Another document's articles stay out
+        load_html(
+            db,
+            "other.html",
+            r#"<article class="post"><h1>Elsewhere</h1></article>"#,
+        );
+
+        let schema: Schema = serde_json::from_value(json!({
+            "root": "article.post",
+            "fields": {
+                "id": "::attr(id)",
+                "title": "h1::text",
+                "tags": {"selector": ".tag::text", "many": true},
+                "comments": {"selector": ".comment", "many": true, "fields": {
+                    "by": "::attr(data-by)",
+                    "text": "p::text"
+                }},
+                "missing": "footer::text"
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            extract(db, page, &schema).unwrap(),
+            vec![
+                json!({
+                    "id": "first",
+                    "title": "Hello",
+                    "tags": ["news", "rust"],
+                    "comments": [{"by": "ann", "text": "Nice"}, {"by": "bob", "text": null}],
+                    "missing": null
+                }),
+                json!({
+                    "id": "second",
+                    "title": "Again",
+                    "tags": [],
+                    "comments": [],
+                    "missing": null
+                }),
+            ]
+        );
+
+        // Without a root the root element is the only record
+        let schema: Schema = serde_json::from_value(
+            json!({"fields": {"titles": {"selector": "h1::text", "many": true}}}),
+        )
+        .unwrap();
+        assert_eq!(
+            extract(db, page, &schema).unwrap(),
+            vec![json!({"titles": ["Hello", "Again"]})]
+        );
+    }
+}
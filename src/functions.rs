@@ -0,0 +1,530 @@
+//! Scalar SQL functions for navigating the stored tree.
+//!
+//! These are registered on every connection the crate opens, so they are
+//! available from `execute_sql` as well as to SQL generated by the selector
+//! engine. `node_path` counts siblings by `position` and `inner_text` joins
+//! text by `document_order`, so neither depends on the order of node ids.
+//!
+//! SQLite ships without a `REGEXP` implementation, so one is provided here
+//! (`X REGEXP Y` calls `regexp(Y, X)`) together with `regexp_extract` and
+//! `regexp_replace`. Compiled patterns are cached per statement via auxdata.
+//!
+//! `css_attr_match` implements the CSS attribute operators for the selector
+//! engine, so their exact semantics live in one tested Rust function.
+
+use crate::database::{query, query_value};
+use crate::selector::{attribute_matches, AttributeOperator};
+use crate::tree::outer_xml;
+use regex_lite::Regex;
+use serde_json::{json, Value};
+use sqlite_wasm_rs::{
+    sqlite3, sqlite3_context, sqlite3_context_db_handle, sqlite3_create_function_v2,
+    sqlite3_get_auxdata, sqlite3_result_error, sqlite3_result_int64, sqlite3_result_null,
+    sqlite3_result_text, sqlite3_set_auxdata, sqlite3_value, sqlite3_value_double,
+    sqlite3_value_int64, sqlite3_value_text, sqlite3_value_type, SQLITE_FLOAT, SQLITE_INTEGER,
+    SQLITE_NULL, SQLITE_OK, SQLITE_TRANSIENT, SQLITE_UTF8,
+};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+
+type ScalarFn = unsafe extern "C" fn(*mut sqlite3_context, c_int, *mut *mut sqlite3_value);
+
+const SCALAR_FUNCTIONS: &[(&str, c_int, ScalarFn)] = &[
+    ("inner_text", 1, inner_text_fn),
+    ("node_path", 1, node_path_fn),
+    ("attr", 2, attr_fn),
+    ("is_ancestor", 2, is_ancestor_fn),
+    ("has_class", 2, has_class_fn),
+    ("outer_xml", 1, outer_xml_fn),
+    ("regexp", 2, regexp_fn),
+    ("regexp_extract", 2, regexp_extract_fn),
+    ("regexp_extract", 3, regexp_extract_fn),
+    ("regexp_replace", 3, regexp_replace_fn),
+    ("css_attr_match", 4, css_attr_match_fn),
+];
+
+pub fn register_functions(db: *mut sqlite3) -> Result<(), String> {
+    for (name, n_arg, func) in SCALAR_FUNCTIONS {
+        register_scalar(db, name, *n_arg, *func)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn register_scalar(
+    db: *mut sqlite3,
+    name: &str,
+    n_arg: c_int,
+    func: ScalarFn,
+) -> Result<(), String> {
+    let c_name = CString::new(name).unwrap();
+    let ret = unsafe {
+        sqlite3_create_function_v2(
+            db,
+            c_name.as_ptr(),
+            n_arg,
+            SQLITE_UTF8,
+            ptr::null_mut(),
+            Some(func),
+            None,
+            None,
+            None,
+        )
+    };
+    if ret != SQLITE_OK {
+        return Err(format!("Failed to register SQL function {}", name));
+    }
+    Ok(())
+}
+
+/// Concatenated text of a node and all of its descendants, in document order.
+pub fn inner_text(db: *mut sqlite3, node_id: i64) -> Result<Option<String>, String> {
+    let sql = "WITH RECURSIVE subtree(id) AS (
+        SELECT id FROM nodes WHERE id = ?1
+        UNION ALL
+        SELECT n.id FROM nodes n JOIN subtree s ON n.parent_id = s.id
+    )
+    SELECT group_concat(text_content, ' ') FROM (
+        SELECT n.text_content FROM nodes n JOIN subtree s ON n.id = s.id
+        WHERE n.text_content IS NOT NULL
+        ORDER BY n.document_order
+    )";
+    let value = query_value(db, sql, &[json!(node_id)])?;
+    Ok(value.and_then(|v| v.as_str().map(str::to_string)))
+}
+
+/// XPath-like location of a node, e.g. `/html/body/div[2]`.
+///
+/// The `[n]` index is only emitted when the parent has several children with
+/// the same tag, mirroring how browsers abbreviate paths.
+pub fn node_path(db: *mut sqlite3, node_id: i64) -> Result<Option<String>, String> {
+    let sql = "WITH RECURSIVE chain(id, parent_id, tag_name, position, lvl) AS (
+        SELECT id, parent_id, tag_name, position, 0 FROM nodes WHERE id = ?1
+        UNION ALL
+        SELECT n.id, n.parent_id, n.tag_name, n.position, c.lvl + 1
+        FROM nodes n JOIN chain c ON n.id = c.parent_id
+    )
+    SELECT c.tag_name,
+        (SELECT COUNT(*) FROM nodes s
+         WHERE s.parent_id IS c.parent_id AND s.tag_name = c.tag_name
+           AND s.position <= c.position
+           AND (c.parent_id IS NOT NULL OR s.id = c.id)),
+        (SELECT COUNT(*) FROM nodes s
+         WHERE s.parent_id IS c.parent_id AND s.tag_name = c.tag_name
+           AND (c.parent_id IS NOT NULL OR s.id = c.id))
+    FROM chain c
+    ORDER BY c.lvl DESC";
+    let result = query(db, sql, &[json!(node_id)])?;
+    if result.rows.is_empty() {
+        return Ok(None);
+    }
+
+    let mut path = String::new();
+    for row in &result.rows {
+        let tag = row[0].as_str().unwrap_or_default();
+        let index = row[1].as_i64().unwrap_or(1);
+        let total = row[2].as_i64().unwrap_or(1);
+        path.push('/');
+        path.push_str(tag);
+        if total > 1 {
+            path.push_str(&format!("[{}]", index));
+        }
+    }
+    Ok(Some(path))
+}
+
+pub fn attr(db: *mut sqlite3, node_id: i64, name: &str) -> Result<Option<String>, String> {
+    let sql = "SELECT value FROM attributes WHERE node_id = ?1 AND name = ?2 LIMIT 1";
+    let value = query_value(db, sql, &[json!(node_id), json!(name)])?;
+    Ok(value.and_then(|v| v.as_str().map(str::to_string)))
+}
+
+/// True when `ancestor` is a proper ancestor of `node`.
+pub fn is_ancestor(db: *mut sqlite3, ancestor: i64, node: i64) -> Result<bool, String> {
+    let sql = "WITH RECURSIVE up(id) AS (
+        SELECT parent_id FROM nodes WHERE id = ?2
+        UNION ALL
+        SELECT n.parent_id FROM nodes n JOIN up ON n.id = up.id
+    )
+    SELECT EXISTS (SELECT 1 FROM up WHERE id = ?1)";
+    let value = query_value(db, sql, &[json!(ancestor), json!(node)])?;
+    Ok(value.and_then(|v| v.as_i64()).unwrap_or(0) != 0)
+}
+
+pub fn has_class(db: *mut sqlite3, node_id: i64, class: &str) -> Result<bool, String> {
+    Ok(attr(db, node_id, "class")?
+        .map(|classes| classes.split_whitespace().any(|c| c == class))
+        .unwrap_or(false))
+}
+
+unsafe extern "C" fn inner_text_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let Some(node_id) = arg_i64(argv, 0) else {
+        return sqlite3_result_null(ctx);
+    };
+    result_text(ctx, inner_text(sqlite3_context_db_handle(ctx), node_id));
+}
+
+unsafe extern "C" fn node_path_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let Some(node_id) = arg_i64(argv, 0) else {
+        return sqlite3_result_null(ctx);
+    };
+    result_text(ctx, node_path(sqlite3_context_db_handle(ctx), node_id));
+}
+
+unsafe extern "C" fn outer_xml_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let Some(node_id) = arg_i64(argv, 0) else {
+        return sqlite3_result_null(ctx);
+    };
+    result_text(ctx, outer_xml(sqlite3_context_db_handle(ctx), node_id));
+}
+
+unsafe extern "C" fn attr_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let (Some(node_id), Some(name)) = (arg_i64(argv, 0), arg_text(argv, 1)) else {
+        return sqlite3_result_null(ctx);
+    };
+    result_text(ctx, attr(sqlite3_context_db_handle(ctx), node_id, &name));
+}
+
+unsafe extern "C" fn is_ancestor_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let (Some(ancestor), Some(node)) = (arg_i64(argv, 0), arg_i64(argv, 1)) else {
+        return sqlite3_result_null(ctx);
+    };
+    result_bool(
+        ctx,
+        is_ancestor(sqlite3_context_db_handle(ctx), ancestor, node),
+    );
+}
+
+unsafe extern "C" fn has_class_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let (Some(node_id), Some(class)) = (arg_i64(argv, 0), arg_text(argv, 1)) else {
+        return sqlite3_result_null(ctx);
+    };
+    result_bool(
+        ctx,
+        has_class(sqlite3_context_db_handle(ctx), node_id, &class),
+    );
+}
+
+unsafe extern "C" fn css_attr_match_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let (Some(value), Some(symbol), Some(expected)) =
+        (arg_text(argv, 0), arg_text(argv, 1), arg_text(argv, 2))
+    else {
+        return sqlite3_result_null(ctx);
+    };
+    let Some(operator) = AttributeOperator::from_symbol(&symbol) else {
+        return result_error(ctx, &format!("Unknown attribute operator: {}", symbol));
+    };
+    let case_insensitive = arg_i64(argv, 3).unwrap_or(0) != 0;
+    result_bool(
+        ctx,
+        Ok(attribute_matches(
+            &operator,
+            &value,
+            &expected,
+            case_insensitive,
+        )),
+    );
+}
+
+unsafe extern "C" fn regexp_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let Some(text) = arg_text(argv, 1) else {
+        return sqlite3_result_null(ctx);
+    };
+    match with_regex(ctx, argv, 0, |re| re.is_match(&text)) {
+        Ok(Some(matched)) => result_bool(ctx, Ok(matched)),
+        Ok(None) => sqlite3_result_null(ctx),
+        Err(e) => result_error(ctx, &e),
+    }
+}
+
+unsafe extern "C" fn regexp_extract_fn(
+    ctx: *mut sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let Some(text) = arg_text(argv, 0) else {
+        return sqlite3_result_null(ctx);
+    };
+    let group = if argc > 2 {
+        arg_i64(argv, 2).unwrap_or(0)
+    } else {
+        0
+    };
+    let extracted = with_regex(ctx, argv, 1, |re| {
+        re.captures(&text)
+            .and_then(|caps| caps.get(group.max(0) as usize))
+            .map(|m| m.as_str().to_string())
+    });
+    result_text(ctx, extracted.map(Option::flatten));
+}
+
+unsafe extern "C" fn regexp_replace_fn(
+    ctx: *mut sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    let (Some(text), Some(replacement)) = (arg_text(argv, 0), arg_text(argv, 2)) else {
+        return sqlite3_result_null(ctx);
+    };
+    let replaced = with_regex(ctx, argv, 1, |re| {
+        re.replace_all(&text, replacement.as_str()).into_owned()
+    });
+    result_text(ctx, replaced);
+}
+
+/// Runs `f` with the regex compiled from argument `i`, or `None` if it is NULL.
+///
+/// The compiled pattern is handed to SQLite as auxdata, so a constant pattern
+/// is only compiled once per statement rather than once per row.
+unsafe fn with_regex<T>(
+    ctx: *mut sqlite3_context,
+    argv: *mut *mut sqlite3_value,
+    i: usize,
+    f: impl FnOnce(&Regex) -> T,
+) -> Result<Option<T>, String> {
+    let cached = sqlite3_get_auxdata(ctx, i as c_int) as *const Regex;
+    if !cached.is_null() {
+        return Ok(Some(f(&*cached)));
+    }
+
+    let Some(pattern) = arg_text(argv, i) else {
+        return Ok(None);
+    };
+    let regex = Regex::new(&pattern).map_err(|e| format!("Invalid regular expression: {}", e))?;
+    let out = f(&regex);
+    sqlite3_set_auxdata(
+        ctx,
+        i as c_int,
+        Box::into_raw(Box::new(regex)) as *mut c_void,
+        Some(drop_regex),
+    );
+    Ok(Some(out))
+}
+
+unsafe extern "C" fn drop_regex(regex: *mut c_void) {
+    drop(Box::from_raw(regex as *mut Regex));
+}
+
+pub(crate) unsafe fn arg_i64(argv: *mut *mut sqlite3_value, i: usize) -> Option<i64> {
+    let value = *argv.add(i);
+    if sqlite3_value_type(value) == SQLITE_NULL {
+        None
+    } else {
+        Some(sqlite3_value_int64(value))
+    }
+}
+
+pub(crate) unsafe fn arg_value(argv: *mut *mut sqlite3_value, i: usize) -> Value {
+    let value = *argv.add(i);
+    match sqlite3_value_type(value) {
+        SQLITE_NULL => Value::Null,
+        SQLITE_INTEGER => json!(sqlite3_value_int64(value)),
+        SQLITE_FLOAT => json!(sqlite3_value_double(value)),
+        _ => arg_text(argv, i).map(Value::String).unwrap_or(Value::Null),
+    }
+}
+
+pub(crate) unsafe fn arg_text(argv: *mut *mut sqlite3_value, i: usize) -> Option<String> {
+    let value = *argv.add(i);
+    if sqlite3_value_type(value) == SQLITE_NULL {
+        return None;
+    }
+    let text = sqlite3_value_text(value);
+    if text.is_null() {
+        return None;
+    }
+    Some(
+        CStr::from_ptr(text as *const c_char)
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+pub(crate) unsafe fn result_text(ctx: *mut sqlite3_context, value: Result<Option<String>, String>) {
+    match value {
+        Ok(Some(text)) => match CString::new(text) {
+            Ok(c_text) => sqlite3_result_text(ctx, c_text.as_ptr(), -1, SQLITE_TRANSIENT()),
+            Err(_) => result_error(ctx, "Result contains a NUL byte"),
+        },
+        Ok(None) => sqlite3_result_null(ctx),
+        Err(e) => result_error(ctx, &e),
+    }
+}
+
+pub(crate) unsafe fn result_bool(ctx: *mut sqlite3_context, value: Result<bool, String>) {
+    match value {
+        Ok(b) => sqlite3_result_int64(ctx, b as i64),
+        Err(e) => result_error(ctx, &e),
+    }
+}
+
+pub(crate) unsafe fn result_error(ctx: *mut sqlite3_context, msg: &str) {
+    let c_msg = CString::new(msg.replace('\0', "")).unwrap();
+    sqlite3_result_error(ctx, c_msg.as_ptr(), -1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{load_xml, node_id, open};
+
+    const LIBRARY: &str = r#"<library>
+        <shelf name="a"><book class="new  featured">First</book><book>Second</book></shelf>
+        <shelf name="b"><title>Poems</title><book>Third <em>edition</em></book></shelf>
+    </library>"#;
+
+    fn ids(db: *mut sqlite3, sql: &str) -> Vec<i64> {
+        query(db, sql, &[])
+            .unwrap()
+            .rows
+            .iter()
+            .filter_map(|row| row[0].as_i64())
+            .collect()
+    }
+
+    #[test]
+    fn test_node_path_indexes_repeated_tags() {
+        let db = open();
+        load_xml(db, "library.xml", LIBRARY);
+        let books = ids(
+            db,
+            "SELECT id FROM nodes WHERE tag_name = 'book' ORDER BY id",
+        );
+        let paths: Vec<String> = books
+            .iter()
+            .map(|&id| node_path(db, id).unwrap().unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/library/shelf[1]/book[1]",
+                "/library/shelf[1]/book[2]",
+                "/library/shelf[2]/book"
+            ]
+        );
+        assert_eq!(
+            node_path(db, node_id(db, "library")).unwrap().as_deref(),
+            Some("/library")
+        );
+        assert_eq!(node_path(db, 999).unwrap(), None);
+    }
+
+    #[test]
+    fn test_inner_text_joins_in_document_order() {
+        let db = open();
+        load_xml(db, "library.xml", LIBRARY);
+        assert_eq!(
+            inner_text(db, node_id(db, "library")).unwrap().as_deref(),
+            Some("First Second Poems Third edition")
+        );
+        let shelves = ids(
+            db,
+            "SELECT id FROM nodes WHERE tag_name = 'shelf' ORDER BY id",
+        );
+        assert_eq!(
+            inner_text(db, shelves[1]).unwrap().as_deref(),
+            Some("Poems Third edition")
+        );
+        assert_eq!(inner_text(db, 999).unwrap(), None);
+    }
+
+    #[test]
+    fn test_attribute_functions() {
+        let db = open();
+        load_xml(db, "library.xml", LIBRARY);
+        let book = node_id(db, "book");
+        assert_eq!(
+            attr(db, book, "class").unwrap().as_deref(),
+            Some("new  featured")
+        );
+        assert_eq!(attr(db, book, "id").unwrap(), None);
+        assert!(has_class(db, book, "featured").unwrap());
+        assert!(!has_class(db, book, "feat").unwrap());
+        assert!(!has_class(db, node_id(db, "shelf"), "new").unwrap());
+
+        let library = node_id(db, "library");
+        assert!(is_ancestor(db, library, book).unwrap());
+        assert!(!is_ancestor(db, book, library).unwrap());
+        assert!(!is_ancestor(db, book, book).unwrap());
+    }
+
+    #[test]
+    fn test_sql_functions() {
+        let db = open();
+        load_xml(db, "library.xml", LIBRARY);
+        let rows = query(
+            db,
+            "SELECT attr(id, 'name'), outer_xml(id) FROM nodes
+            WHERE tag_name = 'shelf' AND has_class(id, 'x') = 0 ORDER BY id",
+            &[],
+        )
+        .unwrap()
+        .rows;
+        assert_eq!(rows[0][0], json!("a"));
+        assert_eq!(
+            rows[1][1],
+            json!(
+                "<shelf name=\"b\"><title>Poems</title><book>Third <em>edition</em></book></shelf>"
+            )
+        );
+        assert_eq!(
+            ids(
+                db,
+                "SELECT id FROM nodes WHERE text_content REGEXP '^(First|Third)$'"
+            )
+            .len(),
+            2
+        );
+        assert_eq!(
+            query_value(
+                db,
+                "SELECT regexp_extract('isbn 978-3-16', '(\\d+)-(\\d+)', 2)",
+                &[]
+            )
+            .unwrap(),
+            Some(json!("3"))
+        );
+        assert_eq!(
+            query_value(db, "SELECT regexp_extract('no digits', '\\d+')", &[]).unwrap(),
+            None
+        );
+        assert_eq!(
+            query_value(db, "SELECT regexp_replace('a1b22', '\\d+', '#')", &[]).unwrap(),
+            Some(json!("a#b#"))
+        );
+        assert!(query(db, "SELECT 'x' REGEXP '('", &[])
+            .unwrap_err()
+            .contains("Invalid regular expression"));
+    }
+}
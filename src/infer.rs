@@ -0,0 +1,572 @@
+//! Structural schema inference: a per-path summary of a loaded document,
+//! plus DTD and XSD skeletons derived from it.
+//!
+//! Everything is computed from a `StoredTree`, so one pass over the stored
+//! document is enough. Value types reuse the inference behind
+//! `materialize_table`.
+
+use crate::materialize::{infer_type, ColumnType};
+use crate::tree::{escape_attribute, StoredTree};
+use serde::Serialize;
+use sqlite_wasm_rs::sqlite3;
+use std::collections::{HashMap, HashSet};
+
+/// Distinct values kept per text node or attribute.
+const MAX_SAMPLES: usize = 5;
+const MAX_SAMPLE_CHARS: usize = 80;
+/// Distinct child sequences listed per path.
+const MAX_SEQUENCES: usize = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaReport {
+    pub root: String,
+    /// Every distinct element path, in document order of first appearance.
+    pub elements: Vec<ElementReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ElementReport {
+    /// e.g. `/library/book/title`
+    pub path: String,
+    pub name: String,
+    pub count: usize,
+    /// Fewest and most occurrences under one parent; 1 for the root.
+    pub min_occurs: usize,
+    pub max_occurs: usize,
+    /// Child element names, in the order they should appear, or in order
+    /// of first appearance when the instances disagree.
+    pub children: Vec<String>,
+    /// Whether every instance lists its children in `children` order.
+    pub ordered: bool,
+    pub child_sequences: Vec<SequenceCount>,
+    pub attributes: Vec<AttributeReport>,
+    /// `None` when no instance has text of its own.
+    pub text: Option<ValueReport>,
+}
+
+/// Child names of one instance with repeats collapsed, e.g. `title author+ year`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequenceCount {
+    pub sequence: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttributeReport {
+    pub name: String,
+    /// Present on every instance of the element.
+    pub required: bool,
+    pub values: ValueReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueReport {
+    pub count: usize,
+    pub distinct: usize,
+    #[serde(rename = "type")]
+    pub value_type: ColumnType,
+    pub samples: Vec<String>,
+}
+
+impl ValueReport {
+    fn from_values(values: &[String]) -> ValueReport {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let distinct: Vec<&String> = values.iter().filter(|v| seen.insert(v)).collect();
+        ValueReport {
+            count: values.len(),
+            distinct: distinct.len(),
+            value_type: infer_type(values.iter().map(String::as_str)),
+            samples: distinct
+                .iter()
+                .take(MAX_SAMPLES)
+                .map(|v| v.chars().take(MAX_SAMPLE_CHARS).collect())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PathStats {
+    path: String,
+    name: String,
+    parent: Option<usize>,
+    count: usize,
+    /// Child names of each instance, in document order.
+    instances: Vec<Vec<String>>,
+    attributes: Vec<(String, Vec<String>)>,
+    texts: Vec<String>,
+}
+
+impl SchemaReport {
+    pub fn from_tree(tree: &StoredTree) -> SchemaReport {
+        let mut stats: Vec<PathStats> = Vec::new();
+        let mut by_path: HashMap<String, usize> = HashMap::new();
+        collect(tree, 0, None, &mut stats, &mut by_path);
+
+        let elements = stats
+            .iter()
+            .map(|path| {
+                let (min_occurs, max_occurs) = match path.parent {
+                    None => (1, 1),
+                    Some(parent) => {
+                        let counts: Vec<usize> = stats[parent]
+                            .instances
+                            .iter()
+                            .map(|children| children.iter().filter(|c| **c == path.name).count())
+                            .collect();
+                        (
+                            counts.iter().copied().min().unwrap_or(0),
+                            counts.iter().copied().max().unwrap_or(0),
+                        )
+                    }
+                };
+                let order = child_order(&path.instances);
+                let children = order.clone().unwrap_or_else(|| {
+                    let mut seen: HashSet<&str> = HashSet::new();
+                    path.instances
+                        .iter()
+                        .flatten()
+                        .filter(|name| seen.insert(name))
+                        .cloned()
+                        .collect()
+                });
+                ElementReport {
+                    path: path.path.clone(),
+                    name: path.name.clone(),
+                    count: path.count,
+                    min_occurs,
+                    max_occurs,
+                    children,
+                    ordered: order.is_some(),
+                    child_sequences: child_sequences(&path.instances),
+                    attributes: path
+                        .attributes
+                        .iter()
+                        .map(|(name, values)| AttributeReport {
+                            name: name.clone(),
+                            required: values.len() == path.count,
+                            values: ValueReport::from_values(values),
+                        })
+                        .collect(),
+                    text: (!path.texts.is_empty()).then(|| ValueReport::from_values(&path.texts)),
+                }
+            })
+            .collect();
+
+        SchemaReport {
+            root: tree.nodes[0].tag_name.clone(),
+            elements,
+        }
+    }
+
+    fn child_reports<'a>(&'a self, parent: &'a ElementReport) -> Vec<&'a ElementReport> {
+        parent
+            .children
+            .iter()
+            .filter_map(|name| {
+                let path = format!("{}/{}", parent.path, name);
+                self.elements.iter().find(|e| e.path == path)
+            })
+            .collect()
+    }
+
+    /// A DTD with one declaration per element name. Names used at several
+    /// paths with different content get `ANY`.
+    pub fn to_dtd(&self) -> String {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let names: Vec<&str> = self
+            .elements
+            .iter()
+            .map(|e| e.name.as_str())
+            .filter(|name| seen.insert(name))
+            .collect();
+
+        let mut out = String::new();
+        for name in names {
+            let reports: Vec<&ElementReport> =
+                self.elements.iter().filter(|e| e.name == name).collect();
+            let models: Vec<String> = reports.iter().map(|e| self.content_model(e)).collect();
+            let model = if models.iter().all(|m| *m == models[0]) {
+                models[0].clone()
+            } else {
+                "ANY".to_string()
+            };
+            out.push_str(&format!("<!ELEMENT {} {}>\n", name, model));
+
+            let mut attributes: Vec<(&str, bool)> = Vec::new();
+            for report in &reports {
+                for attribute in &report.attributes {
+                    match attributes.iter_mut().find(|(n, _)| *n == attribute.name) {
+                        Some((_, required)) => *required &= attribute.required,
+                        None => attributes.push((&attribute.name, attribute.required)),
+                    }
+                }
+            }
+            // Required only if required wherever the element appears
+            for (attribute, required) in attributes.iter_mut() {
+                *required &= reports
+                    .iter()
+                    .all(|r| r.attributes.iter().any(|a| a.name == *attribute));
+            }
+            if !attributes.is_empty() {
+                out.push_str(&format!("<!ATTLIST {}", name));
+                for (attribute, required) in attributes {
+                    let default = if required { "#REQUIRED" } else { "#IMPLIED" };
+                    out.push_str(&format!("\n    {} CDATA {}", attribute, default));
+                }
+                out.push_str(">\n");
+            }
+        }
+        out
+    }
+
+    fn content_model(&self, element: &ElementReport) -> String {
+        let children = self.child_reports(element);
+        match (children.is_empty(), element.text.is_some()) {
+            (true, true) => "(#PCDATA)".to_string(),
+            (true, false) => "EMPTY".to_string(),
+            (false, true) => format!("(#PCDATA | {})*", element.children.join(" | ")),
+            (false, false) if element.ordered => format!(
+                "({})",
+                children
+                    .iter()
+                    .map(|c| format!("{}{}", c.name, occurrence_suffix(c)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            (false, false) => format!("({})*", element.children.join(" | ")),
+        }
+    }
+
+    /// An XSD with nested anonymous types following the element paths.
+    pub fn to_xsd(&self) -> String {
+        let mut out = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <xs:schema xmlns:xs=\"http://www.w3.org/2001/XMLSchema\" elementFormDefault=\"qualified\">\n",
+        );
+        if let Some(root) = self.elements.first() {
+            self.write_xsd_element(root, true, 1, &mut out);
+        }
+        out.push_str("</xs:schema>\n");
+        out
+    }
+
+    fn write_xsd_element(
+        &self,
+        element: &ElementReport,
+        is_root: bool,
+        depth: usize,
+        out: &mut String,
+    ) {
+        let indent = "  ".repeat(depth);
+        let mut occurs = String::new();
+        if !is_root {
+            // Observed counts are only a sample, so the bounds stay loose
+            if element.min_occurs == 0 {
+                occurs.push_str(" minOccurs=\"0\"");
+            }
+            if element.max_occurs > 1 {
+                occurs.push_str(" maxOccurs=\"unbounded\"");
+            }
+        }
+        let name = escape_attribute(&element.name);
+        let children = self.child_reports(element);
+        let text_type = element.text.as_ref().map(|t| xsd_type(t.value_type));
+
+        if children.is_empty() && element.attributes.is_empty() {
+            match text_type {
+                Some(t) => out.push_str(&format!(
+                    "{}<xs:element name=\"{}\" type=\"{}\"{}/>\n",
+                    indent, name, t, occurs
+                )),
+                None => out.push_str(&format!(
+                    "{0}<xs:element name=\"{1}\"{2}>\n{0}  <xs:complexType/>\n{0}</xs:element>\n",
+                    indent, name, occurs
+                )),
+            }
+            return;
+        }
+
+        out.push_str(&format!(
+            "{}<xs:element name=\"{}\"{}>\n",
+            indent, name, occurs
+        ));
+        let mixed = if !children.is_empty() && text_type.is_some() {
+            " mixed=\"true\""
+        } else {
+            ""
+        };
+        out.push_str(&format!("{}  <xs:complexType{}>\n", indent, mixed));
+        let mut attribute_indent = format!("{}    ", indent);
+        if !children.is_empty() {
+            let group = if element.ordered {
+                "<xs:sequence>".to_string()
+            } else {
+                "<xs:choice minOccurs=\"0\" maxOccurs=\"unbounded\">".to_string()
+            };
+            out.push_str(&format!("{}    {}\n", indent, group));
+            for child in &children {
+                if element.ordered {
+                    self.write_xsd_element(child, false, depth + 3, out);
+                } else {
+                    self.write_xsd_element(
+                        &ElementReport {
+                            min_occurs: 1,
+                            max_occurs: 1,
+                            ..(*child).clone()
+                        },
+                        false,
+                        depth + 3,
+                        out,
+                    );
+                }
+            }
+            let close = if element.ordered {
+                "</xs:sequence>"
+            } else {
+                "</xs:choice>"
+            };
+            out.push_str(&format!("{}    {}\n", indent, close));
+        } else if let Some(t) = text_type {
+            out.push_str(&format!(
+                "{0}    <xs:simpleContent>\n{0}      <xs:extension base=\"{1}\">\n",
+                indent, t
+            ));
+            attribute_indent = format!("{}        ", indent);
+        }
+        for attribute in &element.attributes {
+            out.push_str(&format!(
+                "{}<xs:attribute name=\"{}\" type=\"{}\"{}/>\n",
+                attribute_indent,
+                escape_attribute(&attribute.name),
+                xsd_type(attribute.values.value_type),
+                if attribute.required {
+                    " use=\"required\""
+                } else {
+                    ""
+                }
+            ));
+        }
+        if children.is_empty() && text_type.is_some() {
+            out.push_str(&format!(
+                "{0}      </xs:extension>\n{0}    </xs:simpleContent>\n",
+                indent
+            ));
+        }
+        out.push_str(&format!("{}  </xs:complexType>\n", indent));
+        out.push_str(&format!("{}</xs:element>\n", indent));
+    }
+}
+
+fn collect(
+    tree: &StoredTree,
+    index: usize,
+    parent: Option<usize>,
+    stats: &mut Vec<PathStats>,
+    by_path: &mut HashMap<String, usize>,
+) {
+    let node = &tree.nodes[index];
+    let path = match parent {
+        Some(parent) => format!("{}/{}", stats[parent].path, node.tag_name),
+        None => format!("/{}", node.tag_name),
+    };
+    let slot = *by_path.entry(path.clone()).or_insert_with(|| {
+        stats.push(PathStats {
+            path,
+            name: node.tag_name.clone(),
+            parent,
+            ..PathStats::default()
+        });
+        stats.len() - 1
+    });
+
+    let entry = &mut stats[slot];
+    entry.count += 1;
+    entry.instances.push(
+        node.children
+            .iter()
+            .map(|&c| tree.nodes[c].tag_name.clone())
+            .collect(),
+    );
+    for (name, value) in &node.attributes {
+        match entry.attributes.iter_mut().find(|(n, _)| n == name) {
+            Some((_, values)) => values.push(value.clone()),
+            None => entry.attributes.push((name.clone(), vec![value.clone()])),
+        }
+    }
+    if let Some(text) = node.text_content.as_ref().filter(|t| !t.trim().is_empty()) {
+        entry.texts.push(text.clone());
+    }
+
+    for &child in &node.children {
+        collect(tree, child, Some(slot), stats, by_path);
+    }
+}
+
+/// Collapses repeats: `[a, b, b, c]` becomes `[a, b, c]` with `b` repeated.
+fn runs(children: &[String]) -> Vec<(&str, usize)> {
+    let mut runs: Vec<(&str, usize)> = Vec::new();
+    for name in children {
+        match runs.last_mut() {
+            Some((last, count)) if *last == name.as_str() => *count += 1,
+            _ => runs.push((name, 1)),
+        }
+    }
+    runs
+}
+
+fn child_sequences(instances: &[Vec<String>]) -> Vec<SequenceCount> {
+    let mut sequences: Vec<SequenceCount> = Vec::new();
+    for children in instances {
+        let sequence = runs(children)
+            .iter()
+            .map(|(name, count)| {
+                if *count > 1 {
+                    format!("{}+", name)
+                } else {
+                    name.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        match sequences.iter_mut().find(|s| s.sequence == sequence) {
+            Some(existing) => existing.count += 1,
+            None => sequences.push(SequenceCount { sequence, count: 1 }),
+        }
+    }
+    sequences.sort_by_key(|s| std::cmp::Reverse(s.count));
+    sequences.truncate(MAX_SEQUENCES);
+    sequences
+}
+
+/// An order of child names that every instance follows, with each name in
+/// one contiguous run, or `None` if the instances disagree.
+fn child_order(instances: &[Vec<String>]) -> Option<Vec<String>> {
+    let mut names: Vec<&str> = Vec::new();
+    let mut edges: HashSet<(usize, usize)> = HashSet::new();
+    for children in instances {
+        let runs = runs(children);
+        let mut previous: Option<usize> = None;
+        let mut seen: Vec<usize> = Vec::new();
+        for (name, _) in runs {
+            let index = match names.iter().position(|n| *n == name) {
+                Some(index) => index,
+                None => {
+                    names.push(name);
+                    names.len() - 1
+                }
+            };
+            if seen.contains(&index) {
+                return None;
+            }
+            seen.push(index);
+            if let Some(previous) = previous {
+                edges.insert((previous, index));
+            }
+            previous = Some(index);
+        }
+    }
+
+    // Topological sort, preferring the order of first appearance
+    let mut order = Vec::new();
+    let mut placed = vec![false; names.len()];
+    while order.len() < names.len() {
+        let next = (0..names.len())
+            .find(|&i| !placed[i] && edges.iter().all(|&(from, to)| to != i || placed[from]))?;
+        placed[next] = true;
+        order.push(names[next].to_string());
+    }
+    Some(order)
+}
+
+fn occurrence_suffix(element: &ElementReport) -> &'static str {
+    match (element.min_occurs, element.max_occurs) {
+        (0, max) if max > 1 => "*",
+        (0, _) => "?",
+        (_, max) if max > 1 => "+",
+        _ => "",
+    }
+}
+
+fn xsd_type(value_type: ColumnType) -> &'static str {
+    match value_type {
+        ColumnType::Integer => "xs:integer",
+        ColumnType::Real => "xs:decimal",
+        ColumnType::Date => "xs:date",
+        ColumnType::Text => "xs:string",
+    }
+}
+
+/// Summarizes the structure of a loaded document, or `None` if it does not
+/// exist or is empty.
+pub fn infer_schema(db: *mut sqlite3, document_id: i64) -> Result<Option<SchemaReport>, String> {
+    Ok(StoredTree::load_document(db, document_id)?.map(|tree| SchemaReport::from_tree(&tree)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::StoredNode;
+
+    fn tree(spec: &[(&str, Option<&str>, Vec<usize>)]) -> StoredTree {
+        StoredTree {
+            nodes: spec
+                .iter()
+                .enumerate()
+                .map(|(i, (tag, text, children))| StoredNode {
+                    id: i as i64 + 1,
+                    tag_name: tag.to_string(),
+                    text_content: text.map(str::to_string),
+                    attributes: Vec::new(),
+                    children: children.clone(),
+                    ..StoredNode::default()
+                })
+                .collect(),
+        }
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_child_order() {
+        let instances = vec![names(&["a", "c"]), names(&["a", "b", "b", "c"])];
+        assert_eq!(child_order(&instances), Some(names(&["a", "b", "c"])));
+        assert_eq!(child_order(&[names(&["a", "b", "a"])]), None);
+        assert_eq!(child_order(&[names(&["a", "b"]), names(&["b", "a"])]), None);
+    }
+
+    #[test]
+    fn test_report_and_skeletons() {
+        // <list><item id="1">x</item><item>2</item><note/></list>
+        let mut t = tree(&[
+            ("list", None, vec![1, 2, 3]),
+            ("item", Some("x"), vec![]),
+            ("item", Some("2"), vec![]),
+            ("note", None, vec![]),
+        ]);
+        t.nodes[1]
+            .attributes
+            .push(("id".to_string(), "1".to_string()));
+        let report = SchemaReport::from_tree(&t);
+        let item = &report.elements[1];
+        assert_eq!(item.path, "/list/item");
+        assert_eq!((item.count, item.min_occurs, item.max_occurs), (2, 2, 2));
+        assert!(!item.attributes[0].required);
+        assert_eq!(item.attributes[0].values.value_type, ColumnType::Integer);
+        assert_eq!(item.text.as_ref().unwrap().samples, names(&["x", "2"]));
+        assert_eq!(report.elements[0].child_sequences[0].sequence, "item+ note");
+
+        let dtd = report.to_dtd();
+        assert!(dtd.contains("<!ELEMENT list (item+, note)>"));
+        assert!(dtd.contains("<!ELEMENT item (#PCDATA)>"));
+        assert!(dtd.contains("<!ATTLIST item\n    id CDATA #IMPLIED>"));
+        assert!(dtd.contains("<!ELEMENT note EMPTY>"));
+
+        let xsd = report.to_xsd();
+        assert!(xsd.contains("<xs:element name=\"item\" maxOccurs=\"unbounded\">"));
+        assert!(xsd.contains("<xs:extension base=\"xs:string\">"));
+        assert!(xsd.contains("<xs:attribute name=\"id\" type=\"xs:integer\"/>"));
+    }
+}
@@ -1,28 +1,50 @@
 use serde::{Deserialize, Serialize};
 use sqlite_wasm_rs::{
-    sqlite3, sqlite3_close, sqlite3_errmsg, sqlite3_exec, sqlite3_free, sqlite3_open_v2, SQLITE_OK,
+    sqlite3, sqlite3_close, sqlite3_errmsg, sqlite3_free, sqlite3_open_v2, SQLITE_OK,
     SQLITE_OPEN_CREATE, SQLITE_OPEN_MEMORY, SQLITE_OPEN_READWRITE,
 };
 use sqlite_wasm_rs::{
-    sqlite3_bind_int64, sqlite3_bind_text, sqlite3_column_count, sqlite3_column_name,
-    sqlite3_column_text, sqlite3_column_type, sqlite3_finalize, sqlite3_last_insert_rowid,
+    sqlite3_bind_int64, sqlite3_bind_text, sqlite3_finalize, sqlite3_last_insert_rowid,
     sqlite3_prepare_v2, sqlite3_step,
 };
 use sqlite_wasm_rs::{
-    sqlite3_deserialize, sqlite3_malloc, sqlite3_serialize, SQLITE_BLOB,
-    SQLITE_DESERIALIZE_FREEONCLOSE, SQLITE_DESERIALIZE_RESIZEABLE, SQLITE_DONE, SQLITE_FLOAT,
-    SQLITE_INTEGER, SQLITE_NULL, SQLITE_ROW, SQLITE_TEXT,
+    sqlite3_deserialize, sqlite3_malloc, sqlite3_serialize, SQLITE_DESERIALIZE_FREEONCLOSE,
+    SQLITE_DESERIALIZE_RESIZEABLE, SQLITE_DONE,
 };
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::ptr;
 use wasm_bindgen::prelude::*;
 
+mod archive;
+mod c14n;
+mod database;
+mod diff;
+mod dtd;
+mod duplicates;
+mod extract;
+mod functions;
+mod infer;
+mod locator;
+mod materialize;
 mod parser;
+mod reload;
+mod rules;
 mod selector;
-
-use parser::{parse_html_to_nodes, parse_xml_to_nodes};
-use selector::css_to_sql;
+#[cfg(test)]
+mod testing;
+mod tree;
+mod vtab;
+mod xpath;
+mod xsd;
+
+use database::{init_schema, DocumentKind, SegmentKind};
+use parser::{
+    parse_html_to_nodes, parse_json_to_nodes, parse_xml_to_nodes, parse_xml_to_nodes_with_options,
+    XmlOptions,
+};
+use selector::{css_to_sql, css_to_sql_scoped, DocumentFilter, SelectorScope};
+use xpath::xpath_to_sql;
 
 // Use wee_alloc as the global allocator for smaller WASM binary
 #[global_allocator]
@@ -43,9 +65,51 @@ pub struct NodeData {
     pub id: i64,
     pub tag_name: String,
     pub text_content: Option<String>,
+    /// By name as written, `prefix:local` for prefixed attributes.
     pub attributes: HashMap<String, String>,
     pub parent_id: Option<i64>,
     pub depth: i32,
+    /// 1-based position of the start tag, when the parser reports it.
+    pub source_line: Option<u32>,
+    pub source_column: Option<u32>,
+    /// Hash of the element's whole subtree; identical subtrees share it.
+    #[serde(default)]
+    pub content_hash: String,
+    /// Number of elements in the subtree, counting the element itself.
+    #[serde(default)]
+    pub subtree_size: u32,
+    /// 1-based index among the element's siblings.
+    #[serde(default)]
+    pub position: u32,
+    /// Namespace prefix of the tag as written; `tag_name` is the local name.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Namespace declarations made on this element as (prefix, URI), with
+    /// `""` for the default namespace and `("", "")` for `xmlns=""`.
+    #[serde(default)]
+    pub namespaces: Vec<(String, String)>,
+    /// Prefixes of prefixed attributes, by name as in `attributes`.
+    #[serde(default)]
+    pub attribute_prefixes: HashMap<String, String>,
+    /// Text, comments and processing instructions among the children, as
+    /// written. Unlike `text_content` they are not part of the hash.
+    #[serde(default)]
+    pub segments: Vec<Segment>,
+    /// Comments and processing instructions outside the root element, kept
+    /// on the first root.
+    #[serde(default)]
+    pub document_segments: Vec<Segment>,
+}
+
+/// A run of character data, a comment or a processing instruction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    /// Number of sibling elements before it.
+    pub slot: u32,
+    pub kind: SegmentKind,
+    /// Target of a processing instruction.
+    pub target: Option<String>,
+    pub value: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -80,7 +144,7 @@ impl XmlSqlDb {
         }
 
         // Init schema
-        if let Err(e) = init_schema_ffi(db) {
+        if let Err(e) = init_schema(db) {
             unsafe { sqlite3_close(db) };
             return Err(JsValue::from_str(&format!(
                 "Failed to initialize schema: {}",
@@ -88,6 +152,14 @@ impl XmlSqlDb {
             )));
         }
 
+        if let Err(e) = register_extensions(db) {
+            unsafe { sqlite3_close(db) };
+            return Err(JsValue::from_str(&format!(
+                "Failed to register SQL functions: {}",
+                e
+            )));
+        }
+
         console_log!("Database initialized successfully");
 
         Ok(XmlSqlDb { db })
@@ -98,8 +170,103 @@ impl XmlSqlDb {
         console_log!("Loading XML document: {}", document_name);
         let nodes = parse_xml_to_nodes(content)
             .map_err(|e| JsValue::from_str(&format!("XML parsing failed: {}", e)))?;
-        self.insert_document(document_name, &nodes)
-            .map_err(|e| JsValue::from_str(&format!("Database insertion failed: {}", e)))
+        self.insert_xml_document(content, document_name, &nodes)
+    }
+
+    /// Loads XML with DTD handling options: `{allow_dtd,
+    /// max_entity_expansion, apply_default_attributes, validate_dtd}`.
+    /// Missing fields keep the `load_xml` defaults.
+    #[wasm_bindgen]
+    pub fn load_xml_with_options(
+        &mut self,
+        content: &str,
+        document_name: &str,
+        options: JsValue,
+    ) -> Result<u64, JsValue> {
+        let options: XmlOptions = if options.is_undefined() || options.is_null() {
+            XmlOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)
+                .map_err(|e| JsValue::from_str(&format!("Invalid options: {}", e)))?
+        };
+        console_log!("Loading XML document: {}", document_name);
+        let nodes = parse_xml_to_nodes_with_options(content, &options)
+            .map_err(|e| JsValue::from_str(&format!("XML parsing failed: {}", e)))?;
+        self.insert_xml_document(content, document_name, &nodes)
+    }
+
+    /// Loads `content` under `name`, which may already be loaded. `mode` is
+    /// `append` (always a new document), `replace` (same document id, all
+    /// nodes rewritten) or `merge` (same document id, only changed subtrees
+    /// rewritten so unchanged nodes keep their ids). `.html`/`.htm` names
+    /// are parsed as HTML and `.json` names as JSON. Returns the row counts
+    /// of the reload.
+    #[wasm_bindgen]
+    pub fn load_or_replace(
+        &mut self,
+        name: &str,
+        content: &str,
+        mode: &str,
+    ) -> Result<JsValue, JsValue> {
+        let mode = reload::ReloadMode::parse(mode).map_err(|e| JsValue::from_str(&e))?;
+        let kind = DocumentKind::from_name(name);
+        let nodes = match kind {
+            DocumentKind::Xml => parse_xml_to_nodes(content)
+                .map_err(|e| JsValue::from_str(&format!("XML parsing failed: {}", e)))?,
+            DocumentKind::Html => parse_html_to_nodes(content)
+                .map_err(|e| JsValue::from_str(&format!("HTML parsing failed: {}", e)))?,
+            DocumentKind::Json => parse_json_to_nodes(content)
+                .map_err(|e| JsValue::from_str(&format!("JSON parsing failed: {}", e)))?,
+        };
+        let doctype = match kind {
+            DocumentKind::Json => None,
+            _ => dtd::parse_doctype(content).ok().flatten(),
+        };
+        let doctype = doctype.as_ref().map(|d| d.declaration.as_str());
+
+        let existing = reload::find_document(self.db, name).map_err(|e| JsValue::from_str(&e))?;
+        // One savepoint around the reload and the table refresh, so a
+        // failed refresh does not leave the new content committed
+        self.exec_internal("SAVEPOINT load_or_replace")
+            .map_err(|e| JsValue::from_str(&e))?;
+        let summary = match (mode, existing) {
+            (reload::ReloadMode::Replace, Some(document_id)) => {
+                reload::replace_document(self.db, document_id, kind, doctype, &nodes)
+            }
+            (reload::ReloadMode::Merge, Some(document_id)) => {
+                reload::merge_document(self.db, document_id, kind, doctype, &nodes)
+            }
+            _ => self
+                .insert_document(name, kind, doctype, &nodes)
+                .map(|document_id| reload::ReloadSummary {
+                    document_id: document_id as i64,
+                    mode,
+                    kept: 0,
+                    updated: 0,
+                    inserted: nodes.len(),
+                    deleted: 0,
+                }),
+        }
+        .map_err(|e| format!("Database update failed: {}", e))
+        .and_then(|summary| {
+            if existing.is_some() && mode != reload::ReloadMode::Append {
+                materialize::refresh_tables(self.db, summary.document_id)?;
+            }
+            Ok(summary)
+        });
+        let summary = match summary {
+            Ok(summary) => {
+                self.exec_internal("RELEASE load_or_replace")
+                    .map_err(|e| JsValue::from_str(&e))?;
+                summary
+            }
+            Err(e) => {
+                let _ = self.exec_internal("ROLLBACK TO load_or_replace; RELEASE load_or_replace");
+                return Err(JsValue::from_str(&e));
+            }
+        };
+        serde_wasm_bindgen::to_value(&summary)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
     }
 
     #[wasm_bindgen]
@@ -107,10 +274,44 @@ impl XmlSqlDb {
         console_log!("Loading HTML document: {}", document_name);
         let nodes = parse_html_to_nodes(content)
             .map_err(|e| JsValue::from_str(&format!("HTML parsing failed: {}", e)))?;
-        self.insert_document(document_name, &nodes)
+        // html5ever drops the doctype; a malformed one is just not recorded
+        let doctype = dtd::parse_doctype(content).ok().flatten();
+        self.insert_document(
+            document_name,
+            DocumentKind::Html,
+            doctype.as_ref().map(|d| d.declaration.as_str()),
+            &nodes,
+        )
+        .map_err(|e| JsValue::from_str(&format!("Database insertion failed: {}", e)))
+    }
+
+    /// Loads JSON as elements: the top-level value is `<json>`, object
+    /// members are elements named by key, array entries are `<item>`, and
+    /// scalar members become attributes, so `items > item[type="book"]`
+    /// works on `{"items": [{"type": "book"}]}`. The reserved `json-type`
+    /// and `json-types` attributes keep the JSON types that text loses.
+    #[wasm_bindgen]
+    pub fn load_json(&mut self, content: &str, document_name: &str) -> Result<u64, JsValue> {
+        console_log!("Loading JSON document: {}", document_name);
+        let nodes = parse_json_to_nodes(content)
+            .map_err(|e| JsValue::from_str(&format!("JSON parsing failed: {}", e)))?;
+        self.insert_document(document_name, DocumentKind::Json, None, &nodes)
             .map_err(|e| JsValue::from_str(&format!("Database insertion failed: {}", e)))
     }
 
+    /// Loads a zip container (.docx, .xlsx, .pptx, .odt, .epub, ...): each
+    /// XML or XHTML part becomes a document named `name/part/path`, linked
+    /// to one `containers` row. Returns the loaded and skipped parts.
+    #[wasm_bindgen]
+    pub fn load_archive(&mut self, bytes: &[u8], name: &str) -> Result<JsValue, JsValue> {
+        console_log!("Loading archive: {}", name);
+        let summary = self
+            .insert_archive(bytes, name)
+            .map_err(|e| JsValue::from_str(&format!("Archive loading failed: {}", e)))?;
+        serde_wasm_bindgen::to_value(&summary)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
+
     #[wasm_bindgen]
     pub fn query_selector(&self, selector: &str) -> Result<JsValue, JsValue> {
         console_log!("Executing CSS selector: {}", selector);
@@ -120,89 +321,208 @@ impl XmlSqlDb {
         self.execute_sql(&sql)
     }
 
+    /// `querySelectorAll` on a stored node: only its descendants can match,
+    /// and `:scope` (or a leading combinator, as in `> li`) refers to it.
     #[wasm_bindgen]
-    pub fn execute_sql(&self, sql: &str) -> Result<JsValue, JsValue> {
-        console_log!("Executing SQL: {}", sql);
+    pub fn query_selector_within(&self, node_id: i64, selector: &str) -> Result<JsValue, JsValue> {
+        let scope = SelectorScope {
+            context_node: Some(node_id),
+            ..SelectorScope::default()
+        };
+        self.query_selector_scoped(selector, &scope)
+    }
 
-        let mut stmt = ptr::null_mut();
-        let c_sql = CString::new(sql).map_err(|_| JsValue::from_str("Invalid SQL string"))?;
+    #[wasm_bindgen]
+    pub fn query_selector_in_document(
+        &self,
+        document_id: i64,
+        selector: &str,
+    ) -> Result<JsValue, JsValue> {
+        let scope = SelectorScope {
+            document: Some(DocumentFilter::Id(document_id)),
+            ..SelectorScope::default()
+        };
+        self.query_selector_scoped(selector, &scope)
+    }
 
-        let ret =
-            unsafe { sqlite3_prepare_v2(self.db, c_sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) };
+    /// Runs `selector` against documents whose name matches a glob such as
+    /// `books-*.xml`.
+    #[wasm_bindgen]
+    pub fn query_selector_in_documents(
+        &self,
+        name_glob: &str,
+        selector: &str,
+    ) -> Result<JsValue, JsValue> {
+        let scope = SelectorScope {
+            document: Some(DocumentFilter::NameGlob(name_glob.to_string())),
+            ..SelectorScope::default()
+        };
+        self.query_selector_scoped(selector, &scope)
+    }
 
-        if ret != SQLITE_OK {
-            let err_msg = unsafe {
-                let c_str = sqlite3_errmsg(self.db);
-                std::ffi::CStr::from_ptr(c_str)
-                    .to_string_lossy()
-                    .into_owned()
-            };
-            return Err(JsValue::from_str(&format!(
-                "SQL preparation failed: {}",
-                err_msg
-            )));
-        }
+    /// Evaluates `[{selector, name}, ...]` against a document and returns,
+    /// per matched node, the applicable rules sorted by specificity.
+    #[wasm_bindgen]
+    pub fn match_rules(&self, document_id: i64, rules: JsValue) -> Result<JsValue, JsValue> {
+        let rules: Vec<rules::Rule> = serde_wasm_bindgen::from_value(rules)
+            .map_err(|e| JsValue::from_str(&format!("Invalid rules: {}", e)))?;
+        let matches =
+            rules::match_rules(self.db, document_id, &rules).map_err(|e| JsValue::from_str(&e))?;
+        serde_wasm_bindgen::to_value(&matches)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
 
-        let mut column_names = Vec::new();
-        let col_count = unsafe { sqlite3_column_count(stmt) };
+    /// Runs a JSON extraction schema (`{root, fields}`) against a document
+    /// and returns an array of records.
+    #[wasm_bindgen]
+    pub fn extract(&self, document_id: i64, schema: JsValue) -> Result<JsValue, JsValue> {
+        let schema: extract::Schema = serde_wasm_bindgen::from_value(schema)
+            .map_err(|e| JsValue::from_str(&format!("Invalid schema: {}", e)))?;
+        let records =
+            extract::extract(self.db, document_id, &schema).map_err(|e| JsValue::from_str(&e))?;
+        records
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
 
-        for i in 0..col_count {
-            let name = unsafe {
-                let c_name = sqlite3_column_name(stmt, i);
-                std::ffi::CStr::from_ptr(c_name)
-                    .to_string_lossy()
-                    .into_owned()
-            };
-            column_names.push(name);
-        }
+    /// Creates (or rebuilds) a SQLite table with one row per record matched
+    /// by `record_selector`, typed columns for its child elements and
+    /// attributes, and returns the table's shape. With `keep_refreshed` the
+    /// table is rebuilt whenever a document with the same name is loaded.
+    #[wasm_bindgen]
+    pub fn materialize_table(
+        &self,
+        document_id: i64,
+        record_selector: &str,
+        table_name: &str,
+        keep_refreshed: bool,
+    ) -> Result<JsValue, JsValue> {
+        let table = materialize::materialize_table(
+            self.db,
+            document_id,
+            record_selector,
+            table_name,
+            keep_refreshed,
+        )
+        .map_err(|e| JsValue::from_str(&e))?;
+        serde_wasm_bindgen::to_value(&table)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
 
-        let mut rows = Vec::new();
-
-        loop {
-            let step = unsafe { sqlite3_step(stmt) };
-            if step == SQLITE_ROW {
-                let mut row_data = Vec::new();
-                for i in 0..col_count {
-                    let val = unsafe {
-                        let col_type = sqlite3_column_type(stmt, i);
-                        match col_type {
-                            SQLITE_INTEGER => {
-                                serde_json::json!(sqlite3_column_int64(stmt, i))
-                            }
-                            SQLITE_FLOAT => {
-                                serde_json::json!(sqlite3_column_double(stmt, i))
-                            }
-                            SQLITE_TEXT => {
-                                let text = sqlite3_column_text(stmt, i);
-                                if text.is_null() {
-                                    serde_json::json!("")
-                                } else {
-                                    let s = std::ffi::CStr::from_ptr(text as *const i8)
-                                        .to_string_lossy();
-                                    serde_json::json!(s)
-                                }
-                            }
-                            SQLITE_NULL => serde_json::json!(""),
-                            _ => serde_json::json!(""), // Handle BLOBs if needed
-                        }
-                    };
-                    row_data.push(val);
-                }
-                rows.push(row_data);
-            } else if step == SQLITE_DONE {
-                break;
-            } else {
-                unsafe { sqlite3_finalize(stmt) };
-                return Err(JsValue::from_str("Error during query execution"));
-            }
+    /// Summarizes a document's structure: per element path, counts,
+    /// occurrences per parent, child sequences, attributes and text samples.
+    #[wasm_bindgen]
+    pub fn infer_schema(&self, document_id: i64) -> Result<JsValue, JsValue> {
+        let report = infer::infer_schema(self.db, document_id)
+            .map_err(|e| JsValue::from_str(&e))?
+            .ok_or_else(|| JsValue::from_str(&format!("Document {} is empty", document_id)))?;
+        serde_wasm_bindgen::to_value(&report)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
+
+    /// A `"dtd"` or `"xsd"` skeleton inferred from a document.
+    #[wasm_bindgen]
+    pub fn schema_skeleton(&self, document_id: i64, format: &str) -> Result<String, JsValue> {
+        let report = infer::infer_schema(self.db, document_id)
+            .map_err(|e| JsValue::from_str(&e))?
+            .ok_or_else(|| JsValue::from_str(&format!("Document {} is empty", document_id)))?;
+        match format {
+            "dtd" => Ok(report.to_dtd()),
+            "xsd" => Ok(report.to_xsd()),
+            _ => Err(JsValue::from_str(&format!(
+                "Unknown schema format '{}': expected 'dtd' or 'xsd'",
+                format
+            ))),
         }
+    }
 
-        unsafe { sqlite3_finalize(stmt) };
+    /// Validates a document against an XML Schema (common subset). Returns
+    /// the violations with node ids and source positions; with `store` they
+    /// also replace the document's rows in `validation_errors`.
+    #[wasm_bindgen]
+    pub fn validate(&self, document_id: i64, xsd: &str, store: bool) -> Result<JsValue, JsValue> {
+        let violations =
+            xsd::validate(self.db, document_id, xsd, store).map_err(|e| JsValue::from_str(&e))?;
+        serde_wasm_bindgen::to_value(&violations)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
 
-        let result = QueryResult {
-            columns: column_names,
-            rows,
+    /// Structural diff from one stored document to another: inserted,
+    /// deleted, moved and updated nodes plus attribute changes. With `store`
+    /// the changes also replace the pair's rows in `diffs`.
+    #[wasm_bindgen]
+    pub fn diff_documents(
+        &self,
+        from_document: i64,
+        to_document: i64,
+        store: bool,
+    ) -> Result<JsValue, JsValue> {
+        let report = diff::diff_documents(self.db, from_document, to_document, store)
+            .map_err(|e| JsValue::from_str(&e))?;
+        serde_wasm_bindgen::to_value(&report)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
+
+    /// Canonical XML of the subtree at `node_id`, e.g. for signing or
+    /// hashing. `options` is `{ exclusive, with_comments }`, both false by
+    /// default; pass a document's `root_node_id` for the whole document.
+    #[wasm_bindgen]
+    pub fn canonicalize(&self, node_id: i64, options: JsValue) -> Result<String, JsValue> {
+        let options: c14n::C14nOptions = if options.is_undefined() || options.is_null() {
+            c14n::C14nOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)
+                .map_err(|e| JsValue::from_str(&format!("Invalid options: {}", e)))?
         };
+        c14n::canonicalize(self.db, node_id, &options)
+            .map_err(|e| JsValue::from_str(&e))?
+            .ok_or_else(|| JsValue::from_str(&format!("Node {} not found", node_id)))
+    }
+
+    /// Subtrees of at least `min_size` elements repeated within or across
+    /// documents, largest first, each with the nodes where it occurs.
+    #[wasm_bindgen]
+    pub fn find_duplicates(&self, min_size: i64) -> Result<JsValue, JsValue> {
+        let groups =
+            duplicates::find_duplicates(self.db, min_size).map_err(|e| JsValue::from_str(&e))?;
+        serde_wasm_bindgen::to_value(&groups)
+            .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
+    }
+
+    /// A short CSS selector that matches only `node_id` within its document,
+    /// like devtools "Copy selector".
+    #[wasm_bindgen]
+    pub fn selector_for_node(&self, node_id: i64) -> Result<Option<String>, JsValue> {
+        locator::selector_for_node(self.db, node_id).map_err(|e| JsValue::from_str(&e))
+    }
+
+    /// A short XPath that matches only `node_id` within its document.
+    #[wasm_bindgen]
+    pub fn xpath_for_node(&self, node_id: i64) -> Result<Option<String>, JsValue> {
+        locator::xpath_for_node(self.db, node_id).map_err(|e| JsValue::from_str(&e))
+    }
+
+    #[wasm_bindgen]
+    pub fn query_xpath(&self, expr: &str) -> Result<JsValue, JsValue> {
+        console_log!("Executing XPath: {}", expr);
+        let sql = xpath_to_sql(expr)
+            .map_err(|e| JsValue::from_str(&format!("XPath parsing failed: {}", e)))?;
+        console_log!("Generated SQL: {}", sql);
+        self.execute_sql(&sql)
+    }
+
+    #[wasm_bindgen]
+    pub fn execute_sql(&self, sql: &str) -> Result<JsValue, JsValue> {
+        console_log!("Executing SQL: {}", sql);
+
+        let mut result = database::query(self.db, sql, &[]).map_err(|e| JsValue::from_str(&e))?;
+
+        // NULLs have always been reported to the UI as empty strings
+        for value in result.rows.iter_mut().flatten() {
+            if value.is_null() {
+                *value = serde_json::json!("");
+            }
+        }
 
         serde_wasm_bindgen::to_value(&result)
             .map_err(|e| JsValue::from_str(&format!("Serialization failed: {}", e)))
@@ -292,6 +612,22 @@ impl XmlSqlDb {
             )));
         }
 
+        if let Err(e) = init_schema(new_db) {
+            unsafe { sqlite3_close(new_db) };
+            return Err(JsValue::from_str(&format!(
+                "Failed to upgrade database schema: {}",
+                e
+            )));
+        }
+
+        if let Err(e) = register_extensions(new_db) {
+            unsafe { sqlite3_close(new_db) };
+            return Err(JsValue::from_str(&format!(
+                "Failed to register SQL functions: {}",
+                e
+            )));
+        }
+
         // 4. Close OLD connection and Swap
         unsafe { sqlite3_close(self.db) };
         self.db = new_db;
@@ -303,19 +639,55 @@ impl XmlSqlDb {
 
     #[wasm_bindgen]
     pub fn get_documents(&self) -> Result<JsValue, JsValue> {
-        let sql = "SELECT id, name, created_at FROM documents ORDER BY created_at DESC";
+        let sql =
+            "SELECT id, name, created_at, kind, doctype FROM documents ORDER BY created_at DESC";
         self.execute_sql(sql)
     }
 
-    fn insert_document(&self, name: &str, nodes: &[NodeData]) -> Result<u64, String> {
-        // NOTE: A full transaction wrapper would be better, but doing simple EXEC for BEGIN/COMMIT here
+    fn query_selector_scoped(
+        &self,
+        selector: &str,
+        scope: &SelectorScope,
+    ) -> Result<JsValue, JsValue> {
+        console_log!("Executing CSS selector: {}", selector);
+        let sql = css_to_sql_scoped(selector, scope)
+            .map_err(|e| JsValue::from_str(&format!("Selector parsing failed: {}", e)))?;
+        console_log!("Generated SQL: {}", sql);
+        self.execute_sql(&sql)
+    }
 
-        self.exec_internal("BEGIN TRANSACTION")?;
+    /// Stores parsed XML along with the DOCTYPE declaration of its source.
+    fn insert_xml_document(
+        &self,
+        content: &str,
+        document_name: &str,
+        nodes: &[NodeData],
+    ) -> Result<u64, JsValue> {
+        let doctype = dtd::parse_doctype(content).ok().flatten();
+        self.insert_document(
+            document_name,
+            DocumentKind::Xml,
+            doctype.as_ref().map(|d| d.declaration.as_str()),
+            nodes,
+        )
+        .map_err(|e| JsValue::from_str(&format!("Database insertion failed: {}", e)))
+    }
 
-        let doc_id = match self.insert_doc_record(name) {
+    fn insert_document(
+        &self,
+        name: &str,
+        kind: DocumentKind,
+        doctype: Option<&str>,
+        nodes: &[NodeData],
+    ) -> Result<u64, String> {
+        // A savepoint rather than BEGIN, so archives can load several
+        // documents inside one outer savepoint
+        self.exec_internal("SAVEPOINT insert_document")?;
+
+        let doc_id = match self.insert_doc_record(name, kind, doctype) {
             Ok(id) => id,
             Err(e) => {
-                let _ = self.exec_internal("ROLLBACK");
+                let _ = self.exec_internal("ROLLBACK TO insert_document; RELEASE insert_document");
                 return Err(e);
             }
         };
@@ -323,16 +695,17 @@ impl XmlSqlDb {
         // Map from parser local ID to database global ID
         let mut id_map: HashMap<i64, i64> = HashMap::new();
 
-        for node in nodes {
+        for (index, node) in nodes.iter().enumerate() {
             // Resolve parent ID using the map
             let db_parent_id = node.parent_id.and_then(|pid| id_map.get(&pid).copied());
 
-            match self.insert_node_record(doc_id, node, db_parent_id) {
+            match self.insert_node_record(doc_id, node, db_parent_id, index as i64 + 1) {
                 Ok(new_id) => {
                     id_map.insert(node.id, new_id);
                 }
                 Err(e) => {
-                    let _ = self.exec_internal("ROLLBACK");
+                    let _ =
+                        self.exec_internal("ROLLBACK TO insert_document; RELEASE insert_document");
                     return Err(e);
                 }
             }
@@ -345,42 +718,91 @@ impl XmlSqlDb {
                     "UPDATE documents SET root_node_id = {} WHERE id = {}",
                     root_db_id, doc_id
                 );
-                if let Err(e) = self.exec_internal(&sql) {
-                    let _ = self.exec_internal("ROLLBACK");
+                if let Err(e) = self.exec_internal(&sql).and_then(|_| {
+                    tree::insert_segments(self.db, doc_id, None, &root.document_segments)
+                }) {
+                    let _ =
+                        self.exec_internal("ROLLBACK TO insert_document; RELEASE insert_document");
                     return Err(e);
                 }
             }
         }
 
-        self.exec_internal("COMMIT")?;
+        // Inside the savepoint, so a failed refresh leaves no document
+        // behind and the load can simply be retried
+        if let Err(e) = materialize::refresh_tables(self.db, doc_id) {
+            let _ = self.exec_internal("ROLLBACK TO insert_document; RELEASE insert_document");
+            return Err(e);
+        }
+        self.exec_internal("RELEASE insert_document")?;
         Ok(doc_id as u64)
     }
 
-    fn exec_internal(&self, sql: &str) -> Result<(), String> {
-        let c_sql = CString::new(sql).unwrap();
-        let mut err_msg = ptr::null_mut();
-        let ret =
-            unsafe { sqlite3_exec(self.db, c_sql.as_ptr(), None, ptr::null_mut(), &mut err_msg) };
-
-        if ret != SQLITE_OK {
-            let msg = unsafe {
-                if !err_msg.is_null() {
-                    let s = std::ffi::CStr::from_ptr(err_msg)
-                        .to_string_lossy()
-                        .into_owned();
-                    sqlite3_free(err_msg as *mut _);
-                    s
-                } else {
-                    "Unknown error".to_string()
-                }
+    /// Loads every markup part of a zip container in one savepoint, so a
+    /// failure leaves no partial container behind.
+    fn insert_archive(&self, bytes: &[u8], name: &str) -> Result<archive::ArchiveSummary, String> {
+        let zip = archive::ZipArchive::parse(bytes)?;
+        let parts = archive::markup_parts(&zip)?;
+        let format = archive::container_format(name);
+
+        self.exec_internal("SAVEPOINT load_archive")?;
+        let result = (|| {
+            let mut summary = archive::ArchiveSummary {
+                container_id: archive::insert_container(self.db, name, format)?,
+                format: format.to_string(),
+                parts: Vec::new(),
+                skipped: Vec::new(),
             };
-            return Err(msg);
+            for (entry, kind) in parts {
+                let data = zip.read(entry)?;
+                let part = match archive::parse_part(&entry.path, kind, &data) {
+                    Ok(part) => part,
+                    Err(reason) => {
+                        summary.skipped.push(archive::SkippedPart {
+                            path: entry.path.clone(),
+                            reason,
+                        });
+                        continue;
+                    }
+                };
+                let document_id = self.insert_document(
+                    &format!("{}/{}", name, entry.path),
+                    part.kind,
+                    part.doctype.as_deref(),
+                    &part.nodes,
+                )? as i64;
+                archive::link_part(self.db, document_id, summary.container_id, &entry.path)?;
+                summary.parts.push(archive::ArchivePart {
+                    document_id,
+                    path: entry.path.clone(),
+                });
+            }
+            Ok(summary)
+        })();
+        match result {
+            Ok(summary) => {
+                self.exec_internal("RELEASE load_archive")?;
+                Ok(summary)
+            }
+            Err(e) => {
+                let _ = self.exec_internal("ROLLBACK TO load_archive; RELEASE load_archive");
+                Err(e)
+            }
         }
-        Ok(())
     }
 
-    fn insert_doc_record(&self, name: &str) -> Result<i64, String> {
-        let sql = "INSERT INTO documents (name, root_node_id) VALUES (?, NULL)";
+    fn exec_internal(&self, sql: &str) -> Result<(), String> {
+        database::exec(self.db, sql)
+    }
+
+    fn insert_doc_record(
+        &self,
+        name: &str,
+        kind: DocumentKind,
+        doctype: Option<&str>,
+    ) -> Result<i64, String> {
+        let sql =
+            "INSERT INTO documents (name, root_node_id, kind, doctype) VALUES (?, NULL, ?, ?)";
         let mut stmt = ptr::null_mut();
         let c_sql = CString::new(sql).unwrap();
 
@@ -393,6 +815,13 @@ impl XmlSqlDb {
             // Bind name (index 1)
             let c_name = CString::new(name).unwrap();
             sqlite3_bind_text(stmt, 1, c_name.as_ptr(), -1, None);
+            let c_kind = CString::new(kind.as_str()).unwrap();
+            sqlite3_bind_text(stmt, 2, c_kind.as_ptr(), -1, None);
+            let c_doctype = doctype.map(|d| CString::new(d).unwrap_or_default());
+            match &c_doctype {
+                Some(d) => sqlite3_bind_text(stmt, 3, d.as_ptr(), -1, None),
+                None => sqlite_wasm_rs::sqlite3_bind_null(stmt, 3),
+            };
 
             if sqlite3_step(stmt) != SQLITE_DONE {
                 sqlite3_finalize(stmt);
@@ -409,9 +838,10 @@ impl XmlSqlDb {
         doc_id: i64,
         node: &NodeData,
         db_parent_id: Option<i64>,
+        document_order: i64,
     ) -> Result<i64, String> {
         // Allow ID to be autoincremented (pass NULL for id)
-        let sql = "INSERT INTO nodes (id, document_id, parent_id, tag_name, text_content, depth, position) VALUES (NULL, ?, ?, ?, ?, ?, 0)";
+        let sql = "INSERT INTO nodes (id, document_id, parent_id, tag_name, text_content, depth, source_line, source_column, content_hash, subtree_size, prefix, position, document_order) VALUES (NULL, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
         let mut stmt = ptr::null_mut();
         let c_sql = CString::new(sql).unwrap();
 
@@ -453,6 +883,30 @@ impl XmlSqlDb {
             // Index 5: depth
             sqlite3_bind_int64(stmt, 5, node.depth as i64);
 
+            // Index 6-7: source position
+            for (index, value) in [(6, node.source_line), (7, node.source_column)] {
+                match value {
+                    Some(v) => sqlite3_bind_int64(stmt, index, v as i64),
+                    None => sqlite_wasm_rs::sqlite3_bind_null(stmt, index),
+                };
+            }
+
+            // Index 8-9: content hash and subtree size
+            let c_hash = CString::new(node.content_hash.as_str()).unwrap();
+            sqlite3_bind_text(stmt, 8, c_hash.as_ptr(), -1, None);
+            sqlite3_bind_int64(stmt, 9, node.subtree_size as i64);
+
+            // Index 10: namespace prefix
+            let c_prefix = node.prefix.as_deref().map(|p| CString::new(p).unwrap());
+            match &c_prefix {
+                Some(c) => sqlite3_bind_text(stmt, 10, c.as_ptr(), -1, None),
+                None => sqlite_wasm_rs::sqlite3_bind_null(stmt, 10),
+            };
+
+            // Index 11-12: sibling position and document order
+            sqlite3_bind_int64(stmt, 11, node.position as i64);
+            sqlite3_bind_int64(stmt, 12, document_order);
+
             if sqlite3_step(stmt) != SQLITE_DONE {
                 sqlite3_finalize(stmt);
                 return Err("Failed to insert node".to_string());
@@ -464,14 +918,33 @@ impl XmlSqlDb {
 
         // attributes (use new_id)
         for (k, v) in &node.attributes {
-            self.insert_attribute(new_id, k, v)?;
+            let prefix = node.attribute_prefixes.get(k).map(String::as_str);
+            self.insert_attribute(new_id, tree::local_name(k, prefix), v, prefix)?;
+        }
+        for (prefix, uri) in &node.namespaces {
+            database::query(
+                self.db,
+                "INSERT INTO namespaces (node_id, prefix, uri) VALUES (?1, ?2, ?3)",
+                &[
+                    serde_json::json!(new_id),
+                    serde_json::json!(prefix),
+                    serde_json::json!(uri),
+                ],
+            )?;
         }
+        tree::insert_segments(self.db, doc_id, Some(new_id), &node.segments)?;
 
         Ok(new_id)
     }
 
-    fn insert_attribute(&self, node_id: i64, name: &str, value: &str) -> Result<(), String> {
-        let sql = "INSERT INTO attributes (node_id, name, value) VALUES (?, ?, ?)";
+    fn insert_attribute(
+        &self,
+        node_id: i64,
+        name: &str,
+        value: &str,
+        prefix: Option<&str>,
+    ) -> Result<(), String> {
+        let sql = "INSERT INTO attributes (node_id, name, value, prefix) VALUES (?, ?, ?, ?)";
         let mut stmt = ptr::null_mut();
         let c_sql = CString::new(sql).unwrap();
 
@@ -482,6 +955,11 @@ impl XmlSqlDb {
             sqlite3_bind_text(stmt, 2, c_name.as_ptr(), -1, None);
             let c_val = CString::new(value).unwrap();
             sqlite3_bind_text(stmt, 3, c_val.as_ptr(), -1, None);
+            let c_prefix = prefix.map(|p| CString::new(p).unwrap());
+            match &c_prefix {
+                Some(c) => sqlite3_bind_text(stmt, 4, c.as_ptr(), -1, None),
+                None => sqlite_wasm_rs::sqlite3_bind_null(stmt, 4),
+            };
 
             let ret = sqlite3_step(stmt);
             sqlite3_finalize(stmt);
@@ -493,53 +971,12 @@ impl XmlSqlDb {
     }
 }
 
-// Re-implement init_schema to work with raw db pointer
-fn init_schema_ffi(db: *mut sqlite3) -> Result<(), String> {
-    let schema_sql = "
-    CREATE TABLE IF NOT EXISTS documents (
-        id INTEGER PRIMARY KEY,
-        name TEXT NOT NULL,
-        root_node_id INTEGER,
-        created_at TEXT DEFAULT CURRENT_TIMESTAMP
-    );
-
-    CREATE TABLE IF NOT EXISTS nodes (
-        id INTEGER PRIMARY KEY,
-        document_id INTEGER NOT NULL,
-        parent_id INTEGER,
-        tag_name TEXT NOT NULL,
-        text_content TEXT,
-        depth INTEGER NOT NULL,
-        position INTEGER NOT NULL,
-        FOREIGN KEY (document_id) REFERENCES documents(id),
-        FOREIGN KEY (parent_id) REFERENCES nodes(id)
-    );
-
-    CREATE TABLE IF NOT EXISTS attributes (
-        id INTEGER PRIMARY KEY,
-        node_id INTEGER NOT NULL,
-        name TEXT NOT NULL,
-        value TEXT,
-        FOREIGN KEY (node_id) REFERENCES nodes(id)
-    );
-    ";
-
-    let c_sql = CString::new(schema_sql).unwrap();
-    let mut err_msg = ptr::null_mut();
-
-    let ret = unsafe { sqlite3_exec(db, c_sql.as_ptr(), None, ptr::null_mut(), &mut err_msg) };
-
-    if ret != SQLITE_OK {
-        unsafe { sqlite3_free(err_msg as *mut _) };
-        return Err("Failed to init schema".to_string());
-    }
-    Ok(())
+/// Registers the crate's SQL functions and table-valued functions on a connection.
+fn register_extensions(db: *mut sqlite3) -> Result<(), String> {
+    functions::register_functions(db)?;
+    vtab::register_table_functions(db)
 }
 
-// Additional FFI exports
-use sqlite_wasm_rs::sqlite3_column_double;
-use sqlite_wasm_rs::sqlite3_column_int64;
-
 #[wasm_bindgen(start)]
 pub fn main() {
     console_log!("WASM module loaded successfully");
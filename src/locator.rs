@@ -0,0 +1,339 @@
+//! Reverse lookups: a short CSS selector or XPath that finds one stored node,
+//! like the "Copy selector" command of browser devtools.
+//!
+//! Locators are built from the node upwards and each candidate is checked by
+//! compiling and running it, so a result always matches exactly that node
+//! within its document.
+
+use crate::database::query;
+use crate::selector::{css_to_node_sql, escape_identifier, DocumentFilter, SelectorScope};
+use crate::xpath::{string_literal, xpath_to_sql};
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+
+struct Element {
+    id: i64,
+    parent_id: Option<i64>,
+    document_id: i64,
+    tag_name: String,
+    html_id: Option<String>,
+    classes: Vec<String>,
+}
+
+const ELEMENT_COLUMNS: &str = "n.id, n.parent_id, n.document_id, n.tag_name,
+    (SELECT value FROM attributes WHERE node_id = n.id AND name = 'id'),
+    (SELECT value FROM attributes WHERE node_id = n.id AND name = 'class')";
+
+fn load_elements(db: *mut sqlite3, condition: &str, id: i64) -> Result<Vec<Element>, String> {
+    let result = query(
+        db,
+        &format!(
+            "SELECT {} FROM nodes n WHERE {} ORDER BY n.document_order",
+            ELEMENT_COLUMNS, condition
+        ),
+        &[json!(id)],
+    )?;
+    Ok(result
+        .rows
+        .into_iter()
+        .map(|row| Element {
+            id: row[0].as_i64().unwrap_or_default(),
+            parent_id: row[1].as_i64(),
+            document_id: row[2].as_i64().unwrap_or_default(),
+            tag_name: row[3].as_str().unwrap_or_default().to_string(),
+            html_id: row[4]
+                .as_str()
+                .filter(|id| !id.is_empty())
+                .map(str::to_string),
+            classes: row[5]
+                .as_str()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        })
+        .collect())
+}
+
+fn load_element(db: *mut sqlite3, node_id: i64) -> Result<Option<Element>, String> {
+    Ok(load_elements(db, "n.id = ?1", node_id)?.pop())
+}
+
+/// The element's siblings, itself included, in document order.
+fn siblings(db: *mut sqlite3, element: &Element) -> Result<Vec<Element>, String> {
+    match element.parent_id {
+        Some(parent) => load_elements(db, "n.parent_id = ?1", parent),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Whether `sql` (over `nodes` columns) returns `node_id` and nothing else
+/// from `document_id`.
+fn matches_only(
+    db: *mut sqlite3,
+    sql: &str,
+    document_id: i64,
+    node_id: i64,
+) -> Result<bool, String> {
+    let result = query(
+        db,
+        &format!(
+            "SELECT id FROM ({}) matched WHERE document_id = ?1 LIMIT 2",
+            sql
+        ),
+        &[json!(document_id)],
+    )?;
+    Ok(result.rows.len() == 1 && result.rows[0][0].as_i64() == Some(node_id))
+}
+
+/// Generated class names (`css-1x2y3z`, `ng-tns-c12-3`) change between
+/// builds, so classes containing digits are not used.
+fn is_stable_class(class: &str) -> bool {
+    !class.chars().any(|ch| ch.is_ascii_digit())
+}
+
+/// A compound selector that tells `element` apart from its siblings: the
+/// tag name, then as many stable classes as needed, then `:nth-child`.
+fn css_step(element: &Element, siblings: &[Element]) -> String {
+    let tag = escape_identifier(&element.tag_name);
+    let mut rivals: Vec<&Element> = siblings
+        .iter()
+        .filter(|s| s.id != element.id && s.tag_name == element.tag_name)
+        .collect();
+    if rivals.is_empty() {
+        return tag;
+    }
+
+    let mut step = tag.clone();
+    for class in element.classes.iter().filter(|c| is_stable_class(c)) {
+        if rivals.iter().all(|r| r.classes.contains(class)) {
+            continue;
+        }
+        step.push('.');
+        step.push_str(&escape_identifier(class));
+        rivals.retain(|r| r.classes.contains(class));
+        if rivals.is_empty() {
+            return step;
+        }
+    }
+
+    let position = siblings
+        .iter()
+        .position(|s| s.id == element.id)
+        .unwrap_or(0)
+        + 1;
+    format!("{}:nth-child({})", tag, position)
+}
+
+/// The shortest selector found for `node_id`, or `None` if the node does not
+/// exist. It is unique within the node's document.
+pub fn selector_for_node(db: *mut sqlite3, node_id: i64) -> Result<Option<String>, String> {
+    let Some(mut element) = load_element(db, node_id)? else {
+        return Ok(None);
+    };
+    let document_id = element.document_id;
+    let scope = SelectorScope {
+        document: Some(DocumentFilter::Id(document_id)),
+        ..SelectorScope::default()
+    };
+    let is_unique = |selector: &str| -> Result<bool, String> {
+        let sql = css_to_node_sql(selector, &scope)?;
+        matches_only(db, &sql, document_id, node_id)
+    };
+
+    let mut steps: Vec<String> = Vec::new();
+    loop {
+        if let Some(id) = &element.html_id {
+            let mut candidate = vec![format!("#{}", escape_identifier(id))];
+            candidate.extend(steps.iter().cloned());
+            let candidate = candidate.join(" > ");
+            if is_unique(&candidate)? {
+                return Ok(Some(candidate));
+            }
+        }
+
+        let step = css_step(&element, &siblings(db, &element)?);
+        steps.insert(0, step);
+        let candidate = steps.join(" > ");
+        if is_unique(&candidate)? {
+            return Ok(Some(candidate));
+        }
+
+        match element.parent_id {
+            Some(parent) => {
+                element = load_element(db, parent)?
+                    .ok_or_else(|| format!("Node {} has a missing parent", element.id))?;
+            }
+            None => break,
+        }
+    }
+
+    // Every step is unique among its siblings, so anchoring the chain at the
+    // document root settles it
+    steps[0].push_str(":root");
+    let candidate = steps.join(" > ");
+    if is_unique(&candidate)? {
+        Ok(Some(candidate))
+    } else {
+        Err(format!(
+            "Could not build a unique selector for node {}",
+            node_id
+        ))
+    }
+}
+
+/// The shortest XPath found for `node_id`, or `None` if the node does not
+/// exist: `//*[@id="x"]/...` from the nearest ancestor with a unique id,
+/// otherwise an absolute path. It is unique within the node's document.
+pub fn xpath_for_node(db: *mut sqlite3, node_id: i64) -> Result<Option<String>, String> {
+    let Some(mut element) = load_element(db, node_id)? else {
+        return Ok(None);
+    };
+    let document_id = element.document_id;
+
+    let mut path = String::new();
+    loop {
+        if let Some(id) = &element.html_id {
+            let candidate = format!("//*[@id={}]{}", string_literal(id), path);
+            if matches_only(db, &xpath_to_sql(&candidate)?, document_id, node_id)? {
+                return Ok(Some(candidate));
+            }
+        }
+
+        let siblings = siblings(db, &element)?;
+        let same_tag: Vec<&Element> = siblings
+            .iter()
+            .filter(|s| s.tag_name == element.tag_name)
+            .collect();
+        path = if same_tag.len() > 1 {
+            let index = same_tag
+                .iter()
+                .position(|s| s.id == element.id)
+                .unwrap_or(0)
+                + 1;
+            format!("/{}[{}]{}", name_test(&element.tag_name), index, path)
+        } else {
+            format!("/{}{}", name_test(&element.tag_name), path)
+        };
+
+        match element.parent_id {
+            Some(parent) => {
+                element = load_element(db, parent)?
+                    .ok_or_else(|| format!("Node {} has a missing parent", element.id))?;
+            }
+            None => break,
+        }
+    }
+
+    if matches_only(db, &xpath_to_sql(&path)?, document_id, node_id)? {
+        Ok(Some(path))
+    } else {
+        Err(format!(
+            "Could not build a unique XPath for node {}",
+            node_id
+        ))
+    }
+}
+
+/// A name test for `tag`: the name itself, or `*[local-name()='...']` for
+/// names XPath cannot spell, such as JSON keys with spaces.
+fn name_test(tag: &str) -> String {
+    let mut chars = tag.chars();
+    let is_name = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if is_name {
+        tag.to_string()
+    } else {
+        format!("*[local-name()={}]", string_literal(tag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{load_html, node_id, open};
+
+    /// Every element of `document_id` with its CSS selector and XPath,
+    /// each checked to match that element alone.
+    fn locators(db: *mut sqlite3, document_id: i64) -> Vec<(String, String)> {
+        let ids: Vec<i64> = query(
+            db,
+            "SELECT id FROM nodes WHERE document_id = ?1 ORDER BY document_order",
+            &[json!(document_id)],
+        )
+        .unwrap()
+        .rows
+        .iter()
+        .filter_map(|row| row[0].as_i64())
+        .collect();
+        let scope = SelectorScope {
+            document: Some(DocumentFilter::Id(document_id)),
+            ..SelectorScope::default()
+        };
+        ids.into_iter()
+            .map(|id| {
+                let selector = selector_for_node(db, id).unwrap().unwrap();
+                let sql = css_to_node_sql(&selector, &scope).unwrap();
+                assert!(
+                    matches_only(db, &sql, document_id, id).unwrap(),
+                    "{}",
+                    selector
+                );
+                let xpath = xpath_for_node(db, id).unwrap().unwrap();
+                let sql = xpath_to_sql(&xpath).unwrap();
+                assert!(
+                    matches_only(db, &sql, document_id, id).unwrap(),
+                    "{}",
+                    xpath
+                );
+                (selector, xpath)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_locators_match_one_node() {
+        let db = open();
+        let page = load_html(
+            db,
+            "page.html",
+            r#"<html><body>
+                <div id="main"><p class="lead x1">a</p><p class="lead">b</p><p>c</p></div>
+                <div><span>d</span></div>
+            </body></html>"#,
+        );
+        let found = locators(db, page);
+        assert!(found.contains(&(
+            "p:nth-child(2)".to_string(),
+            "//*[@id=\"main\"]/p[2]".to_string()
+        )));
+        assert!(found.contains(&("span".to_string(), "/html/body/div[2]/span".to_string())));
+        assert_eq!(selector_for_node(db, 999).unwrap(), None);
+        assert_eq!(xpath_for_node(db, 999).unwrap(), None);
+    }
+
+    #[test]
+    fn test_xpath_for_keys_that_are_not_names() {
+        let db = open();
+        let nodes = crate::parser::parse_json_to_nodes(
+            r#"{"people": [{"first name": {"given": "Ada"}}, {"first name": {"given": "Alan"}}]}"#,
+        )
+        .unwrap();
+        let document_id = crate::XmlSqlDb { db }
+            .insert_document(
+                "people.json",
+                crate::database::DocumentKind::Json,
+                None,
+                &nodes,
+            )
+            .unwrap() as i64;
+        let found = locators(db, document_id);
+        assert_eq!(
+            xpath_for_node(db, node_id(db, "first name"))
+                .unwrap()
+                .as_deref(),
+            Some("/json/people/item[1]/*[local-name()=\"first name\"]")
+        );
+        assert_eq!(found.len(), 6);
+    }
+}
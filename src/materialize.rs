@@ -0,0 +1,447 @@
+//! Shreds repeated records (`library > book`) into a real SQLite table with
+//! one column per child element and attribute, so they can be queried
+//! without self-joining `nodes`.
+//!
+//! Tables are listed in `materialized_tables`. Those created with
+//! `refresh` are rebuilt whenever a document with the same name is loaded
+//! again.
+
+use crate::database::{exec, query, query_value};
+use crate::selector::{css_to_node_sql, DocumentFilter, SelectorScope};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlite_wasm_rs::sqlite3;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Date,
+    Text,
+}
+
+impl ColumnType {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+            ColumnType::Date => "DATE",
+            ColumnType::Text => "TEXT",
+        }
+    }
+
+    /// The narrowest type that can hold `value`. Numbers with leading zeros
+    /// (`007`, zip codes) stay text so they are not altered.
+    pub fn of(value: &str) -> ColumnType {
+        let value = value.trim();
+        let digits = value.strip_prefix('-').unwrap_or(value);
+        let leading_zero = digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.");
+        let numeric = !digits.is_empty()
+            && digits.starts_with(|c: char| c.is_ascii_digit())
+            && digits
+                .chars()
+                .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '-' | '+'));
+        if numeric && !leading_zero {
+            if value.parse::<i64>().is_ok() {
+                return ColumnType::Integer;
+            }
+            if value.parse::<f64>().is_ok() {
+                return ColumnType::Real;
+            }
+        }
+        if is_iso_date(value) {
+            ColumnType::Date
+        } else {
+            ColumnType::Text
+        }
+    }
+
+    /// The type of a column holding values of both types.
+    pub fn widen(self, other: ColumnType) -> ColumnType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Integer, ColumnType::Real) | (ColumnType::Real, ColumnType::Integer) => {
+                ColumnType::Real
+            }
+            _ => ColumnType::Text,
+        }
+    }
+
+    fn to_param(self, value: &str) -> Value {
+        let value = value.trim();
+        match self {
+            _ if value.is_empty() => Value::Null,
+            ColumnType::Integer => value
+                .parse::<i64>()
+                .map(|v| json!(v))
+                .unwrap_or(json!(value)),
+            ColumnType::Real => value
+                .parse::<f64>()
+                .map(|v| json!(v))
+                .unwrap_or(json!(value)),
+            ColumnType::Date | ColumnType::Text => json!(value),
+        }
+    }
+}
+
+/// `YYYY-MM-DD`, optionally followed by a time (`T10:00:00Z`, ` 10:00`).
+fn is_iso_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    let date_ok = bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        });
+    date_ok && (bytes.len() == 10 || matches!(bytes[10], b'T' | b' '))
+}
+
+/// Infers a column type from its values; empty values are ignored and a
+/// column with no values at all is text.
+pub fn infer_type<'a>(values: impl IntoIterator<Item = &'a str>) -> ColumnType {
+    values
+        .into_iter()
+        .filter(|v| !v.trim().is_empty())
+        .map(ColumnType::of)
+        .reduce(ColumnType::widen)
+        .unwrap_or(ColumnType::Text)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Source {
+    Text,
+    Child(String),
+    Attribute(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaterializedTable {
+    pub table_name: String,
+    pub rows: usize,
+    pub columns: Vec<ColumnInfo>,
+}
+
+/// Picks a column name not yet in `taken` (SQLite compares them ignoring
+/// case). An attribute named like a child element becomes `name_attr`.
+fn column_name(source: &Source, taken: &[String]) -> String {
+    let is_taken = |name: &str| taken.iter().any(|t| t.eq_ignore_ascii_case(name));
+    let base = match source {
+        Source::Text => "text".to_string(),
+        Source::Child(name) => name.clone(),
+        Source::Attribute(name) if is_taken(name) => format!("{}_attr", name),
+        Source::Attribute(name) => name.clone(),
+    };
+    let mut name = base.clone();
+    let mut suffix = 2;
+    while is_taken(&name) {
+        name = format!("{}_{}", base, suffix);
+        suffix += 1;
+    }
+    name
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Builds `table_name` from the records `record_selector` matches in
+/// `document_id`. The first column, `node_id`, links rows back to `nodes`.
+///
+/// Each child element becomes a column holding its inner text (the first
+/// one if it repeats), and so does each attribute of the record element.
+/// An existing table is only replaced if it was created here.
+pub fn materialize_table(
+    db: *mut sqlite3,
+    document_id: i64,
+    record_selector: &str,
+    table_name: &str,
+    refresh: bool,
+) -> Result<MaterializedTable, String> {
+    if table_name.trim().is_empty() {
+        return Err("A table name is required".to_string());
+    }
+    let table_exists = query_value(
+        db,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type IN ('table', 'view') AND name = ?1 COLLATE NOCASE",
+        &[json!(table_name)],
+    )?
+    .and_then(|v| v.as_i64())
+        == Some(1);
+    let registered = query_value(
+        db,
+        "SELECT COUNT(*) FROM materialized_tables WHERE table_name = ?1 COLLATE NOCASE",
+        &[json!(table_name)],
+    )?
+    .and_then(|v| v.as_i64())
+        == Some(1);
+    if table_exists && !registered {
+        return Err(format!(
+            "Table '{}' already exists and was not created by materialize_table",
+            table_name
+        ));
+    }
+    let document_name = query_value(
+        db,
+        "SELECT name FROM documents WHERE id = ?1",
+        &[json!(document_id)],
+    )?
+    .and_then(|v| v.as_str().map(str::to_string))
+    .ok_or_else(|| format!("Document {} does not exist", document_id))?;
+
+    let scope = SelectorScope {
+        document: Some(DocumentFilter::Id(document_id)),
+        ..SelectorScope::default()
+    };
+    let records_sql = format!(
+        "SELECT id FROM ({}) matched",
+        css_to_node_sql(record_selector, &scope)?
+    );
+    let record_ids: Vec<i64> = query(
+        db,
+        &format!(
+            "SELECT id FROM nodes WHERE id IN ({}) ORDER BY document_order",
+            records_sql
+        ),
+        &[],
+    )?
+    .rows
+    .iter()
+    .filter_map(|row| row[0].as_i64())
+    .collect();
+
+    let mut sources: Vec<Source> = Vec::new();
+    let mut values: HashMap<i64, HashMap<Source, String>> = HashMap::new();
+    let mut collect = |node_id: i64, source: Source, value: String| {
+        if !sources.contains(&source) {
+            sources.push(source.clone());
+        }
+        values
+            .entry(node_id)
+            .or_default()
+            .entry(source)
+            .or_insert(value);
+    };
+    let texts = query(
+        db,
+        &format!(
+            "SELECT id, text_content FROM nodes WHERE id IN ({}) AND text_content IS NOT NULL ORDER BY document_order",
+            records_sql
+        ),
+        &[],
+    )?;
+    for row in texts.rows {
+        let text = row[1].as_str().unwrap_or_default().to_string();
+        collect(row[0].as_i64().unwrap_or_default(), Source::Text, text);
+    }
+    let children = query(
+        db,
+        &format!(
+            "SELECT parent_id, tag_name, COALESCE(inner_text(id), '') FROM nodes
+            WHERE parent_id IN ({}) ORDER BY document_order",
+            records_sql
+        ),
+        &[],
+    )?;
+    for row in children.rows {
+        let tag = row[1].as_str().unwrap_or_default().to_string();
+        let text = row[2].as_str().unwrap_or_default().to_string();
+        collect(
+            row[0].as_i64().unwrap_or_default(),
+            Source::Child(tag),
+            text,
+        );
+    }
+    let attributes = query(
+        db,
+        &format!(
+            "SELECT node_id, name, COALESCE(value, '') FROM attributes
+            WHERE node_id IN ({}) ORDER BY node_id, id",
+            records_sql
+        ),
+        &[],
+    )?;
+    for row in attributes.rows {
+        let name = row[1].as_str().unwrap_or_default().to_string();
+        let value = row[2].as_str().unwrap_or_default().to_string();
+        collect(
+            row[0].as_i64().unwrap_or_default(),
+            Source::Attribute(name),
+            value,
+        );
+    }
+
+    // Children claim their names before attributes
+    sources.sort_by_key(|source| matches!(source, Source::Attribute(_)));
+    let mut names = vec!["node_id".to_string()];
+    let mut columns = Vec::new();
+    for source in &sources {
+        let name = column_name(source, &names);
+        names.push(name.clone());
+        let column_type = infer_type(
+            values
+                .values()
+                .filter_map(|record| record.get(source).map(String::as_str)),
+        );
+        columns.push(ColumnInfo { name, column_type });
+    }
+
+    let definitions: Vec<String> = std::iter::once("node_id INTEGER PRIMARY KEY".to_string())
+        .chain(
+            columns
+                .iter()
+                .map(|c| format!("{} {}", quote_identifier(&c.name), c.column_type.as_sql())),
+        )
+        .collect();
+    let quoted = quote_identifier(table_name);
+    let placeholders = vec!["?"; columns.len() + 1].join(", ");
+
+    exec(db, "SAVEPOINT materialize")?;
+    let result = (|| -> Result<(), String> {
+        exec(db, &format!("DROP TABLE IF EXISTS {}", quoted))?;
+        exec(
+            db,
+            &format!("CREATE TABLE {} ({})", quoted, definitions.join(", ")),
+        )?;
+        let insert = format!("INSERT INTO {} VALUES ({})", quoted, placeholders);
+        for node_id in &record_ids {
+            let record = values.get(node_id);
+            let mut params = vec![json!(node_id)];
+            for (source, column) in sources.iter().zip(&columns) {
+                params.push(
+                    record
+                        .and_then(|r| r.get(source))
+                        .map_or(Value::Null, |v| column.column_type.to_param(v)),
+                );
+            }
+            query(db, &insert, &params)?;
+        }
+        query(
+            db,
+            "INSERT OR REPLACE INTO materialized_tables
+            (table_name, document_id, document_name, record_selector, refresh)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            &[
+                json!(table_name),
+                json!(document_id),
+                json!(document_name),
+                json!(record_selector),
+                json!(refresh),
+            ],
+        )?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => exec(db, "RELEASE materialize")?,
+        Err(e) => {
+            let _ = exec(db, "ROLLBACK TO materialize; RELEASE materialize");
+            return Err(e);
+        }
+    }
+
+    Ok(MaterializedTable {
+        table_name: table_name.to_string(),
+        rows: record_ids.len(),
+        columns,
+    })
+}
+
+/// Rebuilds the refreshed tables that came from a document with the same
+/// name as `document_id`, pointing them at this newer copy.
+pub fn refresh_tables(db: *mut sqlite3, document_id: i64) -> Result<(), String> {
+    let tables = query(
+        db,
+        "SELECT m.table_name, m.record_selector FROM materialized_tables m
+        JOIN documents d ON d.name = m.document_name
+        WHERE d.id = ?1 AND m.refresh = 1",
+        &[json!(document_id)],
+    )?;
+    for row in tables.rows {
+        let table_name = row[0].as_str().unwrap_or_default();
+        let selector = row[1].as_str().unwrap_or_default();
+        materialize_table(db, document_id, selector, table_name, true)
+            .map_err(|e| format!("Refreshing table '{}' failed: {}", table_name, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_type_of() {
+        assert_eq!(ColumnType::of("1954"), ColumnType::Integer);
+        assert_eq!(ColumnType::of(" -3 "), ColumnType::Integer);
+        assert_eq!(ColumnType::of("0"), ColumnType::Integer);
+        assert_eq!(ColumnType::of("29.99"), ColumnType::Real);
+        assert_eq!(ColumnType::of("0.5"), ColumnType::Real);
+        assert_eq!(ColumnType::of("1e3"), ColumnType::Real);
+        assert_eq!(ColumnType::of("007"), ColumnType::Text);
+        assert_eq!(ColumnType::of("inf"), ColumnType::Text);
+        assert_eq!(ColumnType::of("2024-02-29"), ColumnType::Date);
+        assert_eq!(ColumnType::of("2024-02-29T10:00:00Z"), ColumnType::Date);
+        assert_eq!(ColumnType::of("2024-02-29x"), ColumnType::Text);
+        assert_eq!(ColumnType::of("J.R.R. Tolkien"), ColumnType::Text);
+    }
+
+    #[test]
+    fn test_infer_type_widens() {
+        assert_eq!(infer_type(["1", "2", ""]), ColumnType::Integer);
+        assert_eq!(infer_type(["1", "2.5"]), ColumnType::Real);
+        assert_eq!(infer_type(["1", "2024-01-01"]), ColumnType::Text);
+        assert_eq!(infer_type([""]), ColumnType::Text);
+    }
+
+    #[test]
+    fn test_column_names_do_not_collide() {
+        let taken = vec!["node_id".to_string(), "title".to_string()];
+        assert_eq!(
+            column_name(&Source::Attribute("Title".to_string()), &taken),
+            "Title_attr"
+        );
+        assert_eq!(
+            column_name(&Source::Child("node_id".to_string()), &taken),
+            "node_id_2"
+        );
+        assert_eq!(column_name(&Source::Text, &taken), "text");
+    }
+
+    #[test]
+    fn test_failed_refresh_rolls_back_the_load() {
+        let db = crate::testing::open();
+        query(
+            db,
+            "INSERT INTO materialized_tables
+                (table_name, document_id, document_name, record_selector, refresh)
+            VALUES ('books', 0, 'books.xml', 'book >', 1)",
+            &[],
+        )
+        .unwrap();
+        let nodes = crate::parser::parse_xml_to_nodes("<books><book/></books>").unwrap();
+        let loaded = crate::XmlSqlDb { db }.insert_document(
+            "books.xml",
+            crate::database::DocumentKind::Xml,
+            None,
+            &nodes,
+        );
+        assert!(loaded
+            .unwrap_err()
+            .contains("Refreshing table 'books' failed"));
+        let count = |table: &str| {
+            query(db, &format!("SELECT COUNT(*) FROM {}", table), &[])
+                .unwrap()
+                .rows[0][0]
+                .as_i64()
+        };
+        assert_eq!(count("documents"), Some(0));
+        assert_eq!(count("nodes"), Some(0));
+    }
+}
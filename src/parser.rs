@@ -1,9 +1,241 @@
-use crate::NodeData;
+use crate::database::SegmentKind;
+use crate::dtd::{parse_doctype, qualified_name, Doctype};
+use crate::tree::{hashed_attributes, namespace_uri, node_scopes, qualified};
+use crate::{NodeData, Segment};
+use serde::Deserialize;
 use std::collections::HashMap;
 
+pub const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// How documents with a DOCTYPE are handled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct XmlOptions {
+    /// Accept an internal DTD subset. When false, such documents are
+    /// rejected; a bare `<!DOCTYPE name>` is always accepted.
+    pub allow_dtd: bool,
+    /// Most characters that entity references in the document and in the
+    /// applied attribute defaults may expand to, to stop entity-expansion
+    /// bombs.
+    pub max_entity_expansion: usize,
+    /// Add attributes the DTD declares with a default or `#FIXED` value.
+    pub apply_default_attributes: bool,
+    /// Reject documents that are not valid against their internal subset.
+    pub validate_dtd: bool,
+}
+
+impl Default for XmlOptions {
+    fn default() -> Self {
+        XmlOptions {
+            allow_dtd: true,
+            max_entity_expansion: 1_000_000,
+            apply_default_attributes: true,
+            validate_dtd: false,
+        }
+    }
+}
+
+/// Hashes a subtree from its namespace URI and tag, text, attributes as
+/// (namespace URI, local name, value) in sorted order, and the hashes of its
+/// children in order. Prefixes are left out, so only the namespaces count. FNV-1a rather than `DefaultHasher`, so
+/// hashes stored by one build stay comparable with the next.
+pub fn content_hash<'a>(
+    namespace: Option<&str>,
+    tag_name: &str,
+    text_content: Option<&str>,
+    attributes: impl IntoIterator<Item = (Option<&'a str>, &'a str, &'a str)>,
+    children: impl IntoIterator<Item = u64>,
+) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut write = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    // Length prefixes keep ("ab", "c") apart from ("a", "bc")
+    let mut field = |bytes: &[u8]| {
+        write(&(bytes.len() as u64).to_le_bytes());
+        write(bytes);
+    };
+    // 0xff is not valid UTF-8, so it stands apart from any string
+    let absent: &[u8] = &[0xff];
+    field(namespace.map_or(absent, str::as_bytes));
+    field(tag_name.as_bytes());
+    field(text_content.map_or(absent, str::as_bytes));
+    for (namespace, name, value) in attributes {
+        field(namespace.map_or(absent, str::as_bytes));
+        field(name.as_bytes());
+        field(value.as_bytes());
+    }
+    field(b"children");
+    for child in children {
+        field(&child.to_le_bytes());
+    }
+    hash
+}
+
+/// Fills in `content_hash`, `subtree_size` and `position` for parser output
+/// in document order.
+pub fn assign_content_hashes(nodes: &mut [NodeData]) {
+    let index_of: HashMap<i64, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (node.id, index))
+        .collect();
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    let mut roots = 0;
+    let mut positions = Vec::with_capacity(nodes.len());
+    let mut parents = Vec::with_capacity(nodes.len());
+    for (index, node) in nodes.iter().enumerate() {
+        let parent = node.parent_id.and_then(|p| index_of.get(&p)).copied();
+        parents.push(parent);
+        positions.push(match parent {
+            Some(parent) => {
+                children[parent].push(index);
+                children[parent].len()
+            }
+            None => {
+                roots += 1;
+                roots
+            }
+        });
+    }
+
+    let (scopes, scope_of) = node_scopes(
+        nodes
+            .iter()
+            .zip(&parents)
+            .map(|(node, &parent)| (parent, node.namespaces.as_slice())),
+    );
+    let mut hashes = vec![0u64; nodes.len()];
+    // Children come after their parent, so walk backwards
+    for index in (0..nodes.len()).rev() {
+        let node = &nodes[index];
+        let scope = &scopes[scope_of[index]];
+        hashes[index] = content_hash(
+            namespace_uri(scope, node.prefix.as_deref()),
+            &node.tag_name,
+            node.text_content.as_deref(),
+            hashed_attributes(&node.attributes, &node.attribute_prefixes, scope),
+            children[index].iter().map(|&child| hashes[child]),
+        );
+        let size = 1 + children[index]
+            .iter()
+            .map(|&child| nodes[child].subtree_size)
+            .sum::<u32>();
+        let node = &mut nodes[index];
+        node.content_hash = format!("{:016x}", hashes[index]);
+        node.subtree_size = size;
+        node.position = positions[index] as u32;
+    }
+}
+
+/// The prefix a name was written with at `start` in the source, or `None`
+/// when the source there does not spell `local` (e.g. markup from an entity).
+/// roxmltree resolves prefixes to URIs and does not keep them.
+fn written_prefix(source: &str, start: usize, local: &str) -> Option<Option<String>> {
+    let name = source
+        .get(start..)?
+        .split(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/'))
+        .next()?;
+    match name.split_once(':') {
+        Some((prefix, rest)) if rest == local => Some(Some(prefix.to_string())),
+        None if name == local => Some(None),
+        _ => None,
+    }
+}
+
+fn lookup_prefix(node: roxmltree::Node, uri: &str) -> Option<String> {
+    if uri == XML_NAMESPACE {
+        return Some("xml".to_string());
+    }
+    node.lookup_prefix(uri)
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_string)
+}
+
+/// Adds character data, joining it onto the text segment right before it.
+fn push_text(segments: &mut Vec<Segment>, slot: u32, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match segments.last_mut() {
+        Some(last) if last.kind == SegmentKind::Text && last.slot == slot => {
+            last.value.push_str(text)
+        }
+        _ => segments.push(Segment {
+            slot,
+            kind: SegmentKind::Text,
+            target: None,
+            value: text.to_string(),
+        }),
+    }
+}
+
+/// The comment or processing instruction `node`, if it is one.
+fn xml_segment(node: roxmltree::Node, slot: u32) -> Option<Segment> {
+    let (kind, target, value) = match node.node_type() {
+        roxmltree::NodeType::Comment => (SegmentKind::Comment, None, node.text()?),
+        roxmltree::NodeType::PI => {
+            let pi = node.pi()?;
+            (
+                SegmentKind::Pi,
+                Some(pi.target),
+                pi.value.unwrap_or_default(),
+            )
+        }
+        _ => return None,
+    };
+    Some(Segment {
+        slot,
+        kind,
+        target: target.map(str::to_string),
+        value: value.to_string(),
+    })
+}
+
 pub fn parse_xml_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
-    let doc =
-        roxmltree::Document::parse(content).map_err(|e| format!("XML parsing error: {}", e))?;
+    parse_xml_to_nodes_with_options(content, &XmlOptions::default())
+}
+
+pub fn parse_xml_to_nodes_with_options(
+    content: &str,
+    options: &XmlOptions,
+) -> Result<Vec<NodeData>, String> {
+    let doctype = parse_doctype(content).map_err(|e| format!("DTD error: {}", e))?;
+    // What is left of the expansion limit for default attribute values
+    let mut budget = options.max_entity_expansion;
+    if let Some(doctype) = doctype.as_ref().filter(|d| d.has_subset()) {
+        if !options.allow_dtd {
+            return Err("The document has a DTD, but DTDs are not allowed".to_string());
+        }
+        budget -= doctype.check_entity_expansion(content, options.max_entity_expansion)?;
+    }
+
+    // roxmltree refuses any DOCTYPE unless DTDs are allowed
+    let parsing_options = roxmltree::ParsingOptions {
+        allow_dtd: options.allow_dtd || doctype.as_ref().is_some_and(|d| !d.has_subset()),
+        ..roxmltree::ParsingOptions::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(content, parsing_options)
+        .map_err(|e| format!("XML parsing error: {}", e))?;
+
+    if options.validate_dtd {
+        let doctype = doctype
+            .as_ref()
+            .ok_or("The document has no DOCTYPE to validate against")?;
+        let violations = doctype.validate(&doc);
+        if !violations.is_empty() {
+            return Err(format!(
+                "The document is not valid against its DTD:\n{}",
+                violations.join("\n")
+            ));
+        }
+    }
+    let defaults = doctype
+        .as_ref()
+        .filter(|_| options.apply_default_attributes);
 
     let mut nodes = Vec::new();
     let mut node_id = 1i64;
@@ -14,26 +246,57 @@ pub fn parse_xml_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
         depth: i32,
         nodes: &mut Vec<NodeData>,
         node_id: &mut i64,
-    ) {
+        defaults: Option<&Doctype>,
+        budget: &mut usize,
+    ) -> Result<(), String> {
         if node.is_element() {
             let current_id = *node_id;
             *node_id += 1;
 
+            let source = node.document().input_text();
+            // Keyed by qualified name, so `a:id` and `b:id` stay apart
             let mut attributes = HashMap::new();
+            let mut attribute_prefixes = HashMap::new();
             for attr in node.attributes() {
-                attributes.insert(attr.name().to_string(), attr.value().to_string());
+                let prefix = match written_prefix(source, attr.position(), attr.name()) {
+                    Some(prefix) => prefix,
+                    None => attr.namespace().and_then(|ns| lookup_prefix(node, ns)),
+                };
+                let name = qualified(prefix.as_deref(), attr.name());
+                if let Some(prefix) = prefix {
+                    attribute_prefixes.insert(name.clone(), prefix);
+                }
+                attributes.insert(name, attr.value().to_string());
+            }
+            if let Some(doctype) = defaults {
+                for (name, value) in doctype.default_attributes(
+                    &qualified_name(node),
+                    |name| attributes.contains_key(name),
+                    budget,
+                )? {
+                    if let Some((prefix, _)) = name.split_once(':') {
+                        attribute_prefixes.insert(name.to_string(), prefix.to_string());
+                    }
+                    attributes.insert(name.to_string(), value);
+                }
             }
 
             // Collect text content correctly (including mixed content)
             let mut text_parts = Vec::new();
+            let mut segments = Vec::new();
+            let mut slot = 0;
             for child in node.children() {
-                if child.is_text() {
-                    if let Some(text) = child.text() {
+                match child.node_type() {
+                    roxmltree::NodeType::Element => slot += 1,
+                    roxmltree::NodeType::Text => {
+                        let text = child.text().unwrap_or_default();
+                        push_text(&mut segments, slot, text);
                         let trimmed = text.trim();
                         if !trimmed.is_empty() {
                             text_parts.push(trimmed.to_string());
                         }
                     }
+                    _ => segments.extend(xml_segment(child, slot)),
                 }
             }
 
@@ -43,6 +306,30 @@ pub fn parse_xml_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
                 Some(text_parts.join(" "))
             };
 
+            let local = node.tag_name().name();
+            let prefix = match written_prefix(source, node.range().start + 1, local) {
+                Some(prefix) => prefix,
+                None => node
+                    .tag_name()
+                    .namespace()
+                    .and_then(|ns| lookup_prefix(node, ns)),
+            };
+            let inherited: Vec<(Option<&str>, &str)> = node
+                .parent_element()
+                .map(|parent| {
+                    parent
+                        .namespaces()
+                        .map(|ns| (ns.name(), ns.uri()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let namespaces = node
+                .namespaces()
+                .filter(|ns| !inherited.contains(&(ns.name(), ns.uri())))
+                .map(|ns| (ns.name().unwrap_or("").to_string(), ns.uri().to_string()))
+                .collect();
+
+            let position = node.document().text_pos_at(node.range().start);
             nodes.push(NodeData {
                 id: current_id,
                 tag_name: node.tag_name().name().to_string(),
@@ -50,18 +337,54 @@ pub fn parse_xml_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
                 attributes,
                 parent_id,
                 depth,
+                source_line: Some(position.row),
+                source_column: Some(position.col),
+                content_hash: String::new(),
+                subtree_size: 0,
+                position: 0,
+                prefix,
+                namespaces,
+                attribute_prefixes,
+                segments,
+                document_segments: Vec::new(),
             });
 
             for child in node.children() {
                 if child.is_element() {
-                    traverse_xml(child, Some(current_id), depth + 1, nodes, node_id);
+                    traverse_xml(
+                        child,
+                        Some(current_id),
+                        depth + 1,
+                        nodes,
+                        node_id,
+                        defaults,
+                        budget,
+                    )?;
                 }
             }
         }
+        Ok(())
     }
 
     // roxmltree::Document::root_element() returns Node (not Option)
-    traverse_xml(doc.root_element(), None, 0, &mut nodes, &mut node_id);
+    traverse_xml(
+        doc.root_element(),
+        None,
+        0,
+        &mut nodes,
+        &mut node_id,
+        defaults,
+        &mut budget,
+    )?;
+    let mut slot = 0;
+    for child in doc.root().children() {
+        if child.is_element() {
+            slot += 1;
+        } else {
+            nodes[0].document_segments.extend(xml_segment(child, slot));
+        }
+    }
+    assign_content_hashes(&mut nodes);
 
     Ok(nodes)
 }
@@ -107,6 +430,15 @@ pub fn parse_html_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
                     })
                     .collect::<Vec<_>>()
                     .join(" ");
+                let mut segments = Vec::new();
+                let mut slot = 0;
+                for child in node.children() {
+                    match child.value() {
+                        ScraperNode::Element(_) => slot += 1,
+                        ScraperNode::Text(text) => push_text(&mut segments, slot, text),
+                        _ => segments.extend(html_segment(child.value(), slot)),
+                    }
+                }
 
                 let text_content = if text_content.is_empty() {
                     None
@@ -121,6 +453,17 @@ pub fn parse_html_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
                     attributes,
                     parent_id,
                     depth,
+                    // html5ever does not report source positions
+                    source_line: None,
+                    source_column: None,
+                    content_hash: String::new(),
+                    subtree_size: 0,
+                    position: 0,
+                    prefix: None,
+                    namespaces: Vec::new(),
+                    attribute_prefixes: HashMap::new(),
+                    segments,
+                    document_segments: Vec::new(),
                 });
 
                 for child in node.children() {
@@ -128,14 +471,263 @@ pub fn parse_html_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
                 }
             }
             _ => {
-                // Skip non-element nodes (text is handled by parent, comments skipped)
+                // Skip non-element nodes (the parent keeps them as segments)
             }
         }
     }
 
+    /// The comment or processing instruction `node`, if it is one.
+    fn html_segment(node: &ScraperNode, slot: u32) -> Option<Segment> {
+        let (kind, target, value) = match node {
+            ScraperNode::Comment(comment) => (SegmentKind::Comment, None, &comment.comment),
+            ScraperNode::ProcessingInstruction(pi) => {
+                (SegmentKind::Pi, Some(pi.target.to_string()), &pi.data)
+            }
+            _ => return None,
+        };
+        Some(Segment {
+            slot,
+            kind,
+            target,
+            value: value.to_string(),
+        })
+    }
+
     // Parse root element (<html>)
     // document.root_element() returns ElementRef, which derefs to NodeRef
     traverse_html(*document.root_element(), None, 0, &mut nodes, &mut node_id);
+    let mut slot = 0;
+    for child in document.tree.root().children() {
+        match child.value() {
+            ScraperNode::Element(_) => slot += 1,
+            value => nodes[0].document_segments.extend(html_segment(value, slot)),
+        }
+    }
+    assign_content_hashes(&mut nodes);
+
+    Ok(nodes)
+}
+
+/// A JSON value that keeps object members in document order, which
+/// `serde_json::Value` only does with its `preserve_order` feature.
+enum JsonValue {
+    Null,
+    Scalar(String, ScalarType),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ScalarType {
+    String,
+    Number,
+    Boolean,
+}
+
+impl JsonValue {
+    /// The stored text and, unless it is a string, the JSON type of a scalar.
+    fn scalar(&self) -> Option<(String, Option<&'static str>)> {
+        match self {
+            JsonValue::Null => Some((String::new(), Some("null"))),
+            JsonValue::Scalar(text, ScalarType::String) => Some((text.clone(), None)),
+            JsonValue::Scalar(text, ScalarType::Number) => Some((text.clone(), Some("number"))),
+            JsonValue::Scalar(text, ScalarType::Boolean) => Some((text.clone(), Some("boolean"))),
+            JsonValue::Array(_) | JsonValue::Object(_) => None,
+        }
+    }
+}
+
+/// Attribute holding the JSON type of an element that is not an object or
+/// string: `array`, or `number`, `boolean` or `null` for a scalar `<item>`.
+pub const JSON_TYPE_ATTRIBUTE: &str = "json-type";
+
+/// Attribute holding the JSON types of an object's scalar members that are
+/// not strings, as a JSON object such as `{"price":"number"}`.
+pub const JSON_TYPES_ATTRIBUTE: &str = "json-types";
+
+impl<'de> Deserialize<'de> for JsonValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        use serde::de::Error;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = JsonValue;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON value")
+            }
+
+            fn visit_unit<E>(self) -> Result<JsonValue, E> {
+                Ok(JsonValue::Null)
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<JsonValue, E> {
+                Ok(JsonValue::Scalar(v.to_string(), ScalarType::Boolean))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<JsonValue, E> {
+                Ok(JsonValue::Scalar(v.to_string(), ScalarType::Number))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<JsonValue, E> {
+                Ok(JsonValue::Scalar(v.to_string(), ScalarType::Number))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<JsonValue, E> {
+                // serde_json's formatting, so 1.0 stays "1.0"
+                Ok(JsonValue::Scalar(
+                    serde_json::Number::from_f64(v)
+                        .map_or_else(|| v.to_string(), |n| n.to_string()),
+                    ScalarType::Number,
+                ))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<JsonValue, E> {
+                self.visit_string(v.to_string())
+            }
+
+            fn visit_string<E: serde::de::Error>(self, v: String) -> Result<JsonValue, E> {
+                check_nul(&v)?;
+                Ok(JsonValue::Scalar(v, ScalarType::String))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<JsonValue, A::Error> {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(JsonValue::Array(items))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<JsonValue, A::Error> {
+                let mut members = Vec::new();
+                while let Some((key, value)) = map.next_entry::<String, JsonValue>()? {
+                    check_nul(&key)?;
+                    if key == JSON_TYPE_ATTRIBUTE || key == JSON_TYPES_ATTRIBUTE {
+                        return Err(A::Error::custom(format!("the key '{}' is reserved", key)));
+                    }
+                    members.push((key, value));
+                }
+                Ok(JsonValue::Object(members))
+            }
+        }
+
+        /// JSON allows `\u0000`, but stored names and text cannot hold it,
+        /// as no parsed XML or HTML does.
+        fn check_nul<E: serde::de::Error>(text: &str) -> Result<(), E> {
+            if text.contains('\0') {
+                return Err(E::custom("NUL characters cannot be stored"));
+            }
+            Ok(())
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Maps JSON onto elements: the top-level value is `<json>`, an object
+/// member is an element named by its key and an array entry is `<item>`.
+/// Scalar members of an object become its attributes, so `{"type": "book"}`
+/// matches `[type="book"]`; scalar array entries become `<item>` text.
+/// Values are stored as written, with `null` as an empty value. The JSON
+/// types that text cannot show are kept in the reserved `json-type` and
+/// `json-types` attributes, so no two documents load the same way.
+pub fn parse_json_to_nodes(content: &str) -> Result<Vec<NodeData>, String> {
+    let value: JsonValue =
+        serde_json::from_str(content).map_err(|e| format!("JSON parsing error: {}", e))?;
+
+    fn traverse_json(
+        name: &str,
+        value: &JsonValue,
+        parent_id: Option<i64>,
+        depth: i32,
+        nodes: &mut Vec<NodeData>,
+    ) {
+        let current_id = nodes.len() as i64 + 1;
+        let mut attributes = HashMap::new();
+        let mut text_content = None;
+        let mut children: Vec<(&str, &JsonValue)> = Vec::new();
+        match value {
+            JsonValue::Array(items) => {
+                attributes.insert(JSON_TYPE_ATTRIBUTE.to_string(), "array".to_string());
+                children.extend(items.iter().map(|item| ("item", item)));
+            }
+            JsonValue::Object(members) => {
+                let mut types = Vec::new();
+                for (key, member) in members {
+                    match member.scalar() {
+                        Some((text, scalar_type)) => {
+                            attributes.insert(key.clone(), text);
+                            if let Some(scalar_type) = scalar_type {
+                                types.push(format!(
+                                    "{}:\"{}\"",
+                                    serde_json::json!(key),
+                                    scalar_type
+                                ));
+                            }
+                        }
+                        None => children.push((key, member)),
+                    }
+                }
+                if !types.is_empty() {
+                    attributes.insert(
+                        JSON_TYPES_ATTRIBUTE.to_string(),
+                        format!("{{{}}}", types.join(",")),
+                    );
+                }
+            }
+            scalar => {
+                // an empty string keeps its text, unlike null and `{}`
+                let (text, scalar_type) = scalar.scalar().unwrap_or_default();
+                match scalar_type {
+                    Some(scalar_type) => {
+                        attributes.insert(JSON_TYPE_ATTRIBUTE.to_string(), scalar_type.to_string());
+                        text_content = Some(text).filter(|t| !t.is_empty());
+                    }
+                    None => text_content = Some(text),
+                }
+            }
+        }
+
+        let mut segments = Vec::new();
+        if let Some(text) = &text_content {
+            push_text(&mut segments, 0, text);
+        }
+        nodes.push(NodeData {
+            id: current_id,
+            tag_name: name.to_string(),
+            text_content,
+            attributes,
+            parent_id,
+            depth,
+            // serde_json does not report source positions
+            source_line: None,
+            source_column: None,
+            content_hash: String::new(),
+            subtree_size: 0,
+            position: 0,
+            prefix: None,
+            namespaces: Vec::new(),
+            attribute_prefixes: HashMap::new(),
+            segments,
+            document_segments: Vec::new(),
+        });
+
+        for (name, child) in children {
+            traverse_json(name, child, Some(current_id), depth + 1, nodes);
+        }
+    }
+
+    let mut nodes = Vec::new();
+    traverse_json("json", &value, None, 0, &mut nodes);
+    assign_content_hashes(&mut nodes);
 
     Ok(nodes)
 }
@@ -160,6 +752,8 @@ mod tests {
         let root = &nodes[0];
         assert_eq!(root.tag_name, "root");
         assert_eq!(root.parent_id, None);
+        let positions: Vec<u32> = nodes.iter().map(|n| n.position).collect();
+        assert_eq!(positions, [1, 1, 2]);
     }
 
     #[test]
@@ -211,4 +805,210 @@ mod tests {
         assert!(text.contains("A"), "Missing 'A'");
         assert!(text.contains("C"), "Missing 'C'");
     }
+
+    #[test]
+    fn test_xml_dtd_options() {
+        let xml = r#"<!DOCTYPE shelf [
+            <!ENTITY pub "Acme &amp; Sons">
+            <!ELEMENT shelf (book*)>
+            <!ELEMENT book (#PCDATA)>
+            <!ATTLIST book lang CDATA "en" publisher CDATA "&pub;">
+        ]>
+        <shelf><book lang="de">&pub;</book><book/></shelf>"#;
+
+        let nodes = parse_xml_to_nodes(xml).unwrap();
+        assert_eq!(nodes[1].text_content.as_deref(), Some("Acme & Sons"));
+        assert_eq!(nodes[1].attributes["lang"], "de");
+        assert_eq!(nodes[2].attributes["lang"], "en");
+        assert_eq!(nodes[2].attributes["publisher"], "Acme & Sons");
+
+        let strict = XmlOptions {
+            validate_dtd: true,
+            ..XmlOptions::default()
+        };
+        assert!(parse_xml_to_nodes_with_options(xml, &strict).is_ok());
+        let invalid = xml.replace("<book/>", "<cover/>");
+        let error = parse_xml_to_nodes_with_options(&invalid, &strict).unwrap_err();
+        assert!(
+            error.contains("Element 'cover' is not declared"),
+            "{}",
+            error
+        );
+
+        let no_defaults = XmlOptions {
+            apply_default_attributes: false,
+            ..XmlOptions::default()
+        };
+        let nodes = parse_xml_to_nodes_with_options(xml, &no_defaults).unwrap();
+        assert!(nodes[2].attributes.is_empty());
+
+        let no_dtd = XmlOptions {
+            allow_dtd: false,
+            ..XmlOptions::default()
+        };
+        assert!(parse_xml_to_nodes_with_options(xml, &no_dtd).is_err());
+        assert!(parse_xml_to_nodes_with_options("<!DOCTYPE a><a/>", &no_dtd).is_ok());
+
+        // Defaults applied to many elements share the expansion limit
+        let many = xml.replace("<book/>", &"<book/>".repeat(20));
+        let tight = XmlOptions {
+            max_entity_expansion: 200,
+            ..XmlOptions::default()
+        };
+        assert!(parse_xml_to_nodes_with_options(&many, &tight).is_err());
+        assert!(parse_xml_to_nodes_with_options(&many, &XmlOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_content_hashes() {
+        let xml = r#"<root>
+            <item a="1" b="2"><name>x</name></item>
+            <item b="2" a="1"><name>x</name></item>
+            <item a="1" b="2"><name>y</name></item>
+        </root>"#;
+        let nodes = parse_xml_to_nodes(xml).unwrap();
+        assert_eq!(nodes[0].subtree_size, 7);
+        assert_eq!(nodes[1].subtree_size, 2);
+        // Attribute order does not matter, text does
+        assert_eq!(nodes[1].content_hash, nodes[3].content_hash);
+        assert_ne!(nodes[1].content_hash, nodes[5].content_hash);
+        assert_eq!(nodes[1].content_hash.len(), 16);
+
+        // HTML and stored trees hash the same way
+        let html = parse_html_to_nodes("<ul><li>x</li><li>x</li></ul>").unwrap();
+        let items: Vec<_> = html.iter().filter(|n| n.tag_name == "li").collect();
+        assert_eq!(items[0].content_hash, items[1].content_hash);
+        let tree = crate::tree::StoredTree::from_parsed(&nodes);
+        assert_eq!(
+            format!("{:016x}", tree.subtree_hashes()[0]),
+            nodes[0].content_hash
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let json = r#"{"store": "main", "items": [
+            {"type": "book", "price": 12.5, "tags": ["a", 1.0, null], "used": false, "isbn": null},
+            {"type": "pen", "stock": {"count": 3}}
+        ]}"#;
+        let nodes = parse_json_to_nodes(json).unwrap();
+        let tags: Vec<&str> = nodes.iter().map(|n| n.tag_name.as_str()).collect();
+        assert_eq!(
+            tags,
+            ["json", "items", "item", "tags", "item", "item", "item", "item", "stock"]
+        );
+        assert_eq!(nodes[0].attributes["store"], "main");
+        assert_eq!(nodes[2].attributes["type"], "book");
+        assert_eq!(nodes[2].attributes["price"], "12.5");
+        assert_eq!(nodes[2].attributes["used"], "false");
+        assert_eq!(nodes[2].attributes["isbn"], "");
+        assert_eq!(
+            nodes[2].attributes[JSON_TYPES_ATTRIBUTE],
+            r#"{"price":"number","used":"boolean","isbn":"null"}"#
+        );
+        assert_eq!(nodes[1].attributes[JSON_TYPE_ATTRIBUTE], "array");
+        assert_eq!(nodes[4].text_content.as_deref(), Some("a"));
+        assert!(!nodes[4].attributes.contains_key(JSON_TYPE_ATTRIBUTE));
+        assert_eq!(nodes[5].text_content.as_deref(), Some("1.0"));
+        assert_eq!(nodes[5].attributes[JSON_TYPE_ATTRIBUTE], "number");
+        assert_eq!(nodes[6].text_content, None);
+        assert_eq!(nodes[6].attributes[JSON_TYPE_ATTRIBUTE], "null");
+        assert!(!nodes[7].attributes.contains_key(JSON_TYPES_ATTRIBUTE));
+        assert_eq!(nodes[8].attributes["count"], "3");
+        assert_eq!(nodes[8].parent_id, Some(8));
+        assert_eq!(nodes[8].depth, 3);
+
+        assert!(parse_json_to_nodes("{\"a\": }").is_err());
+        let error = parse_json_to_nodes(r#"{"json-types": 1}"#).unwrap_err();
+        assert!(
+            error.contains("the key 'json-types' is reserved"),
+            "{}",
+            error
+        );
+        for nul in [r#"{"a": "x\u0000y"}"#, r#"{"a\u0000": 1}"#, r#"["\u0000"]"#] {
+            let error = parse_json_to_nodes(nul).unwrap_err();
+            assert!(
+                error.contains("NUL characters cannot be stored"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    /// Rebuilds the JSON value of `nodes[index]` from the stored mapping.
+    fn json_value(nodes: &[NodeData], index: usize) -> serde_json::Value {
+        use serde_json::Value;
+        let node = &nodes[index];
+        let children = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.parent_id == Some(node.id));
+        let typed = |text: &str, json_type: Option<&str>| match json_type {
+            None => Value::String(text.to_string()),
+            Some("null") => Value::Null,
+            Some(_) => serde_json::from_str(text).unwrap(),
+        };
+        match node.attributes.get(JSON_TYPE_ATTRIBUTE).map(String::as_str) {
+            Some("array") => Value::Array(children.map(|(i, _)| json_value(nodes, i)).collect()),
+            Some(json_type) => typed(node.text_content.as_deref().unwrap_or(""), Some(json_type)),
+            None if node.text_content.is_some() => {
+                typed(node.text_content.as_deref().unwrap(), None)
+            }
+            None => {
+                let types: serde_json::Map<String, Value> = node
+                    .attributes
+                    .get(JSON_TYPES_ATTRIBUTE)
+                    .map(|t| serde_json::from_str(t).unwrap())
+                    .unwrap_or_default();
+                let mut object = serde_json::Map::new();
+                for (key, text) in node
+                    .attributes
+                    .iter()
+                    .filter(|(k, _)| *k != JSON_TYPES_ATTRIBUTE)
+                {
+                    object.insert(
+                        key.clone(),
+                        typed(text, types.get(key).and_then(Value::as_str)),
+                    );
+                }
+                for (i, child) in children {
+                    object.insert(child.tag_name.clone(), json_value(nodes, i));
+                }
+                Value::Object(object)
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_types_round_trip() {
+        for json in [
+            r#"{"a": null, "b": "", "c": "1", "d": 1, "e": "true", "f": true, "g": 1.0}"#,
+            r#"[null, "", "1", 1, "true", true, [], {}, [[]], {"item": {}}]"#,
+            r#"{"list": [], "object": {}, "nested": {"x": [{"y": "null"}]}}"#,
+            r#""""#,
+            "null",
+            "-2.5e10",
+        ] {
+            let nodes = parse_json_to_nodes(json).unwrap();
+            let expected: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(json_value(&nodes, 0), expected, "{}", json);
+        }
+
+        // an empty string item stays distinct from `{}` once stored
+        let db = crate::testing::open();
+        let nodes = parse_json_to_nodes(r#"["", {}]"#).unwrap();
+        crate::XmlSqlDb { db }
+            .insert_document("t.json", crate::database::DocumentKind::Json, None, &nodes)
+            .unwrap();
+        let texts = crate::database::query(
+            db,
+            "SELECT text_content FROM nodes WHERE tag_name = 'item' ORDER BY id",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            texts.rows,
+            [vec![serde_json::json!("")], vec![serde_json::Value::Null]]
+        );
+    }
 }
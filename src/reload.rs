@@ -0,0 +1,646 @@
+//! Reloading a document under a name that is already loaded.
+//!
+//! `replace` rewrites the document's nodes but keeps its `documents` row.
+//! `merge` compares the stored tree with the new one by subtree hashes and
+//! only writes what changed: identical subtrees and nodes updated in place
+//! keep their ids, wherever the new rows are inserted. Order lives in
+//! `position` and `document_order` rather than in ids, so kept rows whose
+//! place moved only have those two columns renumbered.
+
+use crate::database::{exec, query, query_value, DocumentKind};
+use crate::diff::out_of_order;
+use crate::tree::{insert_segments, local_name, qualified, StoredTree};
+use crate::NodeData;
+use serde::Serialize;
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReloadMode {
+    /// Always add a new document, like `load_xml`
+    Append,
+    /// Rewrite every node of the existing document
+    Replace,
+    /// Rewrite only the nodes that changed
+    Merge,
+}
+
+impl ReloadMode {
+    pub fn parse(mode: &str) -> Result<ReloadMode, String> {
+        match mode {
+            "append" => Ok(ReloadMode::Append),
+            "replace" => Ok(ReloadMode::Replace),
+            "merge" => Ok(ReloadMode::Merge),
+            _ => Err(format!(
+                "Unknown mode '{}': expected 'append', 'replace' or 'merge'",
+                mode
+            )),
+        }
+    }
+}
+
+/// Row counts of one reload.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReloadSummary {
+    pub document_id: i64,
+    pub mode: ReloadMode,
+    /// Nodes left untouched
+    pub kept: usize,
+    /// Nodes whose text, attributes or segments were updated in place
+    pub updated: usize,
+    pub inserted: usize,
+    pub deleted: usize,
+}
+
+/// The most recently loaded document called `name`.
+pub fn find_document(db: *mut sqlite3, name: &str) -> Result<Option<i64>, String> {
+    Ok(query_value(
+        db,
+        "SELECT id FROM documents WHERE name = ?1 ORDER BY id DESC LIMIT 1",
+        &[json!(name)],
+    )?
+    .and_then(|v| v.as_i64()))
+}
+
+/// For each new node, the old node it keeps the row of.
+struct Plan {
+    keep: Vec<Option<usize>>,
+    /// New nodes whose kept row needs its text, attributes or segments
+    /// rewritten
+    updated: HashSet<usize>,
+}
+
+struct Aligner<'a> {
+    old: &'a StoredTree,
+    new: &'a StoredTree,
+    old_hashes: Vec<u64>,
+    new_hashes: Vec<u64>,
+    plan: Plan,
+}
+
+impl Aligner<'_> {
+    fn keep_identical(&mut self, a: usize, b: usize) {
+        self.plan.keep[b] = Some(a);
+        // Whitespace and comments are not part of the hash
+        if self.old.nodes[a].segments != self.new.nodes[b].segments {
+            self.plan.updated.insert(b);
+        }
+        let pairs: Vec<(usize, usize)> = self.old.nodes[a]
+            .children
+            .iter()
+            .copied()
+            .zip(self.new.nodes[b].children.iter().copied())
+            .collect();
+        for (a, b) in pairs {
+            self.keep_identical(a, b);
+        }
+    }
+
+    /// Matches `b` to `a` (same tag) and then their children: identical
+    /// subtrees in order first, then same-tag children between them.
+    fn align(&mut self, a: usize, b: usize) {
+        if self.old_hashes[a] == self.new_hashes[b] {
+            return self.keep_identical(a, b);
+        }
+        self.plan.keep[b] = Some(a);
+        let (old_node, new_node) = (&self.old.nodes[a], &self.new.nodes[b]);
+        if old_node.text_content != new_node.text_content
+            || old_node.attributes != new_node.attributes
+            || old_node.segments != new_node.segments
+        {
+            self.plan.updated.insert(b);
+        }
+
+        let old_children = &old_node.children;
+        let new_children = &new_node.children;
+        let mut by_hash: HashMap<u64, VecDeque<usize>> = HashMap::new();
+        for (position, &child) in old_children.iter().enumerate() {
+            by_hash
+                .entry(self.old_hashes[child])
+                .or_default()
+                .push_back(position);
+        }
+        let candidates: Vec<(usize, usize)> = new_children
+            .iter()
+            .enumerate()
+            .filter_map(|(position, &child)| {
+                by_hash
+                    .get_mut(&self.new_hashes[child])
+                    .and_then(VecDeque::pop_front)
+                    .map(|old_position| (position, old_position))
+            })
+            .collect();
+        let old_positions: Vec<usize> = candidates.iter().map(|&(_, o)| o).collect();
+        let mut anchors = candidates;
+        for index in out_of_order(&old_positions).into_iter().rev() {
+            anchors.remove(index);
+        }
+
+        let (mut old_start, mut new_start) = (0, 0);
+        let sentinel = (new_children.len(), old_children.len());
+        for (new_end, old_end) in anchors.into_iter().chain([sentinel]) {
+            let mut cursor = old_start;
+            for &b in &new_children[new_start..new_end] {
+                let tag = &self.new.nodes[b].tag_name;
+                let found = old_children[cursor..old_end]
+                    .iter()
+                    .position(|&a| self.old.nodes[a].tag_name == *tag);
+                if let Some(offset) = found {
+                    self.align(old_children[cursor + offset], b);
+                    cursor += offset + 1;
+                }
+            }
+            if new_end < new_children.len() {
+                self.keep_identical(old_children[old_end], new_children[new_end]);
+            }
+            old_start = old_end + 1;
+            new_start = new_end + 1;
+        }
+    }
+}
+
+fn plan_merge(old: &StoredTree, new: &StoredTree) -> Plan {
+    let mut aligner = Aligner {
+        old,
+        new,
+        old_hashes: old.subtree_hashes(),
+        new_hashes: new.subtree_hashes(),
+        plan: Plan {
+            keep: vec![None; new.nodes.len()],
+            updated: HashSet::new(),
+        },
+    };
+    if old.nodes[0].tag_name == new.nodes[0].tag_name {
+        aligner.align(0, 0);
+    }
+    aligner.plan
+}
+
+fn insert_node(
+    db: *mut sqlite3,
+    document_id: i64,
+    node: &NodeData,
+    parent_id: Option<i64>,
+    document_order: usize,
+) -> Result<i64, String> {
+    let id = query(
+        db,
+        "INSERT INTO nodes (document_id, parent_id, tag_name, text_content, depth, position,
+            source_line, source_column, content_hash, subtree_size, prefix, document_order)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12) RETURNING id",
+        &[
+            json!(document_id),
+            json!(parent_id),
+            json!(node.tag_name),
+            json!(node.text_content),
+            json!(node.depth),
+            json!(node.position),
+            json!(node.source_line),
+            json!(node.source_column),
+            json!(node.content_hash),
+            json!(node.subtree_size),
+            json!(node.prefix),
+            json!(document_order),
+        ],
+    )?
+    .rows
+    .first()
+    .and_then(|row| row[0].as_i64())
+    .ok_or("Failed to insert node")?;
+    insert_attributes(db, id, node)?;
+    insert_namespaces(db, id, node)?;
+    insert_segments(db, document_id, Some(id), &node.segments)?;
+    Ok(id)
+}
+
+fn insert_attributes(db: *mut sqlite3, node_id: i64, node: &NodeData) -> Result<(), String> {
+    for (name, value) in &node.attributes {
+        let prefix = node.attribute_prefixes.get(name).map(String::as_str);
+        query(
+            db,
+            "INSERT INTO attributes (node_id, name, value, prefix) VALUES (?1, ?2, ?3, ?4)",
+            &[
+                json!(node_id),
+                json!(local_name(name, prefix)),
+                json!(value),
+                json!(prefix),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_namespaces(db: *mut sqlite3, node_id: i64, node: &NodeData) -> Result<(), String> {
+    for (prefix, uri) in &node.namespaces {
+        query(
+            db,
+            "INSERT INTO namespaces (node_id, prefix, uri) VALUES (?1, ?2, ?3)",
+            &[json!(node_id), json!(prefix), json!(uri)],
+        )?;
+    }
+    Ok(())
+}
+
+fn delete_nodes(db: *mut sqlite3, ids: &[i64]) -> Result<(), String> {
+    for chunk in ids.chunks(500) {
+        let list = chunk
+            .iter()
+            .map(i64::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        exec(
+            db,
+            &format!(
+                "DELETE FROM attributes WHERE node_id IN ({0});
+                DELETE FROM namespaces WHERE node_id IN ({0});
+                DELETE FROM segments WHERE node_id IN ({0});
+                DELETE FROM nodes WHERE id IN ({0});",
+                list
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+/// Runs `body` inside a savepoint, rolling back if it fails.
+fn in_savepoint<T>(
+    db: *mut sqlite3,
+    body: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    exec(db, "SAVEPOINT reload")?;
+    match body() {
+        Ok(value) => {
+            exec(db, "RELEASE reload")?;
+            Ok(value)
+        }
+        Err(e) => {
+            let _ = exec(db, "ROLLBACK TO reload; RELEASE reload");
+            Err(e)
+        }
+    }
+}
+
+/// Points the document at its new root, stores what surrounds it and drops
+/// results that refer to its old nodes.
+fn finish_document(
+    db: *mut sqlite3,
+    document_id: i64,
+    kind: DocumentKind,
+    doctype: Option<&str>,
+    nodes: &[NodeData],
+) -> Result<(), String> {
+    query(
+        db,
+        "UPDATE documents SET kind = ?2, doctype = ?3,
+            root_node_id = (
+                SELECT id FROM nodes WHERE document_id = ?1 AND parent_id IS NULL
+                ORDER BY document_order LIMIT 1
+            )
+        WHERE id = ?1",
+        &[json!(document_id), json!(kind.as_str()), json!(doctype)],
+    )?;
+    query(
+        db,
+        "DELETE FROM segments WHERE document_id = ?1 AND node_id IS NULL",
+        &[json!(document_id)],
+    )?;
+    if let Some(root) = nodes.first() {
+        insert_segments(db, document_id, None, &root.document_segments)?;
+    }
+    query(
+        db,
+        "DELETE FROM validation_errors WHERE document_id = ?1",
+        &[json!(document_id)],
+    )?;
+    Ok(())
+}
+
+/// Replaces every node of `document_id` with `nodes`.
+pub fn replace_document(
+    db: *mut sqlite3,
+    document_id: i64,
+    kind: DocumentKind,
+    doctype: Option<&str>,
+    nodes: &[NodeData],
+) -> Result<ReloadSummary, String> {
+    in_savepoint(db, || {
+        let old_ids: Vec<i64> = query(
+            db,
+            "SELECT id FROM nodes WHERE document_id = ?1",
+            &[json!(document_id)],
+        )?
+        .rows
+        .iter()
+        .filter_map(|row| row[0].as_i64())
+        .collect();
+        delete_nodes(db, &old_ids)?;
+
+        let mut id_map: HashMap<i64, i64> = HashMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            let parent = node.parent_id.and_then(|p| id_map.get(&p).copied());
+            let id = insert_node(db, document_id, node, parent, index + 1)?;
+            id_map.insert(node.id, id);
+        }
+        finish_document(db, document_id, kind, doctype, nodes)?;
+        Ok(ReloadSummary {
+            document_id,
+            mode: ReloadMode::Replace,
+            kept: 0,
+            updated: 0,
+            inserted: nodes.len(),
+            deleted: old_ids.len(),
+        })
+    })
+}
+
+/// Updates `document_id` to `nodes`, rewriting only the changed subtrees.
+pub fn merge_document(
+    db: *mut sqlite3,
+    document_id: i64,
+    kind: DocumentKind,
+    doctype: Option<&str>,
+    nodes: &[NodeData],
+) -> Result<ReloadSummary, String> {
+    let Some(old) = StoredTree::load_document(db, document_id)? else {
+        let mut summary = replace_document(db, document_id, kind, doctype, nodes)?;
+        summary.mode = ReloadMode::Merge;
+        return Ok(summary);
+    };
+    let new = StoredTree::from_parsed(nodes);
+    if new.nodes.is_empty() {
+        return Err("The new content has no elements".to_string());
+    }
+    let plan = plan_merge(&old, &new);
+
+    let kept_old: HashSet<usize> = plan.keep.iter().flatten().copied().collect();
+    let deleted: Vec<i64> = (0..old.nodes.len())
+        .filter(|a| !kept_old.contains(a))
+        .map(|a| old.nodes[a].id)
+        .collect();
+    // Source position, hash, size, prefix and order as stored, to update
+    // only the rows that changed. Ancestors of an edit keep their id but
+    // get a new hash, and nodes after an insert or delete move.
+    let stored: HashMap<i64, Vec<serde_json::Value>> = query(
+        db,
+        "SELECT id, source_line, source_column, content_hash, subtree_size, prefix,
+            position, document_order
+        FROM nodes WHERE document_id = ?1",
+        &[json!(document_id)],
+    )?
+    .rows
+    .into_iter()
+    .map(|row| (row[0].as_i64().unwrap_or_default(), row[1..].to_vec()))
+    .collect();
+    // Attribute prefixes are not part of the content hash
+    let mut stored_prefixes: HashMap<i64, HashMap<String, String>> = HashMap::new();
+    for row in query(
+        db,
+        "SELECT a.node_id, a.name, a.prefix FROM attributes a JOIN nodes n ON n.id = a.node_id
+        WHERE n.document_id = ?1 AND a.prefix IS NOT NULL",
+        &[json!(document_id)],
+    )?
+    .rows
+    {
+        stored_prefixes
+            .entry(row[0].as_i64().unwrap_or_default())
+            .or_default()
+            .insert(
+                qualified(row[2].as_str(), row[1].as_str().unwrap_or_default()),
+                row[2].as_str().unwrap_or_default().to_string(),
+            );
+    }
+
+    in_savepoint(db, || {
+        delete_nodes(db, &deleted)?;
+        // Declarations are few; rewriting them is simpler than comparing
+        query(
+            db,
+            "DELETE FROM namespaces
+            WHERE node_id IN (SELECT id FROM nodes WHERE document_id = ?1)",
+            &[json!(document_id)],
+        )?;
+
+        let mut inserted = 0;
+        let mut db_ids: Vec<i64> = Vec::with_capacity(nodes.len());
+        let parents = new.parents();
+        for (b, node) in nodes.iter().enumerate() {
+            let id = match plan.keep[b] {
+                Some(a) => {
+                    let id = old.nodes[a].id;
+                    let updated = plan.updated.contains(&b);
+                    if updated {
+                        query(
+                            db,
+                            "UPDATE nodes SET text_content = ?2 WHERE id = ?1",
+                            &[json!(id), json!(node.text_content)],
+                        )?;
+                        if old.nodes[a].segments != node.segments {
+                            query(db, "DELETE FROM segments WHERE node_id = ?1", &[json!(id)])?;
+                            insert_segments(db, document_id, Some(id), &node.segments)?;
+                        }
+                    }
+                    let prefixes = stored_prefixes.remove(&id).unwrap_or_default();
+                    if updated || prefixes != node.attribute_prefixes {
+                        query(
+                            db,
+                            "DELETE FROM attributes WHERE node_id = ?1",
+                            &[json!(id)],
+                        )?;
+                        insert_attributes(db, id, node)?;
+                    }
+                    insert_namespaces(db, id, node)?;
+                    let current = vec![
+                        json!(node.source_line),
+                        json!(node.source_column),
+                        json!(node.content_hash),
+                        json!(node.subtree_size),
+                        json!(node.prefix),
+                        json!(node.position),
+                        json!(b + 1),
+                    ];
+                    if stored.get(&id) != Some(&current) {
+                        let mut params = vec![json!(id)];
+                        params.extend(current);
+                        query(
+                            db,
+                            "UPDATE nodes SET source_line = ?2, source_column = ?3,
+                                    content_hash = ?4, subtree_size = ?5, prefix = ?6,
+                                    position = ?7, document_order = ?8
+                                WHERE id = ?1",
+                            &params,
+                        )?;
+                    }
+                    id
+                }
+                None => {
+                    inserted += 1;
+                    let parent = parents[b].map(|p| db_ids[p]);
+                    insert_node(db, document_id, node, parent, b + 1)?
+                }
+            };
+            db_ids.push(id);
+        }
+        finish_document(db, document_id, kind, doctype, nodes)?;
+
+        Ok(ReloadSummary {
+            document_id,
+            mode: ReloadMode::Merge,
+            kept: nodes.len() - inserted - plan.updated.len(),
+            updated: plan.updated.len(),
+            inserted,
+            deleted: deleted.len(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_xml_to_nodes;
+
+    fn tree(xml: &str) -> StoredTree {
+        StoredTree::from_parsed(&parse_xml_to_nodes(xml).unwrap())
+    }
+
+    /// `(new index, kept old index)` for every kept node.
+    fn kept(old: &str, new: &str) -> (Vec<(usize, usize)>, Vec<usize>) {
+        let plan = plan_merge(&tree(old), &tree(new));
+        let kept = plan
+            .keep
+            .iter()
+            .enumerate()
+            .filter_map(|(b, a)| a.map(|a| (b, a)))
+            .collect();
+        let mut updated: Vec<usize> = plan.updated.into_iter().collect();
+        updated.sort();
+        (kept, updated)
+    }
+
+    #[test]
+    fn test_merge_keeps_unchanged_and_updated_nodes() {
+        // 0 list, 1 item a, 2 item b, 3 item c
+        let old = "<list><item>a</item><item>b</item><item>c</item></list>";
+        let (keep, updated) = kept(old, old);
+        assert_eq!(keep, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+        assert!(updated.is_empty());
+
+        let (keep, updated) = kept(
+            old,
+            "<list><item>a</item><item>B</item><item>c</item></list>",
+        );
+        assert_eq!(keep, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+        assert_eq!(updated, vec![2]);
+
+        let (keep, _) = kept(old, "<list><item>a</item><item>c</item></list>");
+        assert_eq!(keep, vec![(0, 0), (1, 1), (2, 3)]);
+
+        // Appended at the end: everything else keeps its id
+        let (keep, _) = kept(
+            old,
+            "<list><item>a</item><item>b</item><item>c</item><item>d</item></list>",
+        );
+        assert_eq!(keep, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn test_merge_keeps_content_after_an_insertion() {
+        let old = "<list><item>a</item><item>b</item><item>c</item></list>";
+        let (keep, _) = kept(
+            old,
+            "<list><item>a</item><item>new</item><item>b</item><item>c</item></list>",
+        );
+        assert_eq!(keep, vec![(0, 0), (1, 1), (3, 2), (4, 3)]);
+        assert_eq!(kept(old, "<other/>").0, Vec::new());
+    }
+
+    #[test]
+    fn test_merge_inserts_before_existing_siblings() {
+        let db = crate::testing::open();
+        let old = "<list><item>a</item><item>b<em>x</em></item></list>";
+        let document_id = crate::testing::load_xml(db, "list.xml", old);
+        let rows = |db| {
+            query(
+                db,
+                "SELECT id, tag_name, text_content, position, document_order FROM nodes
+                ORDER BY document_order",
+                &[],
+            )
+            .unwrap()
+            .rows
+        };
+        let before = rows(db);
+
+        let new = "<list><item>new</item><item>a</item><item>b<em>x</em></item></list>";
+        let nodes = parse_xml_to_nodes(new).unwrap();
+        let summary = merge_document(db, document_id, DocumentKind::Xml, None, &nodes).unwrap();
+        assert_eq!((summary.inserted, summary.deleted), (1, 0));
+
+        let after = rows(db);
+        assert_eq!(after.len(), 5);
+        assert_eq!(after[1][2], json!("new"));
+        assert_eq!(after[1][3], json!(1));
+        // The old items and the <em> keep their ids and move down by one
+        for (old_row, new_row) in before[1..].iter().zip(&after[2..]) {
+            assert_eq!(new_row[0], old_row[0]);
+            assert_eq!(new_row[4], json!(old_row[4].as_i64().unwrap() + 1));
+        }
+        assert_eq!(
+            (after[2][3].clone(), after[3][3].clone()),
+            (json!(2), json!(3))
+        );
+        assert_eq!(after[4][3], json!(1));
+
+        let first = query_value(
+            db,
+            "SELECT text_content FROM nodes WHERE id = (
+                SELECT id FROM css_select('item:first-child'))",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(first, Some(json!("new")));
+        let sql = crate::xpath::xpath_to_sql("//item[text() = 'new']/following-sibling::item[1]")
+            .unwrap();
+        let next = query(db, &sql, &[]).unwrap().rows;
+        assert_eq!(next[0][0], before[1][0]);
+    }
+
+    #[test]
+    fn test_merge_rewrites_whitespace_and_comments() {
+        let db = crate::testing::open();
+        let old = "<list>\n  <item>a</item>\n  <item>b</item>\n</list>";
+        let document_id = crate::testing::load_xml(db, "list.xml", old);
+        let ids = |db| {
+            query(db, "SELECT id FROM nodes ORDER BY document_order", &[])
+                .unwrap()
+                .rows
+        };
+        let before = ids(db);
+
+        let new = "<?keep this?><list><!-- first --><item>a</item><item>b</item></list>";
+        let nodes = parse_xml_to_nodes(new).unwrap();
+        let summary = merge_document(db, document_id, DocumentKind::Xml, None, &nodes).unwrap();
+        assert_eq!(
+            (
+                summary.kept,
+                summary.updated,
+                summary.inserted,
+                summary.deleted
+            ),
+            (2, 1, 0, 0)
+        );
+        assert_eq!(ids(db), before);
+        let options = crate::c14n::C14nOptions {
+            with_comments: true,
+            ..Default::default()
+        };
+        let root = before[0][0].as_i64().unwrap();
+        assert_eq!(
+            crate::c14n::canonicalize(db, root, &options)
+                .unwrap()
+                .unwrap(),
+            "<?keep this?>\n<list><!-- first --><item>a</item><item>b</item></list>"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Stylesheet-like rule matching: which named selectors apply to each node
+//! of a document, most specific first.
+
+use crate::database::query;
+use crate::selector::{css_to_node_sql, specificity, DocumentFilter, SelectorScope, Specificity};
+use serde::{Deserialize, Serialize};
+use sqlite_wasm_rs::sqlite3;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    pub selector: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    pub name: String,
+    pub selector: String,
+    /// `[ids, classes, types]`
+    pub specificity: [u32; 3],
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeRules {
+    pub node_id: i64,
+    pub tag_name: String,
+    pub rules: Vec<RuleMatch>,
+}
+
+/// A node's tag name and the (specificity, rule index) pairs that match it.
+type Matches = (String, Vec<(Specificity, usize)>);
+
+/// Evaluates every rule against `document_id` and groups the matches by
+/// node, in document order. Each node's rules are sorted by descending
+/// specificity; on a tie the later rule comes first, as it would win in the
+/// cascade. Nodes that no rule matches are left out.
+pub fn match_rules(
+    db: *mut sqlite3,
+    document_id: i64,
+    rules: &[Rule],
+) -> Result<Vec<NodeRules>, String> {
+    let scope = SelectorScope {
+        document: Some(DocumentFilter::Id(document_id)),
+        ..SelectorScope::default()
+    };
+
+    // Keyed by (document_order, id): ids follow insertion, not the document
+    let mut nodes: BTreeMap<(i64, i64), Matches> = BTreeMap::new();
+    for (index, rule) in rules.iter().enumerate() {
+        let with_rule = |e: String| format!("Rule '{}': {}", rule.name, e);
+        let rank = specificity(&rule.selector).map_err(with_rule)?;
+        let sql = css_to_node_sql(&rule.selector, &scope).map_err(with_rule)?;
+        let result = query(
+            db,
+            &format!("SELECT id, tag_name, document_order FROM ({}) matched", sql),
+            &[],
+        )
+        .map_err(with_rule)?;
+        for row in result.rows {
+            let node_id = row[0].as_i64().unwrap_or_default();
+            let tag_name = row[1].as_str().unwrap_or_default().to_string();
+            let order = row[2].as_i64().unwrap_or_default();
+            nodes
+                .entry((order, node_id))
+                .or_insert_with(|| (tag_name, Vec::new()))
+                .1
+                .push((rank, index));
+        }
+    }
+
+    Ok(nodes
+        .into_iter()
+        .map(|((_, node_id), (tag_name, mut matched))| {
+            matched.sort_by(|a, b| b.cmp(a));
+            NodeRules {
+                node_id,
+                tag_name,
+                rules: matched
+                    .into_iter()
+                    .map(|(rank, index)| RuleMatch {
+                        name: rules[index].name.clone(),
+                        selector: rules[index].selector.clone(),
+                        specificity: [rank.0, rank.1, rank.2],
+                    })
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DocumentKind;
+    use crate::parser::parse_xml_to_nodes;
+    use crate::reload::merge_document;
+    use crate::testing::{load_xml, open};
+
+    fn rule(selector: &str, name: &str) -> Rule {
+        Rule {
+            selector: selector.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_match_rules_in_document_order() {
+        let db = open();
+        let document_id = load_xml(
+            db,
+            "list.xml",
+            r#"<list><item class="a">one</item><item>two</item></list>"#,
+        );
+        let rules = [
+            rule("item", "item"),
+            rule(".a", "first"),
+            rule("list > item", "child"),
+        ];
+        let matched = match_rules(db, document_id, &rules).unwrap();
+        assert_eq!(matched.len(), 2);
+        let names: Vec<&str> = matched[0].rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["first", "child", "item"]);
+        assert_eq!(matched[0].rules[0].specificity, [0, 1, 0]);
+
+        let nodes = parse_xml_to_nodes(
+            r#"<list><item>new</item><item class="a">one</item><item>two</item></list>"#,
+        )
+        .unwrap();
+        merge_document(db, document_id, DocumentKind::Xml, None, &nodes).unwrap();
+        let matched = match_rules(db, document_id, &[rule("item", "item")]).unwrap();
+        let ids: Vec<i64> = matched.iter().map(|m| m.node_id).collect();
+        let in_order: Vec<i64> = query(
+            db,
+            "SELECT id FROM nodes WHERE document_id = ?1 AND tag_name = 'item' ORDER BY document_order",
+            &[serde_json::json!(document_id)],
+        )
+        .unwrap()
+        .rows
+        .iter()
+        .filter_map(|row| row[0].as_i64())
+        .collect();
+        assert_eq!(ids, in_order);
+        // The inserted item comes first but was stored last
+        assert!(ids[0] > ids[2]);
+    }
+}
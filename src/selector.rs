@@ -18,14 +18,81 @@ impl fmt::Display for SelectorError {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     TagName(String),
+    /// `*` or `*|*`: any element
+    Universal,
+    /// `ns|` or `|` before a tag name or `*`; `*|` emits nothing
+    Namespace(NamespacePrefix),
     Class(String),
     Id(String),
     Attribute {
         name: String,
+        namespace: NamespacePrefix,
         value: Option<String>,
         operator: AttributeOperator,
+        case: CaseFlag,
     },
     Combinator(Combinator),
+    PseudoClass(PseudoClass),
+    PseudoElement(PseudoElement),
+}
+
+/// Scrapy-style extraction suffix that changes the result columns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PseudoElement {
+    Text,         // ::text
+    InnerText,    // ::inner-text
+    Attr(String), // ::attr(name)
+    Outer,        // ::outer
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PseudoClass {
+    MatchesText(String), // :matches-text(/regex/)
+    Scope,               // :scope
+    FirstChild,          // :first-child
+    LastChild,           // :last-child
+    OnlyChild,           // :only-child
+    Contains(String),    // :contains("text")
+    IContains(String),   // :icontains("text")
+    Empty,               // :empty
+    Blank,               // :blank
+    Root,                // :root
+    Lang(Vec<String>),   // :lang(en, fr)
+    NthChild(i64, i64),  // :nth-child(An+B)
+}
+
+/// Namespace part of a type or attribute selector, compared with the
+/// prefix stored for the element or attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamespacePrefix {
+    /// No prefix written, or `*|`: any namespace
+    Any,
+    /// `|name`: no prefix
+    Unprefixed,
+    /// `ns|name`
+    Prefix(String),
+}
+
+impl NamespacePrefix {
+    /// SQL condition on a `prefix` column, or `None` when anything matches.
+    fn condition(&self, column: &str) -> Option<String> {
+        match self {
+            NamespacePrefix::Any => None,
+            NamespacePrefix::Unprefixed => Some(format!("{} IS NULL", column)),
+            NamespacePrefix::Prefix(prefix) => {
+                Some(format!("{} = '{}'", column, escape_sql(prefix)))
+            }
+        }
+    }
+}
+
+/// The optional `i`/`s` flag of an attribute selector.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseFlag {
+    /// No flag: case-sensitive, except for some attributes on HTML documents
+    Default,
+    Insensitive, // [attr=value i]
+    Sensitive,   // [attr=value s]
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -36,6 +103,18 @@ pub enum Combinator {
     GeneralSibling, // "~"
 }
 
+impl fmt::Display for Combinator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Combinator::Descendant => "' '",
+            Combinator::Child => "'>'",
+            Combinator::NextSibling => "'+'",
+            Combinator::GeneralSibling => "'~'",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AttributeOperator {
     Exists,     // [attr]
@@ -44,11 +123,161 @@ pub enum AttributeOperator {
     StartsWith, // [attr^=value]
     EndsWith,   // [attr$=value]
     WordMatch,  // [attr~=value]
+    DashMatch,  // [attr|=value]
+    Matches,    // [attr~/regex/]
+}
+
+impl AttributeOperator {
+    /// The operator as written in a selector, e.g. `^=`.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            AttributeOperator::Exists => "",
+            AttributeOperator::Equals => "=",
+            AttributeOperator::Contains => "*=",
+            AttributeOperator::StartsWith => "^=",
+            AttributeOperator::EndsWith => "$=",
+            AttributeOperator::WordMatch => "~=",
+            AttributeOperator::DashMatch => "|=",
+            AttributeOperator::Matches => "~/",
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<AttributeOperator> {
+        [
+            AttributeOperator::Exists,
+            AttributeOperator::Equals,
+            AttributeOperator::Contains,
+            AttributeOperator::StartsWith,
+            AttributeOperator::EndsWith,
+            AttributeOperator::WordMatch,
+            AttributeOperator::DashMatch,
+            AttributeOperator::Matches,
+        ]
+        .into_iter()
+        .find(|op| op.symbol() == symbol)
+    }
+}
+
+/// Selectors Level 4 attribute value matching, exposed to SQL as
+/// `css_attr_match(value, operator, expected, case_insensitive)`.
+///
+/// Substring operators never match an empty `expected`, and `~=` never
+/// matches a value containing whitespace. `Matches` is handled by `REGEXP`.
+pub fn attribute_matches(
+    operator: &AttributeOperator,
+    actual: &str,
+    expected: &str,
+    case_insensitive: bool,
+) -> bool {
+    let (actual, expected) = if case_insensitive {
+        (actual.to_ascii_lowercase(), expected.to_ascii_lowercase())
+    } else {
+        (actual.to_string(), expected.to_string())
+    };
+    match operator {
+        AttributeOperator::Exists => true,
+        AttributeOperator::Equals => actual == expected,
+        AttributeOperator::Contains => !expected.is_empty() && actual.contains(&expected),
+        AttributeOperator::StartsWith => !expected.is_empty() && actual.starts_with(&expected),
+        AttributeOperator::EndsWith => !expected.is_empty() && actual.ends_with(&expected),
+        AttributeOperator::WordMatch => {
+            !expected.is_empty()
+                && !expected.contains(is_css_whitespace)
+                && actual.split(is_css_whitespace).any(|word| word == expected)
+        }
+        AttributeOperator::DashMatch => {
+            actual == expected
+                || actual
+                    .strip_prefix(&expected)
+                    .is_some_and(|rest| rest.starts_with('-'))
+        }
+        AttributeOperator::Matches => false,
+    }
+}
+
+fn is_css_whitespace(ch: char) -> bool {
+    matches!(ch, ' ' | '\t' | '\n' | '\r' | '\x0C')
+}
+
+/// Selects which loaded documents a query runs against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocumentFilter {
+    Id(i64),
+    /// SQLite GLOB over document names, e.g. `books-*.xml`
+    NameGlob(String),
+}
+
+/// Restricts which nodes a selector may match, like `querySelectorAll`
+/// called on a document or element rather than globally.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SelectorScope {
+    /// Only match nodes of these documents.
+    pub document: Option<DocumentFilter>,
+    /// Only match descendants of this node, which is also what `:scope`
+    /// refers to. Without it `:scope` matches document roots.
+    pub context_node: Option<i64>,
+    /// Let the context node itself match as well as its descendants, like
+    /// Scrapy's `.css()` on a selection.
+    pub include_context: bool,
 }
 
 pub fn css_to_sql(selector: &str) -> Result<String, String> {
+    css_to_sql_scoped(selector, &SelectorScope::default())
+}
+
+/// Compiles `selector` with matches limited to `scope`. A selector that
+/// starts with a combinator (`> li`) is relative to `:scope`.
+///
+/// Rows end with a `document_name` column so results from several loaded
+/// files can be told apart.
+pub fn css_to_sql_scoped(selector: &str, scope: &SelectorScope) -> Result<String, String> {
     let tokens = tokenize(selector)?;
-    generate_sql(&tokens)
+    generate_sql(&tokens, scope, true)
+}
+
+/// Like `css_to_sql_scoped`, but rejects pseudo-elements so the result
+/// always has the columns of the `nodes` table.
+pub fn css_to_node_sql(selector: &str, scope: &SelectorScope) -> Result<String, String> {
+    let tokens = tokenize(selector)?;
+    if tokens.iter().any(|t| matches!(t, Token::PseudoElement(_))) {
+        return Err("Pseudo-elements are not allowed here".to_string());
+    }
+    generate_sql(&tokens, scope, false)
+}
+
+/// Compiles `selector` to `id` plus one value column per match: the
+/// pseudo-element's value (`a::attr(href)`), or the element's inner text
+/// when there is none.
+pub fn css_to_value_sql(selector: &str, scope: &SelectorScope) -> Result<String, String> {
+    let tokens = tokenize(selector)?;
+    let sql = generate_sql(&tokens, scope, false)?;
+    if matches!(tokens.last(), Some(Token::PseudoElement(_))) {
+        Ok(sql)
+    } else {
+        Ok(format!(
+            "SELECT id, inner_text(id) AS value FROM ({}) matched",
+            sql
+        ))
+    }
+}
+
+/// CSS specificity: (ids, classes/attributes/pseudo-classes, types/pseudo-elements).
+/// Values compare in cascade order, so the most specific selector is the max.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity(pub u32, pub u32, pub u32);
+
+/// Computes the specificity of `selector`; `*` and combinators add nothing.
+pub fn specificity(selector: &str) -> Result<Specificity, String> {
+    let mut result = Specificity::default();
+    for token in tokenize(selector)? {
+        match token {
+            Token::Id(_) => result.0 += 1,
+            Token::Class(_) | Token::Attribute { .. } | Token::PseudoClass(_) => result.1 += 1,
+            Token::TagName(_) | Token::PseudoElement(_) => result.2 += 1,
+            Token::Universal | Token::Namespace(_) | Token::Combinator(_) => {}
+        }
+    }
+    Ok(result)
 }
 
 fn tokenize(selector: &str) -> Result<Vec<Token>, String> {
@@ -94,11 +323,17 @@ fn tokenize(selector: &str) -> Result<Vec<Token>, String> {
             '.' => {
                 chars.next();
                 let class_name = collect_identifier(&mut chars);
+                if class_name.is_empty() {
+                    return Err("Expected a class name after '.'".to_string());
+                }
                 tokens.push(Token::Class(class_name));
             }
             '#' => {
                 chars.next();
                 let id = collect_identifier(&mut chars);
+                if id.is_empty() {
+                    return Err("Expected an id after '#'".to_string());
+                }
                 tokens.push(Token::Id(id));
             }
             '[' => {
@@ -106,14 +341,24 @@ fn tokenize(selector: &str) -> Result<Vec<Token>, String> {
                 let attr_token = parse_attribute(&mut chars)?;
                 tokens.push(attr_token);
             }
-            _ if ch.is_alphabetic() || ch == '*' => {
-                let tag_name = collect_identifier(&mut chars);
-                if tag_name != "*" {
-                    tokens.push(Token::TagName(tag_name));
-                }
+            ':' => {
+                chars.next();
+                let pseudo_token = if chars.peek() == Some(&':') {
+                    chars.next();
+                    parse_pseudo_element(&mut chars)?
+                } else {
+                    parse_pseudo_class(&mut chars)?
+                };
+                tokens.push(pseudo_token);
+            }
+            _ if is_identifier_start(ch) || ch == '*' || ch == '|' => {
+                tokens.extend(parse_type_selector(&mut chars)?);
             }
             _ => {
-                return Err(format!("Unexpected character: {}", ch));
+                return Err(format!(
+                    "Unexpected character '{}': expected a tag name, '*', '.', '#', '[', ':' or a combinator",
+                    ch
+                ));
             }
         }
     }
@@ -121,10 +366,52 @@ fn tokenize(selector: &str) -> Result<Vec<Token>, String> {
     Ok(tokens)
 }
 
+/// Parses `tag`, `*`, or either one with a namespace prefix (`ns|`, `*|`,
+/// `|`). A prefix other than `*|` becomes a `Namespace` token before it.
+fn parse_type_selector(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut name = collect_name_or_star(chars);
+    if chars.peek() == Some(&'|') {
+        chars.next();
+        let namespace = match name.as_str() {
+            "*" => NamespacePrefix::Any,
+            "" => NamespacePrefix::Unprefixed,
+            _ => NamespacePrefix::Prefix(name),
+        };
+        if namespace != NamespacePrefix::Any {
+            tokens.push(Token::Namespace(namespace));
+        }
+        name = collect_name_or_star(chars);
+        if name.is_empty() {
+            return Err("Expected a tag name or '*' after '|'".to_string());
+        }
+    }
+    tokens.push(if name == "*" {
+        Token::Universal
+    } else {
+        Token::TagName(name)
+    });
+    Ok(tokens)
+}
+
+fn collect_name_or_star(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    if chars.peek() == Some(&'*') {
+        chars.next();
+        "*".to_string()
+    } else {
+        collect_identifier(chars)
+    }
+}
+
 fn collect_identifier(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
     let mut identifier = String::new();
     while let Some(&ch) = chars.peek() {
-        if ch.is_alphanumeric() || ch == '-' || ch == '_' {
+        if ch == '\\' {
+            chars.next();
+            identifier.push(consume_escape(chars));
+        } else if ch.is_alphanumeric() || ch == '-' || ch == '_' || !ch.is_ascii() {
             identifier.push(ch);
             chars.next();
         } else {
@@ -134,119 +421,464 @@ fn collect_identifier(chars: &mut std::iter::Peekable<std::str::Chars>) -> Strin
     identifier
 }
 
-fn parse_attribute(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Token, String> {
-    let name = collect_identifier(chars);
+fn is_identifier_start(ch: char) -> bool {
+    ch.is_alphabetic() || ch == '_' || ch == '-' || ch == '\\' || !ch.is_ascii()
+}
 
-    // Skip whitespace
-    while let Some(&ch) = chars.peek() {
-        if ch.is_whitespace() {
+/// Decodes a CSS escape after its backslash: up to six hex digits plus one
+/// optional whitespace character (`\31 23` is "123"), or any other
+/// character taken literally.
+fn consume_escape(chars: &mut std::iter::Peekable<std::str::Chars>) -> char {
+    let mut hex = String::new();
+    while hex.len() < 6 {
+        match chars.peek() {
+            Some(&ch) if ch.is_ascii_hexdigit() => {
+                hex.push(ch);
+                chars.next();
+            }
+            _ => break,
+        }
+    }
+    if hex.is_empty() {
+        return chars.next().unwrap_or('\u{FFFD}');
+    }
+    if chars.peek() == Some(&'\r') {
+        chars.next();
+        if chars.peek() == Some(&'\n') {
             chars.next();
+        }
+    } else if chars.peek().is_some_and(|&ch| is_css_whitespace(ch)) {
+        chars.next();
+    }
+    match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+        Some(ch) if ch != '\0' => ch,
+        _ => '\u{FFFD}',
+    }
+}
+
+/// Escapes `value` so it reads back as a single identifier, the way
+/// `CSS.escape` does.
+pub fn escape_identifier(value: &str) -> String {
+    let mut out = String::new();
+    for (i, ch) in value.chars().enumerate() {
+        let leading_digit = ch.is_ascii_digit() && (i == 0 || (i == 1 && value.starts_with('-')));
+        if ch == '\0' {
+            out.push('\u{FFFD}');
+        } else if leading_digit || ch.is_ascii_control() {
+            out.push_str(&format!("\\{:x} ", ch as u32));
+        } else if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' || !ch.is_ascii() {
+            if i == 0 && ch == '-' && value.len() == 1 {
+                out.push('\\');
+            }
+            out.push(ch);
         } else {
-            break;
+            out.push('\\');
+            out.push(ch);
         }
     }
+    out
+}
 
-    let (operator, value) = if let Some(&ch) = chars.peek() {
-        match ch {
-            ']' => {
-                chars.next();
-                return Ok(Token::Attribute {
-                    name,
-                    value: None,
-                    operator: AttributeOperator::Exists,
-                });
+/// Parses the `An+B` argument of `:nth-child`, including `odd` and `even`.
+fn parse_nth(arg: &str) -> Result<(i64, i64), String> {
+    let arg: String = arg.chars().filter(|ch| !ch.is_whitespace()).collect();
+    let arg = arg.to_ascii_lowercase();
+    let invalid = || format!("Invalid :nth-child argument '{}'", arg);
+    match arg.as_str() {
+        "odd" => return Ok((2, 1)),
+        "even" => return Ok((2, 0)),
+        _ => {}
+    }
+    let Some((a, b)) = arg.split_once('n') else {
+        return arg.parse().map(|b| (0, b)).map_err(|_| invalid());
+    };
+    let a = match a {
+        "" | "+" => 1,
+        "-" => -1,
+        _ => a.parse().map_err(|_| invalid())?,
+    };
+    let b = match b {
+        "" => 0,
+        _ if b.starts_with(['+', '-']) => b.parse().map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    };
+    Ok((a, b))
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.peek().is_some_and(|ch| ch.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// Parses the inside of `[...]` following Selectors Level 4:
+/// `[ns|name op value flag]`, where the value is a string or an unquoted
+/// run of characters (so `[data-x=1.5]` works) and the flag is `i` or `s`.
+fn parse_attribute(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Token, String> {
+    skip_whitespace(chars);
+    let (namespace, name) = parse_attribute_name(chars)?;
+    skip_whitespace(chars);
+
+    let mut regex = None;
+    let operator = match chars.next() {
+        Some(']') => {
+            return Ok(Token::Attribute {
+                name,
+                namespace,
+                value: None,
+                operator: AttributeOperator::Exists,
+                case: CaseFlag::Default,
+            });
+        }
+        Some('=') => AttributeOperator::Equals,
+        Some('~') if chars.peek() == Some(&'/') => {
+            regex = Some(parse_regex_literal(chars)?);
+            AttributeOperator::Matches
+        }
+        Some(op @ ('~' | '|' | '^' | '$' | '*')) => {
+            if chars.next() != Some('=') {
+                return Err(format!("Expected '=' after '{}' in attribute selector", op));
             }
-            '=' | '~' | '^' | '$' | '*' => {
-                let op_char = ch;
-                chars.next();
+            AttributeOperator::from_symbol(&format!("{}=", op)).unwrap()
+        }
+        Some(ch) => {
+            return Err(format!(
+                "Unexpected character '{}' in attribute selector: expected ']' or an operator (=, ~=, |=, ^=, $=, *=)",
+                ch
+            ))
+        }
+        None => return Err("Expected ']' to close the attribute selector".to_string()),
+    };
+
+    skip_whitespace(chars);
+    let value = match (regex, chars.peek()) {
+        (Some(pattern), _) => pattern,
+        (None, Some(&quote)) if quote == '"' || quote == '\'' => {
+            chars.next();
+            parse_string(chars, quote)?
+        }
+        (None, Some(&ch)) if ch != ']' => collect_unquoted_value(chars),
+        _ => {
+            return Err(format!(
+                "Expected an attribute value after '{}'",
+                operator.symbol()
+            ))
+        }
+    };
+
+    skip_whitespace(chars);
+    let case = match chars.peek() {
+        Some('i' | 'I') => {
+            chars.next();
+            CaseFlag::Insensitive
+        }
+        Some('s' | 'S') => {
+            chars.next();
+            CaseFlag::Sensitive
+        }
+        _ => CaseFlag::Default,
+    };
+    skip_whitespace(chars);
+
+    match chars.next() {
+        Some(']') => Ok(Token::Attribute {
+            name,
+            namespace,
+            value: Some(value),
+            operator,
+            case,
+        }),
+        Some(ch) => Err(format!(
+            "Unexpected character '{}' in attribute selector: expected ']' or an 'i'/'s' flag",
+            ch
+        )),
+        None => Err("Expected ']' to close the attribute selector".to_string()),
+    }
+}
+
+/// Attribute name with an optional `ns|`, `*|` or `|` prefix.
+fn parse_attribute_name(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<(NamespacePrefix, String), String> {
+    let any = chars.peek() == Some(&'*');
+    let mut name = if any {
+        chars.next();
+        if chars.peek() != Some(&'|') {
+            return Err("Expected '|' after '*' in attribute name".to_string());
+        }
+        String::new()
+    } else {
+        collect_identifier(chars)
+    };
+
+    // `|` starts a namespace separator unless it is the `|=` operator
+    let mut namespace = NamespacePrefix::Any;
+    let mut lookahead = chars.clone();
+    if lookahead.next() == Some('|') && lookahead.next() != Some('=') {
+        chars.next();
+        if !any {
+            namespace = if name.is_empty() {
+                NamespacePrefix::Unprefixed
+            } else {
+                NamespacePrefix::Prefix(name)
+            };
+        }
+        name = collect_identifier(chars);
+    }
 
-                let operator = if chars.peek() == Some(&'=') {
+    if name.is_empty() {
+        return Err("Expected an attribute name after '['".to_string());
+    }
+    Ok((namespace, name))
+}
+
+/// Reads a quoted string after its opening quote, decoding escapes.
+fn parse_string(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    quote: char,
+) -> Result<String, String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some(ch) if ch == quote => return Ok(value),
+            Some('\\') => match chars.peek() {
+                // An escaped newline continues the string
+                Some('\n') => {
                     chars.next();
-                    match op_char {
-                        '~' => AttributeOperator::WordMatch,
-                        '^' => AttributeOperator::StartsWith,
-                        '$' => AttributeOperator::EndsWith,
-                        '*' => AttributeOperator::Contains,
-                        _ => AttributeOperator::Equals,
-                    }
-                } else if op_char == '=' {
-                    AttributeOperator::Equals
-                } else {
-                    return Err(format!("Invalid attribute operator"));
-                };
+                }
+                Some(_) => value.push(consume_escape(chars)),
+                None => {}
+            },
+            Some('\n') => return Err("Unexpected newline in string".to_string()),
+            Some(ch) => value.push(ch),
+            None => return Err(format!("Expected closing {} to end the string", quote)),
+        }
+    }
+}
 
-                // Skip whitespace
+fn collect_unquoted_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut value = String::new();
+    while let Some(&ch) = chars.peek() {
+        if ch == ']' || ch.is_whitespace() {
+            break;
+        }
+        chars.next();
+        if ch == '\\' {
+            value.push(consume_escape(chars));
+        } else {
+            value.push(ch);
+        }
+    }
+    value
+}
+
+fn parse_pseudo_class(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Token, String> {
+    let name = collect_identifier(chars);
+    if name.is_empty() {
+        return Err("Expected pseudo-class name after ':'".to_string());
+    }
+
+    match name.as_str() {
+        "scope" => Ok(Token::PseudoClass(PseudoClass::Scope)),
+        "first-child" => Ok(Token::PseudoClass(PseudoClass::FirstChild)),
+        "last-child" => Ok(Token::PseudoClass(PseudoClass::LastChild)),
+        "only-child" => Ok(Token::PseudoClass(PseudoClass::OnlyChild)),
+        "nth-child" => {
+            let args = parse_pseudo_arguments(chars, &name, false)?;
+            let (a, b) = parse_nth(&args[0])?;
+            Ok(Token::PseudoClass(PseudoClass::NthChild(a, b)))
+        }
+        "empty" => Ok(Token::PseudoClass(PseudoClass::Empty)),
+        "blank" => Ok(Token::PseudoClass(PseudoClass::Blank)),
+        "root" => Ok(Token::PseudoClass(PseudoClass::Root)),
+        "contains" | "icontains" => {
+            let mut args = parse_pseudo_arguments(chars, &name, false)?;
+            let text = args.remove(0);
+            Ok(Token::PseudoClass(if name == "contains" {
+                PseudoClass::Contains(text)
+            } else {
+                PseudoClass::IContains(text)
+            }))
+        }
+        "lang" => Ok(Token::PseudoClass(PseudoClass::Lang(
+            parse_pseudo_arguments(chars, &name, true)?,
+        ))),
+        "matches-text" => {
+            if chars.next() != Some('(') {
+                return Err(format!(":{} requires a /regex/ argument", name));
+            }
+            let pattern = parse_regex_literal(chars)?;
+            if chars.next() != Some(')') {
+                return Err(format!("Expected ')' to close :{}", name));
+            }
+            Ok(Token::PseudoClass(PseudoClass::MatchesText(pattern)))
+        }
+        _ => Err(format!("Unsupported pseudo-class: :{}", name)),
+    }
+}
+
+/// Reads the parenthesized argument of a functional pseudo-class.
+///
+/// Arguments may be quoted or bare; bare text runs to the closing `)` (or to
+/// a `,` when `list` is set) with surrounding whitespace trimmed.
+fn parse_pseudo_arguments(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    name: &str,
+    list: bool,
+) -> Result<Vec<String>, String> {
+    if chars.next() != Some('(') {
+        return Err(format!(
+            ":{0} requires an argument, e.g. :{0}(\"text\")",
+            name
+        ));
+    }
+    let mut args = Vec::new();
+    loop {
+        skip_whitespace(chars);
+        let value = match chars.peek() {
+            Some(&quote) if quote == '"' || quote == '\'' => {
+                chars.next();
+                parse_string(chars, quote)?
+            }
+            _ => {
+                let mut value = String::new();
                 while let Some(&ch) = chars.peek() {
-                    if ch.is_whitespace() {
-                        chars.next();
-                    } else {
+                    if ch == ')' || (list && ch == ',') {
                         break;
                     }
-                }
-
-                // Parse value (can be quoted or unquoted)
-                let value = if let Some(&quote) = chars.peek() {
-                    if quote == '"' || quote == '\'' {
-                        chars.next();
-                        let mut val = String::new();
-                        while let Some(&ch) = chars.peek() {
-                            if ch == quote {
-                                chars.next();
-                                break;
-                            }
-                            val.push(ch);
-                            chars.next();
-                        }
-                        val
+                    chars.next();
+                    if ch == '\\' {
+                        value.push(consume_escape(chars));
                     } else {
-                        collect_identifier(chars)
+                        value.push(ch);
                     }
-                } else {
-                    return Err("Expected attribute value".to_string());
-                };
+                }
+                let value = value.trim_end().to_string();
+                if value.is_empty() {
+                    return Err(format!("Expected an argument for :{}", name));
+                }
+                value
+            }
+        };
+        args.push(value);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(')') => return Ok(args),
+            Some(',') if list => {}
+            _ => return Err(format!("Expected ')' to close :{}", name)),
+        }
+    }
+}
 
-                (operator, Some(value))
+fn parse_pseudo_element(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Token, String> {
+    let name = collect_identifier(chars);
+    let element = match name.as_str() {
+        "text" => PseudoElement::Text,
+        "inner-text" => PseudoElement::InnerText,
+        "outer" => PseudoElement::Outer,
+        "attr" => {
+            if chars.next() != Some('(') {
+                return Err("::attr requires an attribute name, e.g. ::attr(href)".to_string());
             }
-            _ => {
-                return Err(format!(
-                    "Unexpected character in attribute selector: {}",
-                    ch
-                ))
+            let mut attr_name = String::new();
+            loop {
+                match chars.next() {
+                    Some(')') => break,
+                    Some(ch) => attr_name.push(ch),
+                    None => return Err("Expected ')' to close ::attr".to_string()),
+                }
             }
+            let attr_name = attr_name.trim();
+            if attr_name.is_empty() {
+                return Err("::attr requires an attribute name, e.g. ::attr(href)".to_string());
+            }
+            PseudoElement::Attr(attr_name.to_string())
         }
-    } else {
-        return Err("Unexpected end of attribute selector".to_string());
+        "" => return Err("Expected pseudo-element name after '::'".to_string()),
+        _ => return Err(format!("Unsupported pseudo-element: ::{}", name)),
     };
+    Ok(Token::PseudoElement(element))
+}
 
-    // Expect closing bracket
-    if chars.next() != Some(']') {
-        return Err("Expected closing bracket".to_string());
+/// Parses a `/pattern/flags` literal into a pattern string for `REGEXP`.
+///
+/// `\/` inside the literal stands for a slash; other escapes are passed
+/// through to the regex engine. Flags become an inline group, e.g. `(?i)`.
+fn parse_regex_literal(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    if chars.next() != Some('/') {
+        return Err("Expected '/' to start a regular expression".to_string());
     }
 
-    Ok(Token::Attribute {
-        name,
-        value,
-        operator,
-    })
+    let mut pattern = String::new();
+    loop {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('/') => pattern.push('/'),
+                Some(escaped) => {
+                    pattern.push('\\');
+                    pattern.push(escaped);
+                }
+                None => return Err("Unterminated regular expression".to_string()),
+            },
+            Some('/') => break,
+            Some(ch) => pattern.push(ch),
+            None => return Err("Unterminated regular expression".to_string()),
+        }
+    }
+
+    let mut flags = String::new();
+    while let Some(&ch) = chars.peek() {
+        if !ch.is_alphabetic() {
+            break;
+        }
+        if !"imsx".contains(ch) {
+            return Err(format!("Unsupported regular expression flag: {}", ch));
+        }
+        flags.push(ch);
+        chars.next();
+    }
+    if !flags.is_empty() {
+        pattern = format!("(?{}){}", flags, pattern);
+    }
+
+    regex_lite::Regex::new(&pattern)
+        .map_err(|e| format!("Invalid regular expression /{}/: {}", pattern, e))?;
+    Ok(pattern)
 }
 
-fn generate_sql(tokens: &[Token]) -> Result<String, String> {
-    if tokens.is_empty() {
+fn generate_sql(
+    tokens: &[Token],
+    scope: &SelectorScope,
+    with_document_name: bool,
+) -> Result<String, String> {
+    if tokens.is_empty() && *scope == SelectorScope::default() && !with_document_name {
         return Ok("SELECT * FROM nodes".to_string());
     }
 
+    // `> li` is shorthand for `:scope > li`
+    let relative;
+    let tokens = if matches!(tokens.first(), Some(Token::Combinator(_))) {
+        relative = [&[Token::PseudoClass(PseudoClass::Scope)], tokens].concat();
+        &relative[..]
+    } else {
+        tokens
+    };
+
     let mut sql_joins = String::from("FROM nodes n1");
     let mut join_count = 1;
     let mut where_clauses = Vec::new();
     let mut current_table = "n1".to_string();
+    let mut projection = None;
+    // Set when a condition depends on the document kind (`dk.kind`)
+    let mut uses_kind = false;
 
     for (i, token) in tokens.iter().enumerate() {
         match token {
             Token::TagName(tag) => {
-                where_clauses.push(format!(
-                    "{}.tag_name = '{}'",
-                    current_table,
-                    escape_sql(tag)
+                where_clauses.push(name_condition(
+                    &format!("{}.tag_name", current_table),
+                    tag,
+                    &mut uses_kind,
                 ));
             }
             Token::Class(class) => {
@@ -257,12 +889,11 @@ fn generate_sql(tokens: &[Token]) -> Result<String, String> {
                     attr_alias, attr_alias, current_table
                 ));
                 where_clauses.push(format!("{}.name = 'class'", attr_alias));
-                where_clauses.push(format!(
-                    "({}.value = '{}' OR {}.value LIKE '% {}' OR {}.value LIKE '{} %' OR {}.value LIKE '% {} %')",
-                    attr_alias, escape_sql(class),
-                    attr_alias, escape_sql(class),
-                    attr_alias, escape_sql(class),
-                    attr_alias, escape_sql(class)
+                where_clauses.push(attribute_condition(
+                    &attr_alias,
+                    &AttributeOperator::WordMatch,
+                    class,
+                    "0",
                 ));
             }
             Token::Id(id) => {
@@ -277,8 +908,10 @@ fn generate_sql(tokens: &[Token]) -> Result<String, String> {
             }
             Token::Attribute {
                 name,
+                namespace,
                 value,
                 operator,
+                case,
             } => {
                 join_count += 1;
                 let attr_alias = format!("a{}", join_count);
@@ -286,36 +919,183 @@ fn generate_sql(tokens: &[Token]) -> Result<String, String> {
                     "\nJOIN attributes {} ON {}.node_id = {}.id",
                     attr_alias, attr_alias, current_table
                 ));
-                where_clauses.push(format!("{}.name = '{}'", attr_alias, escape_sql(name)));
+                where_clauses.push(name_condition(
+                    &format!("{}.name", attr_alias),
+                    name,
+                    &mut uses_kind,
+                ));
+                where_clauses.extend(namespace.condition(&format!("{}.prefix", attr_alias)));
 
                 if let Some(val) = value {
-                    let condition = match operator {
-                        AttributeOperator::Exists => continue,
-                        AttributeOperator::Equals => {
-                            format!("{}.value = '{}'", attr_alias, escape_sql(val))
-                        }
-                        AttributeOperator::Contains => {
-                            format!("{}.value LIKE '%{}%'", attr_alias, escape_sql(val))
-                        }
-                        AttributeOperator::StartsWith => {
-                            format!("{}.value LIKE '{}%'", attr_alias, escape_sql(val))
-                        }
-                        AttributeOperator::EndsWith => {
-                            format!("{}.value LIKE '%{}'", attr_alias, escape_sql(val))
-                        }
-                        AttributeOperator::WordMatch => {
-                            format!(
-                                "({0}.value = '{1}' OR {0}.value LIKE '% {1}' OR {0}.value LIKE '{1} %' OR {0}.value LIKE '% {1} %')",
-                                attr_alias, escape_sql(val)
-                            )
+                    let case_sql = match case {
+                        CaseFlag::Insensitive => "1",
+                        CaseFlag::Sensitive => "0",
+                        CaseFlag::Default
+                            if HTML_CASE_INSENSITIVE_ATTRIBUTES
+                                .contains(&name.to_ascii_lowercase().as_str()) =>
+                        {
+                            uses_kind = true;
+                            HTML_DOCUMENT
                         }
+                        CaseFlag::Default => "0",
                     };
-                    where_clauses.push(condition);
+                    where_clauses.push(attribute_condition(&attr_alias, operator, val, case_sql));
+                }
+            }
+            Token::Universal => {}
+            Token::Namespace(namespace) => {
+                where_clauses.extend(namespace.condition(&format!("{}.prefix", current_table)));
+            }
+            Token::PseudoClass(
+                pseudo
+                @ (PseudoClass::FirstChild | PseudoClass::LastChild | PseudoClass::OnlyChild),
+            ) => {
+                // Earlier siblings have smaller positions
+                let mut sides = Vec::new();
+                if *pseudo != PseudoClass::LastChild {
+                    sides.push("<");
                 }
+                if *pseudo != PseudoClass::FirstChild {
+                    sides.push(">");
+                }
+                for side in sides {
+                    where_clauses.push(format!(
+                        "NOT EXISTS (SELECT 1 FROM nodes s WHERE s.parent_id = {0}.parent_id AND s.position {1} {0}.position)",
+                        current_table, side
+                    ));
+                }
+            }
+            Token::PseudoClass(PseudoClass::Scope) => {
+                where_clauses.push(match scope.context_node {
+                    Some(node) => format!("{}.id = {}", current_table, node),
+                    None => format!("{}.parent_id IS NULL", current_table),
+                });
+            }
+            Token::PseudoClass(PseudoClass::Contains(text)) => {
+                where_clauses.push(format!(
+                    "instr(inner_text({}.id), '{}') > 0",
+                    current_table,
+                    escape_sql(text)
+                ));
+            }
+            Token::PseudoClass(PseudoClass::IContains(text)) => {
+                where_clauses.push(format!(
+                    "instr(lower(inner_text({}.id)), lower('{}')) > 0",
+                    current_table,
+                    escape_sql(text)
+                ));
+            }
+            Token::PseudoClass(PseudoClass::Empty) => {
+                where_clauses.push(format!(
+                    "NOT EXISTS (SELECT 1 FROM segments s WHERE s.node_id = {0}.id AND s.kind = 'text') AND NOT EXISTS (SELECT 1 FROM nodes c WHERE c.parent_id = {0}.id)",
+                    current_table
+                ));
+            }
+            Token::PseudoClass(PseudoClass::Blank) => {
+                where_clauses.push(format!(
+                    "NOT EXISTS (SELECT 1 FROM segments s WHERE s.node_id = {0}.id AND s.kind = 'text' AND trim(s.value, char(32, 9, 10, 12, 13)) <> '') AND NOT EXISTS (SELECT 1 FROM nodes c WHERE c.parent_id = {0}.id)",
+                    current_table
+                ));
+            }
+            Token::PseudoClass(PseudoClass::NthChild(a, b)) => {
+                let index = format!("{}.position", current_table);
+                where_clauses.push(if *a == 0 {
+                    format!("{} = {}", index, b)
+                } else {
+                    format!(
+                        "(({0} - {1}) % {2} = 0 AND ({0} - {1}) / {2} >= 0)",
+                        index, b, a
+                    )
+                });
+            }
+            Token::PseudoClass(PseudoClass::Root) => {
+                where_clauses.push(format!("{}.parent_id IS NULL", current_table));
+            }
+            Token::PseudoClass(PseudoClass::Lang(ranges)) => {
+                // The nearest ancestor-or-self lang attribute decides; xml:lang
+                // and lang are treated alike
+                let matches = ranges
+                    .iter()
+                    .map(|range| {
+                        format!(
+                            "css_attr_match(lang.value, '|=', '{}', 1)",
+                            escape_sql(range)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" OR ");
+                where_clauses.push(format!(
+                    "(WITH RECURSIVE lang_chain(id, depth) AS (
+                        SELECT {0}.id, 0
+                        UNION ALL
+                        SELECT p.parent_id, c.depth + 1 FROM nodes p JOIN lang_chain c ON p.id = c.id
+                        WHERE p.parent_id IS NOT NULL
+                    )
+                    SELECT {1} FROM (
+                        SELECT a.value FROM lang_chain c
+                        JOIN attributes a ON a.node_id = c.id AND a.name IN ('lang', 'xml:lang')
+                        ORDER BY c.depth LIMIT 1
+                    ) lang)",
+                    current_table, matches
+                ));
+            }
+            Token::PseudoClass(PseudoClass::MatchesText(pattern)) => {
+                where_clauses.push(format!(
+                    "{}.text_content REGEXP '{}'",
+                    current_table,
+                    escape_sql(pattern)
+                ));
+            }
+            Token::PseudoElement(element) => {
+                if i + 1 != tokens.len() {
+                    return Err("Pseudo-elements must come at the end of the selector".to_string());
+                }
+                projection = Some(match element {
+                    PseudoElement::Text => {
+                        format!("{0}.id, {0}.text_content AS text", current_table)
+                    }
+                    PseudoElement::InnerText => {
+                        format!("{0}.id, inner_text({0}.id) AS inner_text", current_table)
+                    }
+                    PseudoElement::Outer => {
+                        format!("{0}.id, outer_xml({0}.id) AS outer", current_table)
+                    }
+                    PseudoElement::Attr(name) => {
+                        join_count += 1;
+                        let attr_alias = format!("a{}", join_count);
+                        sql_joins.push_str(&format!(
+                            "\nJOIN attributes {0} ON {0}.node_id = {1}.id AND {2}",
+                            attr_alias,
+                            current_table,
+                            name_condition(&format!("{}.name", attr_alias), name, &mut uses_kind)
+                        ));
+                        format!(
+                            "{}.id, {}.value AS \"{}\"",
+                            current_table,
+                            attr_alias,
+                            name.replace('"', "\"\"")
+                        )
+                    }
+                });
             }
             Token::Combinator(combinator) => {
-                if i + 1 >= tokens.len() {
-                    return Err("Combinator must be followed by a selector".to_string());
+                match tokens.get(i + 1) {
+                    None => {
+                        return Err(format!("Expected a selector after {}", combinator));
+                    }
+                    Some(Token::Combinator(next)) => {
+                        return Err(format!(
+                            "Expected a selector between {} and {}",
+                            combinator, next
+                        ));
+                    }
+                    Some(Token::PseudoElement(_)) => {
+                        return Err(format!(
+                            "Expected a selector before the pseudo-element after {}",
+                            combinator
+                        ));
+                    }
+                    _ => {}
                 }
 
                 join_count += 1;
@@ -344,10 +1124,16 @@ fn generate_sql(tokens: &[Token]) -> Result<String, String> {
                         ));
                     }
                     Combinator::NextSibling => {
-                        return Err("Next sibling combinator (+) not yet supported".to_string());
+                        sql_joins.push_str(&format!(
+                            "\nJOIN nodes {0} ON {0}.parent_id = {1}.parent_id AND {0}.position = {1}.position + 1",
+                            next_table, current_table
+                        ));
                     }
                     Combinator::GeneralSibling => {
-                        return Err("General sibling combinator (~) not yet supported".to_string());
+                        sql_joins.push_str(&format!(
+                            "\nJOIN nodes {0} ON {0}.parent_id = {1}.parent_id AND {0}.position > {1}.position",
+                            next_table, current_table
+                        ));
                     }
                 }
 
@@ -356,7 +1142,58 @@ fn generate_sql(tokens: &[Token]) -> Result<String, String> {
         }
     }
 
-    let mut sql = format!("SELECT DISTINCT {}.*\n{}", current_table, sql_joins);
+    match &scope.document {
+        Some(DocumentFilter::Id(id)) => {
+            where_clauses.push(format!("{}.document_id = {}", current_table, id));
+        }
+        Some(DocumentFilter::NameGlob(pattern)) => {
+            where_clauses.push(format!(
+                "{}.document_id IN (SELECT id FROM documents WHERE name GLOB '{}')",
+                current_table,
+                escape_sql(pattern)
+            ));
+        }
+        None => {}
+    }
+    if let Some(node) = scope.context_node {
+        let start = if scope.include_context {
+            "id"
+        } else {
+            "parent_id"
+        };
+        where_clauses.push(format!(
+            "{}.id IN (
+    WITH RECURSIVE scope_descendants AS (
+        SELECT id FROM nodes WHERE {} = {}
+        UNION ALL
+        SELECT n.id FROM nodes n
+        JOIN scope_descendants d ON n.parent_id = d.id
+    )
+    SELECT id FROM scope_descendants
+)",
+            current_table, start, node
+        ));
+    }
+
+    let mut projection = projection.unwrap_or_else(|| format!("{}.*", current_table));
+    if with_document_name {
+        if !projection.ends_with(".*") {
+            projection.push_str(&format!(", {}.document_id", current_table));
+        }
+        projection.push_str(", doc.name AS document_name");
+        sql_joins.push_str(&format!(
+            "\nJOIN documents doc ON doc.id = {}.document_id",
+            current_table
+        ));
+    }
+    if uses_kind {
+        sql_joins = sql_joins.replacen(
+            "FROM nodes n1",
+            "FROM nodes n1\nJOIN documents dk ON dk.id = n1.document_id",
+            1,
+        );
+    }
+    let mut sql = format!("SELECT DISTINCT {}\n{}", projection, sql_joins);
 
     if !where_clauses.is_empty() {
         sql.push_str("\nWHERE ");
@@ -366,6 +1203,109 @@ fn generate_sql(tokens: &[Token]) -> Result<String, String> {
     Ok(sql)
 }
 
+/// True when the matched node's document was loaded as HTML.
+const HTML_DOCUMENT: &str = "(dk.kind = 'html')";
+
+/// Attributes whose values HTML matches ASCII case-insensitively when the
+/// selector has no `i`/`s` flag (HTML Standard, "Case-sensitivity of selectors").
+const HTML_CASE_INSENSITIVE_ATTRIBUTES: &[&str] = &[
+    "accept",
+    "accept-charset",
+    "align",
+    "alink",
+    "axis",
+    "bgcolor",
+    "charset",
+    "checked",
+    "clear",
+    "codetype",
+    "color",
+    "compact",
+    "declare",
+    "defer",
+    "dir",
+    "direction",
+    "disabled",
+    "enctype",
+    "face",
+    "frame",
+    "hreflang",
+    "http-equiv",
+    "lang",
+    "language",
+    "link",
+    "media",
+    "method",
+    "multiple",
+    "nohref",
+    "noresize",
+    "noshade",
+    "nowrap",
+    "readonly",
+    "rel",
+    "rev",
+    "rules",
+    "scope",
+    "scrolling",
+    "selected",
+    "shape",
+    "target",
+    "text",
+    "type",
+    "valign",
+    "valuetype",
+    "vlink",
+];
+
+/// Compares a tag or attribute name column. Names are exact on XML
+/// documents and ASCII case-insensitive on HTML ones; html5ever stores HTML
+/// names lowercased, so only selectors written with capitals need the
+/// HTML fallback.
+fn name_condition(column: &str, name: &str, uses_kind: &mut bool) -> String {
+    if !name.chars().any(|c| c.is_ascii_uppercase()) {
+        return format!("{} = '{}'", column, escape_sql(name));
+    }
+    *uses_kind = true;
+    format!(
+        "({0} = '{1}' OR ({2} AND {0} = '{3}'))",
+        column,
+        escape_sql(name),
+        HTML_DOCUMENT,
+        escape_sql(&name.to_ascii_lowercase())
+    )
+}
+
+/// `case_sql` is a SQL boolean: `0`, `1` or a document-kind check.
+fn attribute_condition(
+    attr_alias: &str,
+    operator: &AttributeOperator,
+    value: &str,
+    case_sql: &str,
+) -> String {
+    match operator {
+        AttributeOperator::Exists => "1".to_string(),
+        AttributeOperator::Equals if case_sql == "0" => {
+            format!("{}.value = '{}'", attr_alias, escape_sql(value))
+        }
+        AttributeOperator::Matches => {
+            let flags = if case_sql == "1" { "(?i)" } else { "" };
+            format!(
+                "{}.value REGEXP '{}{}'",
+                attr_alias,
+                flags,
+                escape_sql(value)
+            )
+        }
+        _ => format!(
+            "css_attr_match({}.value, '{}', '{}', {})",
+            attr_alias,
+            operator.symbol(),
+            escape_sql(value),
+            case_sql
+        ),
+    }
+}
+
 fn escape_sql(s: &str) -> String {
     s.replace("'", "''")
 }
@@ -399,8 +1339,10 @@ mod tests {
             tokens,
             vec![Token::Attribute {
                 name: "data-id".to_string(),
+                namespace: NamespacePrefix::Any,
                 value: None,
                 operator: AttributeOperator::Exists,
+                case: CaseFlag::Default,
             }]
         );
     }
@@ -412,8 +1354,10 @@ mod tests {
             tokens,
             vec![Token::Attribute {
                 name: "href".to_string(),
+                namespace: NamespacePrefix::Any,
                 value: Some("#".to_string()),
                 operator: AttributeOperator::Equals,
+                case: CaseFlag::Default,
             }]
         );
     }
@@ -427,6 +1371,314 @@ mod tests {
         assert!(matches!(tokens[2], Token::Combinator(Combinator::Child)));
     }
 
+    #[test]
+    fn test_tokenize_universal() {
+        assert_eq!(
+            tokenize("ul > *:first-child").unwrap(),
+            vec![
+                Token::TagName("ul".to_string()),
+                Token::Combinator(Combinator::Child),
+                Token::Universal,
+                Token::PseudoClass(PseudoClass::FirstChild),
+            ]
+        );
+        assert_eq!(
+            tokenize("ns|*").unwrap(),
+            vec![
+                Token::Namespace(NamespacePrefix::Prefix("ns".to_string())),
+                Token::Universal
+            ]
+        );
+        assert_eq!(tokenize("*|*").unwrap(), vec![Token::Universal]);
+        assert_eq!(
+            tokenize("svg|rect").unwrap(),
+            vec![
+                Token::Namespace(NamespacePrefix::Prefix("svg".to_string())),
+                Token::TagName("rect".to_string())
+            ]
+        );
+        assert_eq!(
+            tokenize("|rect").unwrap(),
+            vec![
+                Token::Namespace(NamespacePrefix::Unprefixed),
+                Token::TagName("rect".to_string())
+            ]
+        );
+        assert_eq!(tokenize("*[lang]").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_css_to_sql_universal_and_siblings() {
+        let sql = css_to_sql("div > *").unwrap();
+        assert!(sql.starts_with("SELECT DISTINCT n2.*"));
+        assert!(!sql.contains("n2.tag_name"));
+        assert!(css_to_sql("* + p")
+            .unwrap()
+            .contains("n2.position = n1.position + 1"));
+        assert!(css_to_sql("h1 ~ p")
+            .unwrap()
+            .contains("n2.position > n1.position"));
+    }
+
+    #[test]
+    fn test_tokenize_text_pseudo_classes() {
+        assert_eq!(
+            tokenize("button:contains(\"Submit\")").unwrap()[1],
+            Token::PseudoClass(PseudoClass::Contains("Submit".to_string()))
+        );
+        assert_eq!(
+            tokenize("p:icontains( Hello, world )").unwrap()[1],
+            Token::PseudoClass(PseudoClass::IContains("Hello, world".to_string()))
+        );
+        assert_eq!(
+            tokenize("p:lang(en, 'fr-CA')").unwrap()[1],
+            Token::PseudoClass(PseudoClass::Lang(vec![
+                "en".to_string(),
+                "fr-CA".to_string()
+            ]))
+        );
+        assert_eq!(
+            tokenize(":root > :empty:blank").unwrap(),
+            vec![
+                Token::PseudoClass(PseudoClass::Root),
+                Token::Combinator(Combinator::Child),
+                Token::PseudoClass(PseudoClass::Empty),
+                Token::PseudoClass(PseudoClass::Blank),
+            ]
+        );
+        assert!(tokenize("p:contains").is_err());
+        assert!(tokenize("p:contains()").is_err());
+        assert!(tokenize("p:contains(\"x\"").is_err());
+    }
+
+    #[test]
+    fn test_css_to_sql_text_pseudo_classes() {
+        let sql = css_to_sql("button:contains(\"it's\")").unwrap();
+        assert!(sql.contains("instr(inner_text(n1.id), 'it''s') > 0"));
+        let sql = css_to_sql("p:lang(en)").unwrap();
+        assert!(sql.contains("a.name IN ('lang', 'xml:lang')"));
+        assert!(sql.contains("css_attr_match(lang.value, '|=', 'en', 1)"));
+        assert!(css_to_sql(":root")
+            .unwrap()
+            .contains("n1.parent_id IS NULL"));
+    }
+
+    #[test]
+    fn test_parse_nth() {
+        assert_eq!(parse_nth("3"), Ok((0, 3)));
+        assert_eq!(parse_nth("odd"), Ok((2, 1)));
+        assert_eq!(parse_nth("EVEN"), Ok((2, 0)));
+        assert_eq!(parse_nth("2n + 1"), Ok((2, 1)));
+        assert_eq!(parse_nth("-n+3"), Ok((-1, 3)));
+        assert_eq!(parse_nth("n"), Ok((1, 0)));
+        assert_eq!(parse_nth("+3n-2"), Ok((3, -2)));
+        assert!(parse_nth("2n1").is_err());
+        assert!(parse_nth("x").is_err());
+    }
+
+    #[test]
+    fn test_escape_identifier_round_trips() {
+        assert_eq!(escape_identifier("main"), "main");
+        assert_eq!(escape_identifier("123"), "\\31 23");
+        assert_eq!(escape_identifier("a.b:c"), "a\\.b\\:c");
+        assert_eq!(escape_identifier("-"), "\\-");
+        for value in ["123", "a.b:c", "-1x", "caf\u{e9}", "x y", "a\"b"] {
+            let tokens = tokenize(&format!("#{}", escape_identifier(value))).unwrap();
+            assert_eq!(tokens, vec![Token::Id(value.to_string())]);
+        }
+    }
+
+    #[test]
+    fn test_specificity() {
+        assert_eq!(specificity("*").unwrap(), Specificity(0, 0, 0));
+        assert_eq!(specificity("li").unwrap(), Specificity(0, 0, 1));
+        assert_eq!(specificity("ul li").unwrap(), Specificity(0, 0, 2));
+        assert_eq!(specificity("ul > li.red").unwrap(), Specificity(0, 1, 2));
+        assert_eq!(specificity("#x34y").unwrap(), Specificity(1, 0, 0));
+        assert_eq!(
+            specificity("a[href^=http]:first-child::text").unwrap(),
+            Specificity(0, 2, 2)
+        );
+        assert!(specificity("#main *").unwrap() > specificity("div.a.b.c").unwrap());
+    }
+
+    #[test]
+    fn test_errors_say_what_was_expected() {
+        assert_eq!(
+            css_to_sql("div >").unwrap_err(),
+            "Expected a selector after '>'"
+        );
+        assert_eq!(
+            css_to_sql("div > + p").unwrap_err(),
+            "Expected a selector between '>' and '+'"
+        );
+        assert_eq!(
+            css_to_sql("div.").unwrap_err(),
+            "Expected a class name after '.'"
+        );
+        assert!(css_to_sql("div!")
+            .unwrap_err()
+            .contains("expected a tag name"));
+    }
+
+    // Attribute selector cases adapted from the WPT suites
+    // css/selectors/parsing/parse-attribute.html and
+    // css/selectors/attribute-selectors/attribute-case/{syntax,semantics}.html
+
+    #[test]
+    fn test_wpt_attribute_syntax() {
+        let valid = [
+            "[att]",
+            "[att=val]",
+            "[att~=val]",
+            "[att|=val]",
+            "h1[title]",
+            "span[class='example']",
+            "a[hreflang=fr]",
+            "a[hreflang|='en']",
+            "object[type^='image/']",
+            "a[href$='.html']",
+            "p[title*='hello']",
+            "[*|att]",
+            "[|att]",
+            "[ns|att=val]",
+            "[foo='BAR' i]",
+            "[foo='BAR' I]",
+            "[foo=BAR i]",
+            "[foo=\"BAR\" i]",
+            "[foo='BAR'i]",
+            "[foo='BAR' i ]",
+            "[ foo='BAR' i]",
+            "[ foo = 'BAR' i ]",
+            "[foo='BAR' s]",
+            "[foo='BAR' S]",
+            "[foo|='BAR' i]",
+            "[data-x=1.5]",
+        ];
+        for selector in valid {
+            assert!(tokenize(selector).is_ok(), "{} should parse", selector);
+        }
+
+        let invalid = [
+            "[*=test]",
+            "[*!=test]",
+            "[*|*]",
+            "[att",
+            "[att=]",
+            "[att=val",
+            "[foo='BAR' i i]",
+            "[foo='BAR' ii]",
+            "[foo i='BAR']",
+            "[foo='BAR' i='']",
+            "[foo='BAR' 'i']",
+            "[foo='BAR' \"i\"]",
+            "[foo='BAR' j]",
+            "[foo='BAR]",
+        ];
+        for selector in invalid {
+            assert!(
+                tokenize(selector).is_err(),
+                "{} should be rejected",
+                selector
+            );
+        }
+    }
+
+    #[test]
+    fn test_wpt_attribute_semantics() {
+        let cases = [
+            ("[foo='bar']", "bar", true),
+            ("[foo='bar']", "BAR", false),
+            ("[foo='bar' i]", "BAR", true),
+            ("[foo='bar' s]", "BAR", false),
+            ("[foo='BAR' i]", "bar", true),
+            ("[foo~='bar' i]", "x BAR y", true),
+            ("[foo~='bar']", "x\tbar\ny", true),
+            ("[foo~='bar']", "xbar", false),
+            ("[foo|='bar' i]", "BAR-baz", true),
+            ("[foo|='bar']", "bar", true),
+            ("[foo|='bar']", "barbaz", false),
+            ("[foo|='bar']", "baz-bar", false),
+            ("[foo^='bar' i]", "BARbaz", true),
+            ("[foo$='bar' i]", "bazBAR", true),
+            ("[foo*='bar' i]", "xBARx", true),
+            ("[foo*='bar']", "xBARx", false),
+            // Only ASCII letters fold
+            ("[foo='\u{E4}' i]", "\u{C4}", false),
+            ("[foo='i' i]", "\u{130}", false),
+            // Empty values never match the substring operators
+            ("[foo^='']", "", false),
+            ("[foo$='']", "bar", false),
+            ("[foo*='']", "bar", false),
+            ("[foo~='']", "", false),
+            ("[foo='']", "", true),
+            ("[foo~='a b']", "a b", false),
+            // No LIKE wildcards
+            ("[foo^='a%']", "abc", false),
+            ("[foo*='_']", "abc", false),
+        ];
+        for (selector, value, expected) in cases {
+            let tokens = tokenize(selector).unwrap();
+            let Token::Attribute {
+                operator,
+                value: Some(pattern),
+                case,
+                ..
+            } = &tokens[0]
+            else {
+                panic!("{} did not parse as a valued attribute selector", selector);
+            };
+            assert_eq!(
+                attribute_matches(operator, value, pattern, *case == CaseFlag::Insensitive),
+                expected,
+                "{} against {:?}",
+                selector,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_css_escapes() {
+        assert_eq!(
+            tokenize(".\\31 23").unwrap(),
+            vec![Token::Class("123".to_string())]
+        );
+        assert_eq!(
+            tokenize("#\\#x").unwrap(),
+            vec![Token::Id("#x".to_string())]
+        );
+        let value_of = |selector: &str| match tokenize(selector).unwrap().remove(0) {
+            Token::Attribute { value, .. } => value.unwrap(),
+            other => panic!("unexpected token {:?}", other),
+        };
+        assert_eq!(value_of("[title=\"a\\\"b\"]"), "a\"b");
+        assert_eq!(value_of("[title='it\\'s']"), "it's");
+        assert_eq!(value_of("[title=\"\\41 B\"]"), "AB");
+        assert_eq!(value_of("[title=\\31 23]"), "123");
+        assert_eq!(value_of("[title='\\0']"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn test_css_to_sql_html_case_folding() {
+        // Lowercase names need no document-kind check
+        let sql = css_to_sql("div[data-id]").unwrap();
+        assert!(!sql.contains("dk.kind"));
+
+        let sql = css_to_sql("DIV[Data-Id]").unwrap();
+        assert!(sql.contains("JOIN documents dk ON dk.id = n1.document_id"));
+        assert!(
+            sql.contains("(n1.tag_name = 'DIV' OR ((dk.kind = 'html') AND n1.tag_name = 'div'))")
+        );
+        assert!(sql.contains("a2.name = 'data-id'"));
+
+        // `type` values fold on HTML unless the selector says otherwise
+        let sql = css_to_sql("input[type=TEXT]").unwrap();
+        assert!(sql.contains("css_attr_match(a2.value, '=', 'TEXT', (dk.kind = 'html'))"));
+        let sql = css_to_sql("input[type=TEXT s]").unwrap();
+        assert!(sql.contains("a2.value = 'TEXT'"));
+    }
+
     #[test]
     fn test_css_to_sql_simple_tag() {
         let sql = css_to_sql("div").unwrap();
@@ -439,4 +1691,152 @@ mod tests {
         assert!(sql.contains("name = 'class'"));
         assert!(sql.contains("value"));
     }
+
+    #[test]
+    fn test_tokenize_pseudo_elements() {
+        let tokens = tokenize("a.more::attr(href)").unwrap();
+        assert_eq!(
+            tokens.last(),
+            Some(&Token::PseudoElement(PseudoElement::Attr(
+                "href".to_string()
+            )))
+        );
+        let tokens = tokenize("li::inner-text").unwrap();
+        assert_eq!(
+            tokens.last(),
+            Some(&Token::PseudoElement(PseudoElement::InnerText))
+        );
+    }
+
+    #[test]
+    fn test_css_to_sql_pseudo_element_projection() {
+        let sql = css_to_sql("h1::text").unwrap();
+        assert!(sql.starts_with("SELECT DISTINCT n1.id, n1.text_content AS text"));
+        let sql = css_to_sql("a::attr(href)").unwrap();
+        assert!(sql.contains("AS \"href\""));
+        assert!(css_to_sql("div::outer > p").is_err());
+        assert!(css_to_node_sql("div::outer", &SelectorScope::default()).is_err());
+    }
+
+    #[test]
+    fn test_tokenize_attribute_regex() {
+        let tokens = tokenize(r"a[href~/^https?:\/\//i]").unwrap();
+        assert_eq!(
+            tokens[1],
+            Token::Attribute {
+                name: "href".to_string(),
+                namespace: NamespacePrefix::Any,
+                value: Some("(?i)^https?://".to_string()),
+                operator: AttributeOperator::Matches,
+                case: CaseFlag::Default,
+            }
+        );
+    }
+
+    #[test]
+    fn test_css_to_sql_scoped() {
+        let scope = SelectorScope {
+            document: Some(DocumentFilter::Id(2)),
+            context_node: Some(7),
+            ..SelectorScope::default()
+        };
+        let sql = css_to_sql_scoped("li", &scope).unwrap();
+        assert!(sql.contains("n1.document_id = 2"));
+        assert!(sql.contains("SELECT id FROM nodes WHERE parent_id = 7"));
+
+        // A leading combinator is relative to the context node
+        let sql = css_to_sql_scoped("> li", &scope).unwrap();
+        assert!(sql.contains("n1.id = 7"));
+        assert!(sql.contains("n2.tag_name = 'li'"));
+
+        let sql = css_to_sql(":scope > body").unwrap();
+        assert!(sql.contains("n1.parent_id IS NULL"));
+
+        let scope = SelectorScope {
+            include_context: true,
+            ..scope
+        };
+        let sql = css_to_sql_scoped("li", &scope).unwrap();
+        assert!(sql.contains("SELECT id FROM nodes WHERE id = 7"));
+    }
+
+    #[test]
+    fn test_css_to_sql_document_filter() {
+        let scope = SelectorScope {
+            document: Some(DocumentFilter::NameGlob("books-*.xml".to_string())),
+            ..SelectorScope::default()
+        };
+        let sql = css_to_sql_scoped("title", &scope).unwrap();
+        assert!(sql.contains("WHERE name GLOB 'books-*.xml'"));
+        assert!(sql.starts_with("SELECT DISTINCT n1.*, doc.name AS document_name"));
+
+        let sql = css_to_sql("a::attr(href)").unwrap();
+        assert!(sql.contains("n1.document_id, doc.name AS document_name"));
+        assert!(!css_to_node_sql("title", &scope)
+            .unwrap()
+            .contains("document_name"));
+    }
+
+    #[test]
+    fn test_css_to_sql_matches_text() {
+        let sql = css_to_sql(r"span:matches-text(/\d{4}/)").unwrap();
+        assert!(sql.contains(r"n1.text_content REGEXP '\d{4}'"));
+        assert!(css_to_sql("p:matches-text(/[/)").is_err());
+    }
+
+    #[test]
+    fn test_namespace_prefixes_are_checked() {
+        let Token::Attribute { namespace, .. } = &tokenize("[xlink|href]").unwrap()[0] else {
+            panic!("expected an attribute selector");
+        };
+        assert_eq!(*namespace, NamespacePrefix::Prefix("xlink".to_string()));
+        let Token::Attribute { namespace, .. } = &tokenize("[*|href]").unwrap()[0] else {
+            panic!("expected an attribute selector");
+        };
+        assert_eq!(*namespace, NamespacePrefix::Any);
+
+        let db = crate::testing::open();
+        crate::testing::load_xml(
+            db,
+            "doc.xml",
+            r#"<w:document xmlns:w="urn:w" xmlns:m="urn:m" xmlns:r="urn:r">
+                <w:p r:id="1"/><m:p id="2"/><p/><w:p id="3"/>
+            </w:document>"#,
+        );
+        let count = |selector: &str| {
+            let sql = css_to_sql(selector).unwrap();
+            crate::database::query(db, &sql, &[]).unwrap().rows.len()
+        };
+        assert_eq!(count("p"), 4);
+        assert_eq!(count("*|p"), 4);
+        assert_eq!(count("w|p"), 2);
+        assert_eq!(count("|p"), 1);
+        assert_eq!(count("w|*"), 3);
+        assert_eq!(count("[r|id]"), 1);
+        assert_eq!(count("[|id]"), 2);
+        assert_eq!(count("[*|id]"), 3);
+        assert_eq!(count("w|p[|id]"), 1);
+    }
+
+    #[test]
+    fn test_empty_and_blank() {
+        let db = crate::testing::open();
+        crate::testing::load_xml(
+            db,
+            "doc.xml",
+            "<doc><a/><a></a><a><!-- note --></a><a> </a><a>\n\t</a><a>x</a><a><b/></a></doc>",
+        );
+        let positions = |selector: &str| {
+            let sql = css_to_sql(selector).unwrap();
+            crate::database::query(db, &format!("SELECT position FROM ({})", sql), &[])
+                .unwrap()
+                .rows
+                .iter()
+                .filter_map(|row| row[0].as_i64())
+                .collect::<Vec<_>>()
+        };
+        // Comments do not count; whitespace only does for :empty
+        assert_eq!(positions("a:empty"), [1, 2, 3]);
+        assert_eq!(positions("a:blank"), [1, 2, 3, 4, 5]);
+    }
 }
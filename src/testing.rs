@@ -0,0 +1,51 @@
+//! In-memory databases for tests that need stored documents.
+
+use crate::database::{init_schema, DocumentKind};
+use crate::parser::{parse_html_to_nodes, parse_xml_to_nodes};
+use crate::{register_extensions, XmlSqlDb};
+use sqlite_wasm_rs::{
+    sqlite3, sqlite3_open_v2, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_MEMORY,
+    SQLITE_OPEN_READWRITE,
+};
+use std::ffi::CString;
+use std::ptr;
+
+/// A fresh database with the schema and SQL functions of `XmlSqlDb::new`.
+pub fn open() -> *mut sqlite3 {
+    let mut db = ptr::null_mut();
+    let filename = CString::new(":memory:").unwrap();
+    let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_MEMORY;
+    let ret = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+    assert_eq!(ret, SQLITE_OK, "failed to open an in-memory database");
+    init_schema(db).unwrap();
+    register_extensions(db).unwrap();
+    db
+}
+
+/// Parses and stores `xml` as `load_xml` does, returning the document id.
+pub fn load_xml(db: *mut sqlite3, name: &str, xml: &str) -> i64 {
+    let nodes = parse_xml_to_nodes(xml).unwrap();
+    XmlSqlDb { db }
+        .insert_document(name, DocumentKind::Xml, None, &nodes)
+        .unwrap() as i64
+}
+
+/// Parses and stores `html` as `load_html` does, returning the document id.
+pub fn load_html(db: *mut sqlite3, name: &str, html: &str) -> i64 {
+    let nodes = parse_html_to_nodes(html).unwrap();
+    XmlSqlDb { db }
+        .insert_document(name, DocumentKind::Html, None, &nodes)
+        .unwrap() as i64
+}
+
+/// The id of the first node with `tag_name`, in document order.
+pub fn node_id(db: *mut sqlite3, tag_name: &str) -> i64 {
+    crate::database::query_value(
+        db,
+        "SELECT id FROM nodes WHERE tag_name = ?1 ORDER BY document_id, document_order LIMIT 1",
+        &[serde_json::json!(tag_name)],
+    )
+    .unwrap()
+    .and_then(|v| v.as_i64())
+    .unwrap_or_else(|| panic!("no <{}> element", tag_name))
+}
@@ -0,0 +1,626 @@
+//! In-memory view of a stored subtree, rebuilt from `nodes`, `attributes`
+//! and `segments`.
+//!
+//! Serialization writes text, comments and processing instructions among
+//! the children where the source had them, so mixed content round-trips.
+//! Tags and attributes are written with their stored namespace prefixes, and
+//! the declarations they need are repeated on the subtree root. Documents
+//! loaded by older versions only kept each element's trimmed text, which is
+//! written before its children.
+
+use crate::database::SegmentKind;
+use crate::database::{query, query_value};
+use crate::parser::{content_hash, XML_NAMESPACE};
+use crate::{NodeData, Segment};
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+use std::collections::{BTreeMap, HashMap};
+
+/// Elements written as `<br/>` rather than `<br></br>` when empty.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Prefix to URI; a `""` key is the default namespace, `""` as URI none.
+pub type Scope = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoredNode {
+    pub id: i64,
+    pub tag_name: String,
+    pub text_content: Option<String>,
+    /// By name as written (`prefix:local` when prefixed), sorted so output
+    /// does not depend on insertion order.
+    pub attributes: Vec<(String, String)>,
+    /// Indexes into `StoredTree::nodes`, in document order.
+    pub children: Vec<usize>,
+    /// Namespace prefix of the tag; `tag_name` is the local name.
+    pub prefix: Option<String>,
+    /// Prefixes of prefixed attributes, by name as in `attributes`.
+    pub attribute_prefixes: HashMap<String, String>,
+    /// Declarations made on the element, `""` being the default namespace.
+    pub namespaces: Vec<(String, String)>,
+    /// Text, comments and processing instructions among the children.
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredTree {
+    /// Pre-order; `nodes[0]` is the subtree root.
+    pub nodes: Vec<StoredNode>,
+}
+
+impl StoredTree {
+    /// Loads the subtree rooted at `root_id`, or `None` if the node does not exist.
+    pub fn load(db: *mut sqlite3, root_id: i64) -> Result<Option<StoredTree>, String> {
+        let subtree = "WITH RECURSIVE subtree(id) AS (
+            SELECT id FROM nodes WHERE id = ?1
+            UNION ALL
+            SELECT n.id FROM nodes n JOIN subtree s ON n.parent_id = s.id
+        )";
+        let node_rows = query(
+            db,
+            &format!(
+                "{} SELECT n.id, n.parent_id, n.tag_name, n.text_content, n.prefix
+                FROM nodes n JOIN subtree USING (id) ORDER BY n.document_order",
+                subtree
+            ),
+            &[json!(root_id)],
+        )?;
+        if node_rows.rows.is_empty() {
+            return Ok(None);
+        }
+
+        let attr_rows = query(
+            db,
+            &format!(
+                "{} SELECT a.node_id, a.name, a.value, a.prefix FROM attributes a
+                WHERE a.node_id IN (SELECT id FROM subtree)",
+                subtree
+            ),
+            &[json!(root_id)],
+        )?;
+        let mut attributes: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+        let mut attribute_prefixes: HashMap<i64, HashMap<String, String>> = HashMap::new();
+        for row in attr_rows.rows {
+            let node_id = row[0].as_i64().unwrap_or_default();
+            let name = qualified(row[3].as_str(), row[1].as_str().unwrap_or_default());
+            let value = row[2].as_str().unwrap_or_default().to_string();
+            if let Some(prefix) = row[3].as_str() {
+                attribute_prefixes
+                    .entry(node_id)
+                    .or_default()
+                    .insert(name.clone(), prefix.to_string());
+            }
+            attributes.entry(node_id).or_default().push((name, value));
+        }
+        for list in attributes.values_mut() {
+            list.sort();
+        }
+
+        let mut namespaces: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+        for row in query(
+            db,
+            &format!(
+                "{} SELECT node_id, prefix, uri FROM namespaces
+                WHERE node_id IN (SELECT id FROM subtree) ORDER BY id",
+                subtree
+            ),
+            &[json!(root_id)],
+        )?
+        .rows
+        {
+            namespaces
+                .entry(row[0].as_i64().unwrap_or_default())
+                .or_default()
+                .push((
+                    row[1].as_str().unwrap_or_default().to_string(),
+                    row[2].as_str().unwrap_or_default().to_string(),
+                ));
+        }
+
+        let mut segments: HashMap<i64, Vec<Segment>> = HashMap::new();
+        for row in query(
+            db,
+            &format!(
+                "{} SELECT node_id, slot, kind, target, value FROM segments
+                WHERE node_id IN (SELECT id FROM subtree) ORDER BY id",
+                subtree
+            ),
+            &[json!(root_id)],
+        )?
+        .rows
+        {
+            if let Some(segment) = segment_from_row(&row[1..]) {
+                segments
+                    .entry(row[0].as_i64().unwrap_or_default())
+                    .or_default()
+                    .push(segment);
+            }
+        }
+
+        let mut nodes: Vec<StoredNode> = Vec::with_capacity(node_rows.rows.len());
+        let mut index_of: HashMap<i64, usize> = HashMap::new();
+        for row in node_rows.rows {
+            let id = row[0].as_i64().unwrap_or_default();
+            let index = nodes.len();
+            if let Some(parent) = row[1].as_i64().and_then(|p| index_of.get(&p)) {
+                nodes[*parent].children.push(index);
+            }
+            index_of.insert(id, index);
+            nodes.push(StoredNode {
+                id,
+                tag_name: row[2].as_str().unwrap_or_default().to_string(),
+                text_content: row[3].as_str().map(str::to_string),
+                attributes: attributes.remove(&id).unwrap_or_default(),
+                children: Vec::new(),
+                prefix: row[4].as_str().map(str::to_string),
+                attribute_prefixes: attribute_prefixes.remove(&id).unwrap_or_default(),
+                namespaces: namespaces.remove(&id).unwrap_or_default(),
+                segments: segments.remove(&id).unwrap_or_default(),
+            });
+        }
+
+        Ok(Some(StoredTree { nodes }))
+    }
+
+    /// Loads a whole document, or `None` if it is empty or does not exist.
+    pub fn load_document(db: *mut sqlite3, document_id: i64) -> Result<Option<StoredTree>, String> {
+        let root = query_value(
+            db,
+            "SELECT id FROM nodes WHERE document_id = ?1 AND parent_id IS NULL
+            ORDER BY document_order LIMIT 1",
+            &[json!(document_id)],
+        )?;
+        match root.and_then(|v| v.as_i64()) {
+            Some(root) => StoredTree::load(db, root),
+            None => Ok(None),
+        }
+    }
+
+    /// Builds a tree from parser output, keeping the parser's local ids.
+    pub fn from_parsed(nodes: &[NodeData]) -> StoredTree {
+        let mut tree = StoredTree {
+            nodes: Vec::with_capacity(nodes.len()),
+        };
+        let mut index_of: HashMap<i64, usize> = HashMap::new();
+        for node in nodes {
+            let index = tree.nodes.len();
+            if let Some(parent) = node.parent_id.and_then(|p| index_of.get(&p)) {
+                tree.nodes[*parent].children.push(index);
+            }
+            index_of.insert(node.id, index);
+            let mut attributes: Vec<(String, String)> = node
+                .attributes
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            attributes.sort();
+            tree.nodes.push(StoredNode {
+                id: node.id,
+                tag_name: node.tag_name.clone(),
+                text_content: node.text_content.clone(),
+                attributes,
+                children: Vec::new(),
+                prefix: node.prefix.clone(),
+                attribute_prefixes: node.attribute_prefixes.clone(),
+                namespaces: node.namespaces.clone(),
+                segments: node.segments.clone(),
+            });
+        }
+        tree
+    }
+
+    /// A hash per node of its whole subtree: namespace and tag, text,
+    /// attributes and children in order. Equal hashes mean identical
+    /// subtrees, and they match the `content_hash` the parsers store.
+    /// Namespaces declared above `nodes[0]` are not seen.
+    pub fn subtree_hashes(&self) -> Vec<u64> {
+        let parents = self.parents();
+        let (scopes, scope_of) = node_scopes(
+            self.nodes
+                .iter()
+                .zip(&parents)
+                .map(|(node, &parent)| (parent, node.namespaces.as_slice())),
+        );
+        let mut hashes = vec![0; self.nodes.len()];
+        // Pre-order, so children come after their parent
+        for index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let scope = &scopes[scope_of[index]];
+            hashes[index] = content_hash(
+                namespace_uri(scope, node.prefix.as_deref()),
+                &node.tag_name,
+                node.text_content.as_deref(),
+                hashed_attributes(
+                    node.attributes.iter().map(|(name, value)| (name, value)),
+                    &node.attribute_prefixes,
+                    scope,
+                ),
+                node.children.iter().map(|&child| hashes[child]),
+            );
+        }
+        hashes
+    }
+
+    /// The namespace URI of each node's tag. Namespaces declared above
+    /// `nodes[0]` are not seen.
+    pub fn element_namespaces(&self) -> Vec<Option<String>> {
+        let (scopes, scope_of) = node_scopes(
+            self.nodes
+                .iter()
+                .zip(self.parents())
+                .map(|(node, parent)| (parent, node.namespaces.as_slice())),
+        );
+        self.nodes
+            .iter()
+            .zip(scope_of)
+            .map(|(node, scope)| {
+                namespace_uri(&scopes[scope], node.prefix.as_deref()).map(str::to_string)
+            })
+            .collect()
+    }
+
+    /// Index of each node's parent.
+    pub fn parents(&self) -> Vec<Option<usize>> {
+        let mut parents = vec![None; self.nodes.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            for &child in &node.children {
+                parents[child] = Some(index);
+            }
+        }
+        parents
+    }
+
+    /// Serializes the subtree as markup, e.g. for `div::outer`. `scope`
+    /// holds the namespaces declared above the root; those the subtree uses
+    /// are declared again on the root so the markup parses on its own.
+    pub fn to_xml(&self, scope: &Scope) -> String {
+        let mut used: Vec<&str> = Vec::new();
+        for node in &self.nodes {
+            used.push(node.prefix.as_deref().unwrap_or(""));
+            used.extend(node.attribute_prefixes.values().map(String::as_str));
+        }
+        let root = &self.nodes[0];
+        let inherited: Vec<(String, String)> = scope
+            .iter()
+            .filter(|(prefix, uri)| {
+                !uri.is_empty()
+                    && used.contains(&prefix.as_str())
+                    && !root.namespaces.iter().any(|(p, _)| p == *prefix)
+            })
+            .map(|(prefix, uri)| (prefix.clone(), uri.clone()))
+            .collect();
+
+        let mut out = String::new();
+        self.write_node(0, &inherited, &mut out);
+        out
+    }
+
+    fn write_node(&self, index: usize, inherited: &[(String, String)], out: &mut String) {
+        let node = &self.nodes[index];
+        let name = qualified(node.prefix.as_deref(), &node.tag_name);
+        out.push('<');
+        out.push_str(&name);
+        for (prefix, uri) in inherited.iter().chain(&node.namespaces) {
+            let attribute = if prefix.is_empty() {
+                "xmlns".to_string()
+            } else {
+                format!("xmlns:{}", prefix)
+            };
+            out.push_str(&format!(" {}=\"{}\"", attribute, escape_attribute(uri)));
+        }
+        for (name, value) in &node.attributes {
+            out.push_str(&format!(" {}=\"{}\"", name, escape_attribute(value)));
+        }
+
+        if node.segments.is_empty() && node.children.is_empty() {
+            if VOID_ELEMENTS.contains(&node.tag_name.as_str()) {
+                out.push_str("/>");
+            } else {
+                out.push_str(&format!("></{}>", name));
+            }
+            return;
+        }
+
+        out.push('>');
+        let mut segments = node.segments.iter().peekable();
+        for (position, &child) in node.children.iter().enumerate() {
+            while let Some(segment) = segments.next_if(|s| s.slot as usize <= position) {
+                write_segment(segment, out);
+            }
+            self.write_node(child, &[], out);
+        }
+        for segment in segments {
+            write_segment(segment, out);
+        }
+        out.push_str(&format!("</{}>", name));
+    }
+}
+
+fn write_segment(segment: &Segment, out: &mut String) {
+    match segment.kind {
+        SegmentKind::Text => out.push_str(&escape_text(&segment.value)),
+        SegmentKind::Comment => out.push_str(&format!("<!--{}-->", segment.value)),
+        SegmentKind::Pi => {
+            out.push_str("<?");
+            out.push_str(segment.target.as_deref().unwrap_or_default());
+            if !segment.value.is_empty() {
+                out.push(' ');
+                out.push_str(&segment.value);
+            }
+            out.push_str("?>");
+        }
+    }
+}
+
+/// A segment from `slot, kind, target, value` columns.
+fn segment_from_row(row: &[serde_json::Value]) -> Option<Segment> {
+    Some(Segment {
+        slot: row[0].as_u64()? as u32,
+        kind: SegmentKind::parse(row[1].as_str()?)?,
+        target: row[2].as_str().map(str::to_string),
+        value: row[3].as_str()?.to_string(),
+    })
+}
+
+/// Stores the segments of `node_id`, or of the document itself for `None`.
+pub fn insert_segments(
+    db: *mut sqlite3,
+    document_id: i64,
+    node_id: Option<i64>,
+    segments: &[Segment],
+) -> Result<(), String> {
+    for segment in segments {
+        query(
+            db,
+            "INSERT INTO segments (document_id, node_id, slot, kind, target, value)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[
+                json!(document_id),
+                json!(node_id),
+                json!(segment.slot),
+                json!(segment.kind.as_str()),
+                json!(segment.target),
+                json!(segment.value),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Comments and processing instructions outside the document's root element.
+pub fn document_segments(db: *mut sqlite3, document_id: i64) -> Result<Vec<Segment>, String> {
+    Ok(query(
+        db,
+        "SELECT slot, kind, target, value FROM segments
+        WHERE document_id = ?1 AND node_id IS NULL ORDER BY id",
+        &[json!(document_id)],
+    )?
+    .rows
+    .iter()
+    .filter_map(|row| segment_from_row(row))
+    .collect())
+}
+
+/// `prefix:name`, or `name` alone without a prefix.
+pub fn qualified(prefix: Option<&str>, name: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{}:{}", prefix, name),
+        None => name.to_string(),
+    }
+}
+
+/// The local part of an attribute name as in `StoredNode::attributes`,
+/// given the attribute's prefix.
+pub fn local_name<'a>(name: &'a str, prefix: Option<&str>) -> &'a str {
+    prefix
+        .and_then(|prefix| name.strip_prefix(prefix))
+        .and_then(|rest| rest.strip_prefix(':'))
+        .unwrap_or(name)
+}
+
+/// The namespace URI `prefix` stands for in `scope`, `None` meaning the
+/// default namespace. `None` when the name is in no namespace.
+pub fn namespace_uri<'a>(scope: &'a Scope, prefix: Option<&str>) -> Option<&'a str> {
+    match prefix {
+        Some("xml") => Some(XML_NAMESPACE),
+        prefix => scope
+            .get(prefix.unwrap_or(""))
+            .map(String::as_str)
+            .filter(|uri| !uri.is_empty()),
+    }
+}
+
+/// The namespaces in scope at each node, given each node's parent index and
+/// declarations in pre-order. Nodes share a scope until one declares
+/// something, so this returns the distinct scopes and an index per node.
+pub fn node_scopes<'a>(
+    nodes: impl IntoIterator<Item = (Option<usize>, &'a [(String, String)])>,
+) -> (Vec<Scope>, Vec<usize>) {
+    let mut scopes = vec![Scope::new()];
+    let mut scope_of: Vec<usize> = Vec::new();
+    for (parent, declarations) in nodes {
+        let inherited = parent.map_or(0, |parent| scope_of[parent]);
+        if declarations.is_empty() {
+            scope_of.push(inherited);
+        } else {
+            let mut scope = scopes[inherited].clone();
+            scope.extend(declarations.iter().cloned());
+            scopes.push(scope);
+            scope_of.push(scopes.len() - 1);
+        }
+    }
+    (scopes, scope_of)
+}
+
+/// Attributes as `content_hash` takes them: (namespace URI, local name,
+/// value), sorted. A prefix that is not in scope stays in the name.
+pub fn hashed_attributes<'a>(
+    attributes: impl IntoIterator<Item = (&'a String, &'a String)>,
+    prefixes: &HashMap<String, String>,
+    scope: &'a Scope,
+) -> Vec<(Option<&'a str>, &'a str, &'a str)> {
+    let mut hashed: Vec<(Option<&str>, &str, &str)> = attributes
+        .into_iter()
+        .map(|(name, value)| {
+            let prefix = prefixes.get(name).map(String::as_str);
+            match prefix.and_then(|prefix| namespace_uri(scope, Some(prefix))) {
+                Some(uri) => (Some(uri), local_name(name, prefix), value.as_str()),
+                None => (None, name.as_str(), value.as_str()),
+            }
+        })
+        .collect();
+    hashed.sort();
+    hashed
+}
+
+/// Namespaces in scope at `node_id` from declarations on its ancestors,
+/// not counting its own.
+pub fn ancestor_namespaces(db: *mut sqlite3, node_id: i64) -> Result<Scope, String> {
+    let mut scope = Scope::new();
+    // Outermost first, so nearer declarations override
+    for row in query(
+        db,
+        "WITH RECURSIVE up(id, level) AS (
+            SELECT parent_id, 1 FROM nodes WHERE id = ?1 AND parent_id IS NOT NULL
+            UNION ALL
+            SELECT n.parent_id, up.level + 1 FROM nodes n JOIN up ON n.id = up.id
+            WHERE n.parent_id IS NOT NULL
+        )
+        SELECT ns.prefix, ns.uri FROM namespaces ns JOIN up ON up.id = ns.node_id
+        ORDER BY up.level DESC, ns.id",
+        &[json!(node_id)],
+    )?
+    .rows
+    {
+        scope.insert(
+            row[0].as_str().unwrap_or_default().to_string(),
+            row[1].as_str().unwrap_or_default().to_string(),
+        );
+    }
+    Ok(scope)
+}
+
+/// Outer markup of a stored node, or `None` if the node does not exist.
+pub fn outer_xml(db: *mut sqlite3, node_id: i64) -> Result<Option<String>, String> {
+    let Some(tree) = StoredTree::load(db, node_id)? else {
+        return Ok(None);
+    };
+    Ok(Some(tree.to_xml(&ancestor_namespaces(db, node_id)?)))
+}
+
+pub fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, tag: &str, text: Option<&str>, children: Vec<usize>) -> StoredNode {
+        StoredNode {
+            id,
+            tag_name: tag.to_string(),
+            text_content: text.map(str::to_string),
+            attributes: Vec::new(),
+            children,
+            segments: text
+                .map(|text| Segment {
+                    slot: 0,
+                    kind: SegmentKind::Text,
+                    target: None,
+                    value: text.to_string(),
+                })
+                .into_iter()
+                .collect(),
+            ..StoredNode::default()
+        }
+    }
+
+    #[test]
+    fn test_to_xml_nested() {
+        let mut root = node(1, "book", None, vec![1, 2]);
+        root.attributes
+            .push(("category".to_string(), "a \"b\" & c".to_string()));
+        let tree = StoredTree {
+            nodes: vec![
+                root,
+                node(2, "title", Some("1 < 2"), vec![]),
+                node(3, "note", None, vec![]),
+            ],
+        };
+        assert_eq!(
+            tree.to_xml(&Scope::new()),
+            "<book category=\"a &quot;b&quot; &amp; c\"><title>1 &lt; 2</title><note></note></book>"
+        );
+    }
+
+    #[test]
+    fn test_to_xml_void_element() {
+        let tree = StoredTree {
+            nodes: vec![
+                node(1, "p", Some("a"), vec![1]),
+                node(2, "br", None, vec![]),
+            ],
+        };
+        assert_eq!(tree.to_xml(&Scope::new()), "<p>a<br/></p>");
+    }
+
+    #[test]
+    fn test_outer_xml_keeps_mixed_content() {
+        let db = crate::testing::open();
+        crate::testing::load_xml(
+            db,
+            "doc.xml",
+            "<doc><p>Hello <b>world</b> and bye<!-- end --><?tidy off?></p>\n</doc>",
+        );
+        let p = crate::testing::node_id(db, "p");
+        assert_eq!(
+            outer_xml(db, p).unwrap().as_deref(),
+            Some("<p>Hello <b>world</b> and bye<!-- end --><?tidy off?></p>")
+        );
+        let doc = crate::testing::node_id(db, "doc");
+        assert_eq!(
+            outer_xml(db, doc).unwrap().as_deref(),
+            Some("<doc><p>Hello <b>world</b> and bye<!-- end --><?tidy off?></p>\n</doc>")
+        );
+    }
+
+    #[test]
+    fn test_outer_xml_keeps_namespaces() {
+        let db = crate::testing::open();
+        crate::testing::load_xml(
+            db,
+            "doc.xml",
+            r#"<w:document xmlns:w="urn:w" xmlns="urn:d" xmlns:r="urn:r"><w:body><w:p r:id="1">Hi</w:p><note/></w:body></w:document>"#,
+        );
+        let body = crate::testing::node_id(db, "body");
+        let outer = outer_xml(db, body).unwrap().unwrap();
+        assert_eq!(
+            outer,
+            "<w:body xmlns=\"urn:d\" xmlns:r=\"urn:r\" xmlns:w=\"urn:w\">\
+             <w:p r:id=\"1\">Hi</w:p><note></note></w:body>"
+        );
+        // The markup parses on its own to the same names
+        let nodes = crate::parser::parse_xml_to_nodes(&outer).unwrap();
+        let reparsed = StoredTree::from_parsed(&nodes);
+        let stored = StoredTree::load(db, body).unwrap().unwrap();
+        for (a, b) in reparsed.nodes.iter().zip(&stored.nodes) {
+            assert_eq!(
+                (&a.prefix, &a.tag_name, &a.attribute_prefixes),
+                (&b.prefix, &b.tag_name, &b.attribute_prefixes)
+            );
+        }
+    }
+}
@@ -0,0 +1,579 @@
+//! Eponymous virtual tables exposing tree traversals as table-valued functions.
+//!
+//! `SELECT * FROM descendants(42) WHERE tag_name = 'a'` returns `nodes` rows,
+//! so the result can be filtered and joined exactly like the base table. The
+//! arguments are hidden columns; `best_index` hands them to `filter` and tells
+//! the planner when rows already come out by `document_order`.
+//!
+//! `css_select(selector [, document [, context_node]])` is built on the same
+//! machinery and runs the CSS → SQL translation from inside a query. The
+//! document is an id or a name glob such as `'books-*.xml'`.
+
+use crate::database::query;
+use crate::functions::{arg_value, result_text};
+use crate::selector::{css_to_node_sql, DocumentFilter, SelectorScope};
+use serde_json::Value;
+use sqlite_wasm_rs::{
+    sqlite3, sqlite3_context, sqlite3_create_module_v2, sqlite3_declare_vtab, sqlite3_free,
+    sqlite3_index_info, sqlite3_int64, sqlite3_module, sqlite3_mprintf, sqlite3_result_double,
+    sqlite3_result_int64, sqlite3_result_null, sqlite3_value, sqlite3_vtab, sqlite3_vtab_cursor,
+    SQLITE_CONSTRAINT, SQLITE_ERROR, SQLITE_INDEX_CONSTRAINT_EQ, SQLITE_OK,
+};
+use std::ffi::{c_char, c_int, c_void, CString};
+
+/// Visible columns of every tree table, identical to the `nodes` table.
+const NODE_COLUMNS: &str =
+    "id, document_id, parent_id, tag_name, text_content, depth, position, document_order";
+const NODE_COLUMN_COUNT: usize = 8;
+/// Index of `document_order` in `NODE_COLUMNS`.
+const DOCUMENT_ORDER_COLUMN: c_int = 7;
+
+pub(crate) type RowsFn = fn(*mut sqlite3, &[Value]) -> Result<Vec<Vec<Value>>, String>;
+
+pub(crate) struct TableFunction {
+    pub name: &'static str,
+    /// Hidden argument columns, in call order.
+    pub args: &'static [&'static str],
+    /// How many leading arguments must be supplied.
+    pub required: usize,
+    /// Planner hint for the number of rows a single call produces.
+    pub estimated_rows: i64,
+    /// Whether `rows` returns nodes of one document sorted by `document_order`.
+    pub in_document_order: bool,
+    pub rows: RowsFn,
+}
+
+const TREE_FUNCTIONS: &[TableFunction] = &[
+    TableFunction {
+        name: "children",
+        args: &["node_id"],
+        required: 1,
+        estimated_rows: 10,
+        in_document_order: true,
+        rows: children_rows,
+    },
+    TableFunction {
+        name: "descendants",
+        args: &["node_id"],
+        required: 1,
+        estimated_rows: 1000,
+        in_document_order: true,
+        rows: descendants_rows,
+    },
+    TableFunction {
+        name: "ancestors",
+        args: &["node_id"],
+        required: 1,
+        estimated_rows: 10,
+        in_document_order: false,
+        rows: ancestors_rows,
+    },
+    TableFunction {
+        name: "siblings",
+        args: &["node_id"],
+        required: 1,
+        estimated_rows: 10,
+        in_document_order: true,
+        rows: siblings_rows,
+    },
+];
+
+static CSS_SELECT: TableFunction = TableFunction {
+    name: "css_select",
+    args: &["selector", "in_document", "context_node"],
+    required: 1,
+    estimated_rows: 100,
+    // matches can span documents
+    in_document_order: false,
+    rows: css_select_rows,
+};
+
+pub fn register_table_functions(db: *mut sqlite3) -> Result<(), String> {
+    for function in TREE_FUNCTIONS {
+        register_table_function(db, function)?;
+    }
+    register_table_function(db, &CSS_SELECT)
+}
+
+pub(crate) fn register_table_function(
+    db: *mut sqlite3,
+    function: &'static TableFunction,
+) -> Result<(), String> {
+    let c_name = CString::new(function.name).unwrap();
+    let ret = unsafe {
+        sqlite3_create_module_v2(
+            db,
+            c_name.as_ptr(),
+            &TABLE_FUNCTION_MODULE,
+            function as *const TableFunction as *mut c_void,
+            None,
+        )
+    };
+    if ret != SQLITE_OK {
+        return Err(format!(
+            "Failed to register table function {}",
+            function.name
+        ));
+    }
+    Ok(())
+}
+
+fn children_rows(db: *mut sqlite3, args: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+    let sql = format!(
+        "SELECT {} FROM nodes WHERE parent_id = ?1 ORDER BY position",
+        NODE_COLUMNS
+    );
+    Ok(query(db, &sql, args)?.rows)
+}
+
+fn descendants_rows(db: *mut sqlite3, args: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+    let sql = format!(
+        "WITH RECURSIVE descendants(id) AS (
+            SELECT id FROM nodes WHERE parent_id = ?1
+            UNION ALL
+            SELECT n.id FROM nodes n JOIN descendants d ON n.parent_id = d.id
+        )
+        SELECT {} FROM nodes WHERE id IN (SELECT id FROM descendants) ORDER BY document_order",
+        NODE_COLUMNS
+    );
+    Ok(query(db, &sql, args)?.rows)
+}
+
+fn ancestors_rows(db: *mut sqlite3, args: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+    // Nearest ancestor first, like XPath's reverse ancestor axis
+    let sql = format!(
+        "WITH RECURSIVE ancestors(id, distance) AS (
+            SELECT parent_id, 1 FROM nodes WHERE id = ?1 AND parent_id IS NOT NULL
+            UNION ALL
+            SELECT n.parent_id, a.distance + 1 FROM nodes n JOIN ancestors a ON n.id = a.id
+            WHERE n.parent_id IS NOT NULL
+        )
+        SELECT {} FROM nodes JOIN ancestors USING (id) ORDER BY distance",
+        NODE_COLUMNS
+    );
+    Ok(query(db, &sql, args)?.rows)
+}
+
+fn siblings_rows(db: *mut sqlite3, args: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+    let sql = format!(
+        "SELECT {} FROM nodes
+        WHERE parent_id = (SELECT parent_id FROM nodes WHERE id = ?1) AND id <> ?1
+        ORDER BY position",
+        NODE_COLUMNS
+    );
+    Ok(query(db, &sql, args)?.rows)
+}
+
+/// Matches of a CSS selector, optionally limited to one document and/or to
+/// the descendants of a context node. NULL for either filter means "any".
+fn css_select_rows(db: *mut sqlite3, args: &[Value]) -> Result<Vec<Vec<Value>>, String> {
+    let selector = args
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| "css_select() requires a selector string".to_string())?;
+    let scope = SelectorScope {
+        document: match args.get(1) {
+            Some(Value::String(pattern)) => Some(DocumentFilter::NameGlob(pattern.clone())),
+            Some(value) => value.as_i64().map(DocumentFilter::Id),
+            None => None,
+        },
+        context_node: args.get(2).and_then(Value::as_i64),
+        ..SelectorScope::default()
+    };
+    let sql = format!(
+        "SELECT {} FROM ({}) matched ORDER BY document_id, document_order",
+        NODE_COLUMNS,
+        css_to_node_sql(selector, &scope)?
+    );
+    Ok(query(db, &sql, &[])?.rows)
+}
+
+#[repr(C)]
+struct TableFunctionVtab {
+    base: sqlite3_vtab,
+    db: *mut sqlite3,
+    function: &'static TableFunction,
+}
+
+#[repr(C)]
+struct TableFunctionCursor {
+    base: sqlite3_vtab_cursor,
+    args: Vec<Value>,
+    rows: Vec<Vec<Value>>,
+    pos: usize,
+}
+
+static TABLE_FUNCTION_MODULE: sqlite3_module = sqlite3_module {
+    iVersion: 0,
+    // No xCreate: the tables are eponymous-only and cannot be CREATEd
+    xCreate: None,
+    xConnect: Some(x_connect),
+    xBestIndex: Some(x_best_index),
+    xDisconnect: Some(x_disconnect),
+    xDestroy: None,
+    xOpen: Some(x_open),
+    xClose: Some(x_close),
+    xFilter: Some(x_filter),
+    xNext: Some(x_next),
+    xEof: Some(x_eof),
+    xColumn: Some(x_column),
+    xRowid: Some(x_rowid),
+    ..unsafe { std::mem::zeroed() }
+};
+
+unsafe extern "C" fn x_connect(
+    db: *mut sqlite3,
+    p_aux: *mut c_void,
+    _argc: c_int,
+    _argv: *const *const c_char,
+    pp_vtab: *mut *mut sqlite3_vtab,
+    _pz_err: *mut *mut c_char,
+) -> c_int {
+    let function = &*(p_aux as *const TableFunction);
+    let hidden: Vec<String> = function
+        .args
+        .iter()
+        .map(|arg| format!(", {} HIDDEN", arg))
+        .collect();
+    let schema = format!("CREATE TABLE x({}{})", NODE_COLUMNS, hidden.concat());
+    let c_schema = CString::new(schema).unwrap();
+
+    let ret = sqlite3_declare_vtab(db, c_schema.as_ptr());
+    if ret != SQLITE_OK {
+        return ret;
+    }
+
+    let vtab = Box::new(TableFunctionVtab {
+        base: std::mem::zeroed(),
+        db,
+        function,
+    });
+    *pp_vtab = Box::into_raw(vtab) as *mut sqlite3_vtab;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_disconnect(p_vtab: *mut sqlite3_vtab) -> c_int {
+    drop(Box::from_raw(p_vtab as *mut TableFunctionVtab));
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_best_index(
+    p_vtab: *mut sqlite3_vtab,
+    info: *mut sqlite3_index_info,
+) -> c_int {
+    let function = (*(p_vtab as *mut TableFunctionVtab)).function;
+    let info = &mut *info;
+    let constraints = slice_or_empty(info.aConstraint, info.nConstraint);
+    let usage = slice_or_empty_mut(info.aConstraintUsage, info.nConstraint);
+
+    // idxNum is a bitmask of the arguments handed to filter(), in column order
+    let mut supplied: c_int = 0;
+    let mut next_argv = 1;
+    for arg_index in 0..function.args.len() {
+        let column = (NODE_COLUMN_COUNT + arg_index) as c_int;
+        let mut found = None;
+        let mut unusable = false;
+        for (i, constraint) in constraints.iter().enumerate() {
+            if constraint.iColumn == column && constraint.op as c_int == SQLITE_INDEX_CONSTRAINT_EQ
+            {
+                if constraint.usable != 0 {
+                    found = Some(i);
+                } else {
+                    unusable = true;
+                }
+            }
+        }
+        match found {
+            Some(i) => {
+                usage[i].argvIndex = next_argv;
+                usage[i].omit = 1;
+                next_argv += 1;
+                supplied |= 1 << arg_index;
+            }
+            // Hidden columns only hold what was passed in, so a plan that cannot
+            // pass an argument it is constrained on would compare against NULL
+            None if unusable => return SQLITE_CONSTRAINT,
+            None => {}
+        }
+    }
+
+    info.idxNum = supplied;
+    let required_mask = (1 << function.required) - 1;
+    if supplied & required_mask != required_mask {
+        // Still a valid plan, but a terrible one: filter() reports the missing argument
+        info.estimatedCost = 1e12;
+        info.estimatedRows = i64::MAX / 2;
+        return SQLITE_OK;
+    }
+
+    info.estimatedCost = function.estimated_rows as f64;
+    info.estimatedRows = function.estimated_rows;
+
+    let order_by = slice_or_empty(info.aOrderBy, info.nOrderBy);
+    if function.in_document_order
+        && order_by.len() == 1
+        && order_by[0].iColumn == DOCUMENT_ORDER_COLUMN
+        && order_by[0].desc == 0
+    {
+        info.orderByConsumed = 1;
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_open(
+    _p_vtab: *mut sqlite3_vtab,
+    pp_cursor: *mut *mut sqlite3_vtab_cursor,
+) -> c_int {
+    let cursor = Box::new(TableFunctionCursor {
+        base: std::mem::zeroed(),
+        args: Vec::new(),
+        rows: Vec::new(),
+        pos: 0,
+    });
+    *pp_cursor = Box::into_raw(cursor) as *mut sqlite3_vtab_cursor;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_close(p_cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    drop(Box::from_raw(p_cursor as *mut TableFunctionCursor));
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_filter(
+    p_cursor: *mut sqlite3_vtab_cursor,
+    idx_num: c_int,
+    _idx_str: *const c_char,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) -> c_int {
+    let cursor = &mut *(p_cursor as *mut TableFunctionCursor);
+    let vtab = &mut *(cursor.base.pVtab as *mut TableFunctionVtab);
+    let function = vtab.function;
+
+    let required_mask = (1 << function.required) - 1;
+    if idx_num & required_mask != required_mask {
+        set_vtab_error(
+            &mut vtab.base,
+            &format!(
+                "{}() requires {} argument(s): {}",
+                function.name,
+                function.required,
+                function.args[..function.required].join(", ")
+            ),
+        );
+        return SQLITE_ERROR;
+    }
+
+    let mut supplied = (0..argc as usize).map(|i| arg_value(argv, i));
+    cursor.args = (0..function.args.len())
+        .map(|arg_index| {
+            if idx_num & (1 << arg_index) != 0 {
+                supplied.next().unwrap_or(Value::Null)
+            } else {
+                Value::Null
+            }
+        })
+        .collect();
+    cursor.pos = 0;
+    cursor.rows.clear();
+
+    match (function.rows)(vtab.db, &cursor.args) {
+        Ok(rows) => {
+            cursor.rows = rows;
+            SQLITE_OK
+        }
+        Err(e) => {
+            set_vtab_error(&mut vtab.base, &e);
+            SQLITE_ERROR
+        }
+    }
+}
+
+unsafe extern "C" fn x_next(p_cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    let cursor = &mut *(p_cursor as *mut TableFunctionCursor);
+    cursor.pos += 1;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_eof(p_cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    let cursor = &*(p_cursor as *mut TableFunctionCursor);
+    (cursor.pos >= cursor.rows.len()) as c_int
+}
+
+unsafe extern "C" fn x_column(
+    p_cursor: *mut sqlite3_vtab_cursor,
+    ctx: *mut sqlite3_context,
+    column: c_int,
+) -> c_int {
+    let cursor = &*(p_cursor as *mut TableFunctionCursor);
+    let column = column as usize;
+    let value = if column < NODE_COLUMN_COUNT {
+        cursor.rows[cursor.pos].get(column)
+    } else {
+        cursor.args.get(column - NODE_COLUMN_COUNT)
+    };
+    result_value(ctx, value.unwrap_or(&Value::Null));
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_rowid(
+    p_cursor: *mut sqlite3_vtab_cursor,
+    p_rowid: *mut sqlite3_int64,
+) -> c_int {
+    let cursor = &*(p_cursor as *mut TableFunctionCursor);
+    *p_rowid = cursor.rows[cursor.pos][0]
+        .as_i64()
+        .unwrap_or(cursor.pos as i64);
+    SQLITE_OK
+}
+
+unsafe fn result_value(ctx: *mut sqlite3_context, value: &Value) {
+    match value {
+        Value::Null => sqlite3_result_null(ctx),
+        Value::Bool(b) => sqlite3_result_int64(ctx, *b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => sqlite3_result_int64(ctx, i),
+            None => sqlite3_result_double(ctx, n.as_f64().unwrap_or(0.0)),
+        },
+        Value::String(s) => result_text(ctx, Ok(Some(s.clone()))),
+        other => result_text(ctx, Ok(Some(other.to_string()))),
+    }
+}
+
+unsafe fn set_vtab_error(vtab: &mut sqlite3_vtab, msg: &str) {
+    if !vtab.zErrMsg.is_null() {
+        sqlite3_free(vtab.zErrMsg as *mut c_void);
+    }
+    let c_fmt = CString::new("%s").unwrap();
+    let c_msg = CString::new(msg.replace('\0', "")).unwrap();
+    vtab.zErrMsg = sqlite3_mprintf(c_fmt.as_ptr(), c_msg.as_ptr());
+}
+
+unsafe fn slice_or_empty<'a, T>(data: *const T, len: c_int) -> &'a [T] {
+    if data.is_null() || len <= 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len as usize)
+    }
+}
+
+unsafe fn slice_or_empty_mut<'a, T>(data: *mut T, len: c_int) -> &'a mut [T] {
+    if data.is_null() || len <= 0 {
+        &mut []
+    } else {
+        std::slice::from_raw_parts_mut(data, len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::query;
+    use crate::testing::{load_html, load_xml, node_id, open};
+    use sqlite_wasm_rs::sqlite3;
+
+    const CATALOG: &str =
+        "<catalog><book><title>A</title><price>1</price></book><book><title>B</title></book><dvd/></catalog>";
+
+    fn tags(db: *mut sqlite3, sql: &str) -> Vec<String> {
+        query(db, sql, &[])
+            .unwrap_or_else(|e| panic!("{}: {}", sql, e))
+            .rows
+            .iter()
+            .map(|row| row[0].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_tree_functions() {
+        let db = open();
+        load_xml(db, "catalog.xml", CATALOG);
+        let catalog = node_id(db, "catalog");
+        let title = node_id(db, "title");
+
+        assert_eq!(
+            tags(db, &format!("SELECT tag_name FROM children({})", catalog)),
+            ["book", "book", "dvd"]
+        );
+        assert_eq!(
+            tags(
+                db,
+                &format!("SELECT tag_name FROM descendants({})", catalog)
+            ),
+            ["book", "title", "price", "book", "title", "dvd"]
+        );
+        assert_eq!(
+            tags(db, &format!("SELECT tag_name FROM ancestors({})", title)),
+            ["book", "catalog"]
+        );
+        // The node itself is not its own sibling
+        assert_eq!(
+            tags(db, &format!("SELECT tag_name FROM siblings({})", title)),
+            ["price"]
+        );
+        assert!(tags(db, &format!("SELECT tag_name FROM siblings({})", catalog)).is_empty());
+
+        // Rows are `nodes` rows, so they filter and join like the base table
+        assert_eq!(
+            tags(
+                db,
+                &format!(
+                    "SELECT t.text_content FROM descendants({}) t
+                    JOIN nodes b ON b.id = t.parent_id WHERE t.tag_name = 'title'",
+                    catalog
+                )
+            ),
+            ["A", "B"]
+        );
+    }
+
+    #[test]
+    fn test_missing_argument_is_rejected() {
+        let db = open();
+        load_xml(db, "catalog.xml", CATALOG);
+        for function in [
+            "children",
+            "descendants",
+            "ancestors",
+            "siblings",
+            "css_select",
+        ] {
+            let sql = format!("SELECT * FROM {}", function);
+            // best_index rejects every plan without the argument
+            let error = query(db, &sql, &[]).unwrap_err();
+            assert!(
+                error.contains("requires 1 argument"),
+                "{}: {}",
+                function,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_css_select() {
+        let db = open();
+        load_xml(db, "catalog.xml", CATALOG);
+        load_html(db, "page.html", "<ul><li class=x>1</li><li>2</li></ul>");
+        assert_eq!(
+            tags(db, "SELECT text_content FROM css_select('book > title')"),
+            ["A", "B"]
+        );
+        assert_eq!(
+            tags(db, "SELECT text_content FROM css_select('li.x', '*.html')"),
+            ["1"]
+        );
+        assert!(tags(db, "SELECT tag_name FROM css_select('li', '*.xml')").is_empty());
+        let book = node_id(db, "book");
+        assert_eq!(
+            tags(
+                db,
+                &format!(
+                    "SELECT text_content FROM css_select('title', NULL, {})",
+                    book
+                )
+            ),
+            ["A"]
+        );
+        assert!(query(db, "SELECT * FROM css_select('book >')", &[]).is_err());
+    }
+}
@@ -0,0 +1,1655 @@
+//! XPath 1.0 → SQL compiler, the XPath counterpart of `selector.rs`.
+//!
+//! Expressions are evaluated over a view of `nodes` that also contains one
+//! row per document for the XPath document node (id = -documents.id), so
+//! `/library`, `//book` and `..` from a root element need no special cases.
+//! Nodes are ordered by `document_order` and siblings by `position`, not
+//! by id; reverse axes number positions in reverse document order.
+//!
+//! Elements keep only their own text, so `text()` yields at most one text
+//! node per element and string values join descendant text with spaces.
+//! Namespace prefixes in name tests are ignored since only local names are
+//! stored.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+enum XToken {
+    Slash,
+    DoubleSlash,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    At,
+    Comma,
+    DoubleColon,
+    Dot,
+    DotDot,
+    Pipe,
+    Plus,
+    Minus,
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    /// `*` used as a name test
+    Star,
+    Multiply,
+    /// `and`, `or`, `div`, `mod` in operator position
+    OperatorName(String),
+    Name(String),
+    Literal(String),
+    Number(f64),
+    Variable(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    Child,
+    Descendant,
+    DescendantOrSelf,
+    Parent,
+    Ancestor,
+    AncestorOrSelf,
+    FollowingSibling,
+    PrecedingSibling,
+    Following,
+    Preceding,
+    Attribute,
+    SelfNode,
+}
+
+impl Axis {
+    fn from_name(name: &str) -> Result<Axis, String> {
+        Ok(match name {
+            "child" => Axis::Child,
+            "descendant" => Axis::Descendant,
+            "descendant-or-self" => Axis::DescendantOrSelf,
+            "parent" => Axis::Parent,
+            "ancestor" => Axis::Ancestor,
+            "ancestor-or-self" => Axis::AncestorOrSelf,
+            "following-sibling" => Axis::FollowingSibling,
+            "preceding-sibling" => Axis::PrecedingSibling,
+            "following" => Axis::Following,
+            "preceding" => Axis::Preceding,
+            "attribute" => Axis::Attribute,
+            "self" => Axis::SelfNode,
+            "namespace" => return Err("The namespace axis is not supported".to_string()),
+            _ => return Err(format!("Unknown axis: {}", name)),
+        })
+    }
+
+    fn is_reverse(self) -> bool {
+        matches!(
+            self,
+            Axis::Parent
+                | Axis::Ancestor
+                | Axis::AncestorOrSelf
+                | Axis::PrecedingSibling
+                | Axis::Preceding
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeTest {
+    /// Local name; any prefix is dropped
+    Name(String),
+    /// `*` or `prefix:*`
+    Any,
+    /// `node()`
+    Node,
+    /// `text()`
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub axis: Axis,
+    pub test: NodeTest,
+    pub predicates: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Or => "OR",
+            BinaryOp::And => "AND",
+            BinaryOp::Eq => "=",
+            BinaryOp::Neq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Lte => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Gte => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+    Union(Box<Expr>, Box<Expr>),
+    /// A location path; `absolute` paths start at the document node
+    Path {
+        absolute: bool,
+        steps: Vec<Step>,
+    },
+    /// `primary[pred]...` optionally followed by `/steps`
+    Filter {
+        primary: Box<Expr>,
+        predicates: Vec<Expr>,
+        steps: Vec<Step>,
+    },
+    Literal(String),
+    Number(f64),
+    Function(String, Vec<Expr>),
+}
+
+pub fn parse_xpath(expr: &str) -> Result<Expr, String> {
+    let tokens = tokenize(expr)?;
+    if tokens.is_empty() {
+        return Err("Empty XPath expression".to_string());
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        return Err(format!("Unexpected token {:?}", token));
+    }
+    Ok(expr)
+}
+
+/// Translates an XPath expression into a SQL query over `nodes`/`attributes`.
+///
+/// Element results have the `nodes` columns, attribute results are
+/// `node_id, name, value`, `text()` results are `id, text` and any other
+/// expression yields a single `value` column.
+pub fn xpath_to_sql(expr: &str) -> Result<String, String> {
+    let ast = parse_xpath(expr)?;
+    let mut compiler = Compiler::default();
+    let body = match compiler.compile(&ast, &Context::Document)? {
+        Compiled::NodeSet(set) => match set.kind {
+            SetKind::Element => {
+                format!(
+                    "SELECT * FROM nodes WHERE id IN ({}) ORDER BY document_id, document_order",
+                    set.sql
+                )
+            }
+            SetKind::Attribute => format!(
+                "SELECT node_id, name, value FROM attributes WHERE id IN ({})
+ORDER BY (SELECT document_id FROM nodes WHERE id = node_id),
+    (SELECT document_order FROM nodes WHERE id = node_id), id",
+                set.sql
+            ),
+            SetKind::Text => format!(
+                "SELECT id, text_content AS text FROM nodes WHERE id IN ({})
+ORDER BY document_id, document_order",
+                set.sql
+            ),
+        },
+        scalar => format!("SELECT {} AS value", compiler.scalar_sql(scalar)),
+    };
+    Ok(format!("WITH {}\n{}", XNODES_VIEW, body))
+}
+
+const XNODES_VIEW: &str = "xnodes(id, document_id, parent_id, tag_name, text_content, is_doc, position, document_order) AS (
+    SELECT id, document_id, COALESCE(parent_id, -document_id), tag_name, text_content, 0, position, document_order FROM nodes
+    UNION ALL
+    SELECT -id, id, NULL, '', NULL, 1, 0, 0 FROM documents
+)";
+
+/// A join from `alias.id` onto its node and the ORDER BY terms that put a
+/// set of `kind` in document order. Attributes follow their element, in the
+/// order they were stored.
+fn document_order(alias: &str, kind: SetKind, descending: bool) -> (String, String) {
+    let dir = if descending { "DESC" } else { "ASC" };
+    let o = format!("{}_order", alias);
+    let (join, tiebreak) = match kind {
+        SetKind::Attribute => (
+            format!(
+                " JOIN attributes {o}_attr ON {o}_attr.id = {a}.id JOIN xnodes {o} ON {o}.id = {o}_attr.node_id",
+                o = o,
+                a = alias
+            ),
+            format!(", {}.id {}", alias, dir),
+        ),
+        SetKind::Element | SetKind::Text => (
+            format!(" JOIN xnodes {o} ON {o}.id = {a}.id", o = o, a = alias),
+            String::new(),
+        ),
+    };
+    let order = format!(
+        "{o}.document_id {d}, {o}.document_order {d}{t}",
+        o = o,
+        d = dir,
+        t = tiebreak
+    );
+    (join, order)
+}
+
+fn tokenize(input: &str) -> Result<Vec<XToken>, String> {
+    let mut tokens: Vec<XToken> = Vec::new();
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+
+    // XPath 1.0 §3.7: `*` and names are operators unless they follow one of
+    // @ :: ( [ , or another operator
+    let operator_position = |tokens: &[XToken]| match tokens.last() {
+        None => false,
+        Some(t) => !matches!(
+            t,
+            XToken::At
+                | XToken::DoubleColon
+                | XToken::LParen
+                | XToken::LBracket
+                | XToken::Comma
+                | XToken::OperatorName(_)
+                | XToken::Slash
+                | XToken::DoubleSlash
+                | XToken::Pipe
+                | XToken::Plus
+                | XToken::Minus
+                | XToken::Eq
+                | XToken::Neq
+                | XToken::Lt
+                | XToken::Lte
+                | XToken::Gt
+                | XToken::Gte
+                | XToken::Multiply
+        ),
+    };
+
+    while i < chars.len() {
+        let ch = chars[i];
+        let next = chars.get(i + 1).copied();
+        match ch {
+            c if c.is_whitespace() => {
+                i += 1;
+            }
+            '/' => {
+                if next == Some('/') {
+                    tokens.push(XToken::DoubleSlash);
+                    i += 2;
+                } else {
+                    tokens.push(XToken::Slash);
+                    i += 1;
+                }
+            }
+            '[' => {
+                tokens.push(XToken::LBracket);
+                i += 1;
+            }
+            ']' => {
+                tokens.push(XToken::RBracket);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(XToken::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(XToken::RParen);
+                i += 1;
+            }
+            '@' => {
+                tokens.push(XToken::At);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(XToken::Comma);
+                i += 1;
+            }
+            '|' => {
+                tokens.push(XToken::Pipe);
+                i += 1;
+            }
+            '+' => {
+                tokens.push(XToken::Plus);
+                i += 1;
+            }
+            '-' => {
+                tokens.push(XToken::Minus);
+                i += 1;
+            }
+            '=' => {
+                tokens.push(XToken::Eq);
+                i += 1;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(XToken::Neq);
+                i += 2;
+            }
+            '<' | '>' => {
+                let token = match (ch, next == Some('=')) {
+                    ('<', true) => XToken::Lte,
+                    ('<', false) => XToken::Lt,
+                    (_, true) => XToken::Gte,
+                    (_, false) => XToken::Gt,
+                };
+                i += if next == Some('=') { 2 } else { 1 };
+                tokens.push(token);
+            }
+            ':' if next == Some(':') => {
+                tokens.push(XToken::DoubleColon);
+                i += 2;
+            }
+            '*' => {
+                if operator_position(&tokens) {
+                    tokens.push(XToken::Multiply);
+                } else {
+                    tokens.push(XToken::Star);
+                }
+                i += 1;
+            }
+            '"' | '\'' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == ch)
+                    .ok_or_else(|| "Unterminated string literal".to_string())?;
+                tokens.push(XToken::Literal(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            '.' if next == Some('.') => {
+                tokens.push(XToken::DotDot);
+                i += 2;
+            }
+            c if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number = text
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid number: {}", text))?;
+                tokens.push(XToken::Number(number));
+            }
+            '.' => {
+                tokens.push(XToken::Dot);
+                i += 1;
+            }
+            '$' => {
+                i += 1;
+                let name = collect_name(&chars, &mut i);
+                tokens.push(XToken::Variable(name));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut name = collect_name(&chars, &mut i);
+                // QName prefix: `ns:local` or `ns:*`, but not the axis separator `::`
+                if chars.get(i) == Some(&':') && chars.get(i + 1) != Some(&':') {
+                    if chars.get(i + 1) == Some(&'*') {
+                        i += 2;
+                        tokens.push(XToken::Star);
+                        continue;
+                    }
+                    i += 1;
+                    name = collect_name(&chars, &mut i);
+                }
+                if operator_position(&tokens)
+                    && matches!(name.as_str(), "and" | "or" | "div" | "mod")
+                {
+                    tokens.push(XToken::OperatorName(name));
+                } else {
+                    tokens.push(XToken::Name(name));
+                }
+            }
+            _ => return Err(format!("Unexpected character in XPath: {}", ch)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn collect_name(chars: &[char], i: &mut usize) -> String {
+    let start = *i;
+    while *i < chars.len() && (chars[*i].is_alphanumeric() || matches!(chars[*i], '-' | '_' | '.'))
+    {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect()
+}
+
+struct Parser {
+    tokens: Vec<XToken>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&XToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&XToken> {
+        self.tokens.get(self.pos + offset)
+    }
+
+    fn next(&mut self) -> Option<XToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: XToken) -> Result<(), String> {
+        match self.next() {
+            Some(ref t) if *t == expected => Ok(()),
+            Some(t) => Err(format!("Expected {:?}, found {:?}", expected, t)),
+            None => Err(format!("Expected {:?}, found end of expression", expected)),
+        }
+    }
+
+    fn eat_operator_name(&mut self, name: &str) -> bool {
+        if matches!(self.peek(), Some(XToken::OperatorName(n)) if n == name) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_and()?;
+        while self.eat_operator_name("or") {
+            let right = self.parse_and()?;
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_equality()?;
+        while self.eat_operator_name("and") {
+            let right = self.parse_equality()?;
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_equality(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_relational()?;
+        loop {
+            let op = match self.peek() {
+                Some(XToken::Eq) => BinaryOp::Eq,
+                Some(XToken::Neq) => BinaryOp::Neq,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_relational()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_relational(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_additive()?;
+        loop {
+            let op = match self.peek() {
+                Some(XToken::Lt) => BinaryOp::Lt,
+                Some(XToken::Lte) => BinaryOp::Lte,
+                Some(XToken::Gt) => BinaryOp::Gt,
+                Some(XToken::Gte) => BinaryOp::Gte,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_additive()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(XToken::Plus) => BinaryOp::Add,
+                Some(XToken::Minus) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_multiplicative()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(XToken::Multiply) => BinaryOp::Mul,
+                Some(XToken::OperatorName(n)) if n == "div" => BinaryOp::Div,
+                Some(XToken::OperatorName(n)) if n == "mod" => BinaryOp::Mod,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(&XToken::Minus) {
+            self.pos += 1;
+            return Ok(Expr::Negate(Box::new(self.parse_unary()?)));
+        }
+        let mut left = self.parse_path_expr()?;
+        while self.peek() == Some(&XToken::Pipe) {
+            self.pos += 1;
+            let right = self.parse_path_expr()?;
+            left = Expr::Union(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_path_expr(&mut self) -> Result<Expr, String> {
+        let starts_filter = match self.peek() {
+            Some(XToken::Literal(_) | XToken::Number(_) | XToken::LParen | XToken::Variable(_)) => {
+                true
+            }
+            Some(XToken::Name(name)) => {
+                self.peek_at(1) == Some(&XToken::LParen) && !is_node_type(name)
+            }
+            _ => false,
+        };
+        if !starts_filter {
+            return self.parse_location_path();
+        }
+
+        let primary = self.parse_primary()?;
+        let predicates = self.parse_predicates()?;
+        let steps = match self.peek() {
+            Some(XToken::Slash) => {
+                self.pos += 1;
+                self.parse_relative_steps()?
+            }
+            Some(XToken::DoubleSlash) => {
+                self.pos += 1;
+                let mut steps = vec![descendant_or_self_step()];
+                steps.extend(self.parse_relative_steps()?);
+                steps
+            }
+            _ => Vec::new(),
+        };
+
+        if predicates.is_empty() && steps.is_empty() {
+            Ok(primary)
+        } else {
+            Ok(Expr::Filter {
+                primary: Box::new(primary),
+                predicates,
+                steps,
+            })
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(XToken::Literal(s)) => Ok(Expr::Literal(s)),
+            Some(XToken::Number(n)) => Ok(Expr::Number(n)),
+            Some(XToken::LParen) => {
+                let expr = self.parse_or()?;
+                self.expect(XToken::RParen)?;
+                Ok(expr)
+            }
+            Some(XToken::Variable(name)) => Err(format!("Variables are not supported: ${}", name)),
+            Some(XToken::Name(name)) => {
+                self.expect(XToken::LParen)?;
+                let mut args = Vec::new();
+                if self.peek() != Some(&XToken::RParen) {
+                    loop {
+                        args.push(self.parse_or()?);
+                        if self.peek() == Some(&XToken::Comma) {
+                            self.pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                self.expect(XToken::RParen)?;
+                Ok(Expr::Function(name, args))
+            }
+            Some(t) => Err(format!("Unexpected token {:?}", t)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+
+    fn parse_location_path(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(XToken::Slash) => {
+                self.pos += 1;
+                let steps = if self.starts_step() {
+                    self.parse_relative_steps()?
+                } else {
+                    Vec::new()
+                };
+                Ok(Expr::Path {
+                    absolute: true,
+                    steps,
+                })
+            }
+            Some(XToken::DoubleSlash) => {
+                self.pos += 1;
+                let mut steps = vec![descendant_or_self_step()];
+                steps.extend(self.parse_relative_steps()?);
+                Ok(Expr::Path {
+                    absolute: true,
+                    steps,
+                })
+            }
+            _ => Ok(Expr::Path {
+                absolute: false,
+                steps: self.parse_relative_steps()?,
+            }),
+        }
+    }
+
+    fn starts_step(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(XToken::Dot | XToken::DotDot | XToken::At | XToken::Star | XToken::Name(_))
+        )
+    }
+
+    fn parse_relative_steps(&mut self) -> Result<Vec<Step>, String> {
+        let mut steps = vec![self.parse_step()?];
+        loop {
+            match self.peek() {
+                Some(XToken::Slash) => {
+                    self.pos += 1;
+                    steps.push(self.parse_step()?);
+                }
+                Some(XToken::DoubleSlash) => {
+                    self.pos += 1;
+                    steps.push(descendant_or_self_step());
+                    steps.push(self.parse_step()?);
+                }
+                _ => return Ok(steps),
+            }
+        }
+    }
+
+    fn parse_step(&mut self) -> Result<Step, String> {
+        match self.peek() {
+            Some(XToken::Dot) => {
+                self.pos += 1;
+                return Ok(Step {
+                    axis: Axis::SelfNode,
+                    test: NodeTest::Node,
+                    predicates: Vec::new(),
+                });
+            }
+            Some(XToken::DotDot) => {
+                self.pos += 1;
+                return Ok(Step {
+                    axis: Axis::Parent,
+                    test: NodeTest::Node,
+                    predicates: Vec::new(),
+                });
+            }
+            _ => {}
+        }
+
+        let axis = if self.peek() == Some(&XToken::At) {
+            self.pos += 1;
+            Axis::Attribute
+        } else if let (Some(XToken::Name(name)), Some(XToken::DoubleColon)) =
+            (self.peek(), self.peek_at(1))
+        {
+            let axis = Axis::from_name(name)?;
+            self.pos += 2;
+            axis
+        } else {
+            Axis::Child
+        };
+
+        let test = match self.next() {
+            Some(XToken::Star) => NodeTest::Any,
+            Some(XToken::Name(name)) => {
+                if self.peek() == Some(&XToken::LParen) && is_node_type(&name) {
+                    self.pos += 1;
+                    self.expect(XToken::RParen)?;
+                    match name.as_str() {
+                        "node" => NodeTest::Node,
+                        "text" => NodeTest::Text,
+                        _ => return Err(format!("{}() nodes are not stored", name)),
+                    }
+                } else {
+                    NodeTest::Name(name)
+                }
+            }
+            Some(t) => return Err(format!("Expected a node test, found {:?}", t)),
+            None => return Err("Expected a node test, found end of expression".to_string()),
+        };
+
+        Ok(Step {
+            axis,
+            test,
+            predicates: self.parse_predicates()?,
+        })
+    }
+
+    fn parse_predicates(&mut self) -> Result<Vec<Expr>, String> {
+        let mut predicates = Vec::new();
+        while self.peek() == Some(&XToken::LBracket) {
+            self.pos += 1;
+            predicates.push(self.parse_or()?);
+            self.expect(XToken::RBracket)?;
+        }
+        Ok(predicates)
+    }
+}
+
+fn is_node_type(name: &str) -> bool {
+    matches!(name, "node" | "text" | "comment" | "processing-instruction")
+}
+
+fn descendant_or_self_step() -> Step {
+    Step {
+        axis: Axis::DescendantOrSelf,
+        test: NodeTest::Node,
+        predicates: Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SetKind {
+    Element,
+    Attribute,
+    Text,
+}
+
+/// SQL selecting a single `id` column: xnodes ids for elements and text,
+/// `attributes.id` for attributes.
+#[derive(Debug, Clone)]
+struct NodeSet {
+    sql: String,
+    kind: SetKind,
+}
+
+#[derive(Debug, Clone)]
+enum Compiled {
+    NodeSet(NodeSet),
+    Str(String),
+    Num(String),
+    Bool(String),
+}
+
+/// The node a (sub)expression is evaluated against.
+enum Context {
+    /// Top level: the document node of every loaded document
+    Document,
+    Node {
+        id: String,
+        kind: SetKind,
+        position: Option<String>,
+        size: Option<String>,
+    },
+}
+
+#[derive(Default)]
+struct Compiler {
+    aliases: usize,
+}
+
+impl Compiler {
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.aliases += 1;
+        format!("{}{}", prefix, self.aliases)
+    }
+
+    fn compile(&mut self, expr: &Expr, ctx: &Context) -> Result<Compiled, String> {
+        match expr {
+            Expr::Literal(s) => Ok(Compiled::Str(format!("'{}'", escape_sql(s)))),
+            Expr::Number(n) => Ok(Compiled::Num(format_number(*n))),
+            Expr::Negate(inner) => {
+                let value = self.compile(inner, ctx)?;
+                Ok(Compiled::Num(format!("(-{})", self.number_sql(value))))
+            }
+            Expr::Binary(op, left, right) => self.compile_binary(*op, left, right, ctx),
+            Expr::Union(left, right) => {
+                let left = self.compile_node_set(left, ctx)?;
+                let right = self.compile_node_set(right, ctx)?;
+                if left.kind != right.kind {
+                    return Err("Cannot union node sets of different kinds".to_string());
+                }
+                Ok(Compiled::NodeSet(NodeSet {
+                    sql: format!(
+                        "SELECT id FROM ({}) UNION SELECT id FROM ({})",
+                        left.sql, right.sql
+                    ),
+                    kind: left.kind,
+                }))
+            }
+            Expr::Path { absolute, steps } => {
+                let start = if *absolute {
+                    self.document_of(ctx)
+                } else {
+                    self.context_set(ctx)
+                };
+                Ok(Compiled::NodeSet(self.compile_steps(start, steps)?))
+            }
+            Expr::Filter {
+                primary,
+                predicates,
+                steps,
+            } => {
+                let base = self.compile_node_set(primary, ctx)?;
+                let relation = format!("SELECT 0 AS ctx, id FROM ({})", base.sql);
+                let filtered = self.apply_predicates(relation, base.kind, false, predicates)?;
+                let set = NodeSet {
+                    sql: format!("SELECT DISTINCT id FROM ({})", filtered),
+                    kind: base.kind,
+                };
+                Ok(Compiled::NodeSet(self.compile_steps(set, steps)?))
+            }
+            Expr::Function(name, args) => self.compile_function(name, args, ctx),
+        }
+    }
+
+    fn compile_node_set(&mut self, expr: &Expr, ctx: &Context) -> Result<NodeSet, String> {
+        match self.compile(expr, ctx)? {
+            Compiled::NodeSet(set) => Ok(set),
+            _ => Err("Expression does not evaluate to a node set".to_string()),
+        }
+    }
+
+    fn document_of(&mut self, ctx: &Context) -> NodeSet {
+        let sql = match ctx {
+            Context::Document => "SELECT -id AS id FROM documents".to_string(),
+            Context::Node { id, kind, .. } => {
+                let node = owner_element(id, *kind);
+                format!("SELECT -document_id AS id FROM xnodes WHERE id = {}", node)
+            }
+        };
+        NodeSet {
+            sql,
+            kind: SetKind::Element,
+        }
+    }
+
+    fn context_set(&mut self, ctx: &Context) -> NodeSet {
+        match ctx {
+            Context::Document => self.document_of(ctx),
+            Context::Node { id, kind, .. } => NodeSet {
+                sql: format!("SELECT {} AS id", id),
+                kind: *kind,
+            },
+        }
+    }
+
+    fn compile_steps(&mut self, mut set: NodeSet, steps: &[Step]) -> Result<NodeSet, String> {
+        for step in steps {
+            set = self.compile_step(set, step)?;
+        }
+        Ok(set)
+    }
+
+    fn compile_step(&mut self, input: NodeSet, step: &Step) -> Result<NodeSet, String> {
+        let c = self.fresh("c");
+        let n = self.fresh("n");
+
+        let (relation, kind) = match (input.kind, step.axis) {
+            (_, Axis::Attribute) => {
+                if input.kind != SetKind::Element {
+                    return Err("Only elements have attributes".to_string());
+                }
+                let test = match &step.test {
+                    NodeTest::Name(name) => format!("{}.name = '{}'", n, escape_sql(name)),
+                    NodeTest::Any | NodeTest::Node => "1".to_string(),
+                    NodeTest::Text => return Err("Attributes have no text() children".to_string()),
+                };
+                (
+                    format!(
+                        "SELECT {c}.id AS ctx, {n}.id AS id FROM ({prev}) {c} JOIN attributes {n} ON {n}.node_id = {c}.id WHERE {test}",
+                        c = c,
+                        n = n,
+                        prev = input.sql,
+                        test = test
+                    ),
+                    SetKind::Attribute,
+                )
+            }
+            (SetKind::Attribute | SetKind::Text, Axis::Parent) => {
+                // The parent of an attribute or text node is its element
+                let owner = if input.kind == SetKind::Attribute {
+                    format!("(SELECT node_id FROM attributes WHERE id = {}.id)", c)
+                } else {
+                    format!("{}.id", c)
+                };
+                (
+                    format!(
+                        "SELECT {c}.id AS ctx, {n}.id AS id FROM ({prev}) {c} JOIN xnodes {n} ON {n}.id = {owner} WHERE {test}",
+                        c = c,
+                        n = n,
+                        prev = input.sql,
+                        owner = owner,
+                        test = element_test(&step.test, &n)?
+                    ),
+                    SetKind::Element,
+                )
+            }
+            (SetKind::Attribute | SetKind::Text, Axis::SelfNode) => {
+                let text_self = input.kind == SetKind::Text && step.test == NodeTest::Text;
+                if !(step.test == NodeTest::Node || text_self) {
+                    return Err(
+                        "Only node() can test attribute or text nodes on the self axis".to_string(),
+                    );
+                }
+                (
+                    format!(
+                        "SELECT {c}.id AS ctx, {c}.id AS id FROM ({prev}) {c}",
+                        c = c,
+                        prev = input.sql
+                    ),
+                    input.kind,
+                )
+            }
+            (SetKind::Attribute | SetKind::Text, _) => {
+                return Err(format!(
+                    "The {:?} axis is not supported from attribute or text nodes",
+                    step.axis
+                ))
+            }
+            (SetKind::Element, _) if step.test == NodeTest::Text => {
+                // Text is stored on its element, so text "children" are the
+                // element itself, restricted to elements that have text
+                let owners = match step.axis {
+                    Axis::Child => format!("{n}.id = {c}.id", n = n, c = c),
+                    Axis::Descendant | Axis::DescendantOrSelf => format!(
+                        "({n}.id = {c}.id OR {desc})",
+                        n = n,
+                        c = c,
+                        desc = self.descendant_condition(&c, &n)
+                    ),
+                    _ => {
+                        return Err(
+                            "text() is only supported on the child and descendant axes".to_string()
+                        )
+                    }
+                };
+                (
+                    format!(
+                        "SELECT {c}.id AS ctx, {n}.id AS id FROM ({prev}) {c} JOIN xnodes {n} ON {owners} WHERE {n}.text_content IS NOT NULL",
+                        c = c,
+                        n = n,
+                        prev = input.sql,
+                        owners = owners
+                    ),
+                    SetKind::Text,
+                )
+            }
+            (SetKind::Element, axis) => {
+                let condition = self.axis_condition(axis, &c, &n);
+                (
+                    format!(
+                        "SELECT {c}.id AS ctx, {n}.id AS id FROM ({prev}) {c} JOIN xnodes {n} ON {cond} WHERE {test}",
+                        c = c,
+                        n = n,
+                        prev = input.sql,
+                        cond = condition,
+                        test = element_test(&step.test, &n)?
+                    ),
+                    SetKind::Element,
+                )
+            }
+        };
+
+        let filtered =
+            self.apply_predicates(relation, kind, step.axis.is_reverse(), &step.predicates)?;
+        Ok(NodeSet {
+            sql: format!("SELECT DISTINCT id FROM ({})", filtered),
+            kind,
+        })
+    }
+
+    fn descendant_condition(&mut self, c: &str, n: &str) -> String {
+        let d = self.fresh("d");
+        format!(
+            "{n}.id IN (
+    WITH RECURSIVE {d}(id) AS (
+        SELECT id FROM xnodes WHERE parent_id = {c}.id
+        UNION ALL
+        SELECT x.id FROM xnodes x JOIN {d} ON x.parent_id = {d}.id
+    )
+    SELECT id FROM {d}
+)",
+            n = n,
+            c = c,
+            d = d
+        )
+    }
+
+    fn ancestor_condition(&mut self, c: &str, n: &str) -> String {
+        let u = self.fresh("u");
+        format!(
+            "{n}.id IN (
+    WITH RECURSIVE {u}(id) AS (
+        SELECT parent_id FROM xnodes WHERE id = {c}.id
+        UNION ALL
+        SELECT x.parent_id FROM xnodes x JOIN {u} ON x.id = {u}.id
+    )
+    SELECT id FROM {u} WHERE id IS NOT NULL
+)",
+            n = n,
+            c = c,
+            u = u
+        )
+    }
+
+    fn axis_condition(&mut self, axis: Axis, c: &str, n: &str) -> String {
+        let parent_of_c = format!("(SELECT parent_id FROM xnodes WHERE id = {}.id)", c);
+        let position_of_c = format!("(SELECT position FROM xnodes WHERE id = {}.id)", c);
+        let order_of_c = format!("(SELECT document_order FROM xnodes WHERE id = {}.id)", c);
+        match axis {
+            Axis::Child => format!("{}.parent_id = {}.id", n, c),
+            Axis::Descendant => self.descendant_condition(c, n),
+            Axis::DescendantOrSelf => format!(
+                "({}.id = {}.id OR {})",
+                n,
+                c,
+                self.descendant_condition(c, n)
+            ),
+            Axis::Parent => format!("{}.id = {}", n, parent_of_c),
+            Axis::Ancestor => self.ancestor_condition(c, n),
+            Axis::AncestorOrSelf => format!(
+                "({}.id = {}.id OR {})",
+                n,
+                c,
+                self.ancestor_condition(c, n)
+            ),
+            Axis::FollowingSibling => format!(
+                "{n}.parent_id = {p} AND {n}.position > {pos}",
+                n = n,
+                p = parent_of_c,
+                pos = position_of_c
+            ),
+            Axis::PrecedingSibling => format!(
+                "{n}.parent_id = {p} AND {n}.position < {pos}",
+                n = n,
+                p = parent_of_c,
+                pos = position_of_c
+            ),
+            Axis::Following => {
+                // `document_order` is a pre-order numbering, so a subtree
+                // occupies a contiguous range ending at its last descendant
+                let x = self.fresh("x");
+                let subtree_end = format!(
+                    "COALESCE((SELECT MAX({x}.document_order) FROM xnodes {x} WHERE {desc}), {order})",
+                    x = x,
+                    desc = self.descendant_condition(c, &x),
+                    order = order_of_c
+                );
+                format!(
+                    "{n}.is_doc = 0 AND {c}.id > 0 AND {n}.document_id = (SELECT document_id FROM xnodes WHERE id = {c}.id) AND {n}.document_order > {end}",
+                    n = n,
+                    c = c,
+                    end = subtree_end
+                )
+            }
+            Axis::Preceding => format!(
+                "{n}.is_doc = 0 AND {n}.document_id = (SELECT document_id FROM xnodes WHERE id = {c}.id) AND {n}.document_order < {order} AND NOT {anc}",
+                n = n,
+                c = c,
+                order = order_of_c,
+                anc = self.ancestor_condition(c, n)
+            ),
+            Axis::SelfNode => format!("{}.id = {}.id", n, c),
+            Axis::Attribute => unreachable!("attribute axis is compiled separately"),
+        }
+    }
+
+    /// Filters a `(ctx, id)` relation by each predicate in turn. Positions are
+    /// counted per context node, in reverse document order on reverse axes.
+    fn apply_predicates(
+        &mut self,
+        mut relation: String,
+        kind: SetKind,
+        reverse: bool,
+        predicates: &[Expr],
+    ) -> Result<String, String> {
+        for predicate in predicates {
+            let s = self.fresh("s");
+            let ctx = Context::Node {
+                id: format!("{}.id", s),
+                kind,
+                position: Some(format!("{}.pos", s)),
+                size: Some(format!("{}.size", s)),
+            };
+            let condition = match self.compile(predicate, &ctx)? {
+                Compiled::Num(n) => format!("{}.pos = {}", s, n),
+                other => self.boolean_sql(other),
+            };
+            let r = self.fresh("r");
+            let (join, order) = document_order(&r, kind, reverse);
+            relation = format!(
+                "SELECT ctx, id FROM (
+    SELECT {r}.ctx, {r}.id,
+        ROW_NUMBER() OVER (PARTITION BY {r}.ctx ORDER BY {order}) AS pos,
+        COUNT(*) OVER (PARTITION BY {r}.ctx) AS size
+    FROM ({rel}) {r}{join}
+) {s} WHERE {cond}",
+                r = r,
+                order = order,
+                join = join,
+                rel = relation,
+                s = s,
+                cond = condition
+            );
+        }
+        Ok(relation)
+    }
+
+    fn compile_binary(
+        &mut self,
+        op: BinaryOp,
+        left: &Expr,
+        right: &Expr,
+        ctx: &Context,
+    ) -> Result<Compiled, String> {
+        let left = self.compile(left, ctx)?;
+        let right = self.compile(right, ctx)?;
+        match op {
+            BinaryOp::Or | BinaryOp::And => Ok(Compiled::Bool(format!(
+                "({} {} {})",
+                self.boolean_sql(left),
+                op,
+                self.boolean_sql(right)
+            ))),
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Mod => {
+                Ok(Compiled::Num(format!(
+                    "({} {} {})",
+                    self.number_sql(left),
+                    op,
+                    self.number_sql(right)
+                )))
+            }
+            BinaryOp::Div => Ok(Compiled::Num(format!(
+                "(CAST({} AS REAL) / {})",
+                self.number_sql(left),
+                self.number_sql(right)
+            ))),
+            _ => self.compile_comparison(op, left, right),
+        }
+    }
+
+    /// XPath 1.0 §3.4: comparisons involving node sets are existential over
+    /// the string values of their members.
+    fn compile_comparison(
+        &mut self,
+        op: BinaryOp,
+        left: Compiled,
+        right: Compiled,
+    ) -> Result<Compiled, String> {
+        let relational = !matches!(op, BinaryOp::Eq | BinaryOp::Neq);
+        let sql = match (left, right) {
+            (Compiled::NodeSet(l), Compiled::NodeSet(r)) => {
+                let (x, y) = (self.fresh("x"), self.fresh("y"));
+                let (lv, rv) = (string_value(&x, l.kind), string_value(&y, r.kind));
+                let (lv, rv) = if relational {
+                    (
+                        format!("CAST({} AS REAL)", lv),
+                        format!("CAST({} AS REAL)", rv),
+                    )
+                } else {
+                    (lv, rv)
+                };
+                format!(
+                    "EXISTS (SELECT 1 FROM ({}) {}, ({}) {} WHERE {} {} {})",
+                    l.sql, x, r.sql, y, lv, op, rv
+                )
+            }
+            (Compiled::NodeSet(set), other) => self.compare_set(set, op, other, false),
+            (other, Compiled::NodeSet(set)) => self.compare_set(set, op, other, true),
+            (l @ Compiled::Bool(_), r) | (l, r @ Compiled::Bool(_)) if !relational => {
+                format!("({} {} {})", self.boolean_sql(l), op, self.boolean_sql(r))
+            }
+            (l @ Compiled::Num(_), r) | (l, r @ Compiled::Num(_)) => {
+                format!("({} {} {})", self.number_sql(l), op, self.number_sql(r))
+            }
+            (l, r) if relational => {
+                format!("({} {} {})", self.number_sql(l), op, self.number_sql(r))
+            }
+            (l, r) => format!("({} {} {})", self.string_sql(l), op, self.string_sql(r)),
+        };
+        Ok(Compiled::Bool(sql))
+    }
+
+    fn compare_set(
+        &mut self,
+        set: NodeSet,
+        op: BinaryOp,
+        other: Compiled,
+        flipped: bool,
+    ) -> String {
+        if let Compiled::Bool(_) = other {
+            let exists = format!("EXISTS ({})", set.sql);
+            let other = self.boolean_sql(other);
+            return if flipped {
+                format!("({} {} {})", other, op, exists)
+            } else {
+                format!("({} {} {})", exists, op, other)
+            };
+        }
+
+        let x = self.fresh("x");
+        let member = string_value(&x, set.kind);
+        let (member, other) = match other {
+            Compiled::Num(n) => (format!("CAST({} AS REAL)", member), n),
+            other if !matches!(op, BinaryOp::Eq | BinaryOp::Neq) => {
+                (format!("CAST({} AS REAL)", member), self.number_sql(other))
+            }
+            other => (member, self.string_sql(other)),
+        };
+        let condition = if flipped {
+            format!("{} {} {}", other, op, member)
+        } else {
+            format!("{} {} {}", member, op, other)
+        };
+        format!(
+            "EXISTS (SELECT 1 FROM ({}) {} WHERE {})",
+            set.sql, x, condition
+        )
+    }
+
+    fn compile_function(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        ctx: &Context,
+    ) -> Result<Compiled, String> {
+        let arity = |min: usize, max: usize| {
+            if args.len() < min || args.len() > max {
+                Err(format!("Wrong number of arguments to {}()", name))
+            } else {
+                Ok(())
+            }
+        };
+
+        match name {
+            "last" | "position" => {
+                arity(0, 0)?;
+                let value = match ctx {
+                    Context::Node { position, size, .. } => {
+                        if name == "last" {
+                            size.clone()
+                        } else {
+                            position.clone()
+                        }
+                    }
+                    Context::Document => None,
+                };
+                Ok(Compiled::Num(value.unwrap_or_else(|| "1".to_string())))
+            }
+            "count" => {
+                arity(1, 1)?;
+                let set = self.compile_node_set(&args[0], ctx)?;
+                Ok(Compiled::Num(format!(
+                    "(SELECT COUNT(*) FROM ({}))",
+                    set.sql
+                )))
+            }
+            "sum" => {
+                arity(1, 1)?;
+                let set = self.compile_node_set(&args[0], ctx)?;
+                let x = self.fresh("x");
+                Ok(Compiled::Num(format!(
+                    "(SELECT TOTAL(CAST({} AS REAL)) FROM ({}) {})",
+                    string_value(&x, set.kind),
+                    set.sql,
+                    x
+                )))
+            }
+            "id" => {
+                arity(1, 1)?;
+                let value = self.compile(&args[0], ctx)?;
+                let ids = self.string_sql(value);
+                Ok(Compiled::NodeSet(NodeSet {
+                    sql: format!(
+                        "SELECT node_id AS id FROM attributes WHERE name = 'id' AND (' ' || {} || ' ') LIKE ('% ' || value || ' %')",
+                        ids
+                    ),
+                    kind: SetKind::Element,
+                }))
+            }
+            "name" | "local-name" => {
+                arity(0, 1)?;
+                let set = match args.first() {
+                    Some(arg) => self.compile_node_set(arg, ctx)?,
+                    None => self.context_set(ctx),
+                };
+                let x = self.fresh("x");
+                let name_sql = match set.kind {
+                    SetKind::Attribute => {
+                        format!("(SELECT name FROM attributes WHERE id = {}.id)", x)
+                    }
+                    SetKind::Element => {
+                        format!("(SELECT tag_name FROM xnodes WHERE id = {}.id)", x)
+                    }
+                    SetKind::Text => "''".to_string(),
+                };
+                let (join, order) = document_order(&x, set.kind, false);
+                Ok(Compiled::Str(format!(
+                    "COALESCE((SELECT {} FROM ({}) {}{} ORDER BY {} LIMIT 1), '')",
+                    name_sql, set.sql, x, join, order
+                )))
+            }
+            "string" => {
+                arity(0, 1)?;
+                let value = self.context_arg(args, ctx)?;
+                Ok(Compiled::Str(self.string_sql(value)))
+            }
+            "concat" => {
+                if args.len() < 2 {
+                    return Err("concat() needs at least two arguments".to_string());
+                }
+                let mut parts = Vec::new();
+                for arg in args {
+                    let value = self.compile(arg, ctx)?;
+                    parts.push(self.string_sql(value));
+                }
+                Ok(Compiled::Str(format!("({})", parts.join(" || "))))
+            }
+            "contains" | "starts-with" | "substring-before" | "substring-after" => {
+                arity(2, 2)?;
+                let haystack = self.compile(&args[0], ctx)?;
+                let haystack = self.string_sql(haystack);
+                let needle = self.compile(&args[1], ctx)?;
+                let needle = self.string_sql(needle);
+                Ok(match name {
+                    "contains" => Compiled::Bool(format!("(instr({}, {}) > 0)", haystack, needle)),
+                    "starts-with" => Compiled::Bool(format!(
+                        "(substr({0}, 1, length({1})) = {1})",
+                        haystack, needle
+                    )),
+                    "substring-before" => Compiled::Str(format!(
+                        "(CASE WHEN instr({0}, {1}) > 0 THEN substr({0}, 1, instr({0}, {1}) - 1) ELSE '' END)",
+                        haystack, needle
+                    )),
+                    _ => Compiled::Str(format!(
+                        "(CASE WHEN instr({0}, {1}) > 0 THEN substr({0}, instr({0}, {1}) + length({1})) ELSE '' END)",
+                        haystack, needle
+                    )),
+                })
+            }
+            "substring" => {
+                arity(2, 3)?;
+                let value = self.compile(&args[0], ctx)?;
+                let value = self.string_sql(value);
+                let start = self.compile(&args[1], ctx)?;
+                let start = format!("CAST(round({}) AS INTEGER)", self.number_sql(start));
+                Ok(Compiled::Str(match args.get(2) {
+                    Some(len) => {
+                        let len = self.compile(len, ctx)?;
+                        let len = format!("CAST(round({}) AS INTEGER)", self.number_sql(len));
+                        // Characters at positions p with start <= p < start + len
+                        format!(
+                            "substr({0}, max({1}, 1), max({1} + {2} - max({1}, 1), 0))",
+                            value, start, len
+                        )
+                    }
+                    None => format!("substr({}, max({}, 1))", value, start),
+                }))
+            }
+            "string-length" => {
+                arity(0, 1)?;
+                let value = self.context_arg(args, ctx)?;
+                Ok(Compiled::Num(format!("length({})", self.string_sql(value))))
+            }
+            "normalize-space" => {
+                arity(0, 1)?;
+                let value = self.context_arg(args, ctx)?;
+                Ok(Compiled::Str(format!(
+                    "trim(regexp_replace({}, '\\s+', ' '))",
+                    self.string_sql(value)
+                )))
+            }
+            "not" | "boolean" => {
+                arity(1, 1)?;
+                let value = self.compile(&args[0], ctx)?;
+                let value = self.boolean_sql(value);
+                Ok(Compiled::Bool(if name == "not" {
+                    format!("(NOT {})", value)
+                } else {
+                    value
+                }))
+            }
+            "true" | "false" => {
+                arity(0, 0)?;
+                Ok(Compiled::Bool(
+                    if name == "true" { "1" } else { "0" }.to_string(),
+                ))
+            }
+            "number" => {
+                arity(0, 1)?;
+                let value = self.context_arg(args, ctx)?;
+                Ok(Compiled::Num(self.number_sql(value)))
+            }
+            "floor" | "ceiling" | "round" => {
+                arity(1, 1)?;
+                let value = self.compile(&args[0], ctx)?;
+                let value = self.number_sql(value);
+                Ok(Compiled::Num(match name {
+                    "floor" => format!("floor({})", value),
+                    "ceiling" => format!("ceil({})", value),
+                    _ => format!("floor({} + 0.5)", value),
+                }))
+            }
+            _ => Err(format!("Unsupported XPath function: {}()", name)),
+        }
+    }
+
+    /// The single argument of a function, defaulting to the context node.
+    fn context_arg(&mut self, args: &[Expr], ctx: &Context) -> Result<Compiled, String> {
+        match args.first() {
+            Some(arg) => self.compile(arg, ctx),
+            None => Ok(Compiled::NodeSet(self.context_set(ctx))),
+        }
+    }
+
+    fn string_sql(&mut self, value: Compiled) -> String {
+        match value {
+            Compiled::Str(s) => s,
+            Compiled::Num(n) => format!("CAST({} AS TEXT)", n),
+            Compiled::Bool(b) => format!("(CASE WHEN {} THEN 'true' ELSE 'false' END)", b),
+            Compiled::NodeSet(set) => {
+                let x = self.fresh("x");
+                let (join, order) = document_order(&x, set.kind, false);
+                format!(
+                    "COALESCE((SELECT {} FROM ({}) {}{} ORDER BY {} LIMIT 1), '')",
+                    string_value(&x, set.kind),
+                    set.sql,
+                    x,
+                    join,
+                    order
+                )
+            }
+        }
+    }
+
+    fn number_sql(&mut self, value: Compiled) -> String {
+        match value {
+            Compiled::Num(n) => n,
+            Compiled::Bool(b) => format!("(CASE WHEN {} THEN 1 ELSE 0 END)", b),
+            other => format!("CAST({} AS REAL)", self.string_sql(other)),
+        }
+    }
+
+    fn boolean_sql(&mut self, value: Compiled) -> String {
+        match value {
+            Compiled::Bool(b) => b,
+            Compiled::Num(n) => format!("({} != 0)", n),
+            Compiled::Str(s) => format!("(length({}) > 0)", s),
+            Compiled::NodeSet(set) => format!("EXISTS ({})", set.sql),
+        }
+    }
+
+    fn scalar_sql(&mut self, value: Compiled) -> String {
+        match value {
+            Compiled::Bool(b) => format!("(CASE WHEN {} THEN 1 ELSE 0 END)", b),
+            Compiled::Num(n) => n,
+            other => self.string_sql(other),
+        }
+    }
+}
+
+fn element_test(test: &NodeTest, n: &str) -> Result<String, String> {
+    Ok(match test {
+        NodeTest::Name(name) => format!(
+            "{n}.is_doc = 0 AND {n}.tag_name = '{name}'",
+            n = n,
+            name = escape_sql(name)
+        ),
+        NodeTest::Any => format!("{}.is_doc = 0", n),
+        NodeTest::Node => "1".to_string(),
+        NodeTest::Text => unreachable!("text() is compiled separately"),
+    })
+}
+
+/// String value of the member `x.id` of a node set.
+fn string_value(x: &str, kind: SetKind) -> String {
+    match kind {
+        SetKind::Element => format!("COALESCE(inner_text({}.id), '')", x),
+        SetKind::Attribute => format!("(SELECT value FROM attributes WHERE id = {}.id)", x),
+        SetKind::Text => format!("(SELECT text_content FROM nodes WHERE id = {}.id)", x),
+    }
+}
+
+/// The element an attribute or text context node belongs to.
+fn owner_element(id: &str, kind: SetKind) -> String {
+    match kind {
+        SetKind::Attribute => format!("(SELECT node_id FROM attributes WHERE id = {})", id),
+        _ => id.to_string(),
+    }
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// Quotes `value` as an XPath 1.0 string literal. XPath has no escapes, so a
+/// value containing both quote characters is built with `concat()`.
+pub fn string_literal(value: &str) -> String {
+    if !value.contains('"') {
+        format!("\"{}\"", value)
+    } else if !value.contains('\'') {
+        format!("'{}'", value)
+    } else {
+        let parts: Vec<String> = value
+            .split('"')
+            .map(|part| format!("\"{}\"", part))
+            .collect();
+        format!("concat({})", parts.join(", '\"', "))
+    }
+}
+
+fn escape_sql(s: &str) -> String {
+    s.replace('\'', "''")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_abbreviated_path() {
+        let expr = parse_xpath("//book[@category='fiction']/title").unwrap();
+        let Expr::Path { absolute, steps } = expr else {
+            panic!("expected a path");
+        };
+        assert!(absolute);
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].axis, Axis::DescendantOrSelf);
+        assert_eq!(steps[1].test, NodeTest::Name("book".to_string()));
+        assert_eq!(steps[1].predicates.len(), 1);
+        assert_eq!(steps[2].test, NodeTest::Name("title".to_string()));
+    }
+
+    #[test]
+    fn test_parse_axes_and_node_types() {
+        let expr = parse_xpath("ancestor-or-self::*/following-sibling::node()/text()").unwrap();
+        let Expr::Path { steps, .. } = expr else {
+            panic!("expected a path");
+        };
+        assert_eq!(steps[0].axis, Axis::AncestorOrSelf);
+        assert_eq!(steps[0].test, NodeTest::Any);
+        assert_eq!(steps[1].axis, Axis::FollowingSibling);
+        assert_eq!(steps[1].test, NodeTest::Node);
+        assert_eq!(steps[2].test, NodeTest::Text);
+    }
+
+    #[test]
+    fn test_tokenize_operator_disambiguation() {
+        // `div` and `*` are operators after an operand, names otherwise
+        let tokens = tokenize("div * 2 div mod").unwrap();
+        assert_eq!(tokens[0], XToken::Name("div".to_string()));
+        assert_eq!(tokens[1], XToken::Multiply);
+        assert_eq!(tokens[3], XToken::OperatorName("div".to_string()));
+        assert_eq!(tokens[4], XToken::Name("mod".to_string()));
+    }
+
+    #[test]
+    fn test_string_literal() {
+        assert_eq!(string_literal("main"), "\"main\"");
+        assert_eq!(string_literal("say \"hi\""), "'say \"hi\"'");
+        let mixed = string_literal("it's \"x\"");
+        assert_eq!(mixed, "concat(\"it's \", '\"', \"x\", '\"', \"\")");
+        assert!(parse_xpath(&format!("//*[@id = {}]", mixed)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_xpath("//book[").is_err());
+        assert!(parse_xpath("namespace::x").is_err());
+        assert!(xpath_to_sql("$var").is_err());
+        assert!(xpath_to_sql("unknown-fn()").is_err());
+    }
+
+    #[test]
+    fn test_xpath_to_sql_result_shapes() {
+        assert!(xpath_to_sql("//book")
+            .unwrap()
+            .contains("SELECT * FROM nodes WHERE id IN"));
+        assert!(xpath_to_sql("//book/@id")
+            .unwrap()
+            .contains("SELECT node_id, name, value FROM attributes"));
+        assert!(xpath_to_sql("//title/text()")
+            .unwrap()
+            .contains("text_content AS text"));
+        assert!(xpath_to_sql("count(//book)").unwrap().contains("AS value"));
+    }
+
+    #[test]
+    fn test_positional_predicate_uses_row_number() {
+        let sql = xpath_to_sql("//book[last()]").unwrap();
+        assert!(sql.contains("ROW_NUMBER() OVER (PARTITION BY r"));
+        assert!(sql.contains("document_order ASC) AS pos"));
+        let sql = xpath_to_sql("//title/ancestor::*[1]").unwrap();
+        assert!(sql.contains("document_order DESC) AS pos"));
+    }
+}
@@ -0,0 +1,1235 @@
+//! Validation of stored documents against a common subset of XML Schema.
+//!
+//! Supported: global and local element declarations (`ref` included),
+//! named and anonymous complex types with `sequence`, `choice` and `all`,
+//! `minOccurs`/`maxOccurs`, `mixed`, `simpleContent` and `complexContent`
+//! extension, `any`/`anyAttribute`, attributes with `use`/`fixed`, and
+//! simple types restricted with the usual facets. Other constructs are
+//! reported as schema errors rather than silently ignored.
+//!
+//! Names are compared without namespaces, since stored documents only keep
+//! local names.
+
+use crate::database::{exec, query};
+use crate::functions::node_path;
+use crate::tree::{local_name, StoredNode, StoredTree};
+use regex_lite::Regex;
+use serde::Serialize;
+use serde_json::json;
+use sqlite_wasm_rs::sqlite3;
+use std::collections::{BTreeSet, HashMap};
+
+const XS: &str = "http://www.w3.org/2001/XMLSchema";
+
+/// Attributes the schema instance namespace adds to documents.
+const INSTANCE_ATTRIBUTES: &[&str] = &["schemaLocation", "noNamespaceSchemaLocation"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    elements: HashMap<String, ElementDecl>,
+    complex_types: HashMap<String, ComplexType>,
+    simple_types: HashMap<String, SimpleType>,
+}
+
+#[derive(Debug, Clone)]
+struct ElementDecl {
+    name: String,
+    kind: TypeRef,
+    fixed: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum TypeRef {
+    Named(String),
+    Complex(Box<ComplexType>),
+    Simple(SimpleType),
+    /// A `ref` to a global element
+    Global(String),
+    AnyType,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ComplexType {
+    /// `complexContent` extension base
+    base: Option<String>,
+    particle: Option<Particle>,
+    /// `simpleContent`: text of this type, no child elements
+    simple_content: Option<SimpleType>,
+    mixed: bool,
+    attributes: Vec<AttributeDecl>,
+    any_attribute: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Occurs {
+    min: usize,
+    /// `None` is unbounded
+    max: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Particle {
+    Element(Occurs, Box<ElementDecl>),
+    Sequence(Occurs, Vec<Particle>),
+    Choice(Occurs, Vec<Particle>),
+    All(Occurs, Vec<Particle>),
+    Any(Occurs),
+}
+
+#[derive(Debug, Clone)]
+struct AttributeDecl {
+    name: String,
+    kind: SimpleType,
+    required: bool,
+    fixed: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct SimpleType {
+    /// A built-in type (`integer`) or the name of a user-defined simple type
+    base: String,
+    facets: Facets,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Facets {
+    enumeration: Vec<String>,
+    patterns: Vec<(String, Regex)>,
+    length: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_inclusive: Option<f64>,
+    max_inclusive: Option<f64>,
+    min_exclusive: Option<f64>,
+    max_exclusive: Option<f64>,
+    total_digits: Option<usize>,
+    fraction_digits: Option<usize>,
+}
+
+fn is_xs(node: &roxmltree::Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(XS) && node.tag_name().name() == name
+}
+
+fn xs_children<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> {
+    node.children().filter(|c| {
+        c.is_element()
+            && !(c.tag_name().namespace() == Some(XS) && c.tag_name().name() == "annotation")
+    })
+}
+
+/// Drops the namespace prefix of a QName such as `xs:string`.
+fn local(qname: &str) -> String {
+    qname.rsplit(':').next().unwrap_or(qname).to_string()
+}
+
+fn unsupported(node: &roxmltree::Node) -> String {
+    format!("Unsupported XSD construct: xs:{}", node.tag_name().name())
+}
+
+impl Schema {
+    pub fn parse(text: &str) -> Result<Schema, String> {
+        let doc =
+            roxmltree::Document::parse(text).map_err(|e| format!("XSD parsing error: {}", e))?;
+        let root = doc.root_element();
+        if !is_xs(&root, "schema") {
+            return Err("The root element must be xs:schema".to_string());
+        }
+        let mut schema = Schema::default();
+        for child in xs_children(root) {
+            let name = child.attribute("name").unwrap_or_default().to_string();
+            match child.tag_name().name() {
+                "element" => {
+                    let decl = parse_element(child)?;
+                    schema.elements.insert(decl.name.clone(), decl);
+                }
+                "complexType" => {
+                    schema
+                        .complex_types
+                        .insert(name, parse_complex_type(child)?);
+                }
+                "simpleType" => {
+                    schema.simple_types.insert(name, parse_simple_type(child)?);
+                }
+                _ => return Err(unsupported(&child)),
+            }
+        }
+        Ok(schema)
+    }
+}
+
+fn parse_occurs(node: roxmltree::Node) -> Result<Occurs, String> {
+    let min = match node.attribute("minOccurs") {
+        Some(v) => v
+            .parse()
+            .map_err(|_| format!("Invalid minOccurs '{}'", v))?,
+        None => 1,
+    };
+    let max = match node.attribute("maxOccurs") {
+        Some("unbounded") => None,
+        Some(v) => Some(
+            v.parse()
+                .map_err(|_| format!("Invalid maxOccurs '{}'", v))?,
+        ),
+        None => Some(1),
+    };
+    Ok(Occurs { min, max })
+}
+
+fn parse_element(node: roxmltree::Node) -> Result<ElementDecl, String> {
+    if let Some(reference) = node.attribute("ref") {
+        return Ok(ElementDecl {
+            name: local(reference),
+            kind: TypeRef::Global(local(reference)),
+            fixed: None,
+        });
+    }
+    let name = node
+        .attribute("name")
+        .ok_or("xs:element needs a name or ref")?
+        .to_string();
+    let mut kind = match node.attribute("type") {
+        Some(t) => TypeRef::Named(local(t)),
+        None => TypeRef::AnyType,
+    };
+    for child in xs_children(node) {
+        kind = match child.tag_name().name() {
+            "complexType" => TypeRef::Complex(Box::new(parse_complex_type(child)?)),
+            "simpleType" => TypeRef::Simple(parse_simple_type(child)?),
+            "key" | "keyref" | "unique" => continue,
+            _ => return Err(unsupported(&child)),
+        };
+    }
+    Ok(ElementDecl {
+        name,
+        kind,
+        fixed: node.attribute("fixed").map(str::to_string),
+    })
+}
+
+fn parse_complex_type(node: roxmltree::Node) -> Result<ComplexType, String> {
+    let mut complex = ComplexType {
+        mixed: node.attribute("mixed") == Some("true"),
+        ..ComplexType::default()
+    };
+    parse_complex_body(node, &mut complex)?;
+    Ok(complex)
+}
+
+/// Reads particles and attributes, descending into content extensions.
+fn parse_complex_body(node: roxmltree::Node, complex: &mut ComplexType) -> Result<(), String> {
+    for child in xs_children(node) {
+        match child.tag_name().name() {
+            "sequence" | "choice" | "all" => complex.particle = Some(parse_particle(child)?),
+            "attribute" => complex.attributes.push(parse_attribute(child)?),
+            "anyAttribute" => complex.any_attribute = true,
+            "complexContent" => {
+                if child.attribute("mixed") == Some("true") {
+                    complex.mixed = true;
+                }
+                for derivation in xs_children(child) {
+                    if !is_xs(&derivation, "extension") && !is_xs(&derivation, "restriction") {
+                        return Err(unsupported(&derivation));
+                    }
+                    if is_xs(&derivation, "extension") {
+                        complex.base = derivation.attribute("base").map(local);
+                    }
+                    parse_complex_body(derivation, complex)?;
+                }
+            }
+            "simpleContent" => {
+                for derivation in xs_children(child) {
+                    if !is_xs(&derivation, "extension") && !is_xs(&derivation, "restriction") {
+                        return Err(unsupported(&derivation));
+                    }
+                    let mut simple = SimpleType {
+                        base: local(derivation.attribute("base").unwrap_or("string")),
+                        facets: Facets::default(),
+                    };
+                    for facet in xs_children(derivation) {
+                        match facet.tag_name().name() {
+                            "attribute" => complex.attributes.push(parse_attribute(facet)?),
+                            "anyAttribute" => complex.any_attribute = true,
+                            _ => parse_facet(facet, &mut simple.facets)?,
+                        }
+                    }
+                    complex.simple_content = Some(simple);
+                }
+            }
+            _ => return Err(unsupported(&child)),
+        }
+    }
+    Ok(())
+}
+
+fn parse_particle(node: roxmltree::Node) -> Result<Particle, String> {
+    let occurs = parse_occurs(node)?;
+    let mut items = Vec::new();
+    for child in xs_children(node) {
+        items.push(match child.tag_name().name() {
+            "element" => Particle::Element(parse_occurs(child)?, Box::new(parse_element(child)?)),
+            "sequence" | "choice" => parse_particle(child)?,
+            "any" => Particle::Any(parse_occurs(child)?),
+            _ => return Err(unsupported(&child)),
+        });
+    }
+    Ok(match node.tag_name().name() {
+        "sequence" => Particle::Sequence(occurs, items),
+        "choice" => Particle::Choice(occurs, items),
+        "all" => Particle::All(occurs, items),
+        _ => return Err(unsupported(&node)),
+    })
+}
+
+fn parse_attribute(node: roxmltree::Node) -> Result<AttributeDecl, String> {
+    let name = node
+        .attribute("name")
+        .or(node.attribute("ref"))
+        .map(local)
+        .ok_or("xs:attribute needs a name")?;
+    let mut kind = SimpleType {
+        base: local(node.attribute("type").unwrap_or("string")),
+        facets: Facets::default(),
+    };
+    for child in xs_children(node) {
+        if !is_xs(&child, "simpleType") {
+            return Err(unsupported(&child));
+        }
+        kind = parse_simple_type(child)?;
+    }
+    Ok(AttributeDecl {
+        name,
+        kind,
+        required: node.attribute("use") == Some("required"),
+        fixed: node.attribute("fixed").map(str::to_string),
+    })
+}
+
+fn parse_simple_type(node: roxmltree::Node) -> Result<SimpleType, String> {
+    let restriction = xs_children(node)
+        .next()
+        .ok_or("xs:simpleType needs a restriction")?;
+    if !is_xs(&restriction, "restriction") {
+        return Err(unsupported(&restriction));
+    }
+    let mut simple = SimpleType {
+        base: local(restriction.attribute("base").unwrap_or("string")),
+        facets: Facets::default(),
+    };
+    for facet in xs_children(restriction) {
+        parse_facet(facet, &mut simple.facets)?;
+    }
+    Ok(simple)
+}
+
+fn parse_facet(node: roxmltree::Node, facets: &mut Facets) -> Result<(), String> {
+    let value = node.attribute("value").unwrap_or_default();
+    let number = || {
+        value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("Invalid xs:{} value '{}'", node.tag_name().name(), value))
+    };
+    let count = || {
+        value
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid xs:{} value '{}'", node.tag_name().name(), value))
+    };
+    match node.tag_name().name() {
+        "enumeration" => facets.enumeration.push(value.to_string()),
+        "pattern" => {
+            let regex = Regex::new(&format!("^(?:{})$", value))
+                .map_err(|e| format!("Invalid xs:pattern '{}': {}", value, e))?;
+            facets.patterns.push((value.to_string(), regex));
+        }
+        "length" => facets.length = Some(count()?),
+        "minLength" => facets.min_length = Some(count()?),
+        "maxLength" => facets.max_length = Some(count()?),
+        "minInclusive" => facets.min_inclusive = Some(number()?),
+        "maxInclusive" => facets.max_inclusive = Some(number()?),
+        "minExclusive" => facets.min_exclusive = Some(number()?),
+        "maxExclusive" => facets.max_exclusive = Some(number()?),
+        "totalDigits" => facets.total_digits = Some(count()?),
+        "fractionDigits" => facets.fraction_digits = Some(count()?),
+        "whiteSpace" => {}
+        _ => return Err(unsupported(&node)),
+    }
+    Ok(())
+}
+
+/// Checks `value` against a built-in type; `None` if it is valid.
+fn check_builtin(base: &str, value: &str) -> Option<String> {
+    let value = value.trim();
+    let integer_range: Option<(i128, i128)> = match base {
+        "integer" => Some((i128::MIN, i128::MAX)),
+        "long" => Some((i64::MIN as i128, i64::MAX as i128)),
+        "int" => Some((i32::MIN as i128, i32::MAX as i128)),
+        "short" => Some((i16::MIN as i128, i16::MAX as i128)),
+        "byte" => Some((i8::MIN as i128, i8::MAX as i128)),
+        "nonNegativeInteger" => Some((0, i128::MAX)),
+        "positiveInteger" => Some((1, i128::MAX)),
+        "nonPositiveInteger" => Some((i128::MIN, 0)),
+        "negativeInteger" => Some((i128::MIN, -1)),
+        "unsignedLong" => Some((0, u64::MAX as i128)),
+        "unsignedInt" => Some((0, u32::MAX as i128)),
+        "unsignedShort" => Some((0, u16::MAX as i128)),
+        "unsignedByte" => Some((0, u8::MAX as i128)),
+        _ => None,
+    };
+    let valid = if let Some((min, max)) = integer_range {
+        value
+            .strip_prefix('+')
+            .unwrap_or(value)
+            .parse::<i128>()
+            .is_ok_and(|v| v >= min && v <= max)
+    } else {
+        match base {
+            "boolean" => matches!(value, "true" | "false" | "1" | "0"),
+            "decimal" => is_decimal(value),
+            "float" | "double" => {
+                matches!(value, "INF" | "-INF" | "NaN")
+                    || (value.parse::<f64>().is_ok() && !value.contains(char::is_alphabetic)
+                        || value.contains(['e', 'E']) && value.parse::<f64>().is_ok())
+            }
+            "date" => is_date(value),
+            "dateTime" => value
+                .split_once('T')
+                .is_some_and(|(date, time)| is_date(date) && is_time(time)),
+            "time" => is_time(value),
+            "gYear" => value.len() >= 4 && value.chars().all(|c| c.is_ascii_digit()),
+            _ => true,
+        }
+    };
+    (!valid).then(|| format!("'{}' is not a valid xs:{}", value, base))
+}
+
+fn is_decimal(value: &str) -> bool {
+    let digits = value.strip_prefix(['+', '-']).unwrap_or(value);
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    !(whole.is_empty() && fraction.is_empty())
+        && whole.chars().all(|c| c.is_ascii_digit())
+        && fraction.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Strips an optional `Z` or `+hh:mm` time zone.
+fn strip_timezone(value: &str) -> &str {
+    if let Some(rest) = value.strip_suffix('Z') {
+        return rest;
+    }
+    if value.len() > 6 {
+        let (rest, zone) = value.split_at(value.len() - 6);
+        let zone = zone.as_bytes();
+        if matches!(zone[0], b'+' | b'-') && zone[3] == b':' {
+            return rest;
+        }
+    }
+    value
+}
+
+fn is_date(value: &str) -> bool {
+    let value = strip_timezone(value);
+    let parts: Vec<&str> = value.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return false;
+    };
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    let number = |s: &str| s.parse::<u32>().unwrap_or_default();
+    year.len() >= 4
+        && digits(year)
+        && month.len() == 2
+        && digits(month)
+        && (1..=12).contains(&number(month))
+        && day.len() == 2
+        && digits(day)
+        && (1..=31).contains(&number(day))
+}
+
+fn is_time(value: &str) -> bool {
+    let value = strip_timezone(value);
+    let (clock, fraction) = value.split_once('.').unwrap_or((value, "0"));
+    let parts: Vec<u32> = clock
+        .split(':')
+        .filter(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_digit()))
+        .filter_map(|p| p.parse().ok())
+        .collect();
+    parts.len() == 3
+        && clock.len() == 8
+        && parts[0] <= 24
+        && parts[1] < 60
+        && parts[2] < 60
+        && !fraction.is_empty()
+        && fraction.chars().all(|c| c.is_ascii_digit())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub node_id: Option<i64>,
+    /// `node_path` of the node, e.g. `/library/book[2]/year`
+    pub path: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub message: String,
+}
+
+struct Validator<'a> {
+    schema: &'a Schema,
+    tree: &'a StoredTree,
+    /// Violations as (node index, message)
+    found: Vec<(usize, String)>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(&mut self, index: usize, message: String) {
+        self.found.push((index, message));
+    }
+
+    fn simple_type(&self, name: &str) -> Option<&'a SimpleType> {
+        self.schema.simple_types.get(name)
+    }
+
+    /// Checks a value against a simple type and its bases; `None` if valid.
+    fn check_simple(&self, simple: &SimpleType, value: &str, depth: usize) -> Option<String> {
+        if depth > 32 {
+            return Some(format!(
+                "Simple type '{}' is defined in a loop",
+                simple.base
+            ));
+        }
+        let base_error = match self.simple_type(&simple.base) {
+            Some(base) => self.check_simple(base, value, depth + 1),
+            None => check_builtin(&simple.base, value),
+        };
+        base_error.or_else(|| check_facets(&simple.facets, value))
+    }
+
+    fn resolve(&self, kind: &'a TypeRef) -> Result<Resolved<'a>, String> {
+        Ok(match kind {
+            TypeRef::Complex(complex) => Resolved::Complex(complex),
+            TypeRef::Simple(simple) => Resolved::Simple(Box::new(simple.clone())),
+            TypeRef::AnyType => Resolved::Any,
+            TypeRef::Global(name) => {
+                let decl = self
+                    .schema
+                    .elements
+                    .get(name)
+                    .ok_or_else(|| format!("Undeclared element reference '{}'", name))?;
+                self.resolve(&decl.kind)?
+            }
+            TypeRef::Named(name) => {
+                if let Some(complex) = self.schema.complex_types.get(name) {
+                    Resolved::Complex(complex)
+                } else if let Some(simple) = self.simple_type(name) {
+                    Resolved::Simple(Box::new(simple.clone()))
+                } else if name == "anyType" {
+                    Resolved::Any
+                } else {
+                    Resolved::Simple(Box::new(SimpleType {
+                        base: name.clone(),
+                        facets: Facets::default(),
+                    }))
+                }
+            }
+        })
+    }
+
+    /// The particles and attributes of a type, including its extension bases.
+    fn flatten(
+        &self,
+        complex: &'a ComplexType,
+        particles: &mut Vec<&'a Particle>,
+        attributes: &mut Vec<&'a AttributeDecl>,
+        depth: usize,
+    ) -> Result<(), String> {
+        if let Some(base) = &complex.base {
+            if depth > 32 {
+                return Err(format!("Complex type '{}' extends itself", base));
+            }
+            if let Some(base_type) = self.schema.complex_types.get(base) {
+                self.flatten(base_type, particles, attributes, depth + 1)?;
+            } else if base != "anyType" {
+                return Err(format!("Unknown base type '{}'", base));
+            }
+        }
+        particles.extend(complex.particle.as_ref());
+        attributes.extend(complex.attributes.iter());
+        Ok(())
+    }
+
+    fn validate_element(&mut self, index: usize, decl: &'a ElementDecl) {
+        let node: &'a StoredNode = &self.tree.nodes[index];
+        let text = node.text_content.as_deref().unwrap_or_default();
+        let resolved = match self.resolve(&decl.kind) {
+            Ok(resolved) => resolved,
+            Err(e) => return self.report(index, e),
+        };
+        if let Some(fixed) = &decl.fixed {
+            if text != fixed {
+                self.report(
+                    index,
+                    format!(
+                        "Element '{}' must have the fixed value '{}'",
+                        node.tag_name, fixed
+                    ),
+                );
+            }
+        }
+
+        match resolved {
+            Resolved::Any => {}
+            Resolved::Simple(simple) => {
+                if !node.children.is_empty() {
+                    self.report(
+                        index,
+                        format!("Element '{}' cannot have child elements", node.tag_name),
+                    );
+                }
+                if let Some(error) = self.check_simple(&simple, text, 0) {
+                    self.report(index, format!("Element '{}': {}", node.tag_name, error));
+                }
+                self.check_attributes(index, &[], false);
+            }
+            Resolved::Complex(complex) => {
+                let mut particles = Vec::new();
+                let mut attributes = Vec::new();
+                if let Err(e) = self.flatten(complex, &mut particles, &mut attributes, 0) {
+                    return self.report(index, e);
+                }
+                self.check_attributes(index, &attributes, complex.any_attribute);
+
+                if let Some(simple) = &complex.simple_content {
+                    if !node.children.is_empty() {
+                        self.report(
+                            index,
+                            format!("Element '{}' cannot have child elements", node.tag_name),
+                        );
+                    }
+                    if let Some(error) = self.check_simple(simple, text, 0) {
+                        self.report(index, format!("Element '{}': {}", node.tag_name, error));
+                    }
+                    return;
+                }
+                if !complex.mixed && !text.trim().is_empty() {
+                    self.report(
+                        index,
+                        format!("Element '{}' cannot contain text", node.tag_name),
+                    );
+                }
+                self.validate_children(index, &particles);
+            }
+        }
+    }
+
+    fn check_attributes(&mut self, index: usize, declared: &[&'a AttributeDecl], any: bool) {
+        let node: &'a StoredNode = &self.tree.nodes[index];
+        for decl in declared {
+            match node.attributes.iter().find(|(name, _)| *name == decl.name) {
+                None if decl.required => self.report(
+                    index,
+                    format!(
+                        "Element '{}' is missing required attribute '{}'",
+                        node.tag_name, decl.name
+                    ),
+                ),
+                None => {}
+                Some((_, value)) => {
+                    if let Some(error) = self.check_simple(&decl.kind, value, 0) {
+                        self.report(index, format!("Attribute '{}': {}", decl.name, error));
+                    }
+                    if decl.fixed.as_ref().is_some_and(|fixed| fixed != value) {
+                        self.report(
+                            index,
+                            format!(
+                                "Attribute '{}' must have the fixed value '{}'",
+                                decl.name,
+                                decl.fixed.as_deref().unwrap_or_default()
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        if !any {
+            for (name, _) in &node.attributes {
+                let local = local_name(name, node.attribute_prefixes.get(name).map(String::as_str));
+                if !declared.iter().any(|d| d.name == *name)
+                    && !INSTANCE_ATTRIBUTES.contains(&local)
+                {
+                    self.report(
+                        index,
+                        format!(
+                            "Attribute '{}' is not allowed on element '{}'",
+                            name, node.tag_name
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn validate_children(&mut self, index: usize, particles: &[&'a Particle]) {
+        let node: &'a StoredNode = &self.tree.nodes[index];
+        let names: Vec<&str> = node
+            .children
+            .iter()
+            .map(|&c| self.tree.nodes[c].tag_name.as_str())
+            .collect();
+
+        let mut ends = BTreeSet::from([0]);
+        let mut furthest = 0;
+        for particle in particles {
+            ends = ends
+                .iter()
+                .flat_map(|&start| match_particle(particle, &names, start, &mut furthest))
+                .collect();
+        }
+
+        if !ends.contains(&names.len()) {
+            if furthest < names.len() {
+                let child = node.children[furthest];
+                self.report(
+                    child,
+                    format!(
+                        "Unexpected element '{}' in '{}'",
+                        names[furthest], node.tag_name
+                    ),
+                );
+            } else {
+                let missing: Vec<&str> = particles
+                    .iter()
+                    .flat_map(|p| required_names(p))
+                    .filter(|name| !names.contains(name))
+                    .collect();
+                let detail = if missing.is_empty() {
+                    String::new()
+                } else {
+                    format!(": expected {}", missing.join(", "))
+                };
+                self.report(
+                    index,
+                    format!("Element '{}' is incomplete{}", node.tag_name, detail),
+                );
+            }
+        }
+
+        for &child in &node.children {
+            let name = &self.tree.nodes[child].tag_name;
+            // Children without a declaration were reported above, or are
+            // allowed by xs:any
+            if let Some(decl) = particles.iter().find_map(|p| find_declaration(p, name)) {
+                self.validate_element(child, decl);
+            }
+        }
+    }
+}
+
+enum Resolved<'a> {
+    Complex(&'a ComplexType),
+    Simple(Box<SimpleType>),
+    Any,
+}
+
+/// A numeric facet: its limit, the operator for messages, and the check.
+type Bound = (Option<f64>, &'static str, fn(f64, f64) -> bool);
+
+fn check_facets(facets: &Facets, value: &str) -> Option<String> {
+    let length = value.chars().count();
+    if !facets.enumeration.is_empty() && !facets.enumeration.iter().any(|e| e == value) {
+        return Some(format!(
+            "'{}' is not one of {}",
+            value,
+            facets
+                .enumeration
+                .iter()
+                .map(|e| format!("'{}'", e))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if let Some((pattern, _)) = facets.patterns.iter().find(|(_, r)| !r.is_match(value)) {
+        return Some(format!(
+            "'{}' does not match the pattern '{}'",
+            value, pattern
+        ));
+    }
+    let length_error = |limit: usize, what: &str| {
+        Some(format!(
+            "'{}' must be {} {} characters long",
+            value, what, limit
+        ))
+    };
+    match (facets.length, facets.min_length, facets.max_length) {
+        (Some(n), _, _) if length != n => return length_error(n, "exactly"),
+        (_, Some(n), _) if length < n => return length_error(n, "at least"),
+        (_, _, Some(n)) if length > n => return length_error(n, "at most"),
+        _ => {}
+    }
+
+    let bounds: [Bound; 4] = [
+        (facets.min_inclusive, ">=", |v, b| v >= b),
+        (facets.max_inclusive, "<=", |v, b| v <= b),
+        (facets.min_exclusive, ">", |v, b| v > b),
+        (facets.max_exclusive, "<", |v, b| v < b),
+    ];
+    if bounds.iter().any(|(bound, _, _)| bound.is_some()) {
+        let Ok(number) = value.trim().parse::<f64>() else {
+            return Some(format!("'{}' is not a number", value));
+        };
+        for (bound, symbol, holds) in bounds {
+            if let Some(bound) = bound {
+                if !holds(number, bound) {
+                    return Some(format!("{} must be {} {}", value, symbol, bound));
+                }
+            }
+        }
+    }
+
+    let digits = value.trim().trim_start_matches(['+', '-']);
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let fraction = fraction.trim_end_matches('0');
+    if let Some(total) = facets.total_digits {
+        let count = whole.trim_start_matches('0').len() + fraction.len();
+        if count > total {
+            return Some(format!("'{}' has more than {} digits", value, total));
+        }
+    }
+    if let Some(limit) = facets.fraction_digits {
+        if fraction.len() > limit {
+            return Some(format!(
+                "'{}' has more than {} fraction digits",
+                value, limit
+            ));
+        }
+    }
+    None
+}
+
+/// Every position where `particle` can stop after starting at `start`.
+/// `furthest` tracks how far any attempt got, to point at the first child
+/// that no path could accept.
+fn match_particle(
+    particle: &Particle,
+    names: &[&str],
+    start: usize,
+    furthest: &mut usize,
+) -> BTreeSet<usize> {
+    let occurs = match particle {
+        Particle::Element(occurs, _)
+        | Particle::Sequence(occurs, _)
+        | Particle::Choice(occurs, _)
+        | Particle::All(occurs, _)
+        | Particle::Any(occurs) => *occurs,
+    };
+
+    let mut ends = BTreeSet::new();
+    if occurs.min == 0 {
+        ends.insert(start);
+    }
+    let mut current = BTreeSet::from([start]);
+    let mut seen = current.clone();
+    let mut repeats = 0;
+    while !current.is_empty() && occurs.max.is_none_or(|max| repeats < max) {
+        repeats += 1;
+        let next: BTreeSet<usize> = current
+            .iter()
+            .flat_map(|&pos| match_once(particle, names, pos, furthest))
+            .collect();
+        if let Some(&last) = next.iter().next_back() {
+            *furthest = (*furthest).max(last);
+        }
+        if repeats >= occurs.min {
+            ends.extend(next.iter().copied());
+        }
+        // Positions reached before cannot lead anywhere new
+        current = next.difference(&seen).copied().collect();
+        seen.extend(current.iter().copied());
+        if repeats >= occurs.min && current.is_empty() {
+            break;
+        }
+        // Empty iterations cannot reach minOccurs by consuming children
+        if repeats > names.len() + occurs.min {
+            break;
+        }
+    }
+    ends
+}
+
+/// One occurrence of `particle`, ignoring its `minOccurs`/`maxOccurs`.
+fn match_once(
+    particle: &Particle,
+    names: &[&str],
+    pos: usize,
+    furthest: &mut usize,
+) -> BTreeSet<usize> {
+    match particle {
+        Particle::Element(_, decl) if names.get(pos) == Some(&decl.name.as_str()) => {
+            BTreeSet::from([pos + 1])
+        }
+        Particle::Any(_) if pos < names.len() => BTreeSet::from([pos + 1]),
+        Particle::Element(..) | Particle::Any(_) => BTreeSet::new(),
+        Particle::Sequence(_, items) => {
+            let mut ends = BTreeSet::from([pos]);
+            for item in items {
+                ends = ends
+                    .iter()
+                    .flat_map(|&p| match_particle(item, names, p, furthest))
+                    .collect();
+            }
+            ends
+        }
+        Particle::Choice(_, items) => items
+            .iter()
+            .flat_map(|item| match_particle(item, names, pos, furthest))
+            .collect(),
+        Particle::All(_, items) => match_all(items, names, pos, furthest),
+    }
+}
+
+/// `xs:all`: each element at most once, in any order.
+fn match_all(
+    items: &[Particle],
+    names: &[&str],
+    start: usize,
+    furthest: &mut usize,
+) -> BTreeSet<usize> {
+    let declared: Vec<(&str, bool)> = items
+        .iter()
+        .filter_map(|item| match item {
+            Particle::Element(occurs, decl) => Some((decl.name.as_str(), occurs.min > 0)),
+            _ => None,
+        })
+        .collect();
+    let mut seen: Vec<&str> = Vec::new();
+    let mut ends = BTreeSet::new();
+    let mut pos = start;
+    loop {
+        if declared
+            .iter()
+            .all(|(name, required)| !required || seen.contains(name))
+        {
+            ends.insert(pos);
+        }
+        match names.get(pos) {
+            Some(name) if declared.iter().any(|(d, _)| d == name) && !seen.contains(name) => {
+                seen.push(name);
+                pos += 1;
+            }
+            _ => break,
+        }
+    }
+    *furthest = (*furthest).max(pos);
+    ends
+}
+
+fn find_declaration<'a>(particle: &'a Particle, name: &str) -> Option<&'a ElementDecl> {
+    match particle {
+        Particle::Element(_, decl) if decl.name == name => Some(decl),
+        Particle::Element(..) | Particle::Any(_) => None,
+        Particle::Sequence(_, items) | Particle::Choice(_, items) | Particle::All(_, items) => {
+            items.iter().find_map(|item| find_declaration(item, name))
+        }
+    }
+}
+
+/// Element names a particle cannot do without, for error messages.
+fn required_names(particle: &Particle) -> Vec<&str> {
+    match particle {
+        Particle::Element(occurs, decl) if occurs.min > 0 => vec![decl.name.as_str()],
+        Particle::Sequence(occurs, items) | Particle::All(occurs, items) if occurs.min > 0 => {
+            items.iter().flat_map(required_names).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Validates a parsed tree; violations are (node index, message).
+pub fn validate_tree(schema: &Schema, tree: &StoredTree) -> Vec<(usize, String)> {
+    let mut validator = Validator {
+        schema,
+        tree,
+        found: Vec::new(),
+    };
+    let root = &tree.nodes[0];
+    match schema.elements.get(&root.tag_name) {
+        Some(decl) => validator.validate_element(0, decl),
+        None => validator.report(
+            0,
+            format!(
+                "No global element declaration for root element '{}'",
+                root.tag_name
+            ),
+        ),
+    }
+    validator.found
+}
+
+/// Validates a stored document against `xsd`. With `store`, the document's
+/// rows in `validation_errors` are replaced by the new violations.
+pub fn validate(
+    db: *mut sqlite3,
+    document_id: i64,
+    xsd: &str,
+    store: bool,
+) -> Result<Vec<Violation>, String> {
+    let schema = Schema::parse(xsd)?;
+    let tree = StoredTree::load_document(db, document_id)?
+        .ok_or_else(|| format!("Document {} is empty or does not exist", document_id))?;
+
+    let positions: HashMap<i64, (Option<u32>, Option<u32>)> = query(
+        db,
+        "SELECT id, source_line, source_column FROM nodes
+        WHERE document_id = ?1 AND source_line IS NOT NULL",
+        &[json!(document_id)],
+    )?
+    .rows
+    .iter()
+    .map(|row| {
+        (
+            row[0].as_i64().unwrap_or_default(),
+            (
+                row[1].as_u64().map(|v| v as u32),
+                row[2].as_u64().map(|v| v as u32),
+            ),
+        )
+    })
+    .collect();
+
+    let mut violations = Vec::new();
+    for (index, message) in validate_tree(&schema, &tree) {
+        let node_id = tree.nodes[index].id;
+        let (line, column) = positions.get(&node_id).copied().unwrap_or_default();
+        violations.push(Violation {
+            node_id: Some(node_id),
+            path: node_path(db, node_id)?,
+            line,
+            column,
+            message,
+        });
+    }
+
+    if store {
+        exec(db, "SAVEPOINT validate")?;
+        let result = (|| -> Result<(), String> {
+            query(
+                db,
+                "DELETE FROM validation_errors WHERE document_id = ?1",
+                &[json!(document_id)],
+            )?;
+            for violation in &violations {
+                query(
+                    db,
+                    "INSERT INTO validation_errors
+                    (document_id, node_id, message, source_line, source_column)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    &[
+                        json!(document_id),
+                        json!(violation.node_id),
+                        json!(violation.message),
+                        json!(violation.line),
+                        json!(violation.column),
+                    ],
+                )?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => exec(db, "RELEASE validate")?,
+            Err(e) => {
+                let _ = exec(db, "ROLLBACK TO validate; RELEASE validate");
+                return Err(e);
+            }
+        }
+    }
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOKS_XSD: &str = r#"<?xml version="1.0"?>
+<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+  <xs:element name="library">
+    <xs:complexType>
+      <xs:sequence>
+        <xs:element ref="book" maxOccurs="unbounded"/>
+      </xs:sequence>
+    </xs:complexType>
+  </xs:element>
+  <xs:element name="book" type="Book"/>
+  <xs:complexType name="Book">
+    <xs:sequence>
+      <xs:element name="title" type="xs:string"/>
+      <xs:choice>
+        <xs:element name="author" type="xs:string" maxOccurs="3"/>
+        <xs:element name="editor" type="xs:string"/>
+      </xs:choice>
+      <xs:element name="year" type="Year" minOccurs="0"/>
+    </xs:sequence>
+    <xs:attribute name="id" type="xs:positiveInteger" use="required"/>
+    <xs:attribute name="category" type="Category"/>
+  </xs:complexType>
+  <xs:simpleType name="Year">
+    <xs:restriction base="xs:integer">
+      <xs:minInclusive value="1450"/>
+      <xs:maxInclusive value="2100"/>
+    </xs:restriction>
+  </xs:simpleType>
+  <xs:simpleType name="Category">
+    <xs:restriction base="xs:string">
+      <xs:enumeration value="fiction"/>
+      <xs:enumeration value="science"/>
+    </xs:restriction>
+  </xs:simpleType>
+</xs:schema>"#;
+
+    fn tree_from(xml: &str) -> StoredTree {
+        let doc = roxmltree::Document::parse(xml).unwrap();
+        let mut nodes: Vec<StoredNode> = Vec::new();
+        fn walk(node: roxmltree::Node, nodes: &mut Vec<StoredNode>) -> usize {
+            let index = nodes.len();
+            let text: String = node
+                .children()
+                .filter(|c| c.is_text())
+                .filter_map(|c| c.text())
+                .map(str::trim)
+                .collect();
+            let mut attributes: Vec<(String, String)> = node
+                .attributes()
+                .map(|a| (a.name().to_string(), a.value().to_string()))
+                .collect();
+            attributes.sort();
+            nodes.push(StoredNode {
+                id: index as i64 + 1,
+                tag_name: node.tag_name().name().to_string(),
+                text_content: (!text.is_empty()).then_some(text),
+                attributes,
+                children: Vec::new(),
+                ..StoredNode::default()
+            });
+            for child in node.children().filter(|c| c.is_element()) {
+                let child_index = walk(child, nodes);
+                nodes[index].children.push(child_index);
+            }
+            index
+        }
+        walk(doc.root_element(), &mut nodes);
+        StoredTree { nodes }
+    }
+
+    fn messages(xml: &str) -> Vec<String> {
+        let schema = Schema::parse(BOOKS_XSD).unwrap();
+        validate_tree(&schema, &tree_from(xml))
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_document() {
+        let xml = r#"<library>
+            <book id="1" category="fiction"><title>A</title><author>X</author><author>Y</author><year>1925</year></book>
+            <book id="2"><title>B</title><editor>Z</editor></book>
+        </library>"#;
+        assert_eq!(messages(xml), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_violations() {
+        let xml = r#"<library>
+            <book category="poetry"><title>A</title><year>1925</year></book>
+            <book id="0"><title>B</title><editor>Z</editor><year>1200</year><isbn/></book>
+            <book id="3" lang="en"><author>X</author></book>
+        </library>"#;
+        let found = messages(xml);
+        assert_eq!(
+            found,
+            vec![
+                "Element 'book' is missing required attribute 'id'",
+                "Attribute 'category': 'poetry' is not one of 'fiction', 'science'",
+                "Unexpected element 'year' in 'book'",
+                "Attribute 'id': '0' is not a valid xs:positiveInteger",
+                "Unexpected element 'isbn' in 'book'",
+                "Element 'year': 1200 must be >= 1450",
+                "Attribute 'lang' is not allowed on element 'book'",
+                "Unexpected element 'author' in 'book'",
+            ]
+        );
+        assert_eq!(
+            messages("<library/>"),
+            vec!["Element 'library' is incomplete: expected book"]
+        );
+        assert_eq!(
+            messages("<shelf/>"),
+            vec!["No global element declaration for root element 'shelf'"]
+        );
+    }
+
+    #[test]
+    fn test_all_and_facets() {
+        let xsd = r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+          <xs:element name="point">
+            <xs:complexType>
+              <xs:all>
+                <xs:element name="x" type="xs:decimal"/>
+                <xs:element name="y" type="xs:decimal"/>
+                <xs:element name="label" minOccurs="0">
+                  <xs:simpleType>
+                    <xs:restriction base="xs:string">
+                      <xs:pattern value="[A-Z]\d+"/>
+                      <xs:maxLength value="4"/>
+                    </xs:restriction>
+                  </xs:simpleType>
+                </xs:element>
+              </xs:all>
+            </xs:complexType>
+          </xs:element>
+        </xs:schema>"#;
+        let schema = Schema::parse(xsd).unwrap();
+        let check = |xml: &str| -> Vec<String> {
+            validate_tree(&schema, &tree_from(xml))
+                .into_iter()
+                .map(|(_, m)| m)
+                .collect()
+        };
+        assert!(check("<point><y>2</y><x>1.5</x><label>P12</label></point>").is_empty());
+        assert_eq!(
+            check("<point><x>1</x><label>p12345</label></point>"),
+            vec![
+                "Element 'point' is incomplete: expected y",
+                "Element 'label': 'p12345' does not match the pattern '[A-Z]\\d+'",
+            ]
+        );
+        assert_eq!(
+            check("<point><x>one</x><y>2</y><x>3</x></point>"),
+            vec![
+                "Unexpected element 'x' in 'point'",
+                "Element 'x': 'one' is not a valid xs:decimal",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builtin_types() {
+        assert!(check_builtin("date", "2024-02-29").is_none());
+        assert!(check_builtin("date", "2024-13-01").is_some());
+        assert!(check_builtin("dateTime", "2024-02-29T10:00:00Z").is_none());
+        assert!(check_builtin("dateTime", "2024-02-29 10:00:00").is_some());
+        assert!(check_builtin("boolean", "yes").is_some());
+        assert!(check_builtin("unsignedByte", "256").is_some());
+        assert!(check_builtin("double", "1e-3").is_none());
+        assert!(check_builtin("double", "INF").is_none());
+    }
+
+    #[test]
+    fn test_unsupported_constructs_are_errors() {
+        let xsd = r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+          <xs:simpleType name="T"><xs:union memberTypes="xs:int xs:date"/></xs:simpleType>
+        </xs:schema>"#;
+        assert_eq!(
+            Schema::parse(xsd).unwrap_err(),
+            "Unsupported XSD construct: xs:union"
+        );
+    }
+}
@@ -1,9 +1,9 @@
 import React, { useState, useEffect } from 'react';
-import FileUploader from './components/FileUploader';
+import FileUploader, { type FileType } from './components/FileUploader';
 import DocumentList from './components/DocumentList';
 import QueryEditor from './components/QueryEditor';
 import ResultsViewer from './components/ResultsViewer';
-import { Document, QueryResult } from './types';
+import { Document, NodeLocator, QueryMode, QueryResult } from './types';
 import './App.css';
 
 // This will be loaded from WASM
@@ -12,7 +12,9 @@ let XmlSqlDb: any = null;
 function App() {
   const [db, setDb] = useState<any>(null);
   const [documents, setDocuments] = useState<Document[]>([]);
+  const [selectedDocument, setSelectedDocument] = useState<number | null>(null);
   const [queryResults, setQueryResults] = useState<QueryResult | null>(null);
+  const [resultsMode, setResultsMode] = useState<QueryMode>('css');
   const [loading, setLoading] = useState(true);
   const [error, setError] = useState<string | null>(null);
 
@@ -90,7 +92,7 @@ function App() {
     }
   };
 
-  const handleFileLoad = async (content: string, filename: string, type: 'xml' | 'html') => {
+  const handleFileLoad = async (content: string | Uint8Array, filename: string, type: FileType) => {
     if (!db) {
       setError('Database not initialized');
       return;
@@ -100,8 +102,12 @@ function App() {
       setLoading(true);
       setError(null);
 
-      if (type === 'xml') {
+      if (typeof content !== 'string') {
+        await db.load_archive(content, filename);
+      } else if (type === 'xml') {
         await db.load_xml(content, filename);
+      } else if (type === 'json') {
+        await db.load_json(content, filename);
       } else {
         await db.load_html(content, filename);
       }
@@ -125,15 +131,20 @@ function App() {
       const docs: Document[] = result.rows.map((row: any[]) => ({
         id: row[0],
         name: row[1],
-        created_at: row[2]
+        created_at: row[2],
+        kind: row[3],
+        doctype: row[4]
       }));
       setDocuments(docs);
+      if (!docs.some((doc) => doc.id === selectedDocument)) {
+        setSelectedDocument(null);
+      }
     } catch (err) {
       console.error('Failed to refresh documents:', err);
     }
   };
 
-  const handleQueryExecute = async (query: string, mode: 'css' | 'sql') => {
+  const handleQueryExecute = async (query: string, mode: QueryMode) => {
     if (!db) {
       setError('Database not initialized');
       return;
@@ -144,10 +155,15 @@ function App() {
       setError(null);
 
       const result = mode === 'css'
-        ? await db.query_selector(query)
-        : await db.execute_sql(query);
+        ? selectedDocument !== null
+          ? await db.query_selector_in_document(BigInt(selectedDocument), query)
+          : await db.query_selector(query)
+        : mode === 'xpath'
+          ? await db.query_xpath(query)
+          : await db.execute_sql(query);
 
       setQueryResults(result);
+      setResultsMode(mode);
       setLoading(false);
     } catch (err: any) {
       console.error('Query execution error:', err);
@@ -156,6 +172,11 @@ function App() {
     }
   };
 
+  const locateNode = async (nodeId: number): Promise<NodeLocator> => ({
+    selector: (await db.selector_for_node(BigInt(nodeId))) ?? '',
+    xpath: (await db.xpath_for_node(BigInt(nodeId))) ?? '',
+  });
+
   if (loading && !db) {
     return (
       <div className="app-container loading">
@@ -214,13 +235,24 @@ function App() {
 
           <section className="section">
             <h2>Documents</h2>
-            <DocumentList documents={documents} />
+            <DocumentList
+              documents={documents}
+              selectedId={selectedDocument}
+              onSelect={setSelectedDocument}
+            />
           </section>
         </div>
 
         <div className="content-area">
           <section className="section">
-            <h2>Query</h2>
+            <h2>
+              Query
+              {selectedDocument !== null && (
+                <span className="query-scope">
+                  CSS selectors limited to {documents.find((doc) => doc.id === selectedDocument)?.name}
+                </span>
+              )}
+            </h2>
             <QueryEditor
               onExecute={handleQueryExecute}
               disabled={loading || documents.length === 0}
@@ -230,7 +262,10 @@ function App() {
           {queryResults && (
             <section className="section">
               <h2>Results ({queryResults.rows.length} rows)</h2>
-              <ResultsViewer results={queryResults} />
+              <ResultsViewer
+                results={queryResults}
+                locateNode={resultsMode === 'sql' ? undefined : locateNode}
+              />
             </section>
           )}
         </div>
@@ -3,9 +3,11 @@ import { Document } from '../types';
 
 interface DocumentListProps {
   documents: Document[];
+  selectedId?: number | null;
+  onSelect?: (id: number | null) => void;
 }
 
-const DocumentList: React.FC<DocumentListProps> = ({ documents }) => {
+const DocumentList: React.FC<DocumentListProps> = ({ documents, selectedId, onSelect }) => {
   if (documents.length === 0) {
     return (
       <div className="empty-state">
@@ -17,9 +19,14 @@ const DocumentList: React.FC<DocumentListProps> = ({ documents }) => {
   return (
     <div className="document-list">
       {documents.map((doc) => (
-        <div key={doc.id} className="document-item">
+        <div
+          key={doc.id}
+          className={`document-item ${doc.id === selectedId ? 'selected' : ''}`}
+          onClick={() => onSelect?.(doc.id === selectedId ? null : doc.id)}
+          title={doc.id === selectedId ? 'Click to query all documents' : 'Click to query only this document'}
+        >
           <div className="document-icon">
-            {doc.name.endsWith('.xml') ? '📄' : '🌐'}
+            {doc.kind === 'html' ? '🌐' : '📄'}
           </div>
           <div className="document-info">
             <div className="document-name">{doc.name}</div>
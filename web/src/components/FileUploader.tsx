@@ -1,10 +1,22 @@
 import React, { useRef } from 'react';
 
+export type FileType = 'xml' | 'html' | 'json' | 'archive';
+
 interface FileUploaderProps {
-  onFileLoad: (content: string, filename: string, type: 'xml' | 'html') => void;
+  onFileLoad: (content: string | Uint8Array, filename: string, type: FileType) => void;
   disabled?: boolean;
 }
 
+const ARCHIVE_PATTERN = /\.(docx|xlsx|pptx|odt|ods|odp|epub|zip)$/i;
+
+const readFile = async (file: File): Promise<[string | Uint8Array, FileType]> => {
+  if (file.name.match(ARCHIVE_PATTERN)) {
+    return [new Uint8Array(await file.arrayBuffer()), 'archive'];
+  }
+  const type = file.name.match(/\.html?$/i) ? 'html' : file.name.match(/\.json$/i) ? 'json' : 'xml';
+  return [await file.text(), type];
+};
+
 const FileUploader: React.FC<FileUploaderProps> = ({ onFileLoad, disabled }) => {
   const fileInputRef = useRef<HTMLInputElement>(null);
 
@@ -13,8 +25,7 @@ const FileUploader: React.FC<FileUploaderProps> = ({ onFileLoad, disabled }) =>
     if (!file) return;
 
     try {
-      const content = await file.text();
-      const type = file.name.match(/\.html?$/i) ? 'html' : 'xml';
+      const [content, type] = await readFile(file);
       onFileLoad(content, file.name, type);
       
       // Reset input
@@ -35,8 +46,7 @@ const FileUploader: React.FC<FileUploaderProps> = ({ onFileLoad, disabled }) =>
     if (!file) return;
 
     try {
-      const content = await file.text();
-      const type = file.name.match(/\.html?$/i) ? 'html' : 'xml';
+      const [content, type] = await readFile(file);
       onFileLoad(content, file.name, type);
     } catch (err) {
       console.error('File read error:', err);
@@ -62,17 +72,17 @@ const FileUploader: React.FC<FileUploaderProps> = ({ onFileLoad, disabled }) =>
             <path strokeLinecap="round" strokeLinejoin="round" strokeWidth={2} d="M7 16a4 4 0 01-.88-7.903A5 5 0 1115.9 6L16 6a5 5 0 011 9.9M15 13l-3-3m0 0l-3 3m3-3v12" />
           </svg>
           <p className="drop-zone-text">
-            Drop XML/HTML file here or click to browse
+            Drop XML/HTML/JSON file or archive here or click to browse
           </p>
           <p className="drop-zone-hint">
-            Supports .xml, .html files
+            Supports .xml, .html, .json, .docx, .xlsx, .pptx, .odt, .epub files
           </p>
         </div>
       </div>
       <input
         ref={fileInputRef}
         type="file"
-        accept=".xml,.html,.htm"
+        accept=".xml,.html,.htm,.json,.docx,.xlsx,.pptx,.odt,.ods,.odp,.epub,.zip"
         onChange={handleFileChange}
         disabled={disabled}
         style={{ display: 'none' }}
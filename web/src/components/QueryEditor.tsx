@@ -14,6 +14,17 @@ const cssExamples = [
   { label: 'Child combinator', value: 'div > p' },
   { label: 'Descendant', value: 'article p' },
   { label: 'Complex', value: 'div.container > p#intro[data-section="1"]' },
+  { label: 'Extract href', value: '#recent-posts a::attr(href)' },
+];
+
+const xpathExamples = [
+  { label: 'All books', value: '//book' },
+  { label: 'First child', value: '/library/book[1]/title' },
+  { label: 'Last', value: '//book[last()]' },
+  { label: 'Attribute test', value: "//book[@category='fiction']/title" },
+  { label: 'Text match', value: "//title[contains(., 'History')]" },
+  { label: 'Axes', value: '//year[. > 1950]/preceding-sibling::title' },
+  { label: 'Count', value: 'count(//book)' },
 ];
 
 const sqlExamples = [
@@ -24,6 +35,8 @@ const sqlExamples = [
   { label: 'Nodes with attrs', value: `SELECT DISTINCT n.* 
 FROM nodes n 
 JOIN attributes a ON a.node_id = n.id` },
+  { label: 'CSS inside SQL', value: "SELECT n.id, attr(n.id, 'href') AS href FROM css_select('#recent-posts a') n" },
+  { label: 'Helper functions', value: "SELECT id, node_path(id), inner_text(id) FROM nodes WHERE has_class(id, 'post')" },
 ];
 
 const QueryEditor: React.FC<QueryEditorProps> = ({ onExecute, disabled }) => {
@@ -40,7 +53,7 @@ const QueryEditor: React.FC<QueryEditorProps> = ({ onExecute, disabled }) => {
     setQuery(example);
   };
 
-  const examples = mode === 'css' ? cssExamples : sqlExamples;
+  const examples = mode === 'css' ? cssExamples : mode === 'xpath' ? xpathExamples : sqlExamples;
 
   return (
     <div className="query-editor">
@@ -51,6 +64,12 @@ const QueryEditor: React.FC<QueryEditorProps> = ({ onExecute, disabled }) => {
         >
           CSS Selector
         </button>
+        <button
+          className={`mode-button ${mode === 'xpath' ? 'active' : ''}`}
+          onClick={() => setMode('xpath')}
+        >
+          XPath
+        </button>
         <button
           className={`mode-button ${mode === 'sql' ? 'active' : ''}`}
           onClick={() => setMode('sql')}
@@ -80,6 +99,8 @@ const QueryEditor: React.FC<QueryEditorProps> = ({ onExecute, disabled }) => {
         placeholder={
           mode === 'css'
             ? 'Enter CSS selector (e.g., div.container > p.intro)'
+            : mode === 'xpath'
+            ? 'Enter XPath expression (e.g., //book[@category="fiction"]/title)'
             : 'Enter SQL query (e.g., SELECT * FROM nodes WHERE tag_name = "div")'
         }
         rows={6}
@@ -92,7 +113,7 @@ const QueryEditor: React.FC<QueryEditorProps> = ({ onExecute, disabled }) => {
           onClick={handleExecute}
           disabled={disabled || !query.trim()}
         >
-          {mode === 'css' ? '🔍 Query Selector' : '▶️ Execute SQL'}
+          {mode === 'css' ? '🔍 Query Selector' : mode === 'xpath' ? '🧭 Evaluate XPath' : '▶️ Execute SQL'}
         </button>
         <button
           className="clear-button"
@@ -1,12 +1,28 @@
-import React, { useState } from 'react';
-import { QueryResult, ExportFormat } from '../types';
+import React, { useEffect, useState } from 'react';
+import { QueryResult, ExportFormat, NodeLocator } from '../types';
 
 interface ResultsViewerProps {
   results: QueryResult;
+  /** When set, clicking a row with a node `id` shows selectors for that node. */
+  locateNode?: (nodeId: number) => Promise<NodeLocator>;
 }
 
-const ResultsViewer: React.FC<ResultsViewerProps> = ({ results }) => {
+const ResultsViewer: React.FC<ResultsViewerProps> = ({ results, locateNode }) => {
   const [viewMode, setViewMode] = useState<'table' | 'json'>('table');
+  const [locator, setLocator] = useState<NodeLocator | null>(null);
+  const idColumn = locateNode ? results.columns.indexOf('id') : -1;
+
+  useEffect(() => setLocator(null), [results]);
+
+  const handleRowClick = async (row: any[]) => {
+    if (!locateNode || idColumn < 0) return;
+    try {
+      setLocator(await locateNode(Number(row[idColumn])));
+    } catch (err) {
+      console.error('Failed to build a selector:', err);
+      setLocator(null);
+    }
+  };
 
   const exportResults = (format: ExportFormat) => {
     let content: string;
@@ -93,6 +109,17 @@ const ResultsViewer: React.FC<ResultsViewerProps> = ({ results }) => {
         </div>
       </div>
 
+      {locator && (
+        <div className="node-locator">
+          <span>Selector</span>
+          <code>{locator.selector}</code>
+          <button onClick={() => navigator.clipboard.writeText(locator.selector)}>Copy</button>
+          <span>XPath</span>
+          <code>{locator.xpath}</code>
+          <button onClick={() => navigator.clipboard.writeText(locator.xpath)}>Copy</button>
+        </div>
+      )}
+
       {viewMode === 'table' ? (
         <div className="table-container">
           <table className="results-table">
@@ -105,7 +132,11 @@ const ResultsViewer: React.FC<ResultsViewerProps> = ({ results }) => {
             </thead>
             <tbody>
               {results.rows.map((row, rowIdx) => (
-                <tr key={rowIdx}>
+                <tr
+                  key={rowIdx}
+                  className={idColumn >= 0 ? 'locatable' : undefined}
+                  onClick={() => handleRowClick(row)}
+                >
                   {row.map((cell, cellIdx) => (
                     <td key={cellIdx}>
                       {cell === null ? <span className="null-value">NULL</span> : String(cell)}
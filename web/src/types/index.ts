@@ -5,6 +5,13 @@ export interface NodeData {
   attributes: Record<string, string>;
   parent_id: number | null;
   depth: number;
+  source_line: number | null;
+  source_column: number | null;
+  content_hash: string;
+  subtree_size: number;
+  prefix: string | null;
+  namespaces: [string, string][];
+  attribute_prefixes: Record<string, string>;
 }
 
 export interface QueryResult {
@@ -16,8 +23,16 @@ export interface Document {
   id: number;
   name: string;
   created_at: string;
+  kind: 'xml' | 'html' | 'json';
+  doctype: string | null;
 }
 
-export type QueryMode = 'css' | 'sql';
+/** Selectors that find one node, for copying into scraping rules. */
+export interface NodeLocator {
+  selector: string;
+  xpath: string;
+}
+
+export type QueryMode = 'css' | 'xpath' | 'sql';
 
 export type ExportFormat = 'json' | 'csv' | 'sqlite';